@@ -0,0 +1,8 @@
+use anyhow::Result;
+use gs_client::bot_tool::run_bot_tool;
+use gs_common::geosia_pre_main;
+
+fn main() -> Result<()> {
+    geosia_pre_main();
+    run_bot_tool()
+}
@@ -0,0 +1,568 @@
+//! Headless bots joining a server through the same network code as the game client, without any rendering, for load testing servers.
+
+use std::f32::consts::TAU;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use gs_common::network::metrics::NetworkMetricsRegistry;
+use gs_common::network::replication::{PlayerStateUpdate, PlayerTransformsUpdate};
+use gs_common::network::thread::NetworkThread;
+use gs_common::player::{step_player_movement, MovementWorld, PlayerInput, PlayerMovementState, PLAYER_SPAWN_POSITION};
+use gs_common::prelude::*;
+use gs_common::voxel::plugin::{NetworkVoxelClient, VoxelUniverseBuilder};
+use gs_common::{builtin_game_registries, GameBevyCommand, GameServer, SECONDS_PER_TICK_F32, TICK};
+use gs_schemas::registries::GameRegistries;
+use gs_schemas::voxel::collision::ChunkGroupCollision;
+use gs_schemas::voxel::voxeltypes::BlockRegistry;
+use gs_schemas::GameSide;
+
+use crate::network::{NetworkThreadClientState, ServerConnectionStatus};
+use crate::voxel::{apply_chunk_packet, ClientVoxelUniverse};
+use crate::ClientData;
+
+/// Fraction of the walking speed the bots move at, leaving room for timing jitter in the server's movement validation.
+const BOT_SPEED_FACTOR: f32 = 0.8;
+/// The number of error messages kept per bot, further errors are only counted.
+const MAX_KEPT_ERRORS: usize = 8;
+/// How close to a waypoint a bot has to get before heading to the next one, in blocks.
+const WAYPOINT_REACH: f32 = 0.5;
+
+/// The server the bots join.
+#[derive(Clone)]
+pub enum BotTarget {
+    /// A server running in this process, joined through local connections.
+    InProcess(Arc<GameServer>),
+    /// A remote server, joined over QUIC.
+    Remote(SocketAddr),
+}
+
+/// Settings of a load test.
+#[derive(Clone, Debug)]
+pub struct LoadTestConfig {
+    /// The number of bots to join.
+    pub bots: usize,
+    /// Delay between starting to join two consecutive bots.
+    pub join_interval: Duration,
+    /// How long the test runs for, counted from when the first bot starts joining.
+    pub duration: Duration,
+    /// Side length of the square path around the spawn point each bot walks along.
+    pub path_size: f32,
+}
+
+impl Default for LoadTestConfig {
+    fn default() -> Self {
+        Self {
+            bots: 8,
+            join_interval: Duration::from_millis(250),
+            duration: Duration::from_secs(30),
+            path_size: 16.0,
+        }
+    }
+}
+
+/// What a single bot experienced during a load test.
+#[derive(Clone, Debug, Default)]
+pub struct BotStats {
+    /// The name the bot authenticated with.
+    pub username: String,
+    /// Time taken to connect, authenticate and download the game data, None if the bot never joined.
+    pub join_latency: Option<Duration>,
+    /// Time from starting to join until the first chunk arrived.
+    pub first_chunk_latency: Option<Duration>,
+    /// How long the bot was in game.
+    pub time_in_game: Duration,
+    /// Number of received chunk data packets.
+    pub chunk_packets: u64,
+    /// Total size of the received chunk data packets.
+    pub chunk_bytes: u64,
+    /// Number of distinct chunks held at the end of the test.
+    pub loaded_chunks: usize,
+    /// Number of player state updates sent to the server.
+    pub state_updates: u64,
+    /// Number of player transforms updates received from the server.
+    pub transforms_updates: u64,
+    /// Number of times the server rejected the bot's movement.
+    pub corrections: u64,
+    /// Number of errors encountered.
+    pub error_count: u64,
+    /// The first few error messages.
+    pub errors: Vec<String>,
+}
+
+impl BotStats {
+    fn record_error(&mut self, error: impl fmt::Display) {
+        warn!("{}: {error}", self.username);
+        self.error_count += 1;
+        if self.errors.len() < MAX_KEPT_ERRORS {
+            self.errors.push(error.to_string());
+        }
+    }
+}
+
+/// The results of a load test.
+#[derive(Clone, Debug, Default)]
+pub struct LoadTestReport {
+    /// Statistics of each bot, in joining order.
+    pub bots: Vec<BotStats>,
+    /// How long the test ran for.
+    pub elapsed: Duration,
+}
+
+impl LoadTestReport {
+    /// Number of bots that managed to join the server.
+    pub fn joined(&self) -> usize {
+        self.bots.iter().filter(|bot| bot.join_latency.is_some()).count()
+    }
+
+    /// Total number of errors encountered by all the bots.
+    pub fn error_count(&self) -> u64 {
+        self.bots.iter().map(|bot| bot.error_count).sum()
+    }
+}
+
+/// Formats the minimum, average and maximum of the given durations.
+fn duration_summary(durations: impl Iterator<Item = Duration>) -> String {
+    let durations: Vec<Duration> = durations.collect();
+    let (Some(min), Some(max)) = (durations.iter().min(), durations.iter().max()) else {
+        return String::from("n/a");
+    };
+    let average = durations.iter().sum::<Duration>() / durations.len() as u32;
+    format!("min {min:.1?}, avg {average:.1?}, max {max:.1?}")
+}
+
+impl fmt::Display for LoadTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chunk_packets: u64 = self.bots.iter().map(|bot| bot.chunk_packets).sum();
+        let chunk_bytes: u64 = self.bots.iter().map(|bot| bot.chunk_bytes).sum();
+        let seconds = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(f, "Load test of {} bot(s) over {:.1?}", self.bots.len(), self.elapsed)?;
+        writeln!(f, "  Joined:             {}/{}", self.joined(), self.bots.len())?;
+        writeln!(
+            f,
+            "  Join latency:       {}",
+            duration_summary(self.bots.iter().filter_map(|bot| bot.join_latency))
+        )?;
+        writeln!(
+            f,
+            "  First chunk after:  {}",
+            duration_summary(self.bots.iter().filter_map(|bot| bot.first_chunk_latency))
+        )?;
+        writeln!(
+            f,
+            "  Chunks received:    {chunk_packets} packets, {:.2} MiB, {:.1} packets/s",
+            chunk_bytes as f64 / (1024.0 * 1024.0),
+            chunk_packets as f64 / seconds
+        )?;
+        writeln!(
+            f,
+            "  Movement:           {} updates sent, {} transforms received, {} corrections",
+            self.bots.iter().map(|bot| bot.state_updates).sum::<u64>(),
+            self.bots.iter().map(|bot| bot.transforms_updates).sum::<u64>(),
+            self.bots.iter().map(|bot| bot.corrections).sum::<u64>()
+        )?;
+        write!(f, "  Errors:             {}", self.error_count())?;
+        for bot in self.bots.iter().filter(|bot| bot.error_count > 0) {
+            for error in &bot.errors {
+                write!(f, "\n    {}: {error}", bot.username)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Joins bots to the target server one by one and lets them walk around until the configured duration passes.
+/// Blocks the calling thread for the duration of the test.
+pub fn run_load_test(target: &BotTarget, config: &LoadTestConfig) -> LoadTestReport {
+    let mut test = LoadTest::start(target.clone(), config.clone());
+    let mut next_tick = Instant::now();
+    while test.elapsed() < config.duration {
+        test.tick();
+
+        next_tick += TICK;
+        match next_tick.checked_duration_since(Instant::now()) {
+            Some(delay) => std::thread::sleep(delay),
+            // Running behind, skip the missed ticks instead of catching up with a burst of updates.
+            None => next_tick = Instant::now(),
+        }
+    }
+    test.finish()
+}
+
+/// A load test in progress, advanced one tick at a time by [`LoadTest::tick`].
+pub struct LoadTest {
+    target: BotTarget,
+    config: LoadTestConfig,
+    started: Instant,
+    bots: Vec<Bot>,
+}
+
+impl LoadTest {
+    /// Starts a load test, the first bot joins on the first tick.
+    pub fn start(target: BotTarget, config: LoadTestConfig) -> Self {
+        Self {
+            target,
+            bots: Vec::with_capacity(config.bots),
+            config,
+            started: Instant::now(),
+        }
+    }
+
+    /// The time since the start of the test.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Starts joining the bots due by now, and updates all the bots by one tick.
+    pub fn tick(&mut self) {
+        while self.bots.len() < self.config.bots && self.elapsed() >= self.config.join_interval * self.bots.len() as u32
+        {
+            let index = self.bots.len();
+            self.bots.push(Bot::join(index, self.target.clone(), &self.config));
+        }
+        for bot in &mut self.bots {
+            bot.update();
+        }
+    }
+
+    /// Statistics of the bots started so far, in joining order.
+    pub fn bot_stats(&self) -> impl Iterator<Item = &BotStats> {
+        self.bots.iter().map(|bot| &bot.stats)
+    }
+
+    /// Disconnects all the bots and returns the results of the test.
+    pub fn finish(self) -> LoadTestReport {
+        let elapsed = self.elapsed();
+        LoadTestReport {
+            bots: self.bots.into_iter().map(Bot::finish).collect(),
+            elapsed,
+        }
+    }
+}
+
+/// A closed loop of waypoints walked along by a bot.
+struct BotPath {
+    waypoints: Vec<Vec3>,
+    next: usize,
+}
+
+impl BotPath {
+    /// A square around the spawn point, rotated differently for each bot to spread them out.
+    fn square(index: usize, count: usize, size: f32) -> Self {
+        let rotation = Quat::from_rotation_y(TAU * index as f32 / count.max(1) as f32);
+        let center = PLAYER_SPAWN_POSITION;
+        let half_size = size / 2.0;
+        let waypoints = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .into_iter()
+            .map(|(x, z)| center + rotation * Vec3::new(x * half_size, 0.0, z * half_size))
+            .collect();
+        Self { waypoints, next: 0 }
+    }
+
+    /// The horizontal direction from `from` towards the next waypoint, moving on to the following waypoint once the
+    /// current one is reached. Only the horizontal position is followed, the height is left to the terrain.
+    fn direction(&mut self, from: Vec3) -> Vec3 {
+        for _ in 0..self.waypoints.len() {
+            let to_target = (self.waypoints[self.next] - from).with_y(0.0);
+            if to_target.length() > WAYPOINT_REACH {
+                return to_target.normalize();
+            }
+            self.next = (self.next + 1) % self.waypoints.len();
+        }
+        Vec3::ZERO
+    }
+}
+
+/// A single simulated player, with its own network thread and chunk storage.
+struct Bot {
+    stats: BotStats,
+    net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+    /// Holds the voxel universe receiving the chunks, and receives the commands of the network thread.
+    world: World,
+    game_commands: StdUnboundedReceiver<Box<GameBevyCommand>>,
+    transforms: StdUnboundedReceiver<PlayerTransformsUpdate>,
+    join_started: Instant,
+    joining: Option<AsyncResult<(GameRegistries, Duration)>>,
+    joined_at: Option<Instant>,
+    lost: bool,
+    pending_tasks: Vec<AsyncResult<()>>,
+    /// The block types of the joined server, used to build the collision data of the received chunks.
+    block_registry: Option<Arc<BlockRegistry>>,
+    collision: ChunkGroupCollision,
+    path: BotPath,
+    movement: PlayerMovementState,
+    tick: u64,
+}
+
+impl Bot {
+    /// Starts connecting a new bot to the target in the background.
+    fn join(index: usize, target: BotTarget, config: &LoadTestConfig) -> Self {
+        let username = format!("Bot{index}");
+        let (control_tx, control_rx) = std_unbounded_channel();
+        let state_username = username.clone();
+        let net_thread = Arc::new(NetworkThread::new(GameSide::Client, move || {
+            NetworkThreadClientState::new(control_tx, NetworkMetricsRegistry::default()).with_username(state_username)
+        }));
+
+        let (transforms_tx, transforms_rx) = std_unbounded_channel();
+        net_thread.insert_datagram_handler(
+            PlayerTransformsUpdate::DATAGRAM_HEADER,
+            Box::new(
+                move |_state, _peer, payload| match PlayerTransformsUpdate::read_datagram(&payload) {
+                    Ok(update) => {
+                        let _ = transforms_tx.send(update);
+                    }
+                    Err(e) => warn!("Invalid player transforms datagram from the server: {e}"),
+                },
+            ),
+        );
+
+        let net_thread2 = Arc::clone(&net_thread);
+        let joining = net_thread.schedule_task(move |state| {
+            Box::pin(async move {
+                let started = Instant::now();
+                match target {
+                    BotTarget::InProcess(server) => {
+                        let pipe = server.create_local_connection().async_wait().await?;
+                        NetworkThreadClientState::connect_locally(state, net_thread2, pipe).await?;
+                    }
+                    BotTarget::Remote(address) => {
+                        NetworkThreadClientState::connect_remotely(state, net_thread2, address).await?;
+                    }
+                }
+                let registries =
+                    NetworkThreadClientState::bootstrap_game_data(state, &builtin_game_registries()).await?;
+                Ok((registries, started.elapsed()))
+            })
+        });
+
+        Self {
+            stats: BotStats {
+                username,
+                ..Default::default()
+            },
+            net_thread,
+            world: World::new(),
+            game_commands: control_rx,
+            transforms: transforms_rx,
+            join_started: Instant::now(),
+            joining: Some(joining),
+            joined_at: None,
+            lost: false,
+            pending_tasks: Vec::new(),
+            block_registry: None,
+            collision: ChunkGroupCollision::new(),
+            path: BotPath::square(index, config.bots, config.path_size),
+            movement: PlayerMovementState::new(PLAYER_SPAWN_POSITION),
+            tick: 0,
+        }
+    }
+
+    /// Processes everything received since the last update, and walks one tick along the path.
+    fn update(&mut self) {
+        if self.lost {
+            return;
+        }
+        if let Some(joining) = &mut self.joining {
+            match joining.poll() {
+                None => return,
+                Some(Ok(_)) => {
+                    let Some(joining) = self.joining.take() else { return };
+                    match joining.blocking_wait() {
+                        Ok((registries, latency)) => self.enter_game(registries, latency),
+                        Err(e) => self.lose(format!("Could not join the server: {e:#}")),
+                    }
+                }
+                Some(Err(e)) => {
+                    let message = format!("Could not join the server: {e:#}");
+                    self.joining = None;
+                    self.lose(message);
+                }
+            }
+            return;
+        }
+
+        while let Ok(command) = self.game_commands.try_recv() {
+            command(&mut self.world);
+        }
+        if let Some(ServerConnectionStatus::Lost { reason }) = self.world.get_resource::<ServerConnectionStatus>() {
+            let message = format!("Lost the connection to the server: {reason}");
+            self.lose(message);
+            return;
+        }
+
+        self.receive_chunks();
+        while let Ok(update) = self.transforms.try_recv() {
+            self.stats.transforms_updates += 1;
+            if let Some(position) = update.correction {
+                self.stats.corrections += 1;
+                self.movement = PlayerMovementState::new(position);
+            }
+        }
+        self.walk();
+
+        let stats = &mut self.stats;
+        self.pending_tasks.retain_mut(|task| match task.poll() {
+            None => true,
+            Some(Ok(())) => false,
+            Some(Err(e)) => {
+                stats.record_error(format!("{e:#}"));
+                false
+            }
+        });
+    }
+
+    fn enter_game(&mut self, registries: GameRegistries, latency: Duration) {
+        info!("{} joined the server in {latency:.1?}", self.stats.username);
+        self.stats.join_latency = Some(latency);
+        self.joined_at = Some(Instant::now());
+        let universe = VoxelUniverseBuilder::<ClientData>::new(
+            &mut self.world,
+            Arc::clone(&registries.block_types),
+            Arc::clone(&registries.biome_types),
+        )
+        .and_then(|builder| builder.with_network_client(&self.net_thread));
+        if let Err(e) = universe {
+            self.lose(format!("Could not set up the voxel universe: {e:#}"));
+            return;
+        }
+        self.block_registry = Some(Arc::clone(&registries.block_types));
+        self.pending_tasks.push(self.net_thread.schedule_task(|state| {
+            Box::pin(async move {
+                NetworkThreadClientState::allow_streams(state).await;
+                Ok(())
+            })
+        }));
+    }
+
+    fn lose(&mut self, reason: String) {
+        self.stats.record_error(reason);
+        self.lost = true;
+    }
+
+    fn receive_chunks(&mut self) {
+        let mut voxel_q = self
+            .world
+            .query::<(&mut NetworkVoxelClient<ClientData>, &mut ClientVoxelUniverse)>();
+        for (mut nvc, mut voxels) in voxel_q.iter_mut(&mut self.world) {
+            while let Ok(packet) = nvc.chunk_packet_receiver.try_recv() {
+                self.stats.chunk_packets += 1;
+                self.stats.chunk_bytes += packet.len() as u64;
+                if self.stats.first_chunk_latency.is_none() {
+                    self.stats.first_chunk_latency = Some(self.join_started.elapsed());
+                }
+                if let Err(e) = apply_chunk_packet(&packet, voxels.loaded_chunks_mut()) {
+                    self.stats.record_error(format!("Invalid chunk packet: {e:#}"));
+                }
+            }
+            self.stats.loaded_chunks = voxels.loaded_chunks().chunks.len();
+            if let Some(registry) = &self.block_registry {
+                self.collision.update(voxels.loaded_chunks(), registry);
+            }
+        }
+    }
+
+    /// Walks one tick along the path with the same physics as players, standing still until the chunks around the bot
+    /// are received.
+    fn walk(&mut self) {
+        let input = PlayerInput {
+            movement: self.path.direction(self.movement.position) * BOT_SPEED_FACTOR,
+            ..default()
+        };
+        let mut voxel_q = self.world.query::<&ClientVoxelUniverse>();
+        if let (Ok(voxels), Some(registry)) = (voxel_q.get_single(&self.world), &self.block_registry) {
+            let world = MovementWorld::new(voxels.loaded_chunks(), &self.collision, registry);
+            step_player_movement(&world, &mut self.movement, &input, SECONDS_PER_TICK_F32);
+        }
+        let velocity = self.movement.velocity;
+        let yaw = if velocity.xz().length_squared() > 0.0 {
+            f32::atan2(-velocity.x, -velocity.z)
+        } else {
+            0.0
+        };
+        self.tick += 1;
+        let datagram = PlayerStateUpdate {
+            tick: self.tick,
+            position: self.movement.position,
+            velocity,
+            yaw,
+            pitch: 0.0,
+        }
+        .write_datagram();
+        self.stats.state_updates += 1;
+        self.pending_tasks.push(self.net_thread.schedule_task(move |state| {
+            Box::pin(async move {
+                // Nothing is sent while reconnecting.
+                if let Some(result) = state
+                    .borrow()
+                    .send_datagram(&PlayerStateUpdate::DATAGRAM_HEADER, &datagram)
+                {
+                    result?;
+                }
+                Ok(())
+            })
+        }));
+    }
+
+    /// Disconnects the bot and returns its final statistics.
+    fn finish(mut self) -> BotStats {
+        self.stats.time_in_game = self.joined_at.map(|joined| joined.elapsed()).unwrap_or_default();
+        self.net_thread.sync_shutdown();
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// How long the bots get to join, receive chunks and start walking.
+    const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn bots_join_and_receive_chunks() {
+        let server = GameServer::new_test();
+        server.set_paused(false);
+        let config = LoadTestConfig {
+            bots: 2,
+            join_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut test = LoadTest::start(BotTarget::InProcess(Arc::clone(&server)), config);
+        let settled = |test: &LoadTest| {
+            test.bot_stats().count() == 2
+                && test
+                    .bot_stats()
+                    .all(|bot| (bot.loaded_chunks > 0 && bot.state_updates > 0) || bot.error_count > 0)
+        };
+        while !settled(&test) && test.elapsed() < JOIN_TIMEOUT {
+            test.tick();
+            std::thread::sleep(TICK);
+        }
+        let report = test.finish();
+        server.shutdown().blocking_wait().unwrap();
+
+        assert_eq!(report.joined(), 2, "{report}");
+        assert_eq!(report.error_count(), 0, "{report}");
+        for bot in &report.bots {
+            assert!(bot.chunk_packets > 0, "{} received no chunks\n{report}", bot.username);
+            assert!(bot.loaded_chunks > 0, "{} holds no chunks\n{report}", bot.username);
+            assert!(bot.state_updates > 0, "{} did not move\n{report}", bot.username);
+        }
+    }
+
+    #[test]
+    fn path_loops_around() {
+        let mut path = BotPath::square(0, 1, 2.0);
+        let corners = path.waypoints.clone();
+        for (i, &corner) in corners.iter().enumerate() {
+            let next = corners[(i + 1) % corners.len()];
+            let direction = path.direction(corner);
+            assert!(
+                direction.abs_diff_eq((next - corner).normalize(), 1.0e-4),
+                "{direction} from {corner}"
+            );
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! The headless load testing bot tool main() implementation
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::Parser;
+use gs_common::config::{GameConfig, ServerConfig};
+use gs_common::prelude::*;
+use gs_common::GameServer;
+
+use crate::bot::{run_load_test, BotTarget, LoadTestConfig};
+
+#[derive(Parser)]
+#[command(name = "gs_bot", about = "Geosia headless load testing bots")]
+struct CliOptions {
+    /// The server to join, a server is started in this process if not given.
+    #[arg(long, value_name = "ADDRESS")]
+    server: Option<SocketAddr>,
+    /// The number of bots to join.
+    #[arg(long, default_value_t = 8)]
+    bots: usize,
+    /// Milliseconds between joining two bots.
+    #[arg(long, default_value_t = 250)]
+    join_interval: u64,
+    /// Seconds to run the test for.
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+    /// Side length of the square path around the spawn point each bot walks along.
+    #[arg(long, default_value_t = 16.0)]
+    path_size: f32,
+}
+
+/// Starts the bot tool CLI, fails if not all of the bots could join.
+pub fn run_bot_tool() -> Result<()> {
+    let cli = CliOptions::parse();
+    let config = LoadTestConfig {
+        bots: cli.bots,
+        join_interval: Duration::from_millis(cli.join_interval),
+        duration: Duration::from_secs(cli.duration),
+        path_size: cli.path_size,
+    };
+
+    let (target, in_process_server) = match cli.server {
+        Some(address) => (BotTarget::Remote(address), None),
+        None => {
+            let game_config = GameConfig {
+                server: ServerConfig {
+                    server_title: String::from("Load test server"),
+                    listen_addresses: Vec::new(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let server = GameServer::new(GameConfig::new_handle(game_config)).context("Could not start the server")?;
+            server.set_paused(false);
+            (BotTarget::InProcess(Arc::clone(&server)), Some(server))
+        }
+    };
+
+    let report = run_load_test(&target, &config);
+    if let Some(server) = in_process_server {
+        server.shutdown().blocking_wait()?;
+    }
+    writeln!(io::stdout().lock(), "{report}")?;
+    ensure!(
+        report.joined() == report.bots.len(),
+        "Only {} of {} bots joined the server",
+        report.joined(),
+        report.bots.len()
+    );
+    Ok(())
+}
@@ -4,11 +4,20 @@
 //
 //THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 
+use std::time::Duration;
+
 use bevy::input::mouse::AccumulatedMouseMotion;
 use bevy::prelude::*;
 use bevy::window::{CursorGrabMode, PrimaryWindow};
+use gs_common::prelude::*;
+use gs_schemas::coordinates::AbsBlockPos;
 
+use crate::player::CameraMode;
 use crate::states::{ClientAppState, InGameSystemSet};
+use crate::ClientNetworkThreadHolder;
+
+/// The minimum time between two queries of the biome under the camera.
+const BIOME_QUERY_INTERVAL: Duration = Duration::from_millis(500);
 
 /// Mouse sensitivity and movement speed
 #[derive(Resource)]
@@ -36,6 +45,7 @@ pub struct KeyBindings {
     pub move_ascend: KeyCode,
     pub move_descend: KeyCode,
     pub toggle_grab_cursor: KeyCode,
+    pub toggle_fly_cam: KeyCode,
 }
 
 impl Default for KeyBindings {
@@ -48,6 +58,7 @@ impl Default for KeyBindings {
             move_ascend: KeyCode::Space,
             move_descend: KeyCode::ShiftLeft,
             toggle_grab_cursor: KeyCode::Escape,
+            toggle_fly_cam: KeyCode::KeyF,
         }
     }
 }
@@ -104,6 +115,7 @@ fn player_move(
     primary_window: Query<&Window, With<PrimaryWindow>>,
     settings: Res<MovementSettings>,
     key_bindings: Res<KeyBindings>,
+    camera_mode: Res<CameraMode>,
     mut camera_query: Query<(&FlyCam, &mut Transform)>, //    mut query: Query<&mut Transform, With<FlyCam>>,
     mut text_writer: TextUiWriter,
     mut set: ParamSet<(Query<Entity, With<BiomeText>>, Query<Entity, With<PositionText>>)>,
@@ -140,24 +152,14 @@ fn player_move(
 
                 velocity = velocity.normalize_or_zero();
 
-                transform.translation += velocity * time.delta_secs() * settings.speed;
+                // In the player camera mode, the local player is moved instead
+                if *camera_mode == CameraMode::Fly {
+                    transform.translation += velocity * time.delta_secs() * settings.speed;
+                }
             }
             camera_pos = transform.translation;
             camera_angle = transform.rotation;
         }
-        /*
-        for mut text in &mut set.p0() {
-            let i_camera_pos = camera_pos.as_ivec3();
-            let biomes = biome_map.biome_map.get(&[i_camera_pos.x, i_camera_pos.z]);
-            if biomes.is_some() {
-                let mut t = String::new();
-                for (i, biome) in biomes.unwrap().iter().enumerate() {
-                    t += format!("\n  biome #{i}:{{id: {0}, weight: {1}}}", biome.lookup(&biome_registry).unwrap(), biome.weight).as_str();
-                }
-                text.sections[1].value = t;
-            }
-        }
-        */
         for text in &set.p1() {
             *text_writer.text(text, 1) = camera_pos.to_string();
             let euler = camera_angle.to_euler(EulerRot::XYZ);
@@ -270,6 +272,94 @@ fn spawn_debug_text(asset_server: Res<AssetServer>, mut commands: Commands) {
         });
 }
 
+/// The state of the server queries of the biome under the camera.
+#[derive(Default)]
+struct BiomeQueryState {
+    /// The block column of the last query.
+    column: Option<IVec2>,
+    since_last_query: Duration,
+    /// The formatted biome text, once the server responds.
+    pending: Option<AsyncResult<String>>,
+}
+
+/// Asks the server about the biome under the camera whenever it moves to another block column, and shows the answer
+/// in the biome text.
+fn biome_text(
+    time: Res<Time>,
+    net_thread: Option<Res<ClientNetworkThreadHolder>>,
+    camera_query: Query<&Transform, With<FlyCam>>,
+    text_query: Query<Entity, (With<BiomeText>, Without<PositionText>)>,
+    mut text_writer: TextUiWriter,
+    mut query: Local<BiomeQueryState>,
+) {
+    query.since_last_query += time.delta();
+    if let Some(pending) = query.pending.as_mut() {
+        let text = match pending.poll() {
+            None => return,
+            Some(Ok(text)) => text.clone(),
+            Some(Err(e)) => {
+                debug!("Could not query the biome under the camera: {e:#}");
+                // Ask again even if the camera doesn't move.
+                query.column = None;
+                " unknown".to_owned()
+            }
+        };
+        query.pending = None;
+        for entity in &text_query {
+            *text_writer.text(entity, 1) = text.clone();
+        }
+    }
+
+    let (Some(net_thread), Ok(camera)) = (net_thread, camera_query.get_single()) else {
+        return;
+    };
+    let position = AbsBlockPos::from(camera.translation.floor().as_ivec3());
+    let column = IVec2::new(position.x, position.z);
+    if query.column == Some(column) || query.since_last_query < BIOME_QUERY_INTERVAL {
+        return;
+    }
+    query.column = Some(column);
+    query.since_last_query = Duration::ZERO;
+    query.pending = Some(net_thread.0.schedule_task(move |state| {
+        Box::pin(async move {
+            let auth_rpc = state.borrow().server_auth_rpc().cloned();
+            let metrics = state.borrow().metrics().cloned();
+            let (Some(auth_rpc), Some(metrics)) = (auth_rpc, metrics) else {
+                bail!("Not connected to a server");
+            };
+            let mut request = auth_rpc.query_terrain_request();
+            let mut request_position = request.get().init_position();
+            request_position.set_x(position.x);
+            request_position.set_y(position.y);
+            request_position.set_z(position.z);
+            let response = metrics.time_rpc(request.send().promise).await?;
+            format_terrain_info(response.get()?.get_info()?)
+        })
+    }));
+}
+
+fn format_terrain_info(info: rpc::terrain_info::Reader) -> Result<String> {
+    let mut text = String::new();
+    for (i, biome) in info.get_biomes()?.iter().enumerate() {
+        let name = biome.get_biome()?;
+        text += &format!(
+            "{}{}:{} {:.0}%",
+            if i == 0 { " " } else { ", " },
+            name.get_ns()?.to_str()?,
+            name.get_key()?.to_str()?,
+            biome.get_weight() * 100.0
+        );
+    }
+    text += &format!(
+        " (temperature {:.2}, moisture {:.2}, elevation {:.2}, surface at Y={})",
+        info.get_temperature(),
+        info.get_moisture(),
+        info.get_elevation(),
+        info.get_surface_height()
+    );
+    Ok(text)
+}
+
 /// Contains everything needed to add first-person fly camera behavior to your game
 pub struct PlayerPlugin;
 impl Plugin for PlayerPlugin {
@@ -281,6 +371,7 @@ impl Plugin for PlayerPlugin {
             .add_systems(OnEnter(ClientAppState::InGame), spawn_debug_text)
             .add_systems(Update, player_move.in_set(InGameSystemSet))
             .add_systems(Update, player_look.in_set(InGameSystemSet))
+            .add_systems(Update, biome_text.in_set(InGameSystemSet))
             .add_systems(Update, cursor_grab.in_set(InGameSystemSet));
     }
 }
@@ -296,6 +387,7 @@ impl Plugin for NoCameraPlayerPlugin {
             .add_systems(OnEnter(ClientAppState::InGame), spawn_debug_text)
             .add_systems(Update, player_move.in_set(InGameSystemSet))
             .add_systems(Update, player_look.in_set(InGameSystemSet))
+            .add_systems(Update, biome_text.in_set(InGameSystemSet))
             .add_systems(Update, cursor_grab.in_set(InGameSystemSet));
     }
 }
@@ -0,0 +1,526 @@
+//! Block targeting, the block hotbar and place/break interactions of the local player, including multi-block tools.
+
+use bevy::input::mouse::AccumulatedMouseScroll;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::window::{CursorGrabMode, PrimaryWindow};
+use gs_common::network::thread::NetworkThread;
+use gs_common::network::MAX_BLOCK_EDITS_PER_REQUEST;
+use gs_common::prelude::*;
+use gs_common::voxel::edit_ops::{EditOperation, EditTool, BLOCK_REACH};
+use gs_common::voxel::plugin::BlockRegistryHolder;
+use gs_schemas::coordinates::{AbsBlockPos, AbsChunkPos};
+use gs_schemas::dependencies::itertools::Itertools;
+use gs_schemas::direction::OctahedralOrientation;
+use gs_schemas::registry::RegistryName;
+use gs_schemas::voxel::raycast::RaycastBlockHit;
+use gs_schemas::voxel::standard_shapes::{
+    StandardShapeMetadata, STANDARD_SHAPE_CORNER, STANDARD_SHAPE_CUBE, STANDARD_SHAPE_INNER_CORNER,
+    STANDARD_SHAPE_SLOPE,
+};
+use gs_schemas::voxel::voxeltypes::{BlockEntry, BlockRegistry, BlockShapeSet, EMPTY_BLOCK_NAME};
+use gs_schemas::GameSide;
+
+use crate::debugcam::FlyCam;
+use crate::network::NetworkThreadClientState;
+use crate::states::{ClientAppState, InGameSystemSet};
+use crate::voxel::{discard_predictions, ClientVoxelUniverse};
+use crate::ClientNetworkThreadHolder;
+
+/// The standard shapes selectable from the hotbar, in cycling order.
+const HOTBAR_SHAPES: [(u16, &str); 4] = [
+    (STANDARD_SHAPE_CUBE, "cube"),
+    (STANDARD_SHAPE_SLOPE, "slope"),
+    (STANDARD_SHAPE_CORNER, "corner"),
+    (STANDARD_SHAPE_INNER_CORNER, "inner corner"),
+];
+
+/// Key configuration for block interactions.
+#[derive(Resource)]
+pub struct InteractionBindings {
+    /// Removes the targeted block.
+    pub break_block: MouseButton,
+    /// Places the selected block against the targeted face.
+    pub place_block: MouseButton,
+    /// Selects the material, shape and orientation of the targeted block in the hotbar.
+    pub pick_block: MouseButton,
+    /// Cycles the selected standard shape.
+    pub next_shape: KeyCode,
+    /// Cycles the selected orientation.
+    pub next_orientation: KeyCode,
+    /// Cycles between single block editing and the multi-block tools.
+    pub next_tool: KeyCode,
+    /// Increases the size of the selected tool.
+    pub grow_tool: KeyCode,
+    /// Decreases the size of the selected tool.
+    pub shrink_tool: KeyCode,
+    /// Selects the hotbar slots by index.
+    pub slots: [KeyCode; 9],
+}
+
+impl Default for InteractionBindings {
+    fn default() -> Self {
+        Self {
+            break_block: MouseButton::Left,
+            place_block: MouseButton::Right,
+            pick_block: MouseButton::Middle,
+            next_shape: KeyCode::KeyR,
+            next_orientation: KeyCode::KeyT,
+            next_tool: KeyCode::KeyG,
+            grow_tool: KeyCode::Equal,
+            shrink_tool: KeyCode::Minus,
+            slots: [
+                KeyCode::Digit1,
+                KeyCode::Digit2,
+                KeyCode::Digit3,
+                KeyCode::Digit4,
+                KeyCode::Digit5,
+                KeyCode::Digit6,
+                KeyCode::Digit7,
+                KeyCode::Digit8,
+                KeyCode::Digit9,
+            ],
+        }
+    }
+}
+
+/// The block variant the player is currently building with.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct Hotbar {
+    /// The materials available in the hotbar slots.
+    pub materials: Vec<RegistryName>,
+    /// Index of the selected material slot.
+    pub selected: usize,
+    /// Index into the standard shape list.
+    pub shape_index: usize,
+    /// The selected block orientation.
+    pub orientation: OctahedralOrientation,
+    /// The selected multi-block tool, or `None` for editing single blocks.
+    pub tool: Option<EditTool>,
+    /// The size of the selected tool.
+    pub tool_size: u8,
+}
+
+impl Hotbar {
+    /// Fills the hotbar with all the drawable block types of the registry.
+    pub fn from_registry(registry: &BlockRegistry) -> Self {
+        Self {
+            materials: registry
+                .iter()
+                .filter(|(_, _, def)| def.has_drawable_mesh)
+                .map(|(_, name, _)| name.to_owned())
+                .collect(),
+            ..default()
+        }
+    }
+
+    /// The standard shape ID selected.
+    pub fn shape(&self) -> u16 {
+        HOTBAR_SHAPES[self.shape_index].0
+    }
+
+    /// Switches to the next multi-block tool, cycling back to single block editing after the last one.
+    pub fn cycle_tool(&mut self) {
+        self.tool = match self.tool {
+            None => EditTool::ALL.first().copied(),
+            Some(tool) => EditTool::ALL.iter().skip_while(|&&t| t != tool).nth(1).copied(),
+        };
+        if let Some(tool) = self.tool {
+            self.tool_size = self.tool_size.clamp(*tool.sizes().start(), *tool.sizes().end());
+        }
+    }
+
+    /// Changes the size of the selected tool by `delta`, within the tool's supported range.
+    pub fn resize_tool(&mut self, delta: i32) {
+        if let Some(tool) = self.tool {
+            let sizes = tool.sizes();
+            self.tool_size = (self.tool_size as i32 + delta).clamp(*sizes.start() as i32, *sizes.end() as i32) as u8;
+        }
+    }
+
+    /// Builds the block entry to place, or `None` if the hotbar is empty or out of sync with the registry.
+    pub fn selected_block(&self, registry: &BlockRegistry) -> Option<BlockEntry> {
+        let name = self.materials.get(self.selected)?;
+        let (id, def) = registry.lookup_name_to_object(name.as_ref())?;
+        let metadata = match def.shape_set {
+            BlockShapeSet::StandardShapedMaterial => {
+                StandardShapeMetadata::from_parts(self.shape(), self.orientation.to_index() as u16)?.to_meta()
+            }
+            _ => 0,
+        };
+        Some(BlockEntry::new(id, metadata))
+    }
+}
+
+/// The block currently targeted by the camera, if any.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct TargetedBlock(pub Option<(RaycastBlockHit, BlockEntry)>);
+
+/// Marker for the outline drawn around the targeted block.
+#[derive(Component)]
+struct TargetOutline;
+
+/// Marker for the hotbar status text.
+#[derive(Component)]
+struct HotbarText;
+
+/// Adds block targeting and place/break interactions to the in-game state.
+pub struct BlockInteractionPlugin;
+
+impl Plugin for BlockInteractionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InteractionBindings>()
+            .init_resource::<Hotbar>()
+            .init_resource::<TargetedBlock>()
+            .add_systems(OnEnter(ClientAppState::InGame), setup_interaction)
+            .add_systems(
+                Update,
+                (
+                    hotbar_input_system,
+                    block_targeting_system,
+                    block_edit_system,
+                    hotbar_text_system,
+                )
+                    .chain()
+                    .in_set(InGameSystemSet),
+            );
+    }
+}
+
+fn setup_interaction(
+    mut commands: Commands,
+    block_registry: Res<BlockRegistryHolder>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.insert_resource(Hotbar::from_registry(&block_registry));
+    commands.insert_resource(TargetedBlock::default());
+
+    commands.spawn((
+        Mesh3d(meshes.add(outline_mesh())),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::BLACK,
+            unlit: true,
+            ..default()
+        })),
+        Transform::default(),
+        Visibility::Hidden,
+        TargetOutline,
+    ));
+
+    let font: Handle<Font> = asset_server.load("fonts/cascadiacode.ttf");
+    commands.spawn((
+        Text::new(""),
+        TextFont::from_font(font).with_font_size(15.0),
+        TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(8.0),
+            left: Val::Px(8.0),
+            ..default()
+        },
+        HotbarText,
+    ));
+}
+
+/// A line mesh of the edges of a unit cube, slightly inflated to avoid z-fighting with the block's faces.
+fn outline_mesh() -> Mesh {
+    const LO: f32 = -0.005;
+    const HI: f32 = 1.005;
+    let positions: Vec<[f32; 3]> = (0..8)
+        .map(|i| {
+            let c = |bit: usize| if (i >> bit) & 1 == 0 { LO } else { HI };
+            [c(0), c(1), c(2)]
+        })
+        .collect();
+    #[rustfmt::skip]
+    let indices = vec![
+        0, 1, 2, 3, 4, 5, 6, 7, // x edges
+        0, 2, 1, 3, 4, 6, 5, 7, // y edges
+        0, 4, 1, 5, 2, 6, 3, 7, // z edges
+    ];
+    Mesh::new(PrimitiveTopology::LineList, RenderAssetUsages::RENDER_WORLD)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_indices(Indices::U16(indices))
+}
+
+fn is_cursor_grabbed(primary_window: &Query<&Window, With<PrimaryWindow>>) -> bool {
+    primary_window
+        .get_single()
+        .is_ok_and(|window| window.cursor_options.grab_mode != CursorGrabMode::None)
+}
+
+fn hotbar_input_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    scroll: Res<AccumulatedMouseScroll>,
+    bindings: Res<InteractionBindings>,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    mut hotbar: ResMut<Hotbar>,
+) {
+    if !is_cursor_grabbed(&primary_window) || hotbar.materials.is_empty() {
+        return;
+    }
+    let nslots = hotbar.materials.len();
+    if let Some(slot) = bindings.slots.iter().position(|&key| keys.just_pressed(key)) {
+        if slot < nslots {
+            hotbar.selected = slot;
+        }
+    }
+    if scroll.delta.y > 0.0 {
+        hotbar.selected = (hotbar.selected + nslots - 1) % nslots;
+    } else if scroll.delta.y < 0.0 {
+        hotbar.selected = (hotbar.selected + 1) % nslots;
+    }
+    if keys.just_pressed(bindings.next_shape) {
+        hotbar.shape_index = (hotbar.shape_index + 1) % HOTBAR_SHAPES.len();
+    }
+    if keys.just_pressed(bindings.next_orientation) {
+        let next = (hotbar.orientation.to_index() + 1) % 24;
+        hotbar.orientation = OctahedralOrientation::try_from_index(next).unwrap_or_default();
+    }
+    if keys.just_pressed(bindings.next_tool) {
+        hotbar.cycle_tool();
+    }
+    if keys.just_pressed(bindings.grow_tool) {
+        hotbar.resize_tool(1);
+    }
+    if keys.just_pressed(bindings.shrink_tool) {
+        hotbar.resize_tool(-1);
+    }
+}
+
+fn block_targeting_system(
+    camera_q: Query<&GlobalTransform, With<FlyCam>>,
+    voxel_q: Query<&ClientVoxelUniverse>,
+    block_registry: Res<BlockRegistryHolder>,
+    mut target: ResMut<TargetedBlock>,
+    mut outline_q: Query<(&mut Transform, &mut Visibility), With<TargetOutline>>,
+) {
+    let hit = match (camera_q.get_single(), voxel_q.get_single()) {
+        (Ok(camera), Ok(voxels)) => {
+            voxels
+                .loaded_chunks()
+                .raycast(camera.translation(), camera.forward().as_vec3(), BLOCK_REACH, |block| {
+                    block.lookup(&block_registry).is_some_and(|def| def.has_drawable_mesh)
+                })
+        }
+        _ => None,
+    };
+    if target.0 != hit {
+        target.0 = hit;
+    }
+
+    for (mut transform, mut visibility) in outline_q.iter_mut() {
+        match &target.0 {
+            Some((hit, _)) => {
+                transform.translation = hit.position.as_vec3();
+                *visibility = Visibility::Visible;
+            }
+            None => {
+                *visibility = Visibility::Hidden;
+            }
+        }
+    }
+}
+
+fn block_edit_system(
+    mouse: Res<ButtonInput<MouseButton>>,
+    bindings: Res<InteractionBindings>,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    target: Res<TargetedBlock>,
+    block_registry: Res<BlockRegistryHolder>,
+    mut hotbar: ResMut<Hotbar>,
+    mut voxel_q: Query<&mut ClientVoxelUniverse>,
+    net_thread: Option<Res<ClientNetworkThreadHolder>>,
+) {
+    if !is_cursor_grabbed(&primary_window) {
+        return;
+    }
+    let Some((hit, targeted_block)) = target.0 else {
+        return;
+    };
+    let registry: &BlockRegistry = &block_registry;
+
+    if mouse.just_pressed(bindings.pick_block) {
+        pick_block(&mut hotbar, registry, targeted_block);
+        return;
+    }
+
+    let breaking = mouse.just_pressed(bindings.break_block);
+    if !breaking && !mouse.just_pressed(bindings.place_block) {
+        return;
+    }
+    let new_block = if breaking {
+        let Some((empty_id, _)) = registry.lookup_name_to_object(EMPTY_BLOCK_NAME.as_ref()) else {
+            return;
+        };
+        BlockEntry::new(empty_id, 0)
+    } else {
+        let Some(block) = hotbar.selected_block(registry) else {
+            return;
+        };
+        block
+    };
+    // Placing starts from the targeted face, breaking and smoothing from the targeted block itself.
+    let anchor = match hotbar.tool {
+        Some(EditTool::Smooth) => Some(hit.position),
+        _ if breaking => Some(hit.position),
+        _ => hit.adjacent_position(),
+    };
+    let Some(anchor) = anchor else {
+        return;
+    };
+
+    let Ok(mut voxels) = voxel_q.get_single_mut() else {
+        return;
+    };
+    let chunks = voxels.loaded_chunks_mut();
+    let mut edits = match hotbar.tool {
+        None => match chunks.get_block(anchor) {
+            Some(old_block) if old_block != new_block => vec![(anchor, new_block)],
+            _ => return,
+        },
+        Some(tool) => {
+            let operation = EditOperation {
+                tool,
+                size: hotbar.tool_size,
+                anchor,
+                orientation: hotbar.orientation,
+                block: new_block,
+            };
+            match operation.plan(chunks, registry) {
+                Ok(edits) => edits,
+                Err(e) => {
+                    warn!("Could not apply {tool:?}: {e}");
+                    return;
+                }
+            }
+        }
+    };
+    // Only replace blocks that can be walked through (empty space, water, etc.) when placing.
+    if !breaking && hotbar.tool != Some(EditTool::Smooth) {
+        edits.retain(|&(pos, _)| {
+            chunks
+                .get_block(pos)
+                .and_then(|old| old.lookup(registry))
+                .is_none_or(|def| !def.has_collision_box)
+        });
+    }
+    if edits.is_empty() {
+        return;
+    }
+
+    // Every request gets its own prediction, so that the server can resolve each of them.
+    for batch in edits.chunks(MAX_BLOCK_EDITS_PER_REQUEST as usize) {
+        chunks.apply_block_edits(batch, GameSide::Client);
+        if let Some(net_thread) = &net_thread {
+            send_block_edits(&net_thread.0, batch.to_vec());
+        }
+    }
+}
+
+/// Copies the material, shape and orientation of the given block into the hotbar.
+fn pick_block(hotbar: &mut Hotbar, registry: &BlockRegistry, block: BlockEntry) {
+    let Some(def) = block.lookup(registry) else {
+        return;
+    };
+    if let Some(slot) = hotbar.materials.iter().position(|name| *name == def.name) {
+        hotbar.selected = slot;
+    }
+    if def.shape_set == BlockShapeSet::StandardShapedMaterial {
+        let meta = StandardShapeMetadata::from_meta(block.metadata);
+        if let Some(shape_index) = HOTBAR_SHAPES.iter().position(|&(id, _)| id == meta.shape_bits()) {
+            hotbar.shape_index = shape_index;
+        }
+        hotbar.orientation = meta.orientation();
+    }
+}
+
+/// Sends a block edit request matching one [`gs_schemas::voxel::chunk_group::ChunkGroup::apply_block_edits`] prediction to the server.
+pub fn send_block_edits(net_thread: &NetworkThread<NetworkThreadClientState>, edits: Vec<(AbsBlockPos, BlockEntry)>) {
+    let _ = net_thread.schedule_task(move |state| {
+        Box::pin(async move {
+            let auth_rpc = state.borrow().server_auth_rpc().cloned();
+            let metrics = state.borrow().metrics().cloned();
+            let (Some(auth_rpc), Some(metrics)) = (auth_rpc, metrics) else {
+                bail!("Not connected to a server");
+            };
+            let mut request = auth_rpc.edit_blocks_request();
+            let mut edits_builder = request.get().init_edits(edits.len() as u32);
+            for (i, &(pos, block)) in edits.iter().enumerate() {
+                let mut edit = edits_builder.reborrow().get(i as u32);
+                let mut position = edit.reborrow().init_position();
+                position.set_x(pos.x);
+                position.set_y(pos.y);
+                position.set_z(pos.z);
+                edit.set_block(block.as_packed());
+            }
+            let discarded: Vec<AbsChunkPos> = match metrics.time_rpc(request.send().promise).await {
+                Ok(response) => response
+                    .get()?
+                    .get_skipped()?
+                    .iter()
+                    .map(|skipped| {
+                        let position = skipped.get_position()?;
+                        let position = AbsChunkPos::new(position.get_x(), position.get_y(), position.get_z());
+                        debug!(
+                            "The server skipped block edits in chunk {position} at revision {}",
+                            skipped.get_revision()
+                        );
+                        Ok(position)
+                    })
+                    .collect::<Result<_>>()?,
+                Err(e) => {
+                    // A rejected batch still gets a new revision for every touched chunk, so the server sends them
+                    // again, possibly before this response.
+                    error!("Block edit request failed: {e}");
+                    edits.iter().map(|&(pos, _)| AbsChunkPos::from(pos)).unique().collect()
+                }
+            };
+            // The predictions in these chunks will never be resolved by the server, unless it corrected them already.
+            if !discarded.is_empty() {
+                let _ = state.borrow().game_control().send(Box::new(move |world: &mut World| {
+                    discard_predictions(world, &discarded)
+                }));
+            }
+            Ok(())
+        })
+    });
+}
+
+fn hotbar_text_system(
+    hotbar: Res<Hotbar>,
+    target: Res<TargetedBlock>,
+    block_registry: Res<BlockRegistryHolder>,
+    mut text_q: Query<&mut Text, With<HotbarText>>,
+) {
+    if !hotbar.is_changed() && !target.is_changed() {
+        return;
+    }
+    let mut status = String::new();
+    for (i, name) in hotbar.materials.iter().enumerate() {
+        if i == hotbar.selected {
+            status += &format!("[{}:{}] ", i + 1, name.key);
+        } else {
+            status += &format!(" {}:{}  ", i + 1, name.key);
+        }
+    }
+    let (_, shape_name) = HOTBAR_SHAPES[hotbar.shape_index];
+    status += &format!("\nShape: {shape_name}, orientation: #{}", hotbar.orientation.to_index());
+    match hotbar.tool {
+        Some(tool) => status += &format!(", tool: {tool:?} ({})", hotbar.tool_size),
+        None => status += ", tool: single block",
+    }
+    if let Some((hit, block)) = target.0 {
+        let name = block
+            .lookup(&block_registry)
+            .map(|def| def.name.to_string())
+            .unwrap_or_else(|| block.id.to_string());
+        status += &format!("\nTarget: {name} at {}", hit.position);
+    }
+    for mut text in text_q.iter_mut() {
+        text.0.clone_from(&status);
+    }
+}
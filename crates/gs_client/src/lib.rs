@@ -7,8 +7,14 @@
 #![allow(clippy::type_complexity)]
 
 //! The clientside of Geosia
+pub mod bot;
+pub mod bot_tool;
 mod debugcam;
+pub mod interaction;
+pub mod netstats;
 pub mod network;
+pub mod player;
+pub mod remote_players;
 pub mod states;
 pub mod voxel;
 
@@ -34,6 +40,7 @@ use bevy::window::{ExitCondition, PresentMode};
 use bevy::winit::WinitPlugin;
 use bevy_egui::EguiPlugin;
 use gs_common::network::thread::NetworkThread;
+use gs_common::player::PlayerMovementPlugin;
 use gs_common::prelude::*;
 use gs_common::voxel::plugin::VoxelUniversePlugin;
 use gs_common::{GameBevyCommand, GAME_BRAND_NAME};
@@ -139,7 +146,12 @@ pub fn client_main() {
     configure_sets(&mut app, FixedPostUpdate);
 
     app.add_plugins(debugcam::PlayerPlugin)
+        .add_plugins(player::LocalPlayerPlugin)
+        .add_plugins(remote_players::RemotePlayersPlugin)
+        .add_plugins(interaction::BlockInteractionPlugin)
+        .add_plugins(netstats::NetworkStatsOverlayPlugin)
         .add_plugins(VoxelUniversePlugin::<ClientData>::new())
+        .add_plugins(PlayerMovementPlugin::<ClientData>::new())
         .add_plugins(states::main_menu::MainMenuPlugin)
         .add_plugins(states::loading_game::LoadingGamePlugin)
         .add_plugins(states::in_game::InGamePlugin);
@@ -0,0 +1,82 @@
+//! The in-game network statistics overlay, toggled with F3.
+
+use bevy::diagnostic::{DiagnosticPath, DiagnosticsStore};
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use gs_common::network::metrics::{
+    NetworkDiagnosticsPlugin, NetworkMetricsRegistry, NETWORK_BACKPRESSURE_STALLS, NETWORK_BYTES_IN, NETWORK_BYTES_OUT,
+    NETWORK_CHUNK_PACKETS_QUEUED, NETWORK_RPC_LATENCY, NETWORK_RTT,
+};
+
+use crate::states::InGameSystemSet;
+
+/// The key toggling the network statistics overlay.
+pub const NETSTATS_TOGGLE_KEY: KeyCode = KeyCode::F3;
+
+/// Shows the connection's traffic, latency and queue statistics on top of the game.
+pub struct NetworkStatsOverlayPlugin;
+
+impl Plugin for NetworkStatsOverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(NetworkDiagnosticsPlugin)
+            .init_resource::<NetworkStatsOverlayVisible>()
+            .add_systems(
+                Update,
+                (toggle_netstats_overlay, netstats_overlay)
+                    .chain()
+                    .in_set(InGameSystemSet),
+            );
+    }
+}
+
+/// Whether the network statistics overlay is shown.
+#[derive(Resource, Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct NetworkStatsOverlayVisible(pub bool);
+
+fn toggle_netstats_overlay(keys: Res<ButtonInput<KeyCode>>, mut visible: ResMut<NetworkStatsOverlayVisible>) {
+    if keys.just_pressed(NETSTATS_TOGGLE_KEY) {
+        visible.0 = !visible.0;
+    }
+}
+
+fn netstats_overlay(
+    mut contexts: EguiContexts,
+    visible: Res<NetworkStatsOverlayVisible>,
+    diagnostics: Res<DiagnosticsStore>,
+    registry: Res<NetworkMetricsRegistry>,
+) {
+    if !visible.0 {
+        return;
+    }
+    let smoothed = |path: &DiagnosticPath| {
+        diagnostics
+            .get(path)
+            .and_then(|diagnostic| diagnostic.smoothed())
+            .unwrap_or_default()
+    };
+    egui::Window::new("Network")
+        .resizable(false)
+        .anchor(egui::Align2::RIGHT_TOP, (-8.0, 8.0))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.monospace(format!(
+                "in {:.1} KiB/s, out {:.1} KiB/s",
+                smoothed(&NETWORK_BYTES_IN) / 1024.0,
+                smoothed(&NETWORK_BYTES_OUT) / 1024.0
+            ));
+            ui.monospace(format!(
+                "rtt {:.1} ms, rpc latency {:.1} ms",
+                smoothed(&NETWORK_RTT),
+                smoothed(&NETWORK_RPC_LATENCY)
+            ));
+            ui.monospace(format!(
+                "chunk packets queued {:.0}, stalls {:.1}/frame",
+                smoothed(&NETWORK_CHUNK_PACKETS_QUEUED),
+                smoothed(&NETWORK_BACKPRESSURE_STALLS)
+            ));
+            for (peer, stats) in registry.snapshot() {
+                ui.separator();
+                ui.label(peer.to_string());
+                ui.monospace(stats.to_string());
+            }
+        });
+}
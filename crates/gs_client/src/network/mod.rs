@@ -1,45 +1,106 @@
 //! The network client thread implementation.
 
 use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
 
 use bevy::log::*;
+use bevy::prelude::{Resource, World};
 use capnp::capability::Promise;
 use capnp::Error;
 use capnp_rpc::rpc_twoparty_capnp::Side;
 use capnp_rpc::twoparty::{VatId, VatNetwork};
 use capnp_rpc::{pry, Disconnector, RpcSystem};
+use futures::future::{self, BoxFuture, LocalBoxFuture};
+use futures::FutureExt;
+use gs_common::network::capture::CaptureDirection;
+use gs_common::network::metrics::{CountingIo, NetworkMetricsRegistry, PeerNetworkMetrics};
+use gs_common::network::protocol::{write_game_version, NegotiatedProtocol, ProtocolInfo};
 use gs_common::network::server::LocalConnectionPipe;
 use gs_common::network::thread::{NetworkThread, NetworkThreadState};
 use gs_common::network::transport::{
-    quinn_client_config, InProcessStream, QuicStream, TransportStream, RPC_CLIENT_READER_OPTIONS,
-    RPC_LOCAL_READER_OPTIONS,
+    quinn_client_config, DatagramReceiver, DatagramSendError, DatagramSender, InProcessStream, QuicStream,
+    StreamOpener, TransportStream, RPC_CLIENT_READER_OPTIONS, RPC_LOCAL_READER_OPTIONS,
 };
 use gs_common::network::PeerAddress;
 use gs_common::prelude::*;
+use gs_schemas::coordinates::AbsChunkPos;
+use gs_schemas::dependencies::uuid::Uuid;
+use gs_schemas::mutwatcher::RevisionNumber;
+use gs_schemas::registries::GameRegistries;
 use gs_schemas::schemas::network_capnp as rpc;
 use gs_schemas::schemas::network_capnp::authenticated_client_connection::{
     AddChatMessageParams, AddChatMessageResults, TerminateConnectionParams, TerminateConnectionResults,
 };
+use gs_schemas::schemas::{NetworkDatagramHeader, NetworkStreamType, SchemaUuidExt};
 use quinn::{Connection, Endpoint, EndpointConfig, RecvStream, SendStream};
 use socket2::{Domain, Socket};
 use tokio::sync::Barrier;
 use tokio::task::{spawn_local, JoinHandle};
 use tracing::Instrument;
 
+use crate::voxel::held_chunk_revisions;
 use crate::GameControlChannel;
 
+#[cfg(target_os = "linux")]
+pub mod server_process;
+
+/// The delay before the first attempt to reconnect to a server after losing the connection.
+pub const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+/// The longest delay between two reconnection attempts, the delay doubles after every failed attempt until reaching it.
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(16);
+/// The number of failed reconnection attempts after which the client gives up.
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// The state of the connection to the server, shown to the player while in game.
+#[derive(Resource, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ServerConnectionStatus {
+    /// Everything is fine.
+    #[default]
+    Connected,
+    /// The connection was lost, and the client is trying to connect again.
+    Reconnecting {
+        /// The number of the current reconnection attempt, starting at 1.
+        attempt: u32,
+        /// Why the connection was lost, or why the previous attempt failed.
+        reason: String,
+    },
+    /// The client gave up on reconnecting.
+    Lost {
+        /// Why the last reconnection attempt failed.
+        reason: String,
+    },
+    /// The singleplayer server running in a separate process stopped, e.g. because it crashed.
+    ServerStopped {
+        /// What happened to the server process.
+        reason: String,
+        /// Whether the server process is still running and can save the world while shutting down, e.g. after the
+        /// connection to it broke.
+        can_save: bool,
+    },
+}
+
 /// Pre-authentication
 pub struct NetworkThreadClientConnectingState {
     /// Address being connected to.
     server_address: PeerAddress,
     /// The RPC object for sending messages to.
     server_rpc: Client2ServerConnection,
+    /// The protocol version and features negotiated with the server.
+    protocol: NegotiatedProtocol,
     /// Object to shut down the RPC system.
     rpc_disconnector: Option<Disconnector<Side>>,
     /// The RPC system task.
     rpc_task: JoinHandle<Result<()>>,
     /// The async stream system task.
     stream_task: JoinHandle<Result<()>>,
+    /// Opens new streams to the server.
+    stream_opener: StreamOpener,
+    /// Unreliable datagrams for the server.
+    datagram_sender: DatagramSender,
+    /// The datagram receiving task.
+    datagram_task: JoinHandle<Result<()>>,
+    /// Traffic statistics of the connection.
+    metrics: Arc<PeerNetworkMetrics>,
 }
 
 /// Post-authentication
@@ -65,14 +126,30 @@ pub enum NetworkThreadClientStateVariant {
 /// The network thread game client state, accessible from network functions.
 pub struct NetworkThreadClientState {
     /// Channel for communicating with the client bevy instance
-    _game_control: GameControlChannel,
+    game_control: GameControlChannel,
     /// The current variant storage.
     variant: NetworkThreadClientStateVariant,
+    /// Statistics of the connections, shared with the bevy world.
+    metrics_registry: NetworkMetricsRegistry,
+    /// The name to authenticate with, defaults to a placeholder depending on the connection type.
+    username: Option<String>,
     ready_to_accept_streams: Option<Arc<Barrier>>,
+    /// The remote server to reconnect to after losing the connection, local connections are never lost.
+    reconnect_address: Option<SocketAddr>,
+    /// The task watching for connection loss and then reconnecting.
+    connection_monitor: Option<JoinHandle<()>>,
+    shutting_down: bool,
 }
 
 impl NetworkThreadState for NetworkThreadClientState {
     async fn shutdown(this: Rc<RefCell<Self>>) {
+        {
+            let mut state = this.borrow_mut();
+            state.shutting_down = true;
+            if let Some(monitor) = state.connection_monitor.take() {
+                monitor.abort();
+            }
+        }
         let disconnector = this
             .borrow_mut()
             .connecting_state_mut()
@@ -85,20 +162,36 @@ impl NetworkThreadState for NetworkThreadClientState {
         if let Some(s) = this.borrow_mut().connecting_state() {
             s.rpc_task.abort();
             s.stream_task.abort();
+            s.datagram_task.abort();
         }
     }
+
+    fn server_protocol(&self) -> Option<NegotiatedProtocol> {
+        self.protocol().copied()
+    }
 }
 
 impl NetworkThreadClientState {
     /// Constructor.
-    pub fn new(game_control: GameControlChannel) -> Self {
+    pub fn new(game_control: GameControlChannel, metrics_registry: NetworkMetricsRegistry) -> Self {
         Self {
-            _game_control: game_control,
+            game_control,
             variant: Default::default(),
+            metrics_registry,
+            username: None,
             ready_to_accept_streams: Some(Arc::new(Barrier::new(2))),
+            reconnect_address: None,
+            connection_monitor: None,
+            shutting_down: false,
         }
     }
 
+    /// Sets the name to authenticate to servers with.
+    pub fn with_username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
     /// Unblocks stream processing, call after all the handlers are registered.
     pub async fn allow_streams(this: &Rc<RefCell<Self>>) {
         let barrier = &this.borrow().ready_to_accept_streams.as_ref().map(Arc::clone);
@@ -137,6 +230,11 @@ impl NetworkThreadClientState {
         }
     }
 
+    /// Returns the channel for running commands on the game's main thread.
+    pub fn game_control(&self) -> &GameControlChannel {
+        &self.game_control
+    }
+
     /// Returns the address of the connected/ing peer.
     pub fn peer_address(&self) -> Option<PeerAddress> {
         self.connecting_state().map(|s| s.server_address)
@@ -147,11 +245,78 @@ impl NetworkThreadClientState {
         self.connecting_state().map(|s| &s.server_rpc)
     }
 
+    /// Opens a fresh stream to the server, handled by the server's handler registered for the stream type.
+    /// Returns a future that actually performs the work to avoid holding the state RefCell borrowed across await points.
+    pub fn open_stream(&self, stream_type: NetworkStreamType) -> BoxFuture<'static, Result<TransportStream>> {
+        match self.connecting_state() {
+            Some(s) => {
+                let metrics = Arc::clone(&s.metrics);
+                s.stream_opener
+                    .open(stream_type)
+                    .map(move |stream| stream.map(|stream| stream.with_metrics(&metrics)))
+                    .boxed()
+            }
+            None => future::ready(Err(anyhow!("Not connected to a server"))).boxed(),
+        }
+    }
+
+    /// Returns the protocol version and features negotiated with the server, if connected.
+    pub fn protocol(&self) -> Option<&NegotiatedProtocol> {
+        self.connecting_state().map(|s| &s.protocol)
+    }
+
+    /// Sends an unreliable datagram to the server, returns None if not connected.
+    pub fn send_datagram(
+        &self,
+        header: &NetworkDatagramHeader,
+        payload: &[u8],
+    ) -> Option<Result<(), DatagramSendError>> {
+        let s = self.connecting_state()?;
+        let result = s.datagram_sender.send(header, payload);
+        if result.is_ok() {
+            s.metrics.record_datagram_out(header, payload);
+        }
+        Some(result)
+    }
+
+    /// Returns the traffic statistics of the server connection, if connected.
+    pub fn metrics(&self) -> Option<&Arc<PeerNetworkMetrics>> {
+        self.connecting_state().map(|s| &s.metrics)
+    }
+
+    /// Starts tracking the statistics of a new connection.
+    fn register_metrics(&self, address: PeerAddress) -> Arc<PeerNetworkMetrics> {
+        self.metrics_registry.register(address)
+    }
+
     /// Returns the authenticated server RPC object, if authenticated.
     pub fn server_auth_rpc(&self) -> Option<&rpc::authenticated_server_connection::Client> {
         self.authenticated_state().map(|s| &s.server_auth_rpc)
     }
 
+    /// Downloads the game data of the authenticated server's world, and maps its registry IDs onto the given registries.
+    pub async fn bootstrap_game_data(
+        this: &Rc<RefCell<Self>>,
+        default_registries: &GameRegistries,
+    ) -> Result<GameRegistries> {
+        let bootstrap_request = this
+            .borrow()
+            .server_auth_rpc()
+            .context("Missing auth endpoint")?
+            .bootstrap_game_data_request();
+        let metrics = this.borrow().metrics().cloned().context("Missing connection metrics")?;
+        let bootstrap_response = metrics
+            .time_rpc(bootstrap_request.send().promise)
+            .await
+            .context("Failed bootstrap request to the remote server")?;
+        let bootstrap_response = bootstrap_response.get()?.get_data()?;
+        let uuid = Uuid::read_from_message(&bootstrap_response.get_universe_id()?);
+        let registries = default_registries.clone_with_serialized_ids(&bootstrap_response)?;
+        let nblocks = registries.block_types.len();
+        info!("Joining server world {uuid} with {nblocks} block types.");
+        Ok(registries)
+    }
+
     /// Initiates a new local connection on the given pipe.
     pub async fn connect_locally(
         this: &Rc<RefCell<Self>>,
@@ -162,24 +327,27 @@ impl NetworkThreadClientState {
             return Err(anyhow!("Already connected to {existing_connection:?}"));
         }
 
-        let (rpc_system, connection) = create_local_rpc_client(address, pipe.rpc_pipe);
+        let metrics = this.borrow().register_metrics(address);
+        let (rpc_system, connection) = create_local_rpc_client(address, pipe.rpc_pipe, Arc::clone(&metrics));
         let rpc_disconnector = rpc_system.get_disconnector();
         let rpc_task: JoinHandle<Result<()>> = spawn_local(
             async move { rpc_system.await.map_err(anyhow::Error::from) }
                 .instrument(tracing::info_span!("client-rpc", address = ?address)),
         );
 
+        let protocol = connection.negotiate_protocol().await?;
+
         // Authenticate
+        let username = this.borrow().username.clone();
         let mut auth_request = connection.server_rpc.authenticate_request();
         {
             let mut builder = auth_request.get();
-            builder.set_username("LocalPlayer");
+            builder.set_username(username.as_deref().unwrap_or("LocalPlayer"));
             let auth_rpc = AuthenticatedClientConnectionImpl {};
             builder.set_connection(capnp_rpc::new_client(auth_rpc));
         }
-        let auth_response = auth_request
-            .send()
-            .promise
+        let auth_response = metrics
+            .time_rpc(auth_request.send().promise)
             .await
             .context("RPC failure to authenticate with integrated server")?;
         let auth_response = auth_response.get().context("Invalid authentication response")?;
@@ -203,8 +371,24 @@ impl NetworkThreadClientState {
         );
 
         let stream_task: JoinHandle<Result<()>> = spawn_local(
-            Self::local_stream_acceptor(Rc::clone(this), Arc::clone(&net_thread), pipe.incoming_streams)
-                .instrument(tracing::info_span!("client-stream", address = ?address)),
+            Self::local_stream_acceptor(
+                Rc::clone(this),
+                Arc::clone(&net_thread),
+                address,
+                Arc::clone(&metrics),
+                pipe.incoming_streams,
+            )
+            .instrument(tracing::info_span!("client-stream", address = ?address)),
+        );
+        let datagram_task: JoinHandle<Result<()>> = spawn_local(
+            Self::datagram_receiver(
+                Rc::clone(this),
+                Arc::clone(&net_thread),
+                address,
+                Arc::clone(&metrics),
+                DatagramReceiver::Process(pipe.incoming_datagrams),
+            )
+            .instrument(tracing::info_span!("client-datagram", address = ?address)),
         );
 
         this.borrow_mut().variant =
@@ -212,9 +396,14 @@ impl NetworkThreadClientState {
                 connection: NetworkThreadClientConnectingState {
                     server_address: connection.server_addr,
                     server_rpc: connection,
+                    protocol,
                     rpc_disconnector: Some(rpc_disconnector),
                     rpc_task,
                     stream_task,
+                    stream_opener: StreamOpener::Process(pipe.outgoing_streams),
+                    datagram_sender: DatagramSender::Process(pipe.outgoing_datagrams),
+                    datagram_task,
+                    metrics,
                 },
                 server_auth_rpc,
             });
@@ -222,7 +411,7 @@ impl NetworkThreadClientState {
         Ok(())
     }
 
-    /// Initiates a new remote connection to the given address.
+    /// Initiates a new remote connection to the given address, reconnecting automatically if it gets lost.
     pub async fn connect_remotely(
         this: &Rc<RefCell<Self>>,
         net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
@@ -231,7 +420,17 @@ impl NetworkThreadClientState {
         if let Some(existing_connection) = this.borrow().peer_address() {
             return Err(anyhow!("Already connected to {existing_connection:?}"));
         }
+        Self::open_remote_connection(this, net_thread, remote_address, &[]).await?;
+        this.borrow_mut().reconnect_address = Some(remote_address);
+        Ok(())
+    }
 
+    async fn open_remote_connection(
+        this: &Rc<RefCell<Self>>,
+        net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+        remote_address: SocketAddr,
+        held_chunks: &[(AbsChunkPos, RevisionNumber)],
+    ) -> Result<()> {
         let socket = Socket::new(Domain::IPV6, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
         socket.set_only_v6(false)?;
         socket.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into())?;
@@ -252,24 +451,104 @@ impl NetworkThreadClientState {
             remote: quic_connection.remote_address(),
         };
 
-        let (rpc_system, connection) = create_quic_rpc_client(address, rpc_tx, rpc_rx);
+        let metrics = this.borrow().register_metrics(address);
+        metrics.set_quic_connection(quic_connection.clone());
+        let (rpc_system, connection) = create_quic_rpc_client(address, rpc_tx, rpc_rx, Arc::clone(&metrics));
         let rpc_disconnector = rpc_system.get_disconnector();
         let rpc_task: JoinHandle<Result<()>> = spawn_local(
             async move { rpc_system.await.map_err(anyhow::Error::from) }
                 .instrument(tracing::info_span!("client-rpc", address = ?address)),
         );
 
-        // Authenticate
+        let authenticated = Self::authenticate_remotely(this, &connection, &metrics, held_chunks).await;
+        let (protocol, server_auth_rpc) = match authenticated {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
+                // Don't leave a half-open connection behind, e.g. between reconnection attempts.
+                rpc_task.abort();
+                quic_connection.close(0u32.into(), b"Connection setup failed");
+                this.borrow().metrics_registry.remove(address);
+                return Err(e);
+            }
+        };
+
+        info!("Authenticated to the remote server via {:?}", connection.server_addr);
+
+        let stream_task: JoinHandle<Result<()>> = spawn_local(
+            Self::remote_stream_acceptor(
+                Rc::clone(this),
+                Arc::clone(&net_thread),
+                address,
+                Arc::clone(&metrics),
+                quic_connection.clone(),
+            )
+            .instrument(tracing::info_span!("client-stream", address = ?address)),
+        );
+        let datagram_task: JoinHandle<Result<()>> = spawn_local(
+            Self::datagram_receiver(
+                Rc::clone(this),
+                Arc::clone(&net_thread),
+                address,
+                Arc::clone(&metrics),
+                DatagramReceiver::Network(quic_connection.clone()),
+            )
+            .instrument(tracing::info_span!("client-datagram", address = ?address)),
+        );
+        let connection_monitor = spawn_local(
+            Self::connection_monitor(Rc::clone(this), Arc::clone(&net_thread), quic_connection.clone())
+                .instrument(tracing::info_span!("client-monitor", address = ?address)),
+        );
+        this.borrow_mut().connection_monitor = Some(connection_monitor);
+
+        this.borrow_mut().variant =
+            NetworkThreadClientStateVariant::Authenticated(NetworkThreadClientAuthenticatedState {
+                connection: NetworkThreadClientConnectingState {
+                    server_address: connection.server_addr,
+                    server_rpc: connection,
+                    protocol,
+                    rpc_disconnector: Some(rpc_disconnector),
+                    rpc_task,
+                    stream_task,
+                    stream_opener: StreamOpener::Network(quic_connection.clone()),
+                    datagram_sender: DatagramSender::Network(quic_connection),
+                    datagram_task,
+                    metrics,
+                },
+                server_auth_rpc,
+            });
+
+        Ok(())
+    }
+
+    /// Negotiates the protocol and authenticates on a new remote connection, telling the server which chunks are held.
+    async fn authenticate_remotely(
+        this: &Rc<RefCell<Self>>,
+        connection: &Client2ServerConnection,
+        metrics: &PeerNetworkMetrics,
+        held_chunks: &[(AbsChunkPos, RevisionNumber)],
+    ) -> Result<(NegotiatedProtocol, rpc::authenticated_server_connection::Client)> {
+        let protocol = connection.negotiate_protocol().await?;
+
+        let username = this.borrow().username.clone();
         let mut auth_request = connection.server_rpc.authenticate_request();
         {
             let mut builder = auth_request.get();
-            builder.set_username("InternetPlayer");
+            builder.set_username(username.as_deref().unwrap_or("InternetPlayer"));
+
             let auth_rpc = AuthenticatedClientConnectionImpl {};
             builder.set_connection(capnp_rpc::new_client(auth_rpc));
+            let mut held_builder = builder.init_held_chunks(held_chunks.len() as u32);
+            for (i, &(position, revision)) in held_chunks.iter().enumerate() {
+                let mut held = held_builder.reborrow().get(i as u32);
+                held.set_revision(revision.get());
+                let mut held_position = held.init_position();
+                held_position.set_x(position.x);
+                held_position.set_y(position.y);
+                held_position.set_z(position.z);
+            }
         }
-        let auth_response = auth_request
-            .send()
-            .promise
+        let auth_response = metrics
+            .time_rpc(auth_request.send().promise)
             .await
             .context("RPC failure to authenticate with remote server")?;
         let auth_response = auth_response.get().context("Invalid authentication response")?;
@@ -287,50 +566,138 @@ impl NetworkThreadClientState {
             }
         };
 
-        info!("Authenticated to the remote server via {:?}", connection.server_addr);
+        Ok((protocol, server_auth_rpc))
+    }
 
-        let stream_task: JoinHandle<Result<()>> = spawn_local(
-            Self::remote_stream_acceptor(Rc::clone(this), Arc::clone(&net_thread), quic_connection.clone())
-                .instrument(tracing::info_span!("client-stream", address = ?address)),
-        );
+    /// Waits for the connection to get lost (e.g. after the idle timeout without any keep-alive packets arriving), then reconnects.
+    /// Returns a boxed future, because reconnecting spawns a new monitor.
+    fn connection_monitor(
+        this: Rc<RefCell<Self>>,
+        net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+        connection: Connection,
+    ) -> LocalBoxFuture<'static, ()> {
+        async move {
+            let reason = connection.closed().await;
+            if this.borrow().shutting_down {
+                return;
+            }
+            warn!("Lost the connection to the server: {reason}");
+            Self::reconnect(this, net_thread, reason.to_string()).await;
+        }
+        .boxed_local()
+    }
 
-        this.borrow_mut().variant =
-            NetworkThreadClientStateVariant::Authenticated(NetworkThreadClientAuthenticatedState {
-                connection: NetworkThreadClientConnectingState {
-                    server_address: connection.server_addr,
-                    server_rpc: connection,
-                    rpc_disconnector: Some(rpc_disconnector),
-                    rpc_task,
-                    stream_task,
+    /// Tries to connect to the same server again with an exponential backoff, keeping the game world intact.
+    async fn reconnect(
+        this: Rc<RefCell<Self>>,
+        net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+        reason: String,
+    ) {
+        let Some(address) = this.borrow().reconnect_address else {
+            return;
+        };
+        let old_variant = std::mem::take(&mut this.borrow_mut().variant);
+        if let NetworkThreadClientStateVariant::Authenticated(NetworkThreadClientAuthenticatedState {
+            connection: old,
+            ..
+        }) = old_variant
+        {
+            old.rpc_task.abort();
+            old.stream_task.abort();
+            old.datagram_task.abort();
+            this.borrow().metrics_registry.remove(old.server_address);
+        }
+
+        let mut reason = reason;
+        let mut delay = RECONNECT_INITIAL_DELAY;
+        for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+            Self::set_connection_status(
+                &this,
+                ServerConnectionStatus::Reconnecting {
+                    attempt,
+                    reason: reason.clone(),
                 },
-                server_auth_rpc,
-            });
+            );
+            tokio::time::sleep(delay).await;
+            let result = async {
+                let held_chunks = Self::held_chunks(&this).await?;
+                Self::open_remote_connection(&this, Arc::clone(&net_thread), address, &held_chunks).await
+            }
+            .await;
+            match result {
+                Ok(()) => {
+                    info!("Reconnected to the server at {address} after {attempt} attempt(s)");
+                    Self::set_connection_status(&this, ServerConnectionStatus::Connected);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Reconnection attempt {attempt} to {address} failed: {e:#}");
+                    reason = format!("{e:#}");
+                }
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+        error!("Giving up on reconnecting to the server at {address}");
+        Self::set_connection_status(&this, ServerConnectionStatus::Lost { reason });
+    }
+
+    /// Asks the game for the chunks it still holds, to avoid downloading them again after reconnecting.
+    async fn held_chunks(this: &Rc<RefCell<Self>>) -> Result<Vec<(AbsChunkPos, RevisionNumber)>> {
+        let (tx, rx) = async_oneshot_channel();
+        let command = Box::new(move |world: &mut World| {
+            let _ = tx.send(held_chunk_revisions(world));
+        });
+        if this.borrow().game_control.send(command).is_err() {
+            bail!("The game is shutting down");
+        }
+        rx.await.context("The game is shutting down")
+    }
+
+    fn set_connection_status(this: &Rc<RefCell<Self>>, status: ServerConnectionStatus) {
+        // Fails only while the game is shutting down.
+        let _ = this
+            .borrow()
+            .game_control
+            .send(Box::new(move |world: &mut World| world.insert_resource(status)));
+    }
 
+    async fn datagram_receiver(
+        this: Rc<RefCell<Self>>,
+        net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+        address: PeerAddress,
+        metrics: Arc<PeerNetworkMetrics>,
+        mut datagrams: DatagramReceiver,
+    ) -> Result<()> {
+        while let Some((header, payload)) = datagrams.recv().await {
+            metrics.record_datagram_in(&header, &payload);
+            if let Err(header) = net_thread.handle_datagram(&this, address, header, payload) {
+                debug!("No datagram handler found for a server datagram of type {header:?}");
+            }
+        }
         Ok(())
     }
 
+    /// Waits for [`Self::allow_streams`] on the first connection, later (re)connections don't need to wait.
+    async fn wait_until_streams_allowed(this: &Rc<RefCell<Self>>) {
+        let barrier = this.borrow().ready_to_accept_streams.clone();
+        if let Some(barrier) = barrier {
+            barrier.wait().await;
+            this.borrow_mut().ready_to_accept_streams = None;
+        }
+    }
+
     async fn local_stream_acceptor(
         this: Rc<RefCell<Self>>,
         net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+        address: PeerAddress,
+        metrics: Arc<PeerNetworkMetrics>,
         mut incoming_streams: AsyncUnboundedReceiver<InProcessStream>,
     ) -> Result<()> {
-        let barrier = Arc::clone(this.borrow().ready_to_accept_streams.as_ref().unwrap());
-        barrier.wait().await;
-        this.borrow_mut().ready_to_accept_streams = None;
+        Self::wait_until_streams_allowed(&this).await;
 
         while let Some(stream) = incoming_streams.recv().await {
-            let handler = net_thread.create_stream_handler(Rc::clone(&this), stream.into());
-            match handler {
-                Ok(handler) => {
-                    spawn_local(handler);
-                }
-                Err(stream) => {
-                    error!(
-                        "No stream handler found for incoming server stream of type {:?}",
-                        stream.header()
-                    );
-                }
-            }
+            let stream = TransportStream::from(stream).with_metrics(&metrics);
+            net_thread.spawn_stream_handler(Rc::clone(&this), address, stream);
         }
         Ok(())
     }
@@ -338,25 +705,15 @@ impl NetworkThreadClientState {
     async fn remote_stream_acceptor(
         this: Rc<RefCell<Self>>,
         net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+        address: PeerAddress,
+        metrics: Arc<PeerNetworkMetrics>,
         connection: Connection,
     ) -> Result<()> {
-        let barrier = Arc::clone(this.borrow().ready_to_accept_streams.as_ref().unwrap());
-        barrier.wait().await;
-        this.borrow_mut().ready_to_accept_streams = None;
+        Self::wait_until_streams_allowed(&this).await;
 
         while let Ok(stream) = QuicStream::accept(&connection).await {
-            let handler = net_thread.create_stream_handler(Rc::clone(&this), TransportStream::Network(stream));
-            match handler {
-                Ok(handler) => {
-                    spawn_local(handler);
-                }
-                Err(stream) => {
-                    error!(
-                        "No stream handler found for incoming server stream of type {:?}",
-                        stream.header()
-                    );
-                }
-            }
+            let stream = TransportStream::Network(stream).with_metrics(&metrics);
+            net_thread.spawn_stream_handler(Rc::clone(&this), address, stream);
         }
         Ok(())
     }
@@ -366,16 +723,22 @@ impl NetworkThreadClientState {
 pub struct Client2ServerConnection {
     server_addr: PeerAddress,
     server_rpc: rpc::game_server::Client,
+    metrics: Arc<PeerNetworkMetrics>,
 }
 
 struct AuthenticatedClientConnectionImpl {}
 
 impl Client2ServerConnection {
     /// Constructor.
-    pub fn new(server_addr: PeerAddress, server_rpc: rpc::game_server::Client) -> Self {
+    pub fn new(
+        server_addr: PeerAddress,
+        server_rpc: rpc::game_server::Client,
+        metrics: Arc<PeerNetworkMetrics>,
+    ) -> Self {
         Self {
             server_addr,
             server_rpc,
+            metrics,
         }
     }
 
@@ -388,6 +751,46 @@ impl Client2ServerConnection {
     pub fn server_addr(&self) -> PeerAddress {
         self.server_addr
     }
+
+    /// Agrees on the protocol version and optional features with the server, fails if they are incompatible.
+    pub async fn negotiate_protocol(&self) -> Result<NegotiatedProtocol> {
+        let client_protocol = ProtocolInfo::CURRENT;
+        let mut request = self.server_rpc.negotiate_protocol_request();
+        {
+            let mut builder = request.get();
+            write_game_version(builder.reborrow().init_game_version());
+            client_protocol.write_to_message(&mut builder.init_protocol());
+        }
+        let response = match self.metrics.time_rpc(request.send().promise).await {
+            Ok(response) => response,
+            Err(e) if e.kind == capnp::ErrorKind::Unimplemented => {
+                bail!("The server is too old to negotiate the network protocol, it needs to be updated");
+            }
+            Err(e) => return Err(e).context("RPC failure to negotiate the network protocol"),
+        };
+        let response = response.get().context("Invalid protocol negotiation response")?;
+        match response.get_result()?.which()? {
+            gs_schemas::schemas::game_types_capnp::result::Which::Ok(negotiated) => {
+                let negotiated = NegotiatedProtocol::read_from_message(&negotiated?);
+                if !client_protocol.accepts(&negotiated) {
+                    bail!("The server chose an unsupported protocol {negotiated:?}, supported: {client_protocol:?}");
+                }
+                info!(
+                    "Using protocol version {} with features {:?}",
+                    negotiated.version, negotiated.features
+                );
+                if let Some(capture) = self.metrics.capture() {
+                    capture.record_protocol(CaptureDirection::Received, negotiated);
+                }
+                Ok(negotiated)
+            }
+            gs_schemas::schemas::game_types_capnp::result::Which::Err(mismatch) => {
+                let mismatch = mismatch?;
+                let message = mismatch.get_message()?.to_str()?;
+                bail!("The server refused the connection: {message}");
+            }
+        }
+    }
 }
 
 impl gs_schemas::schemas::network_capnp::authenticated_client_connection::Server for AuthenticatedClientConnectionImpl {
@@ -413,12 +816,15 @@ impl gs_schemas::schemas::network_capnp::authenticated_client_connection::Server
 pub fn create_local_rpc_client(
     id: PeerAddress,
     pipe: tokio::io::DuplexStream,
+    metrics: Arc<PeerNetworkMetrics>,
 ) -> (RpcSystem<Side>, Client2ServerConnection) {
-    let (read, write) = pipe.compat().split();
+    let (read, write) = CountingIo::new(pipe.compat(), Arc::clone(metrics.rpc_counters()))
+        .with_capture(metrics.capture())
+        .split();
     let network = VatNetwork::new(read, write, Side::Client, RPC_LOCAL_READER_OPTIONS);
     let mut rpc_system = RpcSystem::new(Box::new(network), None);
     let server_object: rpc::game_server::Client = rpc_system.bootstrap(VatId::Server);
-    (rpc_system, Client2ServerConnection::new(id, server_object))
+    (rpc_system, Client2ServerConnection::new(id, server_object, metrics))
 }
 
 /// Create a Future that will handle in-memory messages coming from a [`Server2ClientEndpoint`] and any child RPC objects on the given `server`&`id`.
@@ -426,9 +832,312 @@ pub fn create_quic_rpc_client(
     id: PeerAddress,
     tx: SendStream,
     rx: RecvStream,
+    metrics: Arc<PeerNetworkMetrics>,
 ) -> (RpcSystem<Side>, Client2ServerConnection) {
+    let rx = CountingIo::new(rx, Arc::clone(metrics.rpc_counters())).with_capture(metrics.capture());
+    let tx = CountingIo::new(tx, Arc::clone(metrics.rpc_counters())).with_capture(metrics.capture());
     let network = VatNetwork::new(rx, tx, Side::Client, RPC_CLIENT_READER_OPTIONS);
     let mut rpc_system = RpcSystem::new(Box::new(network), None);
     let server_object: rpc::game_server::Client = rpc_system.bootstrap(VatId::Server);
-    (rpc_system, Client2ServerConnection::new(id, server_object))
+    (rpc_system, Client2ServerConnection::new(id, server_object, metrics))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use gs_common::config::GameConfig;
+    use gs_common::network::capture::{
+        replay_server_traffic, CaptureWriter, CapturedPayload, ReplayPacing, SharedBuffer,
+    };
+    use gs_common::network::protocol::ProtocolFeatures;
+    use gs_common::network::server::ConnectedPlayersTable;
+    use gs_common::network::simulator::NetworkConditions;
+    use gs_common::voxel::blocks::{DIRT_BLOCK_NAME, STONE_BLOCK_NAME};
+    use gs_common::voxel::plugin::{NetworkVoxelClient, VoxelUniverseBuilder};
+    use gs_common::{builtin_game_registries, GameBevyCommand, GameServer};
+    use gs_schemas::coordinates::AbsBlockPos;
+    use gs_schemas::registry::RegistryName;
+    use gs_schemas::voxel::voxeltypes::BlockEntry;
+    use gs_schemas::GameSide;
+
+    use super::*;
+    use crate::interaction::send_block_edits;
+    use crate::voxel::{apply_chunk_packet, ClientVoxelUniverse};
+    use crate::ClientData;
+
+    /// How long to wait for chunks to arrive before failing.
+    const CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A game client without any rendering, only keeping the chunks streamed to it.
+    struct HeadlessClient {
+        net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
+        world: World,
+        game_commands: StdUnboundedReceiver<Box<GameBevyCommand>>,
+        chunks: HashSet<AbsChunkPos>,
+    }
+
+    impl HeadlessClient {
+        fn new(metrics_registry: NetworkMetricsRegistry) -> Self {
+            let (control_tx, control_rx) = std_unbounded_channel();
+            let net_thread = Arc::new(NetworkThread::new(GameSide::Client, move || {
+                NetworkThreadClientState::new(control_tx, metrics_registry)
+            }));
+            Self {
+                net_thread,
+                world: World::new(),
+                game_commands: control_rx,
+                chunks: HashSet::new(),
+            }
+        }
+
+        /// Creates a voxel universe with the given registries, receiving the chunks the network thread handles.
+        fn create_universe(&mut self, registries: &GameRegistries) {
+            VoxelUniverseBuilder::<ClientData>::new(
+                &mut self.world,
+                Arc::clone(&registries.block_types),
+                Arc::clone(&registries.biome_types),
+            )
+            .and_then(|builder| builder.with_network_client(&self.net_thread))
+            .unwrap();
+        }
+
+        /// Starts accepting chunk streams from the connected server into a new voxel universe.
+        fn enter_game(&mut self, registries: &GameRegistries) {
+            self.create_universe(registries);
+            self.net_thread
+                .schedule_task(|state| {
+                    Box::pin(async move {
+                        NetworkThreadClientState::allow_streams(state).await;
+                        Ok(())
+                    })
+                })
+                .blocking_wait()
+                .unwrap();
+        }
+
+        /// Applies the chunk packets received so far.
+        fn receive_chunks(&mut self) {
+            while let Ok(command) = self.game_commands.try_recv() {
+                command(&mut self.world);
+            }
+            let mut voxel_q = self
+                .world
+                .query::<(&mut NetworkVoxelClient<ClientData>, &mut ClientVoxelUniverse)>();
+            for (mut nvc, mut voxels) in voxel_q.iter_mut(&mut self.world) {
+                while let Ok(packet) = nvc.chunk_packet_receiver.try_recv() {
+                    let position = apply_chunk_packet(&packet, voxels.loaded_chunks_mut()).unwrap();
+                    self.chunks.insert(position);
+                }
+            }
+        }
+
+        /// Receives chunks until `done` returns true for the received chunk positions.
+        fn wait_for_chunks(&mut self, done: impl Fn(&HashSet<AbsChunkPos>) -> bool) {
+            let started = Instant::now();
+            while !done(&self.chunks) {
+                assert!(started.elapsed() < CHUNK_TIMEOUT, "Timed out waiting for chunks");
+                std::thread::sleep(Duration::from_millis(10));
+                self.receive_chunks();
+            }
+        }
+    }
+
+    #[test]
+    fn replayed_capture_loads_the_same_chunks() {
+        let server = GameServer::new_test();
+        server.set_paused(false);
+        let capture = SharedBuffer::default();
+        let writer = CaptureWriter::new(capture.clone());
+
+        let mut recorder = HeadlessClient::new(NetworkMetricsRegistry::default().with_capture(writer.clone()));
+        let thread = Arc::clone(&recorder.net_thread);
+        let connected_server = Arc::clone(&server);
+        let (registries, protocol) = recorder
+            .net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    let pipe = connected_server.create_local_connection().async_wait().await?;
+                    NetworkThreadClientState::connect_locally(state, thread, pipe).await?;
+                    let registries =
+                        NetworkThreadClientState::bootstrap_game_data(state, &builtin_game_registries()).await?;
+                    let protocol = state.borrow().protocol().copied().context("Not connected")?;
+                    Ok((registries, protocol))
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        // The chunks are captured in the packed encoding.
+        assert!(protocol.features.contains(ProtocolFeatures::COMPRESSION));
+        recorder.enter_game(&registries);
+        recorder.wait_for_chunks(|chunks| !chunks.is_empty());
+        recorder.net_thread.sync_shutdown();
+        writer.flush().unwrap();
+
+        let messages = capture.messages();
+        assert!(messages
+            .iter()
+            .any(|message| message.payload == CapturedPayload::Protocol(protocol)));
+        // The replay spawns the stream handlers itself, without a connection accepting streams.
+        let mut replayed = HeadlessClient::new(NetworkMetricsRegistry::default());
+        replayed.create_universe(&registries);
+        let thread = Arc::clone(&replayed.net_thread);
+        let stats = replayed
+            .net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    Ok(replay_server_traffic(
+                        messages,
+                        CaptureDirection::Received,
+                        &thread,
+                        state,
+                        PeerAddress::Local(0),
+                        ReplayPacing::Immediate,
+                    )
+                    .await)
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        assert!(stats.stream_packets > 0, "{stats:?}");
+
+        let recorded = recorder.chunks.clone();
+        replayed.wait_for_chunks(|chunks| chunks.is_superset(&recorded));
+        replayed.net_thread.sync_shutdown();
+        server.shutdown().blocking_wait().unwrap();
+    }
+
+    #[test]
+    fn chunks_and_edits_get_through_a_poor_connection() {
+        let server = GameServer::new_test();
+        server.set_paused(false);
+        let mut client = HeadlessClient::new(NetworkMetricsRegistry::default());
+        let thread = Arc::clone(&client.net_thread);
+        let connected_server = Arc::clone(&server);
+        let registries = client
+            .net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    let pipe = connected_server
+                        .create_simulated_local_connection(NetworkConditions::poor())
+                        .async_wait()
+                        .await?;
+                    NetworkThreadClientState::connect_locally(state, thread, pipe).await?;
+                    NetworkThreadClientState::bootstrap_game_data(state, &builtin_game_registries()).await
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        client.enter_game(&registries);
+        // Right above the spawn position, in reach of the player.
+        let position = AbsBlockPos::new(0, 2, 0);
+        let chunk_position = AbsChunkPos::from(position);
+        client.wait_for_chunks(|chunks| chunks.contains(&chunk_position));
+
+        let mut voxel_q = client.world.query::<&mut ClientVoxelUniverse>();
+        let mut voxels = voxel_q.single_mut(&mut client.world);
+        let chunks = voxels.loaded_chunks_mut();
+        let block_id = |name: RegistryName| registries.block_types.lookup_name_to_object(name.as_ref()).unwrap().0;
+        let (stone, dirt) = (block_id(STONE_BLOCK_NAME), block_id(DIRT_BLOCK_NAME));
+        let old_block = chunks.get_block(position).unwrap();
+        let block = BlockEntry::new(if old_block.id == stone { dirt } else { stone }, 0);
+        let edits = vec![(position, block)];
+        chunks.apply_block_edits(&edits, GameSide::Client);
+        send_block_edits(&client.net_thread, edits);
+
+        // The server resolves the prediction by sending its copy of the edited chunk.
+        let started = Instant::now();
+        loop {
+            client.receive_chunks();
+            let mut voxel_q = client.world.query::<&ClientVoxelUniverse>();
+            let chunks = voxel_q.single(&client.world).loaded_chunks();
+            if chunks
+                .chunks
+                .get(&chunk_position)
+                .is_some_and(|chunk| !chunk.is_prediction())
+            {
+                assert_eq!(chunks.get_block(position), Some(block));
+                break;
+            }
+            assert!(started.elapsed() < CHUNK_TIMEOUT, "Timed out waiting for the edit");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        client.net_thread.sync_shutdown();
+        server.shutdown().blocking_wait().unwrap();
+    }
+
+    #[test]
+    fn reconnecting_reloads_predicted_chunks() {
+        // Remote connections need the cryptography provider `geosia_pre_main` installs in the binaries.
+        let _ = quinn::rustls::crypto::aws_lc_rs::default_provider().install_default();
+        // Reserve a free port for the server to listen on.
+        let address = std::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+            .and_then(|socket| socket.local_addr())
+            .unwrap();
+        let mut game_config = GameConfig::default();
+        "Test server".clone_into(&mut game_config.server.server_title);
+        game_config.server.listen_addresses = vec![address];
+        let server = GameServer::new(GameConfig::new_handle(game_config)).unwrap();
+        server.set_paused(false);
+
+        let mut client = HeadlessClient::new(NetworkMetricsRegistry::default());
+        let thread = Arc::clone(&client.net_thread);
+        let registries = client
+            .net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    // The handshake is retransmitted until the server starts listening.
+                    NetworkThreadClientState::connect_remotely(state, thread, address).await?;
+                    NetworkThreadClientState::bootstrap_game_data(state, &builtin_game_registries()).await
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        client.enter_game(&registries);
+        client.wait_for_chunks(|chunks| !chunks.is_empty());
+
+        // Predict an edit that the lost connection never resolves.
+        let predicted = *client.chunks.iter().next().unwrap();
+        let mut voxel_q = client.world.query::<&mut ClientVoxelUniverse>();
+        let mut voxels = voxel_q.single_mut(&mut client.world);
+        let chunk = voxels.loaded_chunks_mut().chunks.get_mut(&predicted).unwrap();
+        chunk.mutate_predicted();
+        client.chunks.clear();
+
+        client
+            .net_thread
+            .schedule_task(|state| {
+                Box::pin(async move {
+                    let state = state.borrow();
+                    let connection = state.connecting_state().context("Not connected")?;
+                    let StreamOpener::Network(connection) = &connection.stream_opener else {
+                        bail!("Not a remote connection");
+                    };
+                    connection.close(0u32.into(), b"Simulated connection loss");
+                    Ok(())
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+
+        // The chunks held with their server revisions are not sent again, the discarded prediction is.
+        client.wait_for_chunks(|chunks| chunks.contains(&predicted));
+        let mut voxel_q = client.world.query::<&ClientVoxelUniverse>();
+        let voxels = voxel_q.single(&client.world);
+        assert!(!voxels.loaded_chunks().chunks[&predicted].is_prediction());
+        assert_eq!(
+            client.world.get_resource::<ServerConnectionStatus>(),
+            Some(&ServerConnectionStatus::Connected)
+        );
+        // The returning player took over their old entity.
+        let players = server
+            .schedule_bevy(|world| Ok(world.resource::<ConnectedPlayersTable>().players_by_address().len()))
+            .blocking_wait()
+            .unwrap();
+        assert_eq!(players, 1);
+
+        client.net_thread.sync_shutdown();
+        server.shutdown().blocking_wait().unwrap();
+    }
 }
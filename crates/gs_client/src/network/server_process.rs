@@ -0,0 +1,109 @@
+//! Singleplayer with the server running as a separate `gs_dedi_server` process connected over shared memory,
+//! so that a crash of the server cannot take the game down with it, and the other way around.
+
+use std::process::{Child, Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use bevy::log::*;
+use bevy::prelude::{Resource, World};
+use gs_common::network::server::LocalConnectionPipe;
+use gs_common::network::shm::{spawn_bridge_thread, ShmEndpoint};
+use gs_common::network::transport::InProcessDuplex;
+use gs_common::network::PeerAddress;
+use gs_common::prelude::*;
+
+use crate::network::ServerConnectionStatus;
+use crate::GameControlChannel;
+
+/// How long to wait for the server process to shut down cleanly after the game disconnects, before killing it.
+pub const SERVER_PROCESS_EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the watcher checks on the server process.
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The server process of a singleplayer game, waits for it to shut down when dropped.
+#[derive(Resource)]
+pub struct IntegratedServerProcess {
+    child: Arc<Mutex<Child>>,
+}
+
+impl IntegratedServerProcess {
+    /// Starts the dedicated server executable found next to the game's, and returns the connection to it.
+    /// The game is told through [`ServerConnectionStatus::ServerStopped`] if the server process stops unexpectedly.
+    pub fn spawn(game_control: GameControlChannel) -> Result<(Self, LocalConnectionPipe)> {
+        let executable =
+            std::env::current_exe()?.with_file_name(format!("gs_dedi_server{}", std::env::consts::EXE_SUFFIX));
+        let (endpoint, fds) = ShmEndpoint::create()?;
+        let mut command = Command::new(&executable);
+        command.arg("--shared-memory").arg(fds.to_arg()).stdin(Stdio::null());
+        fds.inherit_into(&mut command);
+        let child = command
+            .spawn()
+            .with_context(|| format!("Could not start the server process {}", executable.display()))?;
+        info!(
+            "Started the server process {} with PID {}",
+            executable.display(),
+            child.id()
+        );
+        // The server process has its own copies of the descriptors now.
+        drop(fds);
+
+        let (local, bridged) = InProcessDuplex::new_pair();
+        let bridge = spawn_bridge_thread(endpoint, bridged);
+        let child = Arc::new(Mutex::new(child));
+        let watched_child = Arc::clone(&child);
+        std::thread::Builder::new()
+            .name(String::from("GS Server Process Watcher"))
+            .spawn(move || Self::watch(watched_child, bridge, game_control))?;
+        Ok((Self { child }, (PeerAddress::Local(0), local)))
+    }
+
+    /// Waits until the server process exits or the connection to it breaks, and tells the game why.
+    fn watch(child: Arc<Mutex<Child>>, bridge: JoinHandle<Result<()>>, game_control: GameControlChannel) {
+        let (reason, can_save) = loop {
+            match child.lock().unwrap().try_wait() {
+                Ok(Some(status)) => break (format!("The server process exited unexpectedly ({status})"), false),
+                Ok(None) => {}
+                Err(e) => break (format!("Could not check on the server process: {e}"), false),
+            }
+            if bridge.is_finished() {
+                // The server process saves and exits by itself once the connection is closed.
+                match bridge.join() {
+                    // The game closed the connection.
+                    Ok(Ok(())) => return,
+                    Ok(Err(e)) => break (format!("{e:#}"), true),
+                    Err(_) => break (String::from("The shared memory connection crashed"), true),
+                }
+            }
+            std::thread::sleep(WATCH_INTERVAL);
+        };
+        error!("The integrated server stopped: {reason}");
+        // Fails only while the game is shutting down.
+        let _ = game_control.send(Box::new(move |world: &mut World| {
+            world.insert_resource(ServerConnectionStatus::ServerStopped { reason, can_save })
+        }));
+    }
+}
+
+impl Drop for IntegratedServerProcess {
+    fn drop(&mut self) {
+        let mut child = self.child.lock().unwrap();
+        let deadline = Instant::now() + SERVER_PROCESS_EXIT_TIMEOUT;
+        while Instant::now() < deadline {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    info!("The server process exited ({status})");
+                    return;
+                }
+                Ok(None) => std::thread::sleep(WATCH_INTERVAL),
+                Err(e) => {
+                    error!("Could not check on the server process: {e}");
+                    break;
+                }
+            }
+        }
+        warn!("The server process did not shut down in time, killing it");
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
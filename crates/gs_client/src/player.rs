@@ -0,0 +1,167 @@
+//! The locally controlled player, walking with collisions, with the debug fly camera still available as a toggle.
+
+use bevy::prelude::*;
+use bevy::window::{CursorGrabMode, PrimaryWindow};
+use gs_common::network::replication::{fixed_tick, PlayerStateUpdate};
+use gs_common::player::{player_movement_system, PlayerInput, PlayerPhysics, PLAYER_EYE_HEIGHT, PLAYER_SPAWN_POSITION};
+
+use crate::debugcam::{FlyCam, KeyBindings};
+use crate::states::{ClientAppState, InGameSystemSet};
+use crate::{ClientData, ClientNetworkThreadHolder};
+
+/// Marks the player entity controlled by this client.
+#[derive(Component, Copy, Clone, Debug, Default)]
+pub struct LocalPlayer;
+
+/// Whether the camera follows the local player or flies freely through the terrain.
+#[derive(Resource, Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub enum CameraMode {
+    /// The camera is attached to the eyes of the local player, movement keys control the player.
+    #[default]
+    Player,
+    /// The debug fly camera, movement keys control the camera and the player stands still.
+    Fly,
+}
+
+/// Spawns and controls the local player entity.
+pub struct LocalPlayerPlugin;
+
+impl Plugin for LocalPlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CameraMode>()
+            .add_systems(OnEnter(ClientAppState::InGame), spawn_local_player)
+            .add_systems(
+                Update,
+                (
+                    camera_mode_toggle_system,
+                    local_player_input_system,
+                    camera_follow_system,
+                )
+                    .chain()
+                    .in_set(InGameSystemSet),
+            )
+            .add_systems(
+                FixedUpdate,
+                (local_player_sync_system)
+                    .after(player_movement_system::<ClientData>)
+                    .in_set(InGameSystemSet),
+            );
+    }
+}
+
+fn spawn_local_player(mut commands: Commands) {
+    commands.spawn((LocalPlayer, PlayerPhysics::new(PLAYER_SPAWN_POSITION)));
+}
+
+fn camera_mode_toggle_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    key_bindings: Res<KeyBindings>,
+    mut camera_mode: ResMut<CameraMode>,
+) {
+    if keys.just_pressed(key_bindings.toggle_fly_cam) {
+        *camera_mode = match *camera_mode {
+            CameraMode::Player => CameraMode::Fly,
+            CameraMode::Fly => CameraMode::Player,
+        };
+        info!("Switched camera mode to {:?}", *camera_mode);
+    }
+}
+
+fn local_player_input_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    key_bindings: Res<KeyBindings>,
+    camera_mode: Res<CameraMode>,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    camera_q: Query<&Transform, With<FlyCam>>,
+    mut player_q: Query<&mut PlayerPhysics, With<LocalPlayer>>,
+) {
+    let Ok(mut player) = player_q.get_single_mut() else {
+        return;
+    };
+    let grabbed = primary_window
+        .get_single()
+        .is_ok_and(|window| window.cursor_options.grab_mode != CursorGrabMode::None);
+    let (Ok(camera), CameraMode::Player, true) = (camera_q.get_single(), *camera_mode, grabbed) else {
+        player.input = PlayerInput::default();
+        return;
+    };
+
+    let local_z = camera.local_z();
+    let forward = -Vec3::new(local_z.x, 0., local_z.z);
+    let right = Vec3::new(local_z.z, 0., -local_z.x);
+    let mut movement = Vec3::ZERO;
+    if keys.pressed(key_bindings.move_forward) {
+        movement += forward;
+    }
+    if keys.pressed(key_bindings.move_backward) {
+        movement -= forward;
+    }
+    if keys.pressed(key_bindings.move_left) {
+        movement -= right;
+    }
+    if keys.pressed(key_bindings.move_right) {
+        movement += right;
+    }
+    player.input = PlayerInput {
+        movement: movement.normalize_or_zero(),
+        jump: keys.pressed(key_bindings.move_ascend),
+        descend: keys.pressed(key_bindings.move_descend),
+    };
+}
+
+fn camera_follow_system(
+    time: Res<Time<Fixed>>,
+    camera_mode: Res<CameraMode>,
+    player_q: Query<&PlayerPhysics, With<LocalPlayer>>,
+    mut camera_q: Query<&mut Transform, With<FlyCam>>,
+) {
+    if *camera_mode != CameraMode::Player {
+        return;
+    }
+    let (Ok(player), Ok(mut camera)) = (player_q.get_single(), camera_q.get_single_mut()) else {
+        return;
+    };
+    // Interpolate between the fixed simulation steps for smooth rendering.
+    let position = player
+        .previous_position
+        .lerp(player.state.position, time.overstep_fraction());
+    camera.translation = position + PLAYER_EYE_HEIGHT * Vec3::Y;
+}
+
+/// Reports the simulated state of the local player to the server, which checks it and shares it with other players.
+fn local_player_sync_system(
+    time: Res<Time>,
+    net_thread: Option<Res<ClientNetworkThreadHolder>>,
+    camera_q: Query<&Transform, With<FlyCam>>,
+    player_q: Query<&PlayerPhysics, With<LocalPlayer>>,
+) {
+    let (Some(net_thread), Ok(player)) = (net_thread, player_q.get_single()) else {
+        return;
+    };
+    let (yaw, pitch) = camera_q
+        .get_single()
+        .map(|camera| {
+            let (yaw, pitch, _roll) = camera.rotation.to_euler(EulerRot::YXZ);
+            (yaw, pitch)
+        })
+        .unwrap_or_default();
+    let datagram = PlayerStateUpdate {
+        tick: fixed_tick(&time),
+        position: player.state.position,
+        velocity: player.state.velocity,
+        yaw,
+        pitch,
+    }
+    .write_datagram();
+    let _ = net_thread.0.schedule_task(move |state| {
+        Box::pin(async move {
+            if let Some(result) = state
+                .borrow()
+                .send_datagram(&PlayerStateUpdate::DATAGRAM_HEADER, &datagram)
+            {
+                result?;
+            }
+            Ok(())
+        })
+    });
+}
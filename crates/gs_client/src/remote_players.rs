@@ -0,0 +1,266 @@
+//! Other players in the world, drawn as placeholder meshes smoothly interpolated between the server updates.
+
+use bevy::color::palettes::tailwind;
+use bevy::prelude::*;
+use gs_common::network::replication::{PlayerTransformsUpdate, TransformSnapshots};
+use gs_common::network::thread::NetworkThread;
+use gs_common::player::{PlayerMovementState, PlayerPhysics, PLAYER_EYE_HEIGHT, PLAYER_HALF_EXTENTS};
+use gs_common::prelude::*;
+use gs_common::TICKS_PER_SECOND_F64;
+
+use crate::network::NetworkThreadClientState;
+use crate::player::LocalPlayer;
+use crate::states::InGameSystemSet;
+
+/// How far behind the newest received server tick other players are drawn, to hide jitter and lost datagrams.
+pub const INTERPOLATION_DELAY_TICKS: f64 = 3.0;
+/// Remote players without updates for this many ticks are removed, e.g. after they disconnect.
+const REMOTE_PLAYER_TIMEOUT_TICKS: u64 = 64;
+/// The rendering clock jumps instead of gradually catching up when it is off by more ticks than this.
+const MAX_CLOCK_DRIFT_TICKS: f64 = 16.0;
+/// Fraction of the rendering clock error corrected every frame.
+const CLOCK_CORRECTION_RATE: f64 = 0.05;
+
+/// Another player, as replicated by the server.
+#[derive(Component, Clone, Debug)]
+pub struct RemotePlayer {
+    /// The server-assigned ID of the player.
+    pub id: u64,
+    snapshots: TransformSnapshots,
+}
+
+/// The head of a remote player's placeholder mesh, tilted according to where they look.
+#[derive(Component, Copy, Clone, Debug, Default)]
+struct RemotePlayerHead;
+
+/// Lookup of remote player entities, and the clock used for interpolating their movement.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct RemotePlayers {
+    entities: HashMap<u64, Entity>,
+    latest_tick: Option<u64>,
+    /// The (fractional) server tick remote players are currently drawn at.
+    render_tick: f64,
+}
+
+impl RemotePlayers {
+    /// Finds the entity of a remote player by their server-assigned ID.
+    pub fn get(&self, id: u64) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+}
+
+#[derive(Resource)]
+struct RemotePlayerAssets {
+    body: Handle<Mesh>,
+    head: Handle<Mesh>,
+    body_material: Handle<StandardMaterial>,
+    head_material: Handle<StandardMaterial>,
+}
+
+/// Receives and draws the other players.
+pub struct RemotePlayersPlugin;
+
+impl Plugin for RemotePlayersPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RemotePlayers>()
+            .add_systems(Startup, setup_remote_player_assets)
+            .add_systems(
+                Update,
+                (remote_player_timeout_system, remote_player_interpolation_system)
+                    .chain()
+                    .in_set(InGameSystemSet),
+            );
+    }
+}
+
+fn setup_remote_player_assets(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let radius = PLAYER_HALF_EXTENTS.x;
+    commands.insert_resource(RemotePlayerAssets {
+        body: meshes.add(Capsule3d::new(radius, 2.0 * (PLAYER_HALF_EXTENTS.y - radius))),
+        head: meshes.add(Cuboid::new(1.2 * radius, 0.4 * radius, 0.6 * radius)),
+        body_material: materials.add(Color::from(tailwind::SKY_600)),
+        head_material: materials.add(Color::from(tailwind::AMBER_300)),
+    });
+}
+
+/// Registers the handler passing player transforms datagrams from the server to the game.
+pub fn register_datagram_handlers(net_thread: &NetworkThread<NetworkThreadClientState>) {
+    net_thread.insert_datagram_handler(
+        PlayerTransformsUpdate::DATAGRAM_HEADER,
+        Box::new(|state, _peer, payload| {
+            let update = match PlayerTransformsUpdate::read_datagram(&payload) {
+                Ok(update) => update,
+                Err(e) => {
+                    warn!("Invalid player transforms datagram from the server: {e}");
+                    return;
+                }
+            };
+            // Fails only while the game is shutting down.
+            let _ = state.borrow().game_control().send(Box::new(move |world: &mut World| {
+                receive_player_transforms(world, update)
+            }));
+        }),
+    );
+}
+
+/// Applies a batch of player transforms received from the server.
+pub fn receive_player_transforms(world: &mut World, update: PlayerTransformsUpdate) {
+    if let Some(position) = update.correction {
+        debug!("The server corrected the local player's position to {position}");
+        let mut local_q = world.query_filtered::<&mut PlayerPhysics, With<LocalPlayer>>();
+        for mut physics in local_q.iter_mut(world) {
+            physics.state = PlayerMovementState::new(position);
+            physics.previous_position = position;
+        }
+    }
+
+    for transform in update.players {
+        let existing = world.resource::<RemotePlayers>().get(transform.id);
+        let entity = match existing {
+            Some(entity) if world.get_entity(entity).is_ok() => entity,
+            _ => {
+                let entity = spawn_remote_player(world, transform.id);
+                world
+                    .resource_mut::<RemotePlayers>()
+                    .entities
+                    .insert(transform.id, entity);
+                entity
+            }
+        };
+        if let Some(mut remote) = world.get_mut::<RemotePlayer>(entity) {
+            remote.snapshots.insert(update.tick, transform);
+        }
+    }
+
+    let mut remotes = world.resource_mut::<RemotePlayers>();
+    if remotes.latest_tick.is_none() {
+        remotes.render_tick = update.tick as f64 - INTERPOLATION_DELAY_TICKS;
+    }
+    remotes.latest_tick = remotes.latest_tick.max(Some(update.tick));
+}
+
+fn spawn_remote_player(world: &mut World, id: u64) -> Entity {
+    let assets = world.resource::<RemotePlayerAssets>();
+    let (body, head) = (assets.body.clone(), assets.head.clone());
+    let (body_material, head_material) = (assets.body_material.clone(), assets.head_material.clone());
+    debug!("Spawning remote player {id}");
+    world
+        .spawn((
+            RemotePlayer {
+                id,
+                snapshots: default(),
+            },
+            Transform::default(),
+            Visibility::default(),
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                Mesh3d(body),
+                MeshMaterial3d(body_material),
+                Transform::from_xyz(0.0, PLAYER_HALF_EXTENTS.y, 0.0),
+            ));
+            parent.spawn((
+                RemotePlayerHead,
+                Mesh3d(head),
+                MeshMaterial3d(head_material),
+                Transform::from_xyz(0.0, PLAYER_EYE_HEIGHT, -0.8 * PLAYER_HALF_EXTENTS.x),
+            ));
+        })
+        .id()
+}
+
+fn remote_player_timeout_system(
+    mut commands: Commands,
+    mut remotes: ResMut<RemotePlayers>,
+    remote_q: Query<(Entity, &RemotePlayer)>,
+) {
+    let Some(latest_tick) = remotes.latest_tick else {
+        return;
+    };
+    for (entity, remote) in remote_q.iter() {
+        let last_seen = remote.snapshots.latest_tick().unwrap_or(0);
+        if last_seen + REMOTE_PLAYER_TIMEOUT_TICKS < latest_tick {
+            debug!("Removing remote player {} after a timeout", remote.id);
+            remotes.entities.remove(&remote.id);
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+fn remote_player_interpolation_system(
+    time: Res<Time>,
+    mut remotes: ResMut<RemotePlayers>,
+    mut remote_q: Query<(&RemotePlayer, &mut Transform, &Children)>,
+    mut head_q: Query<&mut Transform, (With<RemotePlayerHead>, Without<RemotePlayer>)>,
+) {
+    let Some(latest_tick) = remotes.latest_tick else {
+        return;
+    };
+    let target = latest_tick as f64 - INTERPOLATION_DELAY_TICKS;
+    let mut render_tick = remotes.render_tick + time.delta_secs_f64() * TICKS_PER_SECOND_F64;
+    if (target - render_tick).abs() > MAX_CLOCK_DRIFT_TICKS {
+        render_tick = target;
+    } else {
+        render_tick += (target - render_tick) * CLOCK_CORRECTION_RATE;
+    }
+    remotes.render_tick = render_tick;
+
+    for (remote, mut transform, children) in remote_q.iter_mut() {
+        let Some(sample) = remote.snapshots.sample(render_tick) else {
+            continue;
+        };
+        transform.translation = sample.position;
+        transform.rotation = Quat::from_rotation_y(sample.yaw);
+        let mut heads = head_q.iter_many_mut(children);
+        while let Some(mut head) = heads.fetch_next() {
+            head.rotation = Quat::from_rotation_x(sample.pitch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::ecs::system::RunSystemOnce;
+    use gs_common::network::replication::PlayerTransform;
+
+    use super::*;
+
+    #[test]
+    fn players_who_left_time_out() {
+        let mut world = World::new();
+        world.init_resource::<RemotePlayers>();
+        world.insert_resource(RemotePlayerAssets {
+            body: default(),
+            head: default(),
+            body_material: default(),
+            head_material: default(),
+        });
+        let other = PlayerTransform {
+            id: 7,
+            position: Vec3::ZERO,
+            yaw: 0.0,
+            pitch: 0.0,
+        };
+        let run_tick = |world: &mut World, tick: u64, players: &[PlayerTransform]| {
+            for update in PlayerTransformsUpdate::batch(tick, players, None) {
+                receive_player_transforms(world, update);
+            }
+            world.run_system_once(remote_player_timeout_system).unwrap();
+        };
+
+        for tick in 0..10 {
+            run_tick(&mut world, tick, &[other]);
+        }
+        let entity = world.resource::<RemotePlayers>().get(other.id).unwrap();
+        // The other player disconnects, leaving the local player alone on the server.
+        for tick in 10..10 + REMOTE_PLAYER_TIMEOUT_TICKS + 2 {
+            run_tick(&mut world, tick, &[]);
+        }
+        assert_eq!(world.resource::<RemotePlayers>().get(other.id), None);
+        assert!(world.get_entity(entity).is_err());
+    }
+}
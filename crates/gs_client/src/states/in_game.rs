@@ -1,8 +1,11 @@
 //! The state for when the player is in game, with all basic gameplay resources fully loaded.
 
+use bevy::app::AppExit;
 use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
 
-use crate::states::ClientAppState;
+use crate::network::{ServerConnectionStatus, MAX_RECONNECT_ATTEMPTS};
+use crate::states::{ClientAppState, InGameSystemSet};
 use crate::ClientNetworkThreadHolder;
 
 /// The "plugin" implementing the in game state.
@@ -10,10 +13,69 @@ pub struct InGamePlugin;
 
 impl Plugin for InGamePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(OnExit(ClientAppState::InGame), ingame_cleanup_on_exit);
+        app.init_resource::<ServerConnectionStatus>()
+            .add_systems(Update, (connection_status_overlay,).in_set(InGameSystemSet))
+            .add_systems(OnExit(ClientAppState::InGame), ingame_cleanup_on_exit);
     }
 }
 
-fn ingame_cleanup_on_exit(net_thread: ResMut<ClientNetworkThreadHolder>) {
-    net_thread.0.sync_shutdown();
+fn ingame_cleanup_on_exit(world: &mut World) {
+    world.resource::<ClientNetworkThreadHolder>().0.sync_shutdown();
+    // Waits for the server process to save and exit after the game disconnected from it.
+    #[cfg(target_os = "linux")]
+    world.remove_resource::<crate::network::server_process::IntegratedServerProcess>();
+    // The next game starts with a fresh connection.
+    world.insert_resource(ServerConnectionStatus::default());
+}
+
+/// Tells the player that the game is frozen while the connection to the server is down.
+fn connection_status_overlay(
+    mut contexts: EguiContexts,
+    mut quit: EventWriter<AppExit>,
+    mut next_state: ResMut<NextState<ClientAppState>>,
+    status: Res<ServerConnectionStatus>,
+) {
+    let can_save = matches!(*status, ServerConnectionStatus::ServerStopped { can_save: true, .. });
+    let (title, message) = match &*status {
+        ServerConnectionStatus::Connected => return,
+        ServerConnectionStatus::Reconnecting { attempt, reason } => (
+            "Reconnecting",
+            format!(
+                "Connection to the server lost: {reason}\nReconnecting, attempt {attempt}/{MAX_RECONNECT_ATTEMPTS}"
+            ),
+        ),
+        ServerConnectionStatus::Lost { reason } => {
+            ("Disconnected", format!("Could not reconnect to the server: {reason}"))
+        }
+        ServerConnectionStatus::ServerStopped { reason, can_save } => {
+            let what = if *can_save {
+                "Lost the connection to the singleplayer server"
+            } else {
+                "The singleplayer server stopped"
+            };
+            (
+                "Server stopped",
+                format!("{what}: {reason}\nThe game can not continue without it."),
+            )
+        }
+    };
+    egui::Window::new(title)
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, (0.0, 0.0))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.vertical_centered(|ui| {
+                ui.add_space(8.0);
+                ui.label(message);
+                ui.add_space(8.0);
+                // Leaving the game waits for the server process to save and exit.
+                if can_save && ui.button("Save and exit to the menu").clicked() {
+                    next_state.set(ClientAppState::MainMenu);
+                }
+                if ui.button("Quit").clicked() {
+                    quit.send(AppExit::Success);
+                }
+                ui.add_space(8.0);
+            });
+        });
 }
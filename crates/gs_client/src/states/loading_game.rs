@@ -5,20 +5,23 @@ use std::net::SocketAddr;
 use bevy::prelude::*;
 use bevy::utils::synccell::SyncCell;
 use gs_common::config::{GameConfig, ServerConfig};
+use gs_common::network::capture::CaptureWriter;
+use gs_common::network::metrics::NetworkMetricsRegistry;
+use gs_common::network::server::LocalConnectionPipe;
 use gs_common::network::thread::NetworkThread;
 use gs_common::prelude::std_unbounded_channel;
 use gs_common::prelude::*;
 use gs_common::voxel::plugin::VoxelUniverseBuilder;
 use gs_common::{builtin_game_registries, GameBevyCommand, GameServer};
-use gs_schemas::dependencies::uuid::Uuid;
-use gs_schemas::registries::GameRegistries;
-use gs_schemas::schemas::SchemaUuidExt;
 use gs_schemas::GameSide;
 
+#[cfg(target_os = "linux")]
+use crate::network::server_process::IntegratedServerProcess;
 use crate::network::NetworkThreadClientState;
+use crate::remote_players::register_datagram_handlers;
 use crate::states::{ClientAppState, LoadingGameSystemSet};
 use crate::voxel::ClientVoxelUniverseBuilder;
-use crate::{ClientData, ClientNetworkThreadHolder, GameClientControlCommandReceiver};
+use crate::{ClientData, ClientNetworkThreadHolder, GameClientControlCommandReceiver, GameControlChannel};
 
 /// The "plugin" implementing the load transition for the game.
 pub struct LoadingGamePlugin;
@@ -26,12 +29,26 @@ pub struct LoadingGamePlugin;
 impl Plugin for LoadingGamePlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<LoadingTransitionParams>()
-            .init_resource::<LoadingPromiseHolder>();
+            .init_resource::<LoadingPromiseHolder>()
+            .insert_resource(network_metrics_registry());
         app.add_systems(OnEnter(ClientAppState::LoadingGame), kickoff_game_transition)
             .add_systems(Update, (loading_game_transition_handler,).in_set(LoadingGameSystemSet));
     }
 }
 
+/// Makes the registry for the statistics of server connections, capturing their traffic if requested.
+fn network_metrics_registry() -> NetworkMetricsRegistry {
+    let registry = NetworkMetricsRegistry::default();
+    match CaptureWriter::from_env() {
+        Ok(Some(capture)) => registry.with_capture(capture),
+        Ok(None) => registry,
+        Err(e) => {
+            error!("Could not start the network capture: {e:#}");
+            registry
+        }
+    }
+}
+
 /// Parameters for the next transition that happens.
 #[derive(Clone, Resource, Debug, Default)]
 pub enum LoadingTransitionParams {
@@ -41,7 +58,10 @@ pub enum LoadingTransitionParams {
     /// Unload the game and go to the main menu
     GoToMainMenu,
     /// Begin a singleplayer game.
-    SinglePlayer {},
+    SinglePlayer {
+        /// Run the server as a separate process connected over shared memory, instead of on threads of the game.
+        separate_process: bool,
+    },
     /// Join a multiplayer game.
     MultiPlayer {
         /// The not-yet-resolved address to join
@@ -66,22 +86,31 @@ fn kickoff_game_transition(world: &mut World) {
             info!("Shutting down the currently running game");
             //
         }
-        LoadingTransitionParams::SinglePlayer {} => {
+        LoadingTransitionParams::SinglePlayer { separate_process } => {
             info!("Starting a new single player game");
 
-            let game_config = GameConfig {
-                server: ServerConfig {
-                    server_title: String::from("Integrated server"),
+            let (control_tx, control_rx) = std_unbounded_channel();
+            let server_pipe = if separate_process {
+                start_server_process(world, control_tx.clone())
+            } else {
+                let game_config = GameConfig {
+                    server: ServerConfig {
+                        server_title: String::from("Integrated server"),
+                        ..Default::default()
+                    },
                     ..Default::default()
-                },
+                };
+                let game_config = GameConfig::new_handle(game_config);
+                let integ_server = GameServer::new(game_config).expect("Could not start integrated server");
+                integ_server.set_paused(false);
+                integ_server.create_local_connection()
             };
-            let game_config = GameConfig::new_handle(game_config);
-            let integ_server = GameServer::new(game_config).expect("Could not start integrated server");
-            integ_server.set_paused(false);
-            let server_pipe = integ_server.create_local_connection();
-            let (control_tx, control_rx) = std_unbounded_channel();
+            let metrics = world.resource::<NetworkMetricsRegistry>().clone();
 
-            let net_thread = NetworkThread::new(GameSide::Client, move || NetworkThreadClientState::new(control_tx));
+            let net_thread = NetworkThread::new(GameSide::Client, move || {
+                NetworkThreadClientState::new(control_tx, metrics)
+            });
+            register_datagram_handlers(&net_thread);
             let net_thread = Arc::new(net_thread);
 
             let net_thread2 = Arc::clone(&net_thread);
@@ -109,8 +138,12 @@ fn kickoff_game_transition(world: &mut World) {
             let server_address: SocketAddr = server_address_raw.parse().expect("Could not parse server address");
 
             let (control_tx, control_rx) = std_unbounded_channel();
+            let metrics = world.resource::<NetworkMetricsRegistry>().clone();
 
-            let net_thread = NetworkThread::new(GameSide::Client, move || NetworkThreadClientState::new(control_tx));
+            let net_thread = NetworkThread::new(GameSide::Client, move || {
+                NetworkThreadClientState::new(control_tx, metrics)
+            });
+            register_datagram_handlers(&net_thread);
             let net_thread = Arc::new(net_thread);
             let net_thread2 = Arc::clone(&net_thread);
 
@@ -131,46 +164,46 @@ fn kickoff_game_transition(world: &mut World) {
     }
 }
 
+/// Starts the singleplayer server process, which the game then connects to like to a server running on its threads.
+#[cfg(target_os = "linux")]
+fn start_server_process(world: &mut World, game_control: GameControlChannel) -> AsyncResult<LocalConnectionPipe> {
+    match IntegratedServerProcess::spawn(game_control) {
+        Ok((process, pipe)) => {
+            world.insert_resource(process);
+            AsyncResult::new_ok(pipe)
+        }
+        Err(e) => AsyncResult::new_err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_server_process(_world: &mut World, _game_control: GameControlChannel) -> AsyncResult<LocalConnectionPipe> {
+    AsyncResult::new_err(anyhow!(
+        "Running the server in a separate process is only supported on Linux"
+    ))
+}
+
 fn kickoff_connected_game_transition(
     world: &mut World,
     authenticated_net_thread: Arc<NetworkThread<NetworkThreadClientState>>,
     game_command_receiver: StdUnboundedReceiver<Box<GameBevyCommand>>,
 ) {
     let default_registries = builtin_game_registries();
-    struct NetBootstrap {
-        registries: GameRegistries,
-    }
-    let bootstrap_data = authenticated_net_thread
+    let registries = authenticated_net_thread
         .schedule_task(move |state| {
             Box::pin(async move {
                 assert!(
                     state.borrow().server_auth_rpc().is_some(),
                     "Network state was not authenticated before running kickoff_connected_game_transition"
                 );
-                let bootstrap_request = state
-                    .borrow()
-                    .server_auth_rpc()
-                    .context("Missing auth endpoint")?
-                    .bootstrap_game_data_request();
-                let bootstrap_response = bootstrap_request
-                    .send()
-                    .promise
-                    .await
-                    .context("Failed bootstrap request to the remote server")?;
-                let bootstrap_response = bootstrap_response.get()?.get_data()?;
-                let uuid = Uuid::read_from_message(&bootstrap_response.get_universe_id()?);
-                let registries = default_registries.clone_with_serialized_ids(&bootstrap_response)?;
-                let nblocks = registries.block_types.len();
-                info!("Joining server world {uuid} with {nblocks} block types.");
-
-                Ok(NetBootstrap { registries })
+                NetworkThreadClientState::bootstrap_game_data(state, &default_registries).await
             })
         })
         .blocking_wait()
         .expect("Could not connect the client to the remote server");
 
     let client_data = ClientData {
-        shared_registries: bootstrap_data.registries,
+        shared_registries: registries,
     };
 
     let mut promises = world.resource_mut::<LoadingPromiseHolder>();
@@ -20,12 +20,14 @@ impl Plugin for MainMenuPlugin {
 
 struct MenuInputs {
     server_ip: String,
+    separate_server_process: bool,
 }
 
 impl Default for MenuInputs {
     fn default() -> Self {
         Self {
             server_ip: String::from("[::1]:28032"),
+            separate_server_process: false,
         }
     }
 }
@@ -46,9 +48,17 @@ fn main_menu_ui(
             ui.vertical_centered(|ui| {
                 ui.add_space(16.0);
                 if ui.button("Play singleplayer").clicked() {
-                    *loading_data = LoadingTransitionParams::SinglePlayer {};
+                    *loading_data = LoadingTransitionParams::SinglePlayer {
+                        separate_process: menu_inputs.separate_server_process,
+                    };
                     state_switch.set(ClientAppState::LoadingGame);
                 }
+                if cfg!(target_os = "linux") {
+                    ui.checkbox(
+                        &mut menu_inputs.separate_server_process,
+                        "Run the server in a separate process",
+                    );
+                }
                 ui.add_space(8.0);
                 ui.label("Server IP");
                 ui.add_space(8.0);
@@ -4,6 +4,7 @@ use bevy::color::palettes::tailwind;
 use bevy::ecs::schedule::ScheduleLabel;
 use bevy::prelude::*;
 use capnp::message::TypedReader;
+use gs_common::network::metrics::NetworkMetricsRegistry;
 use gs_common::network::transport::RPC_LOCAL_READER_OPTIONS;
 use gs_common::prelude::*;
 use gs_common::voxel::plugin::{
@@ -77,6 +78,7 @@ impl ClientVoxelUniverseBuilder for VoxelUniverseBuilder<'_, ClientData> {
 fn client_chunk_packet_receiver_system(
     mut nvc_q: Query<&mut NetworkVoxelClient<ClientData>>,
     mut voxel_q: Query<&mut ClientVoxelUniverse>,
+    metrics: Res<NetworkMetricsRegistry>,
 ) {
     let mut voxels = voxel_q
         .get_single_mut()
@@ -91,17 +93,21 @@ fn client_chunk_packet_receiver_system(
             break;
         }
     }
+    for (_, peer_metrics) in metrics.peers() {
+        peer_metrics.set_chunk_packets_queued(nvc.chunk_packet_receiver.len());
+    }
 
-    let voxels = &mut *voxels;
+    let chunks = voxels.loaded_chunks_mut();
     for raw_packet in batch {
-        if let Err(e) = handle_chunk_packet(raw_packet, voxels) {
+        if let Err(e) = apply_chunk_packet(&raw_packet, chunks) {
             error!("Error while processing received chunk packet: {e}");
         }
     }
 }
 
-fn handle_chunk_packet(raw_packet: Bytes, voxels: &mut ClientVoxelUniverse) -> Result<()> {
-    let mut slice = &raw_packet as &[u8];
+/// Decodes a chunk data stream packet into the chunk group, returns the position of the received chunk.
+pub fn apply_chunk_packet(raw_packet: &[u8], chunks: &mut ClientChunkGroup) -> Result<AbsChunkPos> {
+    let mut slice = raw_packet;
     let msg = capnp::serialize::read_message_from_flat_slice_no_alloc(&mut slice, RPC_LOCAL_READER_OPTIONS)?;
     let typed_reader = TypedReader::<_, rpc::chunk_data_stream_packet::Owned>::new(msg);
     let root = typed_reader.get()?;
@@ -111,12 +117,83 @@ fn handle_chunk_packet(raw_packet: Bytes, voxels: &mut ClientVoxelUniverse) -> R
     let revision: RevisionNumber = root.get_revision().try_into()?;
     let chunk = ClientChunk::read_full(&data_r, default())?;
 
-    voxels
-        .loaded_chunks_mut()
+    let chunks = &mut chunks.chunks;
+    if let Some(old_chunk) = chunks.get_mut(&pos) {
+        // Keep the client-side data (e.g. meshes) around, and don't overwrite pending predictions with stale data.
+        if let Some(old_chunk) = old_chunk.mutate_from_server_revision(revision) {
+            old_chunk.blocks = chunk.blocks;
+            old_chunk.light_level = chunk.light_level;
+        }
+    } else {
+        chunks.insert(pos, MutWatcher::new_saved(chunk, revision));
+    }
+
+    Ok(pos)
+}
+
+/// Lists the cached chunks with the last revisions received from the server, to keep them when reconnecting.
+/// Chunks with pending predictions are discarded first: their edits were sent over the lost connection, so the new
+/// connection might never resolve them, and the server sends these chunks again instead.
+pub fn held_chunk_revisions(world: &mut World) -> Vec<(AbsChunkPos, RevisionNumber)> {
+    let mut voxel_q = world.query::<&ClientVoxelUniverse>();
+    let Ok(voxels) = voxel_q.get_single(world) else {
+        return Vec::new();
+    };
+    let (predicted, held): (Vec<_>, Vec<_>) = voxels
+        .loaded_chunks()
         .chunks
-        .insert(pos, MutWatcher::new_saved(chunk, revision));
+        .iter()
+        .partition(|(_, chunk)| chunk.is_prediction());
+    let predicted: Vec<AbsChunkPos> = predicted.into_iter().map(|(&pos, _)| pos).collect();
+    let held = held
+        .into_iter()
+        .map(|(&pos, chunk)| (pos, chunk.last_known_revision()))
+        .collect();
+    discard_chunks(world, &predicted);
+    held
+}
 
-    Ok(())
+/// Drops the cached chunks that still hold a prediction, keeping the chunks that the server data already replaced.
+/// The server does not consider these chunks held by the player, or sends them again after a new revision.
+pub fn discard_predictions(world: &mut World, positions: &[AbsChunkPos]) {
+    let mut voxel_q = world.query::<&ClientVoxelUniverse>();
+    let Ok(voxels) = voxel_q.get_single(world) else {
+        return;
+    };
+    let chunks = &voxels.loaded_chunks().chunks;
+    let predicted: Vec<AbsChunkPos> = positions
+        .iter()
+        .copied()
+        .filter(|pos| chunks.get(pos).is_some_and(|chunk| chunk.is_prediction()))
+        .collect();
+    discard_chunks(world, &predicted);
+}
+
+/// Drops cached chunks along with their meshes, used for predictions that the server will not resolve.
+/// The server sends the authoritative data again for chunks it still has loaded nearby.
+pub fn discard_chunks(world: &mut World, positions: &[AbsChunkPos]) {
+    let mut voxel_q = world.query::<&mut ClientVoxelUniverse>();
+    let Ok(mut voxels) = voxel_q.get_single_mut(world) else {
+        return;
+    };
+    let chunks = &mut voxels.loaded_chunks_mut().chunks;
+    let old_meshes: Vec<ChunkMeshState> = positions
+        .iter()
+        .filter_map(|pos| chunks.remove(pos))
+        .filter_map(|chunk| chunk.into_inner().extra_data.mesh)
+        .map(MutWatcher::into_inner)
+        .collect();
+    for old_mesh in old_meshes {
+        let mut meshes = world.resource_mut::<Assets<Mesh>>();
+        for mesh in old_mesh.meshes.iter() {
+            meshes.remove(mesh);
+        }
+        for &entity in old_mesh.entities.iter() {
+            if let Ok(entity) = world.get_entity_mut(entity) {
+                entity.despawn_recursive();
+            }
+        }
+    }
 }
 
 fn client_chunk_mesher_system(
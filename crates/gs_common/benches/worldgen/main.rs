@@ -0,0 +1,5 @@
+use criterion::criterion_main;
+
+pub mod terrainbench;
+
+criterion_main!(terrainbench::terrain_benches);
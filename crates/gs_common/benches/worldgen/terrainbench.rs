@@ -0,0 +1,88 @@
+use std::cell::Cell;
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, BatchSize, Criterion};
+use gs_common::voxel::biomes::setup_basic_biomes;
+use gs_common::voxel::blocks::setup_basic_blocks;
+use gs_common::voxel::generator::multi_noise::MultiNoiseGenerator;
+use gs_common::voxel::generator::VoxelGenerator;
+use gs_common::ServerData;
+use gs_schemas::coordinates::AbsChunkPos;
+use gs_schemas::dependencies::itertools::iproduct;
+use gs_schemas::registry::Registry;
+use gs_schemas::voxel::chunk::Chunk;
+
+const SEED: u64 = 123456789;
+
+/// A generator with the builtin biomes, without their features so that only the terrain is measured.
+fn terrain_generator() -> MultiNoiseGenerator {
+    let mut blocks = Registry::default();
+    setup_basic_blocks(&mut blocks);
+    let mut builtin = Registry::default();
+    setup_basic_biomes(&mut builtin);
+    let mut biomes = Registry::default();
+    for (_, _, biome) in builtin.iter() {
+        let mut biome = biome.clone();
+        biome.features.clear();
+        biomes.push_object(biome).unwrap();
+    }
+    MultiNoiseGenerator::new(SEED, Arc::new(biomes), Arc::new(blocks)).unwrap()
+}
+
+fn generate(generator: &MultiNoiseGenerator, position: AbsChunkPos) -> Chunk<ServerData> {
+    generator.generate_chunk(position, Default::default())
+}
+
+/// The same chunk generated from scratch, then with only its biome region cached, then with its whole column cached,
+/// so that the cached timings can be compared against the cold baseline in one group.
+fn bench_chunk_caches(c: &mut Criterion) {
+    let position = AbsChunkPos::new(3, 0, 3);
+    let mut group = c.benchmark_group("Terrain - chunk");
+    group.bench_function("cold", |b| {
+        b.iter_batched(
+            terrain_generator,
+            |generator| generate(&generator, black_box(position)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("cached biome region", |b| {
+        b.iter_batched(
+            || {
+                let generator = terrain_generator();
+                // The neighbouring column is in the same biome region.
+                generate(&generator, AbsChunkPos::new(4, 0, 3));
+                generator
+            },
+            |generator| generate(&generator, black_box(position)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("cached column", |b| {
+        let generator = terrain_generator();
+        generate(&generator, position);
+        let y = Cell::new(0);
+        b.iter(|| {
+            y.set((y.get() + 1) % 16);
+            generate(&generator, black_box(AbsChunkPos::new(3, y.get() - 8, 3)))
+        })
+    });
+    group.finish();
+}
+
+/// A 4x4 area of chunk columns, 4 chunks tall, generated by a fresh generator: the columns share one biome region and
+/// the chunks of a column share its heightmap.
+fn bench_area(c: &mut Criterion) {
+    c.bench_function("Terrain - 4x4x4 chunk area", |b| {
+        b.iter_batched(
+            terrain_generator,
+            |generator| {
+                for (x, y, z) in iproduct!(0..4, -2..2, 0..4) {
+                    black_box(generate(&generator, AbsChunkPos::new(x, y, z)));
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(terrain_benches, bench_chunk_caches, bench_area);
@@ -0,0 +1,6 @@
+use anyhow::Result;
+use gs_common::capture_tool::run_capture_tool;
+
+fn main() -> Result<()> {
+    run_capture_tool()
+}
@@ -0,0 +1,8 @@
+use anyhow::Result;
+use gs_common::geosia_pre_main;
+use gs_common::worldgen_preview::run_worldgen_preview;
+
+fn main() -> Result<()> {
+    geosia_pre_main();
+    run_worldgen_preview()
+}
@@ -0,0 +1,52 @@
+//! The network capture inspection tool main() implementation
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::network::capture::{CaptureDecoder, CaptureReader};
+use crate::prelude::*;
+
+#[derive(Parser)]
+#[command(name = "gs_capture", about = "Geosia network capture inspection tool")]
+struct CliOptions {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints the messages of a capture file as readable text.
+    Decode {
+        /// The capture file, recorded with the server's `--capture` option or the client's `GS_NETWORK_CAPTURE` variable.
+        file: PathBuf,
+        /// Only print the messages exchanged with this peer, as shown in the decoded output.
+        #[arg(long)]
+        peer: Option<String>,
+        /// Shorten message descriptions to this many characters, 0 prints them whole.
+        #[arg(long, default_value_t = 400)]
+        max_length: usize,
+    },
+}
+
+/// Starts the capture tool CLI
+pub fn run_capture_tool() -> Result<()> {
+    let cli = CliOptions::parse();
+    match cli.command {
+        Command::Decode { file, peer, max_length } => {
+            let mut decoder = CaptureDecoder::new((max_length > 0).then_some(max_length));
+            let mut out = io::stdout().lock();
+            for message in CaptureReader::open(&file)? {
+                let message = message.context("Reading the capture file")?;
+                if peer.as_ref().is_some_and(|peer| *peer != message.peer) {
+                    continue;
+                }
+                for line in decoder.decode(&message) {
+                    writeln!(out, "{line}")?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
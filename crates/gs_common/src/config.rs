@@ -1,11 +1,13 @@
 //! Game configuration handling
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use smart_default::SmartDefault;
 
 use crate::prelude::{async_watch_channel, AsyncWatchReceiver, AsyncWatchSender};
+use crate::voxel::generator::multi_noise::DEFAULT_SEA_LEVEL;
 
 /// The server-specific configuration.
 #[derive(Clone, Eq, PartialEq, Debug, SmartDefault)]
@@ -22,6 +24,20 @@ pub struct ServerConfig {
     /// The network IPs and ports to listen on.
     #[default(default_listen_addresses())]
     pub listen_addresses: Vec<SocketAddr>,
+    /// A file to record all the network traffic of the server into, for inspection with `gs_capture`.
+    #[default(None)]
+    pub capture_file: Option<PathBuf>,
+}
+
+/// The world generation configuration.
+#[derive(Clone, Eq, PartialEq, Debug, SmartDefault)]
+pub struct WorldGenConfig {
+    /// Experimental: carves overhangs and caves through the heightmap terrain.
+    #[default = false]
+    pub terrain_density: bool,
+    /// The Y level below which open space is flooded with water.
+    #[default(DEFAULT_SEA_LEVEL)]
+    pub sea_level: i32,
 }
 
 /// All game configuration saved into the config file.
@@ -29,6 +45,8 @@ pub struct ServerConfig {
 pub struct GameConfig {
     /// Server configuration.
     pub server: ServerConfig,
+    /// World generation configuration.
+    pub world_gen: WorldGenConfig,
 }
 
 /// A GameConfig handle that can listen to changes, used as the primary way of accessing the game configuration.
@@ -1,27 +1,55 @@
 //! The dedicated server main() implementation
 
+use std::path::PathBuf;
+
 use bevy::prelude::*;
 use clap::Parser;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
-use crate::config::{GameConfig, ServerConfig};
+use crate::config::{GameConfig, ServerConfig, WorldGenConfig};
 use crate::prelude::*;
+use crate::voxel::generator::multi_noise::DEFAULT_SEA_LEVEL;
 use crate::GameServer;
 
 #[derive(Parser)]
 #[command(name = "gs_dedi_server", about = "Geosia dedicated server")]
-struct CliOptions {}
+struct CliOptions {
+    /// Record all the network traffic into the given capture file.
+    #[arg(long, value_name = "FILE")]
+    capture: Option<PathBuf>,
+    /// Experimental: generate overhangs and caves through the terrain.
+    #[arg(long)]
+    terrain_density: bool,
+    /// The Y level below which open space is flooded with water.
+    #[arg(long, value_name = "Y", default_value_t = DEFAULT_SEA_LEVEL, allow_negative_numbers = true)]
+    sea_level: i32,
+    /// Serve a single game client over the shared memory connection it passed on, used for singleplayer.
+    /// Takes the inherited descriptor numbers, and shuts the server down once the client disconnects.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "FDS")]
+    shared_memory: Option<String>,
+}
 
 /// Starts the dedicated server CLI
 pub fn run_dedicated_server() -> Result<()> {
-    let _cli = CliOptions::parse();
+    let cli = CliOptions::parse();
+
+    #[cfg(target_os = "linux")]
+    if let Some(fds) = cli.shared_memory {
+        return run_shared_memory_server(&fds, cli.capture);
+    }
 
     let game_config = GameConfig {
         server: ServerConfig {
             server_title: String::from("Dedicated server"),
+            capture_file: cli.capture,
             ..Default::default()
         },
+        world_gen: WorldGenConfig {
+            terrain_density: cli.terrain_density,
+            sea_level: cli.sea_level,
+        },
     };
     let game_config = GameConfig::new_handle(game_config);
     let integ_server = GameServer::new(game_config).expect("Could not start dedicated server");
@@ -41,6 +69,15 @@ pub fn run_dedicated_server() -> Result<()> {
                             integ_server.shutdown().blocking_wait()?;
                             break;
                         }
+                        "netstats" => {
+                            let peers = integ_server.network_metrics().snapshot();
+                            if peers.is_empty() {
+                                info!("No connected peers");
+                            }
+                            for (peer, stats) in peers {
+                                info!("Network statistics for {peer}:\n{stats}");
+                            }
+                        }
                         _ => {
                             error!("Unknown command {cmd}");
                         }
@@ -66,3 +103,35 @@ pub fn run_dedicated_server() -> Result<()> {
 
     Ok(())
 }
+
+/// Runs the server for the single client on the other end of the inherited shared memory, until it disconnects.
+#[cfg(target_os = "linux")]
+fn run_shared_memory_server(fds: &str, capture_file: Option<PathBuf>) -> Result<()> {
+    use crate::network::shm::{spawn_bridge_thread, ShmConnectionFds, ShmEndpoint};
+
+    // Safety: the descriptors were inherited from the client that started this process, nothing else uses them.
+    let fds = unsafe { ShmConnectionFds::from_inherited_arg(fds)? };
+    let endpoint = ShmEndpoint::open(fds)?;
+
+    let game_config = GameConfig {
+        server: ServerConfig {
+            server_title: String::from("Integrated server"),
+            listen_addresses: Vec::new(),
+            capture_file,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = GameServer::new(GameConfig::new_handle(game_config)).context("Could not start the server")?;
+    server.set_paused(false);
+    let (_, pipe) = server.create_local_connection().blocking_wait()?;
+    info!("Serving the client connected over shared memory");
+
+    match spawn_bridge_thread(endpoint, pipe).join() {
+        Ok(Ok(())) => info!("The client closed the connection"),
+        Ok(Err(e)) => error!("Lost the connection to the client: {e:#}"),
+        Err(_) => error!("The shared memory connection crashed"),
+    }
+    info!("Shutting down the server...");
+    server.shutdown().blocking_wait()
+}
@@ -8,12 +8,17 @@
 
 //! The common client&server code for Geosia
 
+pub mod capture_tool;
 pub mod config;
 pub mod dedicated_server;
 pub mod network;
+pub mod player;
 pub mod prelude;
 pub mod promises;
+#[cfg(test)]
+mod test_utils;
 pub mod voxel;
+pub mod worldgen_preview;
 
 use std::thread::JoinHandle;
 use std::time::Duration;
@@ -33,28 +38,50 @@ use smallvec::SmallVec;
 use voxel::persistence::generator::GeneratorPersistenceLayer;
 use voxel::plugin::VoxelUniverseBuilder;
 
-use crate::config::{GameConfig, GameConfigHandle};
+use crate::config::{GameConfig, GameConfigHandle, WorldGenConfig};
+use crate::network::capture::CaptureWriter;
+use crate::network::metrics::{NetworkDiagnosticsPlugin, NetworkMetricsRegistry};
+use crate::network::replication::{register_server_datagram_handlers, PlayerReplicationPlugin};
 use crate::network::server::{LocalConnectionPipe, NetworkServerPlugin, NetworkThreadServerState};
+use crate::network::simulator::NetworkConditions;
 use crate::network::thread::NetworkThread;
+use crate::player::PlayerMovementPlugin;
 use crate::prelude::*;
+use crate::voxel::generator::density::TerrainDensitySettings;
 use crate::voxel::generator::multi_noise::MultiNoiseGenerator;
 use crate::voxel::persistence::memory::MemoryPersistenceLayer;
-use crate::voxel::plugin::VoxelUniversePlugin;
+use crate::voxel::plugin::{VoxelUniversePlugin, WorldGeneratorHolder};
 
-// TODO: Populate these from build/git info
 /// The major SemVer field of the current build's version
-pub static GAME_VERSION_MAJOR: u32 = 0;
+pub static GAME_VERSION_MAJOR: u32 = parse_version_field(env!("CARGO_PKG_VERSION_MAJOR"));
 /// The minor SemVer field of the current build's version
-pub static GAME_VERSION_MINOR: u32 = 0;
+pub static GAME_VERSION_MINOR: u32 = parse_version_field(env!("CARGO_PKG_VERSION_MINOR"));
 /// The patch SemVer field of the current build's version
-pub static GAME_VERSION_PATCH: u32 = 1;
-/// The build SemVer field of the current build's version
-pub static GAME_VERSION_BUILD: &str = "todo";
-/// The prerelease SemVer field of the current build's version
-pub static GAME_VERSION_PRERELEASE: &str = "";
+pub static GAME_VERSION_PATCH: u32 = parse_version_field(env!("CARGO_PKG_VERSION_PATCH"));
+/// The build SemVer field of the current build's version: the git commit hash and the cargo profile
+pub static GAME_VERSION_BUILD: &str = env!("GS_BUILD_INFO");
+/// The prerelease SemVer field of the current build's version, set from `GS_VERSION_PRERELEASE` at build time
+pub static GAME_VERSION_PRERELEASE: &str = match option_env!("GS_VERSION_PRERELEASE") {
+    Some(prerelease) => prerelease,
+    None => "",
+};
 /// The name of the game
 pub static GAME_BRAND_NAME: &str = "Geosia";
 
+/// Parses a numeric version field set by cargo at compile time.
+const fn parse_version_field(field: &str) -> u32 {
+    let digits = field.as_bytes();
+    assert!(!digits.is_empty(), "Empty version field");
+    let mut value: u32 = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        assert!(digits[i].is_ascii_digit(), "Non-numeric version field");
+        value = value * 10 + (digits[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
 /// Target (maximum) number of game simulation ticks in a second.
 pub const TICKS_PER_SECOND: i32 = 32;
 /// Target (maximum) number of game simulation ticks in a second, as a `f32`.
@@ -111,6 +138,7 @@ pub struct GameServer {
     network_thread: NetworkThread<NetworkThreadServerState>,
     pause: AtomicBool,
     control_channel: StdUnboundedSender<GameServerControlCommand>,
+    network_metrics: NetworkMetricsRegistry,
 }
 
 /// A handle to a [`GameServer`] accessible from within bevy systems.
@@ -127,6 +155,12 @@ impl GameServer {
         let (tx, rx) = std_bounded_channel(1);
         let (ctrl_tx, ctrl_rx) = std_unbounded_channel();
 
+        let capture_file = config.1.borrow().server.capture_file.clone();
+        let network_metrics = match capture_file {
+            Some(path) => NetworkMetricsRegistry::default().with_capture(CaptureWriter::create(&path)?),
+            None => NetworkMetricsRegistry::default(),
+        };
+
         let network_thread = NetworkThread::new(GameSide::Server, NetworkThreadServerState::new);
 
         let engine_thread = std::thread::Builder::new()
@@ -146,8 +180,11 @@ impl GameServer {
             network_thread,
             pause: AtomicBool::new(true),
             control_channel: ctrl_tx,
+            network_metrics,
         };
         let server = Arc::new(server);
+        // Before any connection can be made, so that no datagram arrives without its handler.
+        register_server_datagram_handlers(&server);
         tx.send(Arc::clone(&server))
             .expect("Could not pass initialization data to the server engine thread");
         Ok(server)
@@ -177,6 +214,11 @@ impl GameServer {
         &self.config
     }
 
+    /// Returns the network statistics of all the connected clients.
+    pub fn network_metrics(&self) -> &NetworkMetricsRegistry {
+        &self.network_metrics
+    }
+
     /// Checks if the game logic is paused.
     pub fn is_paused(&self) -> bool {
         self.pause.load(AtomicOrdering::SeqCst)
@@ -229,7 +271,27 @@ impl GameServer {
     pub fn create_local_connection(self: &Arc<Self>) -> AsyncResult<LocalConnectionPipe> {
         let inner_engine = Arc::clone(self);
         self.network_thread.schedule_task(move |state| {
-            Box::pin(NetworkThreadServerState::accept_local_connection(state, inner_engine))
+            Box::pin(NetworkThreadServerState::accept_local_connection(
+                state,
+                inner_engine,
+                None,
+            ))
+        })
+    }
+
+    /// Asynchronously creates a new local connection to this server's network runtime,
+    /// with all the traffic subject to the given simulated network conditions.
+    pub fn create_simulated_local_connection(
+        self: &Arc<Self>,
+        conditions: NetworkConditions,
+    ) -> AsyncResult<LocalConnectionPipe> {
+        let inner_engine = Arc::clone(self);
+        self.network_thread.schedule_task(move |state| {
+            Box::pin(NetworkThreadServerState::accept_local_connection(
+                state,
+                inner_engine,
+                Some(conditions),
+            ))
         })
     }
 
@@ -267,13 +329,36 @@ impl GameServer {
             .add_plugins(ScheduleRunnerPlugin::run_loop(TICK));
 
         app.add_plugins(VoxelUniversePlugin::<ServerData>::new())
-            .add_plugins(NetworkServerPlugin);
+            .add_plugins(NetworkServerPlugin)
+            .add_plugins(NetworkDiagnosticsPlugin)
+            .add_plugins(PlayerMovementPlugin::<ServerData>::new())
+            .add_plugins(PlayerReplicationPlugin);
 
         let block_registry = Arc::clone(&engine.server_data.shared_registries.block_types);
         let biome_registry = Arc::clone(&engine.server_data.shared_registries.biome_types);
 
-        let generator = MultiNoiseGenerator::new(123456789, Arc::clone(&biome_registry), Arc::clone(&block_registry));
-        let gen_world = GeneratorPersistenceLayer::new(Arc::new(generator), default());
+        let world_gen_config = engine.config().borrow().world_gen.clone();
+        let generator = Arc::new(
+            builtin_world_generator(
+                DEFAULT_WORLD_SEED,
+                &engine.server_data.shared_registries,
+                &world_gen_config,
+            )
+            .expect("Could not set up the world generator"),
+        );
+        app.insert_resource(WorldGeneratorHolder(Arc::clone(&generator)));
+        let network_generator = Arc::clone(&generator);
+        engine
+            .network_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    state.borrow_mut().set_world_generator(network_generator);
+                    Ok(())
+                })
+            })
+            .blocking_wait()
+            .expect("Could not pass the world generator to the network thread");
+        let gen_world = GeneratorPersistenceLayer::new(generator, default());
         let persistence = MemoryPersistenceLayer::new(Box::new(gen_world));
 
         fn configure_sets(app: &mut App, schedule: impl ScheduleLabel) {
@@ -288,6 +373,7 @@ impl GameServer {
 
         app.insert_resource(Time::<Fixed>::from_duration(TICK));
         app.insert_resource(GameServerControlCommandReceiver(SyncCell::new(ctrl_rx)));
+        app.insert_resource(engine.network_metrics.clone());
         app.insert_resource(GameServerResource(engine));
 
         VoxelUniverseBuilder::<ServerData>::new(app.world_mut(), block_registry, biome_registry)
@@ -344,12 +430,23 @@ impl GameServer {
     }
 }
 
-/// Simple hardcoded registries of some game objects.
+/// The directory with game data files, relative to the working directory.
+pub const GAME_DATA_DIRECTORY: &str = "assets/data";
+
+/// Simple hardcoded registries of some game objects, extended with the data files from [`GAME_DATA_DIRECTORY`].
 pub fn builtin_game_registries() -> GameRegistries {
     let mut block_types = Registry::default();
     voxel::blocks::setup_basic_blocks(&mut block_types);
     let mut biome_types = Registry::default();
     voxel::biomes::setup_basic_biomes(&mut biome_types);
+    let data_directory = std::path::Path::new(GAME_DATA_DIRECTORY);
+    if data_directory.is_dir() {
+        match voxel::biomes::load_biome_directory(&mut biome_types, &block_types, data_directory) {
+            Ok(0) => {}
+            Ok(count) => info!("Loaded {count} biomes from {GAME_DATA_DIRECTORY}"),
+            Err(e) => error!("Could not load the biomes from {GAME_DATA_DIRECTORY}: {e:#}"),
+        }
+    }
 
     GameRegistries {
         block_types: Arc::new(block_types),
@@ -357,6 +454,47 @@ pub fn builtin_game_registries() -> GameRegistries {
     }
 }
 
+/// The seed of the generated world, until worlds get saved along with their own seed.
+pub const DEFAULT_WORLD_SEED: u64 = 123456789;
+
+/// The standard world generator with the given registries and the structures from [`GAME_DATA_DIRECTORY`], flooded up
+/// to the sea level of `config`, with the default terrain density settings if enabled in `config`.
+pub fn builtin_world_generator(
+    seed: u64,
+    registries: &GameRegistries,
+    config: &WorldGenConfig,
+) -> Result<MultiNoiseGenerator> {
+    let data_directory = std::path::Path::new(GAME_DATA_DIRECTORY);
+    let structure_templates = if data_directory.is_dir() {
+        match voxel::structures::load_structure_directory(data_directory) {
+            Ok(templates) => {
+                if !templates.is_empty() {
+                    info!("Loaded {} structures from {GAME_DATA_DIRECTORY}", templates.len());
+                }
+                templates
+            }
+            Err(e) => {
+                error!("Could not load the structures from {GAME_DATA_DIRECTORY}: {e:#}");
+                HashMap::new()
+            }
+        }
+    } else {
+        HashMap::new()
+    };
+    let generator = MultiNoiseGenerator::new(
+        seed,
+        Arc::clone(&registries.biome_types),
+        Arc::clone(&registries.block_types),
+    )?
+    .with_structure_templates(&structure_templates)
+    .with_sea_level(config.sea_level);
+    if config.terrain_density {
+        generator.with_terrain_density(&TerrainDensitySettings::default())
+    } else {
+        Ok(generator)
+    }
+}
+
 /// Runs static pre-main setup needed (e.g. cryptography init)
 pub fn geosia_pre_main() {
     // Set up bevy's logging once per process
@@ -0,0 +1,971 @@
+//! Recording of network traffic into capture files, and tools for decoding and replaying the captures.
+//!
+//! A capture file is a sequence of [`rpc::capture_record`] messages in the unpacked capnp encoding.
+//! Stream packets and datagrams are recorded whole, while the main RPC channel is recorded as the raw pieces of its
+//! byte stream, which [`CaptureDecoder`] reassembles into RPC messages.
+
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use capnp::message::TypedBuilder;
+use capnp::traits::HasTypeId;
+use capnp_rpc::rpc_capnp;
+use gs_schemas::schemas::{NetworkDatagramHeader, NetworkStreamType};
+use tokio_util::bytes::Bytes;
+
+use crate::network::protocol::{unpack_stream_packet, write_stream_packet, NegotiatedProtocol, ProtocolFeatures};
+use crate::network::thread::{NetworkThread, NetworkThreadState};
+use crate::network::transport::{InProcessStream, RPC_LOCAL_READER_OPTIONS};
+use crate::network::PeerAddress;
+use crate::prelude::*;
+use crate::voxel::plugin::CHUNK_DATA_STREAM;
+
+/// Environment variable with the path of a capture file to record all the network traffic of the client into.
+pub const NETWORK_CAPTURE_ENV_VAR: &str = "GS_NETWORK_CAPTURE";
+
+/// How often buffered capture records are flushed to the capture file.
+const CAPTURE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The direction of a captured message, relative to the side that recorded it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum CaptureDirection {
+    /// Received from the peer.
+    Received,
+    /// Sent to the peer.
+    Sent,
+}
+
+impl CaptureDirection {
+    /// The opposite direction.
+    pub fn reversed(self) -> Self {
+        match self {
+            Self::Received => Self::Sent,
+            Self::Sent => Self::Received,
+        }
+    }
+
+    fn arrow(self) -> &'static str {
+        match self {
+            Self::Received => "<-",
+            Self::Sent => "->",
+        }
+    }
+}
+
+impl From<CaptureDirection> for rpc::capture_record::Direction {
+    fn from(value: CaptureDirection) -> Self {
+        match value {
+            CaptureDirection::Received => Self::Received,
+            CaptureDirection::Sent => Self::Sent,
+        }
+    }
+}
+
+impl From<rpc::capture_record::Direction> for CaptureDirection {
+    fn from(value: rpc::capture_record::Direction) -> Self {
+        match value {
+            rpc::capture_record::Direction::Received => Self::Received,
+            rpc::capture_record::Direction::Sent => Self::Sent,
+        }
+    }
+}
+
+/// The contents of a single captured message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CapturedPayload {
+    /// A piece of the main RPC channel byte stream.
+    Rpc(Bytes),
+    /// A single packet on an asynchronous stream.
+    Stream {
+        /// Identifies the stream within the capture, unique per peer.
+        id: u64,
+        /// The stream type and version.
+        stream_type: NetworkStreamType,
+        /// The packet contents.
+        payload: Bytes,
+    },
+    /// A single datagram.
+    Datagram {
+        /// The datagram type.
+        header: NetworkDatagramHeader,
+        /// The datagram contents following the header.
+        payload: Bytes,
+    },
+    /// The protocol negotiated with the peer, which determines the encoding of the packets captured after it.
+    Protocol(NegotiatedProtocol),
+}
+
+/// A single captured message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CapturedMessage {
+    /// Time since the start of the capture.
+    pub timestamp: Duration,
+    /// Address of the other side of the connection.
+    pub peer: String,
+    /// Whether the message was sent or received by the recording side.
+    pub direction: CaptureDirection,
+    /// The message contents.
+    pub payload: CapturedPayload,
+}
+
+impl CapturedMessage {
+    /// Serializes the message into a capnp capture record.
+    pub fn write_to_message(&self, builder: &mut rpc::capture_record::Builder) {
+        builder.set_timestamp_micros(self.timestamp.as_micros().try_into().unwrap_or(u64::MAX));
+        builder.set_peer(&self.peer);
+        builder.set_direction(self.direction.into());
+        match &self.payload {
+            CapturedPayload::Rpc(bytes) => builder.set_rpc(bytes),
+            CapturedPayload::Stream {
+                id,
+                stream_type,
+                payload,
+            } => {
+                let mut stream = builder.reborrow().init_stream();
+                stream.set_id(*id);
+                stream_type.write_to_message(&mut stream.reborrow().init_header());
+                stream.set_payload(payload);
+            }
+            CapturedPayload::Datagram { header, payload } => {
+                let mut datagram = builder.reborrow().init_datagram();
+                header.write_to_message(&mut datagram.reborrow().init_header());
+                datagram.set_payload(payload);
+            }
+            CapturedPayload::Protocol(protocol) => {
+                protocol.write_to_message(&mut builder.reborrow().init_protocol());
+            }
+        }
+    }
+
+    /// Deserializes the message from a capnp capture record.
+    pub fn read_from_message(reader: &rpc::capture_record::Reader) -> capnp::Result<Self> {
+        let payload = match reader.which()? {
+            rpc::capture_record::Which::Rpc(bytes) => CapturedPayload::Rpc(Bytes::copy_from_slice(bytes?)),
+            rpc::capture_record::Which::Stream(stream) => {
+                let stream = stream?;
+                CapturedPayload::Stream {
+                    id: stream.get_id(),
+                    stream_type: NetworkStreamType::read_from_message(&stream.get_header()?)?,
+                    payload: Bytes::copy_from_slice(stream.get_payload()?),
+                }
+            }
+            rpc::capture_record::Which::Datagram(datagram) => {
+                let datagram = datagram?;
+                CapturedPayload::Datagram {
+                    header: NetworkDatagramHeader::read_from_message(&datagram.get_header()?)?,
+                    payload: Bytes::copy_from_slice(datagram.get_payload()?),
+                }
+            }
+            rpc::capture_record::Which::Protocol(protocol) => {
+                CapturedPayload::Protocol(NegotiatedProtocol::read_from_message(&protocol?))
+            }
+        };
+        Ok(Self {
+            timestamp: Duration::from_micros(reader.get_timestamp_micros()),
+            peer: reader.get_peer()?.to_string()?,
+            direction: reader.get_direction()?.into(),
+            payload,
+        })
+    }
+}
+
+struct CaptureOutput {
+    output: Box<dyn Write + Send>,
+    last_flush: Instant,
+    failed: bool,
+}
+
+struct CaptureWriterInner {
+    start: Instant,
+    output: Mutex<CaptureOutput>,
+}
+
+/// A capture file being recorded, shared by all the connections of a client or server.
+#[derive(Clone)]
+pub struct CaptureWriter(Arc<CaptureWriterInner>);
+
+impl Debug for CaptureWriter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureWriter").field("start", &self.0.start).finish()
+    }
+}
+
+impl CaptureWriter {
+    /// Starts a capture written into the given output.
+    pub fn new(output: impl Write + Send + 'static) -> Self {
+        let start = Instant::now();
+        Self(Arc::new(CaptureWriterInner {
+            start,
+            output: Mutex::new(CaptureOutput {
+                output: Box::new(output),
+                last_flush: start,
+                failed: false,
+            }),
+        }))
+    }
+
+    /// Starts a capture written into a new file at the given path, replacing any existing file.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Creating the capture file {}", path.display()))?;
+        tracing::info!("Recording network traffic into {}", path.display());
+        Ok(Self::new(BufWriter::new(file)))
+    }
+
+    /// Starts a capture into the file named by the [`NETWORK_CAPTURE_ENV_VAR`] environment variable, if it's set.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var_os(NETWORK_CAPTURE_ENV_VAR) {
+            Some(path) if !path.is_empty() => Self::create(Path::new(&path)).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// The time since the start of the capture.
+    pub fn elapsed(&self) -> Duration {
+        self.0.start.elapsed()
+    }
+
+    /// Appends a message to the capture.
+    /// Write errors are logged once, after which the capture stops recording.
+    pub fn write(&self, message: &CapturedMessage) {
+        let mut builder = TypedBuilder::<rpc::capture_record::Owned>::new_default();
+        message.write_to_message(&mut builder.init_root());
+        let mut output = self.0.output.lock().unwrap();
+        if output.failed {
+            return;
+        }
+        let mut result =
+            capnp::serialize::write_message(&mut output.output, builder.borrow_inner()).map_err(anyhow::Error::from);
+        if result.is_ok() && output.last_flush.elapsed() >= CAPTURE_FLUSH_INTERVAL {
+            output.last_flush = Instant::now();
+            result = output.output.flush().map_err(anyhow::Error::from);
+        }
+        if let Err(e) = result {
+            tracing::error!("Could not write the network capture, stopping the capture: {e}");
+            output.failed = true;
+        }
+    }
+
+    /// Writes out any buffered records.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut output = self.0.output.lock().unwrap();
+        output.last_flush = Instant::now();
+        output.output.flush()
+    }
+
+    /// Makes a recorder for the traffic of a single connection.
+    pub fn peer(&self, peer: impl Display) -> PeerCapture {
+        PeerCapture {
+            writer: self.clone(),
+            peer: peer.to_string().into(),
+            next_stream_id: Default::default(),
+        }
+    }
+}
+
+/// Records the traffic of a single connection into a [`CaptureWriter`].
+#[derive(Clone, Debug)]
+pub struct PeerCapture {
+    writer: CaptureWriter,
+    peer: Arc<str>,
+    next_stream_id: Arc<AtomicU64>,
+}
+
+impl PeerCapture {
+    /// Records a message exchanged with the peer.
+    pub fn record(&self, direction: CaptureDirection, payload: CapturedPayload) {
+        self.writer.write(&CapturedMessage {
+            timestamp: self.writer.elapsed(),
+            peer: self.peer.to_string(),
+            direction,
+            payload,
+        });
+    }
+
+    /// Records a piece of the RPC channel byte stream.
+    pub fn record_rpc(&self, direction: CaptureDirection, bytes: &[u8]) {
+        self.record(direction, CapturedPayload::Rpc(Bytes::copy_from_slice(bytes)));
+    }
+
+    /// Records a datagram.
+    pub fn record_datagram(&self, direction: CaptureDirection, header: &NetworkDatagramHeader, payload: &[u8]) {
+        self.record(
+            direction,
+            CapturedPayload::Datagram {
+                header: header.clone(),
+                payload: Bytes::copy_from_slice(payload),
+            },
+        );
+    }
+
+    /// Records the protocol negotiated with the peer, `direction` being the one of the negotiation response.
+    pub fn record_protocol(&self, direction: CaptureDirection, protocol: NegotiatedProtocol) {
+        self.record(direction, CapturedPayload::Protocol(protocol));
+    }
+
+    /// Makes a recorder for a newly opened stream.
+    pub fn stream(&self, stream_type: &NetworkStreamType) -> StreamCapture {
+        StreamCapture {
+            peer: self.clone(),
+            id: self.next_stream_id.fetch_add(1, AtomicOrdering::Relaxed),
+            stream_type: stream_type.clone(),
+        }
+    }
+}
+
+/// Records the packets of a single stream into a [`CaptureWriter`].
+#[derive(Clone, Debug)]
+pub struct StreamCapture {
+    peer: PeerCapture,
+    id: u64,
+    stream_type: NetworkStreamType,
+}
+
+impl StreamCapture {
+    /// Records a stream packet.
+    pub fn record(&self, direction: CaptureDirection, payload: &Bytes) {
+        self.peer.record(
+            direction,
+            CapturedPayload::Stream {
+                id: self.id,
+                stream_type: self.stream_type.clone(),
+                payload: payload.clone(),
+            },
+        );
+    }
+}
+
+/// An in-memory capture output that can be read while it's still being written to, for tests.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(pub Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    /// Parses all the messages written so far.
+    pub fn messages(&self) -> Vec<CapturedMessage> {
+        let bytes = self.0.lock().unwrap().clone();
+        CaptureReader::new(&bytes[..]).collect::<Result<_>>().unwrap()
+    }
+}
+
+/// Reads the messages of a capture file one by one.
+pub struct CaptureReader<R> {
+    input: R,
+    finished: bool,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Opens a capture file for reading.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Opening the capture file {}", path.display()))?;
+        Ok(Self::new(BufReader::new(file)))
+    }
+}
+
+impl<R: BufRead> CaptureReader<R> {
+    /// Reads a capture from the given input.
+    pub fn new(input: R) -> Self {
+        Self { input, finished: false }
+    }
+
+    fn read_message(&mut self) -> Result<Option<CapturedMessage>> {
+        let Some(message) = capnp::serialize::try_read_message(&mut self.input, RPC_LOCAL_READER_OPTIONS)? else {
+            return Ok(None);
+        };
+        let record = message.get_root::<rpc::capture_record::Reader>()?;
+        Ok(Some(CapturedMessage::read_from_message(&record)?))
+    }
+}
+
+impl<R: BufRead> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = self.read_message();
+        // A truncated or corrupted capture can't be resynchronized, so stop at the first error.
+        self.finished = !matches!(result, Ok(Some(_)));
+        result.transpose()
+    }
+}
+
+macro_rules! rpc_method_table {
+    ($($interface:ident $interface_name:literal {
+        $($method_id:literal => $method_name:literal $params:ident $results:ident,)*
+    })*) => {
+        /// Names the called method and formats its parameters or results, if it's one of the game's RPC methods.
+        fn describe_rpc_payload(
+            interface_id: u64,
+            method_id: u16,
+            content: capnp::any_pointer::Reader,
+            is_results: bool,
+        ) -> (String, capnp::Result<String>) {
+            $(
+                if interface_id == <rpc::$interface::Client as HasTypeId>::TYPE_ID {
+                    return match method_id {
+                        $($method_id => (
+                            concat!($interface_name, ".", $method_name).to_string(),
+                            if is_results {
+                                content.get_as::<rpc::$interface::$results::Reader>().map(|r| format!("{r:?}"))
+                            } else {
+                                content.get_as::<rpc::$interface::$params::Reader>().map(|r| format!("{r:?}"))
+                            },
+                        ),)*
+                        _ => (format!(concat!($interface_name, ".#{}"), method_id), Ok(String::new())),
+                    };
+                }
+            )*
+            let size = content.target_size().map(|size| format!("<{} words>", size.word_count));
+            (format!("{interface_id:#x}.#{method_id}"), size)
+        }
+    };
+}
+
+rpc_method_table! {
+    game_server "GameServer" {
+        0 => "getServerMetadata" get_server_metadata_params get_server_metadata_results,
+        1 => "ping" ping_params ping_results,
+        2 => "authenticate" authenticate_params authenticate_results,
+        3 => "negotiateProtocol" negotiate_protocol_params negotiate_protocol_results,
+    }
+    authenticated_client_connection "AuthenticatedClientConnection" {
+        0 => "terminateConnection" terminate_connection_params terminate_connection_results,
+        1 => "addChatMessage" add_chat_message_params add_chat_message_results,
+    }
+    authenticated_server_connection "AuthenticatedServerConnection" {
+        0 => "bootstrapGameData" bootstrap_game_data_params bootstrap_game_data_results,
+        1 => "sendChatMessage" send_chat_message_params send_chat_message_results,
+        2 => "editBlocks" edit_blocks_params edit_blocks_results,
+        3 => "queryTerrain" query_terrain_params query_terrain_results,
+    }
+}
+
+/// Returns the length of the framed capnp message at the start of `bytes`, or None if the segment table is incomplete.
+fn framed_message_len(bytes: &[u8]) -> Result<Option<usize>> {
+    let read_u32 = |offset: usize| {
+        bytes
+            .get(offset..offset + 4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()) as usize)
+    };
+    let Some(segment_count) = read_u32(0).map(|count| count + 1) else {
+        return Ok(None);
+    };
+    if segment_count > 512 {
+        bail!("Invalid RPC message with {segment_count} segments");
+    }
+    let table_len = (4 + 4 * segment_count).next_multiple_of(8);
+    if bytes.len() < table_len {
+        return Ok(None);
+    }
+    let words: usize = (0..segment_count).map(|i| read_u32(4 + 4 * i).unwrap()).sum();
+    Ok(Some(table_len + words * 8))
+}
+
+/// Turns captured messages into human-readable text.
+/// Keeps track of the RPC channel state, so the messages have to be passed in the captured order.
+#[derive(Default)]
+pub struct CaptureDecoder {
+    max_length: Option<usize>,
+    rpc_buffers: HashMap<(String, CaptureDirection), Vec<u8>>,
+    questions: HashMap<(String, CaptureDirection, u32), (u64, u16)>,
+    protocols: HashMap<String, NegotiatedProtocol>,
+}
+
+impl CaptureDecoder {
+    /// Constructs a decoder that shortens descriptions longer than `max_length` characters, if given.
+    pub fn new(max_length: Option<usize>) -> Self {
+        Self {
+            max_length,
+            ..Default::default()
+        }
+    }
+
+    /// Describes a captured message, one line per decoded message.
+    /// RPC messages are only described once all their bytes were seen, so pieces of the RPC stream can produce no lines.
+    pub fn decode(&mut self, message: &CapturedMessage) -> Vec<String> {
+        let descriptions = match &message.payload {
+            CapturedPayload::Rpc(bytes) => self.decode_rpc(&message.peer, message.direction, bytes),
+            CapturedPayload::Stream {
+                id,
+                stream_type,
+                payload,
+            } => {
+                let compressed = self
+                    .protocols
+                    .get(&message.peer)
+                    .is_some_and(|protocol| protocol.features.contains(ProtocolFeatures::COMPRESSION));
+                let description = describe_stream_packet(stream_type, payload, compressed)
+                    .unwrap_or_else(|e| format!("<undecodable: {e}> ({} bytes)", payload.len()));
+                vec![format!(
+                    "stream #{id} {:?} v{}: {description}",
+                    stream_type.header, stream_type.version
+                )]
+            }
+            CapturedPayload::Datagram { header, payload } => {
+                let description = describe_datagram(header, payload)
+                    .unwrap_or_else(|e| format!("<undecodable: {e}> ({} bytes)", payload.len()));
+                vec![format!("datagram {header:?}: {description}")]
+            }
+            CapturedPayload::Protocol(protocol) => {
+                self.protocols.insert(message.peer.clone(), *protocol);
+                vec![format!(
+                    "protocol v{} with features {:?}",
+                    protocol.version, protocol.features
+                )]
+            }
+        };
+        descriptions
+            .into_iter()
+            .map(|description| {
+                format!(
+                    "{:>12.6}s {} {} {}",
+                    message.timestamp.as_secs_f64(),
+                    message.peer,
+                    message.direction.arrow(),
+                    self.shorten(description)
+                )
+            })
+            .collect()
+    }
+
+    fn shorten(&self, mut description: String) -> String {
+        if let Some(max_length) = self.max_length {
+            let total = description.chars().count();
+            if total > max_length {
+                let cut = description
+                    .char_indices()
+                    .nth(max_length)
+                    .map_or(description.len(), |(i, _)| i);
+                description.truncate(cut);
+                description.push_str(&format!("... ({} more characters)", total - max_length));
+            }
+        }
+        description
+    }
+
+    fn decode_rpc(&mut self, peer: &str, direction: CaptureDirection, bytes: &[u8]) -> Vec<String> {
+        let mut buffer = self
+            .rpc_buffers
+            .remove(&(peer.to_owned(), direction))
+            .unwrap_or_default();
+        buffer.extend_from_slice(bytes);
+        let mut descriptions = Vec::new();
+        let mut consumed = 0;
+        loop {
+            let pending = &buffer[consumed..];
+            match framed_message_len(pending) {
+                Ok(Some(len)) if len <= pending.len() => {
+                    let description = self
+                        .describe_rpc_message(peer, direction, &pending[..len])
+                        .unwrap_or_else(|e| format!("<undecodable: {e}>"));
+                    descriptions.push(format!("rpc {description}"));
+                    consumed += len;
+                }
+                Ok(_) => break,
+                Err(e) => {
+                    // The rest of this direction's stream can't be framed anymore.
+                    descriptions.push(format!("rpc <{e}>"));
+                    consumed = buffer.len();
+                    break;
+                }
+            }
+        }
+        buffer.drain(..consumed);
+        self.rpc_buffers.insert((peer.to_owned(), direction), buffer);
+        descriptions
+    }
+
+    fn describe_rpc_message(&mut self, peer: &str, direction: CaptureDirection, mut bytes: &[u8]) -> Result<String> {
+        let message = capnp::serialize::read_message(&mut bytes, RPC_LOCAL_READER_OPTIONS)?;
+        let message = message.get_root::<rpc_capnp::message::Reader>()?;
+        Ok(match message.which()? {
+            rpc_capnp::message::Call(call) => {
+                let call = call?;
+                let question = call.get_question_id();
+                let (interface_id, method_id) = (call.get_interface_id(), call.get_method_id());
+                self.questions
+                    .insert((peer.to_owned(), direction, question), (interface_id, method_id));
+                let (name, params) =
+                    describe_rpc_payload(interface_id, method_id, call.get_params()?.get_content(), false);
+                format!("call #{question} {name} {}", params?)
+            }
+            rpc_capnp::message::Return(ret) => {
+                let ret = ret?;
+                let answer = ret.get_answer_id();
+                let method = self.questions.remove(&(peer.to_owned(), direction.reversed(), answer));
+                match ret.which()? {
+                    rpc_capnp::return_::Results(results) => match method {
+                        Some((interface_id, method_id)) => {
+                            let (name, results) =
+                                describe_rpc_payload(interface_id, method_id, results?.get_content(), true);
+                            format!("return #{answer} {name} {}", results?)
+                        }
+                        None => format!("return #{answer} {:?}", results?),
+                    },
+                    rpc_capnp::return_::Exception(exception) => {
+                        format!("return #{answer} exception: {}", exception?.get_reason()?.to_string()?)
+                    }
+                    rpc_capnp::return_::Canceled(()) => format!("return #{answer} canceled"),
+                    _ => format!("return #{answer} {ret:?}"),
+                }
+            }
+            rpc_capnp::message::Bootstrap(bootstrap) => format!("bootstrap #{}", bootstrap?.get_question_id()),
+            rpc_capnp::message::Finish(finish) => format!("finish #{}", finish?.get_question_id()),
+            _ => format!("{message:?}"),
+        })
+    }
+}
+
+fn describe_stream_packet(stream_type: &NetworkStreamType, mut payload: &[u8], compressed: bool) -> Result<String> {
+    if stream_type.header != CHUNK_DATA_STREAM.header {
+        return Ok(format!("{} bytes", payload.len()));
+    }
+    let size = payload.len();
+    let (message, encoding) = if compressed {
+        (
+            capnp::serialize_packed::read_message(&mut payload, RPC_LOCAL_READER_OPTIONS)?,
+            " packed",
+        )
+    } else {
+        (
+            capnp::serialize::read_message(&mut payload, RPC_LOCAL_READER_OPTIONS)?,
+            "",
+        )
+    };
+    let packet = message.get_root::<rpc::chunk_data_stream_packet::Reader>()?;
+    let position = packet.get_position()?;
+    Ok(format!(
+        "chunk ({}, {}, {}) tick {} revision {} ({size}{encoding} bytes)",
+        position.get_x(),
+        position.get_y(),
+        position.get_z(),
+        packet.get_tick(),
+        packet.get_revision()
+    ))
+}
+
+fn describe_datagram(header: &NetworkDatagramHeader, mut payload: &[u8]) -> Result<String> {
+    use rpc::datagram_header::StandardTypes;
+    let NetworkDatagramHeader::Standard(standard) = header else {
+        return Ok(format!("{} bytes", payload.len()));
+    };
+    let message = capnp::serialize::read_message(&mut payload, RPC_LOCAL_READER_OPTIONS)?;
+    Ok(match standard {
+        StandardTypes::PlayerState => format!("{:?}", message.get_root::<rpc::player_state_datagram::Reader>()?),
+        StandardTypes::PlayerTransforms => {
+            format!("{:?}", message.get_root::<rpc::player_transforms_datagram::Reader>()?)
+        }
+    })
+}
+
+/// How fast [`replay_server_traffic`] feeds the captured messages.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ReplayPacing {
+    /// Without waiting between the messages.
+    Immediate,
+    /// With the same delays between the messages as in the capture.
+    RealTime,
+}
+
+/// Counts of the messages fed by [`replay_server_traffic`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ReplayStats {
+    /// Streams passed to stream handlers.
+    pub streams: u64,
+    /// Packets sent on the replayed streams.
+    pub stream_packets: u64,
+    /// Datagrams passed to datagram handlers.
+    pub datagrams: u64,
+    /// Stream packets and datagrams dropped because no handler accepted them.
+    pub dropped: u64,
+}
+
+/// Feeds the captured streams and datagrams going in `server_direction` (i.e. [`CaptureDirection::Received`] for
+/// captures made by a client) to the handlers registered on a client's network thread, as if the server at `address`
+/// sent them. Has to run on the network thread, and the messages should come from a single connection.
+/// RPC traffic is skipped, as replaying it would need a live peer to answer the calls. Chunk data packets are converted
+/// to the encoding the client negotiated, when it differs from the one recorded in the capture's protocol record.
+pub async fn replay_server_traffic<State: NetworkThreadState>(
+    messages: impl IntoIterator<Item = CapturedMessage>,
+    server_direction: CaptureDirection,
+    net_thread: &NetworkThread<State>,
+    state: &Rc<RefCell<State>>,
+    address: PeerAddress,
+    pacing: ReplayPacing,
+) -> ReplayStats {
+    let mut stats = ReplayStats::default();
+    let client_compressed = state
+        .borrow()
+        .server_protocol()
+        .is_some_and(|protocol| protocol.features.contains(ProtocolFeatures::COMPRESSION));
+    let mut captured_compressed = false;
+    let mut streams: HashMap<u64, InProcessStream> = HashMap::new();
+    let mut first_timestamp = None;
+    let start = tokio::time::Instant::now();
+    for message in messages {
+        if let CapturedPayload::Protocol(protocol) = &message.payload {
+            captured_compressed = protocol.features.contains(ProtocolFeatures::COMPRESSION);
+            continue;
+        }
+        if message.direction != server_direction {
+            continue;
+        }
+        if pacing == ReplayPacing::RealTime {
+            let first = *first_timestamp.get_or_insert(message.timestamp);
+            tokio::time::sleep_until(start + message.timestamp.saturating_sub(first)).await;
+        }
+        match message.payload {
+            CapturedPayload::Rpc(_) | CapturedPayload::Protocol(_) => {}
+            CapturedPayload::Stream {
+                id,
+                stream_type,
+                payload,
+            } => {
+                let payload = reencoded_stream_packet(&stream_type, payload, captured_compressed, client_compressed);
+                let stream = streams.entry(id).or_insert_with(|| {
+                    let (server_side, client_side) = InProcessStream::new_pair(stream_type);
+                    net_thread.spawn_stream_handler(Rc::clone(state), address, client_side.into());
+                    stats.streams += 1;
+                    server_side
+                });
+                if stream.tx.send(payload).is_ok() {
+                    stats.stream_packets += 1;
+                } else {
+                    stats.dropped += 1;
+                }
+            }
+            CapturedPayload::Datagram { header, payload } => {
+                if net_thread.handle_datagram(state, address, header, payload).is_ok() {
+                    stats.datagrams += 1;
+                } else {
+                    stats.dropped += 1;
+                }
+            }
+        }
+    }
+    stats
+}
+
+/// Converts a chunk data packet between the packed and standard encodings, other packets are returned unchanged.
+/// Packets that fail to convert are also returned unchanged, for the stream handler to report.
+fn reencoded_stream_packet(
+    stream_type: &NetworkStreamType,
+    payload: Bytes,
+    from_packed: bool,
+    to_packed: bool,
+) -> Bytes {
+    if stream_type.header != CHUNK_DATA_STREAM.header || from_packed == to_packed {
+        return payload;
+    }
+    if from_packed {
+        return unpack_stream_packet(&payload).unwrap_or(payload);
+    }
+    let mut plain = &payload[..];
+    let Ok(message) = capnp::serialize::read_message(&mut plain, RPC_LOCAL_READER_OPTIONS) else {
+        return payload;
+    };
+    let mut builder = capnp::message::Builder::new_default();
+    match message.get_root::<capnp::any_pointer::Reader>() {
+        Ok(root) if builder.set_root(root).is_ok() => write_stream_packet(&builder, true),
+        _ => payload,
+    }
+}
+
+/// Capture test utilities
+#[cfg(test)]
+pub mod test {
+    use capnp::message::Builder;
+    use gs_schemas::registry::RegistryName;
+    use gs_schemas::schemas::NetworkStreamHeader;
+    use gs_schemas::GameSide;
+
+    use super::*;
+    use crate::network::server::NetworkThreadServerState;
+
+    const TEST_STREAM: NetworkStreamType = NetworkStreamType::new(
+        NetworkStreamHeader::Custom(RegistryName::new_const("test", "capture")),
+        1,
+    );
+
+    fn ping_call(question: u32, input: i32) -> Vec<u8> {
+        let mut message = Builder::new_default();
+        let mut call = message.init_root::<rpc_capnp::message::Builder>().init_call();
+        call.set_question_id(question);
+        call.set_interface_id(<rpc::game_server::Client as HasTypeId>::TYPE_ID);
+        call.set_method_id(1);
+        call.reborrow()
+            .init_params()
+            .init_content()
+            .init_as::<rpc::game_server::ping_params::Builder>()
+            .set_input(input);
+        let mut bytes = Vec::new();
+        capnp::serialize::write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    fn transforms_datagram(tick: u64) -> Vec<u8> {
+        let mut message = Builder::new_default();
+        message
+            .init_root::<rpc::player_transforms_datagram::Builder>()
+            .set_tick(tick);
+        let mut bytes = Vec::new();
+        capnp::serialize::write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn capture_round_trip() {
+        let buffer = SharedBuffer::default();
+        let writer = CaptureWriter::new(buffer.clone());
+        let capture = writer.peer(PeerAddress::Local(7));
+        let call = ping_call(3, 1234);
+        let (first, second) = call.split_at(call.len() / 2);
+        capture.record_rpc(CaptureDirection::Sent, first);
+        capture.record_rpc(CaptureDirection::Sent, second);
+        let stream = capture.stream(&TEST_STREAM);
+        stream.record(CaptureDirection::Received, &Bytes::from_static(b"hello"));
+        let transforms = NetworkDatagramHeader::Standard(rpc::datagram_header::StandardTypes::PlayerTransforms);
+        capture.record_datagram(CaptureDirection::Received, &transforms, &transforms_datagram(42));
+
+        let messages = buffer.messages();
+        assert_eq!(messages.len(), 4);
+        assert!(messages.iter().all(|message| message.peer == "Local:7"));
+        assert!(messages.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        assert_eq!(
+            messages[1].payload,
+            CapturedPayload::Rpc(Bytes::copy_from_slice(second))
+        );
+        assert_eq!(
+            messages[2].payload,
+            CapturedPayload::Stream {
+                id: 0,
+                stream_type: TEST_STREAM,
+                payload: Bytes::from_static(b"hello"),
+            }
+        );
+
+        let mut decoder = CaptureDecoder::new(None);
+        let lines: Vec<Vec<String>> = messages.iter().map(|message| decoder.decode(message)).collect();
+        assert!(lines[0].is_empty());
+        assert_eq!(lines[1].len(), 1);
+        assert!(
+            lines[1][0].contains("-> rpc call #3 GameServer.ping"),
+            "{}",
+            lines[1][0]
+        );
+        assert!(lines[1][0].contains("1234"), "{}", lines[1][0]);
+        assert!(lines[2][0].contains("<- stream #0"), "{}", lines[2][0]);
+        assert!(lines[2][0].contains("5 bytes"), "{}", lines[2][0]);
+        assert!(lines[3][0].contains("tick = 42"), "{}", lines[3][0]);
+
+        let mut short_decoder = CaptureDecoder::new(Some(10));
+        assert!(short_decoder.decode(&messages[3])[0].contains("more characters"));
+    }
+
+    #[test]
+    fn replay_feeds_client_handlers() {
+        let transforms = NetworkDatagramHeader::Standard(rpc::datagram_header::StandardTypes::PlayerTransforms);
+        let message = |direction, payload| CapturedMessage {
+            timestamp: Duration::ZERO,
+            peer: "Local:0".to_owned(),
+            direction,
+            payload,
+        };
+        let packet = |id, text: &'static str| CapturedPayload::Stream {
+            id,
+            stream_type: TEST_STREAM,
+            payload: Bytes::from_static(text.as_bytes()),
+        };
+        let messages = vec![
+            message(CaptureDirection::Received, packet(0, "a")),
+            message(
+                CaptureDirection::Received,
+                CapturedPayload::Rpc(Bytes::from_static(b"rpc")),
+            ),
+            message(CaptureDirection::Sent, packet(0, "client")),
+            message(CaptureDirection::Received, packet(1, "b")),
+            message(CaptureDirection::Received, packet(0, "c")),
+            message(
+                CaptureDirection::Received,
+                CapturedPayload::Datagram {
+                    header: transforms.clone(),
+                    payload: Bytes::from_static(b"datagram"),
+                },
+            ),
+        ];
+
+        let (received_tx, received_rx) = std_unbounded_channel();
+        let net_thread = Arc::new(NetworkThread::new(GameSide::Client, NetworkThreadServerState::new));
+        let stream_tx = received_tx.clone();
+        net_thread.insert_stream_handler(
+            TEST_STREAM,
+            Box::new(move |_state, stream| {
+                let stream_tx = stream_tx.clone();
+                Box::pin(async move {
+                    let mut packets = Vec::new();
+                    while let Some(packet) = stream.recv().await {
+                        packets.push(packet);
+                    }
+                    stream_tx.send(packets).unwrap();
+                })
+            }),
+        );
+        net_thread.insert_datagram_handler(
+            transforms,
+            Box::new(move |_state, _peer, payload| received_tx.send(vec![payload]).unwrap()),
+        );
+
+        let thread = Arc::clone(&net_thread);
+        let stats = net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    Ok(replay_server_traffic(
+                        messages,
+                        CaptureDirection::Received,
+                        &thread,
+                        state,
+                        PeerAddress::Local(0),
+                        ReplayPacing::Immediate,
+                    )
+                    .await)
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        assert_eq!(
+            stats,
+            ReplayStats {
+                streams: 2,
+                stream_packets: 3,
+                datagrams: 1,
+                dropped: 0,
+            }
+        );
+
+        let mut received: Vec<Vec<Bytes>> = (0..3).map(|_| received_rx.recv().unwrap()).collect();
+        received.sort();
+        assert_eq!(
+            received,
+            vec![
+                vec![Bytes::from_static(b"a"), Bytes::from_static(b"c")],
+                vec![Bytes::from_static(b"b")],
+                vec![Bytes::from_static(b"datagram")],
+            ]
+        );
+        net_thread.sync_shutdown();
+    }
+}
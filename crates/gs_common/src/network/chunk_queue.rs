@@ -0,0 +1,290 @@
+//! Per-client outbound chunk data queues, sending the closest chunks first without piling up data for slow clients.
+
+use bevy::prelude::*;
+use gs_schemas::coordinates::AbsChunkPos;
+use gs_schemas::mutwatcher::RevisionNumber;
+use tokio::select;
+use tokio::sync::Notify;
+use tokio_util::bytes::Bytes;
+
+use crate::network::metrics::PeerNetworkMetrics;
+use crate::network::transport::{StreamOpener, TransportStream};
+use crate::prelude::*;
+use crate::voxel::plugin::CHUNK_DATA_STREAM;
+
+/// The maximum number of distinct chunks waiting to be sent to a single client.
+pub const CHUNK_SEND_QUEUE_DEPTH: usize = 64;
+
+/// The result of offering a chunk packet to a [`ChunkSendQueue`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ChunkQueuePush {
+    /// The chunk was added to the queue.
+    Queued,
+    /// The chunk was already waiting to be sent, only the newest revision will be sent.
+    Coalesced,
+    /// The queue was full, so the farthest chunk was dropped to make room for this one, and has to be offered again later.
+    Evicted(AbsChunkPos),
+    /// The queue is full of closer chunks, the chunk has to be offered again later.
+    Full,
+}
+
+impl ChunkQueuePush {
+    /// Whether the offered chunk is going to be sent.
+    pub fn is_accepted(self) -> bool {
+        self != Self::Full
+    }
+}
+
+#[derive(Clone, Debug)]
+struct QueuedChunk {
+    distance_squared: i64,
+    revision: RevisionNumber,
+    packet: Bytes,
+}
+
+/// A bounded queue of serialized chunk packets, ordered by their distance to the player.
+/// Only the newest revision of each chunk is kept.
+#[derive(Clone, Debug)]
+pub struct ChunkSendQueue {
+    capacity: usize,
+    chunks: HashMap<AbsChunkPos, QueuedChunk>,
+}
+
+impl ChunkSendQueue {
+    /// Constructs an empty queue holding at most `capacity` chunks.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Chunk send queue capacity must be positive");
+        Self {
+            capacity,
+            chunks: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// The number of chunks waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Checks if there are no chunks waiting to be sent.
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    fn farthest(&self) -> Option<(AbsChunkPos, i64)> {
+        self.chunks
+            .iter()
+            .map(|(&pos, chunk)| (pos, chunk.distance_squared))
+            .max_by_key(|&(pos, distance)| (distance, pos))
+    }
+
+    /// Checks if [`Self::push`] would accept the chunk, to avoid serializing chunks that would get rejected anyway.
+    pub fn accepts(&self, position: AbsChunkPos, distance_squared: i64) -> bool {
+        self.chunks.len() < self.capacity
+            || self.chunks.contains_key(&position)
+            || self.farthest().is_some_and(|(_, farthest)| distance_squared < farthest)
+    }
+
+    /// Offers a chunk packet for sending, `distance_squared` is the squared distance from the player in chunks.
+    pub fn push(
+        &mut self,
+        position: AbsChunkPos,
+        revision: RevisionNumber,
+        distance_squared: i64,
+        packet: Bytes,
+    ) -> ChunkQueuePush {
+        if let Some(queued) = self.chunks.get_mut(&position) {
+            if revision > queued.revision {
+                *queued = QueuedChunk {
+                    distance_squared,
+                    revision,
+                    packet,
+                };
+            }
+            return ChunkQueuePush::Coalesced;
+        }
+        let mut result = ChunkQueuePush::Queued;
+        if self.chunks.len() >= self.capacity {
+            match self.farthest() {
+                Some((farthest_pos, farthest)) if distance_squared < farthest => {
+                    self.chunks.remove(&farthest_pos);
+                    result = ChunkQueuePush::Evicted(farthest_pos);
+                }
+                _ => return ChunkQueuePush::Full,
+            }
+        }
+        self.chunks.insert(
+            position,
+            QueuedChunk {
+                distance_squared,
+                revision,
+                packet,
+            },
+        );
+        result
+    }
+
+    /// Takes out the closest chunk's packet.
+    pub fn pop(&mut self) -> Option<(AbsChunkPos, Bytes)> {
+        let (&position, _) = self
+            .chunks
+            .iter()
+            .min_by_key(|&(&pos, chunk)| (chunk.distance_squared, pos))?;
+        let chunk = self.chunks.remove(&position)?;
+        Some((position, chunk.packet))
+    }
+}
+
+struct SharedChunkSendQueue {
+    queue: Mutex<ChunkSendQueue>,
+    wakeup: Notify,
+    metrics: Arc<PeerNetworkMetrics>,
+    compressed: AtomicBool,
+}
+
+/// A handle to a connected client's chunk send queue, filled by the engine and drained by the network thread.
+/// Attached to the client's player entity.
+#[derive(Component, Clone)]
+pub struct ChunkSendQueueHandle(Arc<SharedChunkSendQueue>);
+
+impl ChunkSendQueueHandle {
+    /// Constructs an empty queue holding at most `capacity` chunks, reporting its length to `metrics`.
+    pub fn new(capacity: usize, metrics: Arc<PeerNetworkMetrics>) -> Self {
+        Self(Arc::new(SharedChunkSendQueue {
+            queue: Mutex::new(ChunkSendQueue::new(capacity)),
+            wakeup: Notify::new(),
+            metrics,
+            compressed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Whether the client negotiated [`ProtocolFeatures::COMPRESSION`](crate::network::protocol::ProtocolFeatures::COMPRESSION), and the queued packets should be packed.
+    pub fn is_compressed(&self) -> bool {
+        self.0.compressed.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Enables or disables packing the packets queued from now on, once the protocol is negotiated with the client.
+    pub fn set_compressed(&self, compressed: bool) {
+        self.0.compressed.store(compressed, AtomicOrdering::Relaxed);
+    }
+
+    /// See [`ChunkSendQueue::accepts`].
+    pub fn accepts(&self, position: AbsChunkPos, distance_squared: i64) -> bool {
+        self.0.queue.lock().unwrap().accepts(position, distance_squared)
+    }
+
+    /// See [`ChunkSendQueue::push`], wakes up the sender task.
+    pub fn push(
+        &self,
+        position: AbsChunkPos,
+        revision: RevisionNumber,
+        distance_squared: i64,
+        packet: Bytes,
+    ) -> ChunkQueuePush {
+        let mut queue = self.0.queue.lock().unwrap();
+        let result = queue.push(position, revision, distance_squared, packet);
+        self.0.metrics.set_chunk_packets_queued(queue.len());
+        drop(queue);
+        if result.is_accepted() {
+            self.0.wakeup.notify_one();
+        }
+        result
+    }
+
+    /// Waits for the next chunk packet to send.
+    async fn next_packet(&self) -> Bytes {
+        loop {
+            let popped = {
+                let mut queue = self.0.queue.lock().unwrap();
+                let popped = queue.pop();
+                self.0.metrics.set_chunk_packets_queued(queue.len());
+                popped
+            };
+            if let Some((_position, packet)) = popped {
+                return packet;
+            }
+            self.0.wakeup.notified().await;
+        }
+    }
+
+    /// Sends the queued chunks one by one over a single chunk data stream, opened when the first chunk gets queued.
+    /// Waiting for each send to complete lets the queue absorb the stream backpressure.
+    /// Finishes when the connection gets closed.
+    pub async fn run_sender(self, streams: StreamOpener) -> Result<()> {
+        let mut stream: Option<TransportStream> = None;
+        loop {
+            let packet = select! {
+                packet = self.next_packet() => packet,
+                _ = streams.closed() => return Ok(()),
+            };
+            let stream = match &mut stream {
+                Some(stream) => stream,
+                None => stream.insert(
+                    streams
+                        .open(CHUNK_DATA_STREAM)
+                        .await
+                        .context("Opening the chunk data stream")?
+                        .with_metrics(&self.0.metrics),
+                ),
+            };
+            stream.send(packet).await.context("Sending chunk data")?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rev(n: u64) -> RevisionNumber {
+        RevisionNumber::new(n).unwrap()
+    }
+
+    fn pos(x: i32) -> AbsChunkPos {
+        AbsChunkPos::new(x, 0, 0)
+    }
+
+    fn packet(text: &'static str) -> Bytes {
+        Bytes::from_static(text.as_bytes())
+    }
+
+    #[test]
+    fn closest_chunks_first() {
+        let mut queue = ChunkSendQueue::new(8);
+        assert_eq!(queue.push(pos(3), rev(1), 9, packet("far")), ChunkQueuePush::Queued);
+        assert_eq!(queue.push(pos(1), rev(1), 1, packet("near")), ChunkQueuePush::Queued);
+        assert_eq!(queue.push(pos(2), rev(1), 4, packet("middle")), ChunkQueuePush::Queued);
+        assert_eq!(queue.pop(), Some((pos(1), packet("near"))));
+        assert_eq!(queue.pop(), Some((pos(2), packet("middle"))));
+        assert_eq!(queue.pop(), Some((pos(3), packet("far"))));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn revisions_are_coalesced() {
+        let mut queue = ChunkSendQueue::new(8);
+        queue.push(pos(1), rev(1), 1, packet("r1"));
+        assert_eq!(queue.push(pos(1), rev(3), 1, packet("r3")), ChunkQueuePush::Coalesced);
+        assert_eq!(queue.push(pos(1), rev(2), 1, packet("r2")), ChunkQueuePush::Coalesced);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop(), Some((pos(1), packet("r3"))));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn full_queue_keeps_closest_chunks() {
+        let mut queue = ChunkSendQueue::new(2);
+        queue.push(pos(1), rev(1), 1, packet("1"));
+        queue.push(pos(3), rev(1), 9, packet("3"));
+        assert!(!queue.accepts(pos(4), 16));
+        assert_eq!(queue.push(pos(4), rev(1), 16, packet("4")), ChunkQueuePush::Full);
+        assert!(queue.accepts(pos(3), 9));
+        assert!(queue.accepts(pos(2), 4));
+        assert_eq!(
+            queue.push(pos(2), rev(1), 4, packet("2")),
+            ChunkQueuePush::Evicted(pos(3))
+        );
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop(), Some((pos(1), packet("1"))));
+        assert_eq!(queue.pop(), Some((pos(2), packet("2"))));
+    }
+}
@@ -0,0 +1,556 @@
+//! Per-connection traffic accounting and network statistics, also exposed as bevy diagnostics.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::ops::AddAssign;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
+use bevy::prelude::*;
+use futures::{AsyncRead, AsyncWrite};
+use gs_schemas::schemas::{NetworkDatagramHeader, NetworkStreamHeader};
+use quinn::Connection;
+
+use crate::network::capture::{CaptureDirection, CaptureWriter, PeerCapture};
+use crate::network::PeerAddress;
+use crate::prelude::*;
+
+/// Incoming bytes per second, summed over all connections.
+pub const NETWORK_BYTES_IN: DiagnosticPath = DiagnosticPath::const_new("network/bytes_in");
+/// Outgoing bytes per second, summed over all connections.
+pub const NETWORK_BYTES_OUT: DiagnosticPath = DiagnosticPath::const_new("network/bytes_out");
+/// The highest QUIC round trip time estimate of all connections.
+pub const NETWORK_RTT: DiagnosticPath = DiagnosticPath::const_new("network/rtt");
+/// Average latency of the RPC calls made since the previous measurement.
+pub const NETWORK_RPC_LATENCY: DiagnosticPath = DiagnosticPath::const_new("network/rpc_latency");
+/// Chunk packets waiting to be sent or processed, summed over all connections.
+pub const NETWORK_CHUNK_PACKETS_QUEUED: DiagnosticPath = DiagnosticPath::const_new("network/chunk_packets_queued");
+/// Stream sends that had to wait for the other side since the previous measurement.
+pub const NETWORK_BACKPRESSURE_STALLS: DiagnosticPath = DiagnosticPath::const_new("network/backpressure_stalls");
+
+/// Traffic counters of a single kind of messages (e.g. a stream type) on a connection.
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    stalls: AtomicU64,
+}
+
+/// A point-in-time copy of [`TrafficCounters`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct TrafficSnapshot {
+    /// Total received bytes.
+    pub bytes_in: u64,
+    /// Total sent bytes.
+    pub bytes_out: u64,
+    /// Total received messages.
+    pub messages_in: u64,
+    /// Total sent messages.
+    pub messages_out: u64,
+    /// Number of sends that had to wait for the other side to catch up.
+    pub stalls: u64,
+}
+
+impl TrafficCounters {
+    /// Records a received message of the given size.
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, AtomicOrdering::Relaxed);
+        self.messages_in.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// Records a sent message of the given size.
+    pub fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, AtomicOrdering::Relaxed);
+        self.messages_out.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// Records a send blocked by backpressure.
+    pub fn record_stall(&self) {
+        self.stalls.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// Reads the current values of all the counters.
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            bytes_in: self.bytes_in.load(AtomicOrdering::Relaxed),
+            bytes_out: self.bytes_out.load(AtomicOrdering::Relaxed),
+            messages_in: self.messages_in.load(AtomicOrdering::Relaxed),
+            messages_out: self.messages_out.load(AtomicOrdering::Relaxed),
+            stalls: self.stalls.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+impl AddAssign for TrafficSnapshot {
+    fn add_assign(&mut self, rhs: Self) {
+        self.bytes_in += rhs.bytes_in;
+        self.bytes_out += rhs.bytes_out;
+        self.messages_in += rhs.messages_in;
+        self.messages_out += rhs.messages_out;
+        self.stalls += rhs.stalls;
+    }
+}
+
+/// Network statistics of a single connection, updated from the network thread and read from anywhere.
+#[derive(Debug, Default)]
+pub struct PeerNetworkMetrics {
+    rpc: Arc<TrafficCounters>,
+    streams: Mutex<HashMap<NetworkStreamHeader, Arc<TrafficCounters>>>,
+    datagrams: Mutex<HashMap<NetworkDatagramHeader, Arc<TrafficCounters>>>,
+    rpc_calls: AtomicU64,
+    rpc_latency_samples: AtomicU64,
+    rpc_latency_total_us: AtomicU64,
+    rpc_latency_max_us: AtomicU64,
+    chunk_packets_queued: AtomicU64,
+    connection: OnceLock<Connection>,
+    capture: OnceLock<PeerCapture>,
+}
+
+impl PeerNetworkMetrics {
+    /// Counters for the bytes of the main RPC channel.
+    pub fn rpc_counters(&self) -> &Arc<TrafficCounters> {
+        &self.rpc
+    }
+
+    /// Counters shared by all the streams of the given type.
+    pub fn stream_counters(&self, header: &NetworkStreamHeader) -> Arc<TrafficCounters> {
+        let mut streams = self.streams.lock().unwrap();
+        Arc::clone(streams.entry(header.clone()).or_default())
+    }
+
+    /// Counters for the datagrams of the given type.
+    pub fn datagram_counters(&self, header: &NetworkDatagramHeader) -> Arc<TrafficCounters> {
+        let mut datagrams = self.datagrams.lock().unwrap();
+        Arc::clone(datagrams.entry(header.clone()).or_default())
+    }
+
+    /// Sets the QUIC connection to read the round trip time and congestion window from.
+    pub fn set_quic_connection(&self, connection: Connection) {
+        let _ = self.connection.set(connection);
+    }
+
+    /// Records all the traffic of the connection into the given capture from now on.
+    pub fn set_capture(&self, capture: PeerCapture) {
+        let _ = self.capture.set(capture);
+    }
+
+    /// The capture recording the traffic of the connection, if any.
+    pub fn capture(&self) -> Option<&PeerCapture> {
+        self.capture.get()
+    }
+
+    /// Records a datagram received from the peer.
+    pub fn record_datagram_in(&self, header: &NetworkDatagramHeader, payload: &[u8]) {
+        self.datagram_counters(header).record_in(payload.len());
+        if let Some(capture) = self.capture() {
+            capture.record_datagram(CaptureDirection::Received, header, payload);
+        }
+    }
+
+    /// Records a datagram sent to the peer.
+    pub fn record_datagram_out(&self, header: &NetworkDatagramHeader, payload: &[u8]) {
+        self.datagram_counters(header).record_out(payload.len());
+        if let Some(capture) = self.capture() {
+            capture.record_datagram(CaptureDirection::Sent, header, payload);
+        }
+    }
+
+    /// Records an RPC call handled for the peer.
+    pub fn record_rpc_call(&self) {
+        self.rpc_calls.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// Records an RPC call made to the peer, and the time it took to get the response.
+    pub fn record_rpc_latency(&self, latency: Duration) {
+        let micros = latency.as_micros().try_into().unwrap_or(u64::MAX);
+        self.rpc_calls.fetch_add(1, AtomicOrdering::Relaxed);
+        self.rpc_latency_samples.fetch_add(1, AtomicOrdering::Relaxed);
+        self.rpc_latency_total_us.fetch_add(micros, AtomicOrdering::Relaxed);
+        self.rpc_latency_max_us.fetch_max(micros, AtomicOrdering::Relaxed);
+    }
+
+    /// Awaits an RPC call made to the peer, recording its latency.
+    pub async fn time_rpc<F: Future>(&self, call: F) -> F::Output {
+        let start = Instant::now();
+        let result = call.await;
+        self.record_rpc_latency(start.elapsed());
+        result
+    }
+
+    /// Sets the number of chunk packets waiting to be sent or processed.
+    pub fn set_chunk_packets_queued(&self, queued: usize) {
+        self.chunk_packets_queued.store(queued as u64, AtomicOrdering::Relaxed);
+    }
+
+    /// Reads the current values of all the statistics.
+    pub fn snapshot(&self) -> NetworkMetricsSnapshot {
+        let streams: BTreeMap<String, TrafficSnapshot> = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(header, counters)| (format!("{header:?}"), counters.snapshot()))
+            .collect();
+        let datagrams: BTreeMap<String, TrafficSnapshot> = self
+            .datagrams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(header, counters)| (format!("{header:?}"), counters.snapshot()))
+            .collect();
+        let path = self.connection.get().map(|connection| connection.stats().path);
+        NetworkMetricsSnapshot {
+            rpc: self.rpc.snapshot(),
+            streams,
+            datagrams,
+            rpc_calls: self.rpc_calls.load(AtomicOrdering::Relaxed),
+            rpc_latency_samples: self.rpc_latency_samples.load(AtomicOrdering::Relaxed),
+            rpc_latency_total: Duration::from_micros(self.rpc_latency_total_us.load(AtomicOrdering::Relaxed)),
+            rpc_latency_max: Duration::from_micros(self.rpc_latency_max_us.load(AtomicOrdering::Relaxed)),
+            chunk_packets_queued: self.chunk_packets_queued.load(AtomicOrdering::Relaxed),
+            rtt: path.map(|path| path.rtt),
+            congestion_window: path.map(|path| path.cwnd),
+        }
+    }
+}
+
+/// A point-in-time copy of [`PeerNetworkMetrics`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NetworkMetricsSnapshot {
+    /// Traffic of the main RPC channel, only bytes are counted.
+    pub rpc: TrafficSnapshot,
+    /// Traffic by stream type.
+    pub streams: BTreeMap<String, TrafficSnapshot>,
+    /// Traffic by datagram type, only payload bytes are counted.
+    pub datagrams: BTreeMap<String, TrafficSnapshot>,
+    /// Number of RPC calls made or handled.
+    pub rpc_calls: u64,
+    /// Number of RPC calls with a measured latency.
+    pub rpc_latency_samples: u64,
+    /// Sum of all measured RPC latencies.
+    pub rpc_latency_total: Duration,
+    /// The highest measured RPC latency.
+    pub rpc_latency_max: Duration,
+    /// Chunk packets waiting to be sent or processed.
+    pub chunk_packets_queued: u64,
+    /// QUIC round trip time estimate, None for in-process connections.
+    pub rtt: Option<Duration>,
+    /// QUIC congestion window in bytes, None for in-process connections.
+    pub congestion_window: Option<u64>,
+}
+
+impl NetworkMetricsSnapshot {
+    /// Sums up the traffic of all the message kinds.
+    pub fn total(&self) -> TrafficSnapshot {
+        let mut total = self.rpc;
+        for &traffic in self.streams.values().chain(self.datagrams.values()) {
+            total += traffic;
+        }
+        total
+    }
+
+    /// The average measured RPC latency.
+    pub fn rpc_latency_average(&self) -> Option<Duration> {
+        let samples: u32 = self.rpc_latency_samples.try_into().ok()?;
+        self.rpc_latency_total.checked_div(samples)
+    }
+}
+
+impl Display for TrafficSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "in {} B/{} msgs, out {} B/{} msgs",
+            self.bytes_in, self.messages_in, self.bytes_out, self.messages_out
+        )?;
+        if self.stalls > 0 {
+            write!(f, ", {} stalls", self.stalls)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for NetworkMetricsSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "total: {}", self.total())?;
+        match (self.rtt, self.congestion_window) {
+            (Some(rtt), Some(cwnd)) => writeln!(f, "rtt: {rtt:?}, congestion window: {cwnd} B")?,
+            _ => writeln!(f, "in-process connection")?,
+        }
+        write!(f, "rpc: {} calls", self.rpc_calls)?;
+        if let Some(average) = self.rpc_latency_average() {
+            write!(f, ", latency avg {average:?} max {:?}", self.rpc_latency_max)?;
+        }
+        writeln!(f, ", {} B in, {} B out", self.rpc.bytes_in, self.rpc.bytes_out)?;
+        for (name, traffic) in &self.streams {
+            writeln!(f, "stream {name}: {traffic}")?;
+        }
+        for (name, traffic) in &self.datagrams {
+            writeln!(f, "datagram {name}: {traffic}")?;
+        }
+        write!(f, "chunk packets queued: {}", self.chunk_packets_queued)
+    }
+}
+
+/// The metrics of all the live connections of a client or server, shared with the bevy world as a resource.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct NetworkMetricsRegistry {
+    peers: Arc<Mutex<BTreeMap<PeerAddress, Arc<PeerNetworkMetrics>>>>,
+    capture: Option<CaptureWriter>,
+}
+
+impl NetworkMetricsRegistry {
+    /// Records the traffic of all the connections registered from now on into the given capture.
+    pub fn with_capture(mut self, capture: CaptureWriter) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// The capture recording the traffic of new connections, if any.
+    pub fn capture(&self) -> Option<&CaptureWriter> {
+        self.capture.as_ref()
+    }
+
+    /// Starts tracking a new connection, replacing any old metrics of the same peer.
+    pub fn register(&self, peer: PeerAddress) -> Arc<PeerNetworkMetrics> {
+        let metrics = Arc::new(PeerNetworkMetrics::default());
+        if let Some(capture) = &self.capture {
+            metrics.set_capture(capture.peer(peer));
+        }
+        self.peers.lock().unwrap().insert(peer, Arc::clone(&metrics));
+        metrics
+    }
+
+    /// Stops tracking a closed connection.
+    pub fn remove(&self, peer: PeerAddress) {
+        self.peers.lock().unwrap().remove(&peer);
+    }
+
+    /// Gets the metrics of a connection.
+    pub fn get(&self, peer: PeerAddress) -> Option<Arc<PeerNetworkMetrics>> {
+        self.peers.lock().unwrap().get(&peer).cloned()
+    }
+
+    /// Gets the metrics of all the tracked connections.
+    pub fn peers(&self) -> Vec<(PeerAddress, Arc<PeerNetworkMetrics>)> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(&peer, metrics)| (peer, Arc::clone(metrics)))
+            .collect()
+    }
+
+    /// Reads the current statistics of all the tracked connections.
+    pub fn snapshot(&self) -> Vec<(PeerAddress, NetworkMetricsSnapshot)> {
+        self.peers()
+            .into_iter()
+            .map(|(peer, metrics)| (peer, metrics.snapshot()))
+            .collect()
+    }
+}
+
+/// Wraps a byte channel (e.g. the RPC pipe), counting the bytes flowing through it and optionally capturing them.
+pub struct CountingIo<T> {
+    inner: T,
+    counters: Arc<TrafficCounters>,
+    capture: Option<PeerCapture>,
+}
+
+impl<T> CountingIo<T> {
+    /// Wraps `inner`, recording its traffic in `counters`.
+    pub fn new(inner: T, counters: Arc<TrafficCounters>) -> Self {
+        Self {
+            inner,
+            counters,
+            capture: None,
+        }
+    }
+
+    /// Also records the bytes flowing through the channel into the given capture, as RPC traffic.
+    pub fn with_capture(mut self, capture: Option<&PeerCapture>) -> Self {
+        self.capture = capture.cloned();
+        self
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingIo<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read @ 1..)) = result {
+            self.counters.bytes_in.fetch_add(read as u64, AtomicOrdering::Relaxed);
+            if let Some(capture) = &self.capture {
+                capture.record_rpc(CaptureDirection::Received, &buf[..read]);
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written @ 1..)) = result {
+            self.counters
+                .bytes_out
+                .fetch_add(written as u64, AtomicOrdering::Relaxed);
+            if let Some(capture) = &self.capture {
+                capture.record_rpc(CaptureDirection::Sent, &buf[..written]);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Publishes the totals of a [`NetworkMetricsRegistry`] resource as bevy diagnostics.
+pub struct NetworkDiagnosticsPlugin;
+
+impl Plugin for NetworkDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(NETWORK_BYTES_IN).with_suffix(" B/s"))
+            .register_diagnostic(Diagnostic::new(NETWORK_BYTES_OUT).with_suffix(" B/s"))
+            .register_diagnostic(Diagnostic::new(NETWORK_RTT).with_suffix(" ms"))
+            .register_diagnostic(Diagnostic::new(NETWORK_RPC_LATENCY).with_suffix(" ms"))
+            .register_diagnostic(Diagnostic::new(NETWORK_CHUNK_PACKETS_QUEUED))
+            .register_diagnostic(Diagnostic::new(NETWORK_BACKPRESSURE_STALLS))
+            .add_systems(Update, network_diagnostics_system);
+    }
+}
+
+/// The totals seen by the previous run of [`network_diagnostics_system`], to compute rates from.
+#[derive(Default)]
+struct PreviousNetworkTotals {
+    traffic: TrafficSnapshot,
+    rpc_latency_samples: u64,
+    rpc_latency_total: Duration,
+    measured_at: Option<Instant>,
+}
+
+fn network_diagnostics_system(
+    mut diagnostics: Diagnostics,
+    registry: Option<Res<NetworkMetricsRegistry>>,
+    mut previous: Local<PreviousNetworkTotals>,
+) {
+    let Some(registry) = registry else {
+        return;
+    };
+    let now = Instant::now();
+    let mut traffic = TrafficSnapshot::default();
+    let mut rpc_latency_samples = 0;
+    let mut rpc_latency_total = Duration::ZERO;
+    let mut chunk_packets_queued = 0;
+    let mut rtt: Option<Duration> = None;
+    for (_, snapshot) in registry.snapshot() {
+        traffic += snapshot.total();
+        rpc_latency_samples += snapshot.rpc_latency_samples;
+        rpc_latency_total += snapshot.rpc_latency_total;
+        chunk_packets_queued += snapshot.chunk_packets_queued;
+        rtt = rtt.max(snapshot.rtt);
+    }
+
+    if let Some(measured_at) = previous.measured_at {
+        let elapsed = (now - measured_at).as_secs_f64().max(f64::EPSILON);
+        // Counters of closed connections disappear, saturate instead of reporting negative rates.
+        let bytes_in = traffic.bytes_in.saturating_sub(previous.traffic.bytes_in);
+        let bytes_out = traffic.bytes_out.saturating_sub(previous.traffic.bytes_out);
+        diagnostics.add_measurement(&NETWORK_BYTES_IN, || bytes_in as f64 / elapsed);
+        diagnostics.add_measurement(&NETWORK_BYTES_OUT, || bytes_out as f64 / elapsed);
+        let stalls = traffic.stalls.saturating_sub(previous.traffic.stalls);
+        diagnostics.add_measurement(&NETWORK_BACKPRESSURE_STALLS, || stalls as f64);
+        let new_samples = rpc_latency_samples.saturating_sub(previous.rpc_latency_samples);
+        if new_samples > 0 {
+            let new_total = rpc_latency_total.saturating_sub(previous.rpc_latency_total);
+            diagnostics.add_measurement(&NETWORK_RPC_LATENCY, || {
+                new_total.as_secs_f64() * 1000.0 / new_samples as f64
+            });
+        }
+    }
+    if let Some(rtt) = rtt {
+        diagnostics.add_measurement(&NETWORK_RTT, || rtt.as_secs_f64() * 1000.0);
+    }
+    diagnostics.add_measurement(&NETWORK_CHUNK_PACKETS_QUEUED, || chunk_packets_queued as f64);
+
+    *previous = PreviousNetworkTotals {
+        traffic,
+        rpc_latency_samples,
+        rpc_latency_total,
+        measured_at: Some(now),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use gs_schemas::schemas::network_capnp::stream_header::StandardTypes;
+    use gs_schemas::schemas::NetworkStreamType;
+    use tokio_util::bytes::Bytes;
+
+    use super::*;
+    use crate::network::transport::{InProcessStream, TransportStream};
+
+    #[test]
+    fn stream_and_rpc_counters() {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let registry = NetworkMetricsRegistry::default();
+                let server = registry.register(PeerAddress::Local(0));
+                let client = PeerNetworkMetrics::default();
+
+                let stream_type = NetworkStreamType::new(NetworkStreamHeader::Standard(StandardTypes::ChunkData), 1);
+                let (local, remote) = InProcessStream::new_pair(stream_type);
+                let local = TransportStream::from(local).with_metrics(&server);
+                let remote = TransportStream::from(remote).with_metrics(&client);
+                local.send(Bytes::from_static(b"hello")).await.unwrap();
+                local.send(Bytes::from_static(b"world!")).await.unwrap();
+                assert_eq!(remote.recv().await.unwrap(), Bytes::from_static(b"hello"));
+
+                let header = NetworkStreamHeader::Standard(StandardTypes::ChunkData);
+                let sent = server.stream_counters(&header).snapshot();
+                assert_eq!((sent.bytes_out, sent.messages_out), (11, 2));
+                let received = client.stream_counters(&header).snapshot();
+                assert_eq!((received.bytes_in, received.messages_in), (5, 1));
+
+                let (pipe_a, pipe_b) = tokio::io::duplex(64);
+                let mut counted = CountingIo::new(pipe_a.compat(), Arc::clone(server.rpc_counters()));
+                let mut other = pipe_b.compat();
+                counted.write_all(b"ping").await.unwrap();
+                let mut buf = [0u8; 4];
+                other.read_exact(&mut buf).await.unwrap();
+                other.write_all(b"po").await.unwrap();
+                counted.read_exact(&mut buf[..2]).await.unwrap();
+
+                server.record_rpc_latency(Duration::from_millis(10));
+                server.record_rpc_latency(Duration::from_millis(30));
+                server.set_chunk_packets_queued(1);
+
+                let snapshots = registry.snapshot();
+                assert_eq!(snapshots.len(), 1);
+                let snapshot = &snapshots[0].1;
+                assert_eq!((snapshot.rpc.bytes_out, snapshot.rpc.bytes_in), (4, 2));
+                assert_eq!(snapshot.total().bytes_out, 15);
+                assert_eq!(snapshot.rpc_calls, 2);
+                assert_eq!(snapshot.rpc_latency_average(), Some(Duration::from_millis(20)));
+                assert_eq!(snapshot.rpc_latency_max, Duration::from_millis(30));
+                assert_eq!(snapshot.chunk_packets_queued, 1);
+                assert_eq!(snapshot.rtt, None);
+
+                registry.remove(PeerAddress::Local(0));
+                assert!(registry.snapshot().is_empty());
+            });
+    }
+}
@@ -3,10 +3,27 @@
 use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
 
+use crate::voxel::edit_ops::MAX_OPERATION_EDITS;
+
+pub mod capture;
+pub mod chunk_queue;
+pub mod metrics;
+pub mod protocol;
+pub mod replication;
 pub mod server;
+#[cfg(target_os = "linux")]
+pub mod shm;
+pub mod simulator;
 pub mod thread;
 pub mod transport;
 
+/// The maximum number of block changes accepted in a single block edit request, enough for any one edit operation.
+pub const MAX_BLOCK_EDITS_PER_REQUEST: u32 = MAX_OPERATION_EDITS as u32;
+/// The maximum number of terrain queries a single client can have in progress at once.
+pub const MAX_TERRAIN_QUERIES_IN_FLIGHT: usize = 4;
+/// The maximum horizontal distance from the player, in blocks, of the block columns a client can query the terrain of.
+pub const MAX_TERRAIN_QUERY_DISTANCE: f32 = 512.0;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 /// Address uniquely identifying a connected network or local peer (client or server on the other side of the connection).
 pub enum PeerAddress {
@@ -0,0 +1,261 @@
+//! Network protocol versioning, and the negotiation of optional features between the client and the server.
+
+use bitflags::bitflags;
+use capnp::message::{Allocator, Builder};
+use gs_schemas::schemas::game_types_capnp::version;
+use gs_schemas::schemas::network_capnp as rpc;
+use gs_schemas::schemas::network_capnp::protocol_mismatch::Kind as MismatchKind;
+use thiserror::Error;
+use tokio_util::bytes::Bytes;
+
+use crate::network::transport::RPC_LOCAL_READER_OPTIONS;
+use crate::{GAME_VERSION_BUILD, GAME_VERSION_MAJOR, GAME_VERSION_MINOR, GAME_VERSION_PATCH, GAME_VERSION_PRERELEASE};
+
+/// The network protocol version of this build, increase on every incompatible protocol change.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version of the other side this build can still communicate with.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+bitflags! {
+    /// Optional protocol features, enabled on a connection only if both sides support them.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct ProtocolFeatures: u64 {
+        /// Chunk data stream packets in the capnp packed encoding, see [`write_stream_packet`].
+        const COMPRESSION = 1 << 0;
+    }
+}
+
+impl ProtocolFeatures {
+    /// The optional features implemented by this build.
+    pub const SUPPORTED: Self = Self::COMPRESSION;
+}
+
+/// Serializes a stream packet, in the packed encoding if `compressed`, i.e. [`ProtocolFeatures::COMPRESSION`] is enabled.
+pub fn write_stream_packet<A: Allocator>(message: &Builder<A>, compressed: bool) -> Bytes {
+    let mut buffer = Vec::new();
+    if compressed {
+        capnp::serialize_packed::write_message(&mut buffer, message).unwrap();
+    } else {
+        capnp::serialize::write_message(&mut buffer, message).unwrap();
+    }
+    Bytes::from(buffer)
+}
+
+/// Converts a stream packet in the packed encoding back into the standard one written by [`write_stream_packet`].
+pub fn unpack_stream_packet(mut packet: &[u8]) -> capnp::Result<Bytes> {
+    let message = capnp::serialize_packed::read_message(&mut packet, RPC_LOCAL_READER_OPTIONS)?;
+    Ok(Bytes::from(capnp::serialize::write_message_segments_to_words(
+        &message.into_segments(),
+    )))
+}
+
+/// Writes the version of this build into a capnp message.
+pub fn write_game_version(mut builder: version::Builder) {
+    builder.set_major(GAME_VERSION_MAJOR);
+    builder.set_minor(GAME_VERSION_MINOR);
+    builder.set_patch(GAME_VERSION_PATCH);
+    builder.set_build(GAME_VERSION_BUILD);
+    builder.set_prerelease(GAME_VERSION_PRERELEASE);
+}
+
+/// Formats a serialized game version as a SemVer string.
+pub fn read_game_version(reader: version::Reader) -> capnp::Result<String> {
+    let mut text = format!("{}.{}.{}", reader.get_major(), reader.get_minor(), reader.get_patch());
+    let prerelease = reader.get_prerelease()?.to_str()?;
+    if !prerelease.is_empty() {
+        text.push('-');
+        text.push_str(prerelease);
+    }
+    let build = reader.get_build()?.to_str()?;
+    if !build.is_empty() {
+        text.push('+');
+        text.push_str(build);
+    }
+    Ok(text)
+}
+
+/// The network protocol versions and optional features supported by one side of a connection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ProtocolInfo {
+    /// The protocol version used when talking to an up-to-date peer.
+    pub version: u32,
+    /// The oldest supported protocol version of the other side.
+    pub min_version: u32,
+    /// Supported optional features.
+    pub features: ProtocolFeatures,
+}
+
+/// The protocol agreed on for a single connection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct NegotiatedProtocol {
+    /// The protocol version used on the connection.
+    pub version: u32,
+    /// Optional features supported by both sides.
+    pub features: ProtocolFeatures,
+}
+
+/// The reasons for refusing a client with an incompatible protocol.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Error)]
+pub enum ProtocolMismatch {
+    /// The client needs to be updated.
+    #[error("Client protocol version {client} is older than the oldest version {min} supported by the server, update the game to join")]
+    ClientTooOld {
+        /// The client's protocol version.
+        client: u32,
+        /// The oldest protocol version supported by the server.
+        min: u32,
+    },
+    /// The server needs to be updated.
+    #[error("Server protocol version {server} is older than the oldest version {min} supported by the client, the server needs to be updated")]
+    ClientTooNew {
+        /// The server's protocol version.
+        server: u32,
+        /// The oldest protocol version supported by the client.
+        min: u32,
+    },
+}
+
+impl ProtocolInfo {
+    /// The protocol supported by this build.
+    pub const CURRENT: Self = Self {
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        features: ProtocolFeatures::SUPPORTED,
+    };
+
+    /// Agrees on the protocol to use with a client, called with `self` being the server's protocol.
+    pub fn negotiate(&self, client: &ProtocolInfo) -> Result<NegotiatedProtocol, ProtocolMismatch> {
+        if client.version < self.min_version {
+            return Err(ProtocolMismatch::ClientTooOld {
+                client: client.version,
+                min: self.min_version,
+            });
+        }
+        if self.version < client.min_version {
+            return Err(ProtocolMismatch::ClientTooNew {
+                server: self.version,
+                min: client.min_version,
+            });
+        }
+        Ok(NegotiatedProtocol {
+            version: self.version.min(client.version),
+            features: self.features & client.features,
+        })
+    }
+
+    /// Checks if the protocol agreed on by the server can be used by `self`, the client's protocol.
+    pub fn accepts(&self, negotiated: &NegotiatedProtocol) -> bool {
+        (self.min_version..=self.version).contains(&negotiated.version) && self.features.contains(negotiated.features)
+    }
+
+    /// Serializes the protocol information into a capnp message.
+    pub fn write_to_message(&self, builder: &mut rpc::protocol_info::Builder) {
+        builder.set_protocol_version(self.version);
+        builder.set_min_protocol_version(self.min_version);
+        builder.set_features(self.features.bits());
+    }
+
+    /// Deserializes the protocol information from a capnp message, ignoring unknown features.
+    pub fn read_from_message(reader: &rpc::protocol_info::Reader) -> Self {
+        Self {
+            version: reader.get_protocol_version(),
+            min_version: reader.get_min_protocol_version(),
+            features: ProtocolFeatures::from_bits_truncate(reader.get_features()),
+        }
+    }
+}
+
+impl NegotiatedProtocol {
+    /// Serializes the negotiated protocol into a capnp message.
+    pub fn write_to_message(&self, builder: &mut rpc::negotiated_protocol::Builder) {
+        builder.set_protocol_version(self.version);
+        builder.set_features(self.features.bits());
+    }
+
+    /// Deserializes the negotiated protocol from a capnp message, ignoring unknown features.
+    pub fn read_from_message(reader: &rpc::negotiated_protocol::Reader) -> Self {
+        Self {
+            version: reader.get_protocol_version(),
+            features: ProtocolFeatures::from_bits_truncate(reader.get_features()),
+        }
+    }
+}
+
+impl ProtocolMismatch {
+    /// Serializes the refusal into a capnp message, together with the server's protocol.
+    pub fn write_to_message(&self, server: &ProtocolInfo, builder: &mut rpc::protocol_mismatch::Builder) {
+        builder.set_kind(match self {
+            Self::ClientTooOld { .. } => MismatchKind::ClientTooOld,
+            Self::ClientTooNew { .. } => MismatchKind::ClientTooNew,
+        });
+        builder.set_message(self.to_string().as_str());
+        server.write_to_message(&mut builder.reborrow().init_server());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::schemas::game_types_capnp::i_vec3;
+
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let server = ProtocolInfo {
+            version: 5,
+            min_version: 3,
+            // Including a feature only known to newer builds.
+            features: ProtocolFeatures::COMPRESSION | ProtocolFeatures::from_bits_retain(1 << 63),
+        };
+        let client = ProtocolInfo {
+            version: 4,
+            min_version: 2,
+            features: ProtocolFeatures::COMPRESSION,
+        };
+        let negotiated = server.negotiate(&client).unwrap();
+        assert_eq!(
+            negotiated,
+            NegotiatedProtocol {
+                version: 4,
+                features: ProtocolFeatures::COMPRESSION,
+            }
+        );
+        assert!(client.accepts(&negotiated));
+        assert!(!client.accepts(&NegotiatedProtocol {
+            version: 5,
+            features: ProtocolFeatures::empty(),
+        }));
+
+        let old_client = ProtocolInfo {
+            version: 2,
+            min_version: 1,
+            features: ProtocolFeatures::empty(),
+        };
+        assert_eq!(
+            server.negotiate(&old_client),
+            Err(ProtocolMismatch::ClientTooOld { client: 2, min: 3 })
+        );
+        let new_client = ProtocolInfo {
+            version: 9,
+            min_version: 6,
+            features: ProtocolFeatures::empty(),
+        };
+        assert_eq!(
+            server.negotiate(&new_client),
+            Err(ProtocolMismatch::ClientTooNew { server: 5, min: 6 })
+        );
+        assert!(ProtocolInfo::CURRENT.accepts(&ProtocolInfo::CURRENT.negotiate(&ProtocolInfo::CURRENT).unwrap()));
+    }
+
+    #[test]
+    fn packed_stream_packets() {
+        let mut message = Builder::new_default();
+        let mut root = message.init_root::<i_vec3::Builder>();
+        root.set_x(1);
+        root.set_z(-7);
+        let plain = write_stream_packet(&message, false);
+        let packed = write_stream_packet(&message, true);
+        assert!(packed.len() < plain.len());
+        assert_eq!(unpack_stream_packet(&packed).unwrap(), plain);
+    }
+}
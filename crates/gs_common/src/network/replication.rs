@@ -0,0 +1,748 @@
+//! Player transform replication: clients report the movement of their own player every tick,
+//! the server checks it and sends the transforms of everyone else back, all over unreliable datagrams.
+
+use std::collections::VecDeque;
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+
+use bevy::prelude::*;
+use capnp::message::TypedBuilder;
+use gs_schemas::dependencies::itertools::Itertools;
+use gs_schemas::physics::Aabb;
+use gs_schemas::schemas::game_types_capnp::{option, vec3};
+use gs_schemas::schemas::network_capnp::datagram_header::StandardTypes;
+use gs_schemas::schemas::NetworkDatagramHeader;
+use gs_schemas::voxel::collision::ChunkGroupCollision;
+use gs_schemas::GsExtraData;
+use thiserror::Error;
+use tokio_util::bytes::Bytes;
+
+use crate::network::server::{ConnectedPlayer, ConnectedPlayersTable};
+use crate::network::transport::RPC_SERVER_READER_OPTIONS;
+use crate::network::PeerAddress;
+use crate::player::{
+    player_movement_system, MovementWorld, PlayerMovementState, PlayerPhysics, GRAVITY, PLAYER_HALF_EXTENTS,
+    PLAYER_JUMP_VELOCITY, PLAYER_STEP_HEIGHT, PLAYER_SWIM_SPEED, PLAYER_TERMINAL_VELOCITY, PLAYER_WALK_SPEED,
+};
+use crate::prelude::*;
+use crate::voxel::plugin::{BlockRegistryHolder, VoxelCollision, VoxelUniverse};
+use crate::{GameServer, GameServerResource, InGameSystemSet, ServerData, MICROSECONDS_PER_TICK, SECONDS_PER_TICK_F32};
+
+/// Maximum number of player transforms packed into a single datagram, keeping it well below the usual network MTU.
+pub const MAX_PLAYERS_PER_DATAGRAM: usize = 16;
+/// Distance (in blocks) a reported player movement may exceed the movement limits by.
+pub const MOVEMENT_TOLERANCE: f32 = 0.5;
+/// How much faster than the maximum walking speed players may move horizontally before getting corrected.
+const HORIZONTAL_SPEED_SLACK: f32 = 1.25;
+/// How deep a reported player position may reach into blocks, to allow for float rounding.
+const CLIPPING_TOLERANCE: f32 = 0.05;
+/// How far below the player's feet blocks are looked for to consider them standing on the ground, past the
+/// clipping tolerance.
+const GROUND_PROBE_DEPTH: f32 = 2.0 * CLIPPING_TOLERANCE;
+/// The height a jump reaches above the ground.
+const PLAYER_JUMP_RISE: f32 = PLAYER_JUMP_VELOCITY * PLAYER_JUMP_VELOCITY / (2.0 * GRAVITY);
+
+/// Index of the current fixed simulation tick, based on the time elapsed in [`FixedUpdate`].
+pub fn fixed_tick(time: &Time) -> u64 {
+    (time.elapsed().as_micros() / MICROSECONDS_PER_TICK as u128) as u64
+}
+
+fn write_vec3(mut builder: vec3::Builder, value: Vec3) {
+    builder.set_x(value.x);
+    builder.set_y(value.y);
+    builder.set_z(value.z);
+}
+
+fn read_vec3(reader: vec3::Reader) -> Vec3 {
+    Vec3::new(reader.get_x(), reader.get_y(), reader.get_z())
+}
+
+fn write_datagram_message<T: capnp::traits::Owned>(builder: &TypedBuilder<T>) -> Bytes {
+    let mut buffer = Vec::new();
+    capnp::serialize::write_message(&mut buffer, builder.borrow_inner()).unwrap();
+    Bytes::from(buffer)
+}
+
+/// A client's report of the state of its own player after a simulation tick.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PlayerStateUpdate {
+    /// Client-side tick counter, increasing with every sent update.
+    pub tick: u64,
+    /// Position of the feet of the player.
+    pub position: Vec3,
+    /// Velocity in blocks per second.
+    pub velocity: Vec3,
+    /// Horizontal look angle in radians.
+    pub yaw: f32,
+    /// Vertical look angle in radians.
+    pub pitch: f32,
+}
+
+impl PlayerStateUpdate {
+    /// The header sent in front of player state datagrams.
+    pub const DATAGRAM_HEADER: NetworkDatagramHeader = NetworkDatagramHeader::Standard(StandardTypes::PlayerState);
+
+    /// Serializes the update into a `PlayerStateDatagram`.
+    pub fn write_datagram(&self) -> Bytes {
+        let mut builder = TypedBuilder::<rpc::player_state_datagram::Owned>::new_default();
+        let mut root = builder.init_root();
+        root.set_tick(self.tick);
+        write_vec3(root.reborrow().init_position(), self.position);
+        write_vec3(root.reborrow().init_velocity(), self.velocity);
+        root.set_yaw(self.yaw);
+        root.set_pitch(self.pitch);
+        write_datagram_message(&builder)
+    }
+
+    /// Deserializes the update from a `PlayerStateDatagram`.
+    pub fn read_datagram(mut datagram: &[u8]) -> Result<Self> {
+        let message = capnp::serialize::read_message(&mut datagram, RPC_SERVER_READER_OPTIONS)?;
+        let root = message.get_root::<rpc::player_state_datagram::Reader>()?;
+        Ok(Self {
+            tick: root.get_tick(),
+            position: read_vec3(root.get_position()?),
+            velocity: read_vec3(root.get_velocity()?),
+            yaw: root.get_yaw(),
+            pitch: root.get_pitch(),
+        })
+    }
+}
+
+/// The transform of a player, as sent to other players.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PlayerTransform {
+    /// Server-assigned ID of the player, stable for the duration of their connection.
+    pub id: u64,
+    /// Position of the feet of the player.
+    pub position: Vec3,
+    /// Horizontal look angle in radians.
+    pub yaw: f32,
+    /// Vertical look angle in radians.
+    pub pitch: f32,
+}
+
+impl PlayerTransform {
+    /// Interpolates between two transforms of the same player, turning the shorter way around.
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        let yaw_delta = (other.yaw - self.yaw + PI).rem_euclid(TAU) - PI;
+        Self {
+            id: self.id,
+            position: self.position.lerp(other.position, t),
+            yaw: self.yaw + yaw_delta * t,
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+        }
+    }
+}
+
+/// A batch of player transforms sent to a client on a server tick.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlayerTransformsUpdate {
+    /// The server tick on which the transforms were sampled.
+    pub tick: u64,
+    /// Transforms of other players.
+    pub players: Vec<PlayerTransform>,
+    /// Position the receiving player has to reset to, if their movement got rejected.
+    pub correction: Option<Vec3>,
+}
+
+impl PlayerTransformsUpdate {
+    /// The header sent in front of player transforms datagrams.
+    pub const DATAGRAM_HEADER: NetworkDatagramHeader = NetworkDatagramHeader::Standard(StandardTypes::PlayerTransforms);
+
+    /// Splits the transforms into updates small enough to fit in a datagram each.
+    /// Returns an empty update if there are no other players, so that clients still see the server tick advancing and
+    /// time out the players who left.
+    pub fn batch(tick: u64, players: &[PlayerTransform], correction: Option<Vec3>) -> Vec<Self> {
+        let mut updates = players
+            .chunks(MAX_PLAYERS_PER_DATAGRAM)
+            .map(|players| Self {
+                tick,
+                players: players.to_vec(),
+                correction: None,
+            })
+            .collect_vec();
+        if updates.is_empty() {
+            updates.push(Self { tick, ..default() });
+        }
+        updates[0].correction = correction;
+        updates
+    }
+
+    /// Serializes the update into a `PlayerTransformsDatagram`.
+    pub fn write_datagram(&self) -> Bytes {
+        let mut builder = TypedBuilder::<rpc::player_transforms_datagram::Owned>::new_default();
+        let mut root = builder.init_root();
+        root.set_tick(self.tick);
+        let mut players = root.reborrow().init_players(self.players.len() as u32);
+        for (i, player) in self.players.iter().enumerate() {
+            let mut out = players.reborrow().get(i as u32);
+            out.set_id(player.id);
+            write_vec3(out.reborrow().init_position(), player.position);
+            out.set_yaw(player.yaw);
+            out.set_pitch(player.pitch);
+        }
+        let mut correction = root.init_correction();
+        match self.correction {
+            Some(position) => write_vec3(correction.init_some(), position),
+            None => correction.set_none(()),
+        }
+        write_datagram_message(&builder)
+    }
+
+    /// Deserializes the update from a `PlayerTransformsDatagram`.
+    pub fn read_datagram(mut datagram: &[u8]) -> Result<Self> {
+        let message = capnp::serialize::read_message(&mut datagram, RPC_SERVER_READER_OPTIONS)?;
+        let root = message.get_root::<rpc::player_transforms_datagram::Reader>()?;
+        let players = root
+            .get_players()?
+            .iter()
+            .map(|player| {
+                Ok(PlayerTransform {
+                    id: player.get_id(),
+                    position: read_vec3(player.get_position()?),
+                    yaw: player.get_yaw(),
+                    pitch: player.get_pitch(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let correction = if root.has_correction() {
+            match root.get_correction()?.which()? {
+                option::Which::Some(position) => Some(read_vec3(position?)),
+                option::Which::None(()) => None,
+            }
+        } else {
+            None
+        };
+        Ok(Self {
+            tick: root.get_tick(),
+            players,
+            correction,
+        })
+    }
+}
+
+/// Recently received transforms of a remote player, for rendering them smoothly in between the updates.
+#[derive(Clone, Debug, Default)]
+pub struct TransformSnapshots {
+    snapshots: VecDeque<(u64, PlayerTransform)>,
+}
+
+impl TransformSnapshots {
+    /// Maximum number of stored snapshots, older ones get dropped.
+    pub const CAPACITY: usize = 32;
+
+    /// Stores a transform received for the given tick, datagrams can arrive out of order or duplicated.
+    pub fn insert(&mut self, tick: u64, transform: PlayerTransform) {
+        let index = self.snapshots.partition_point(|&(t, _)| t < tick);
+        if self.snapshots.get(index).is_some_and(|&(t, _)| t == tick) {
+            return;
+        }
+        self.snapshots.insert(index, (tick, transform));
+        while self.snapshots.len() > Self::CAPACITY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// The tick of the newest stored snapshot.
+    pub fn latest_tick(&self) -> Option<u64> {
+        self.snapshots.back().map(|&(tick, _)| tick)
+    }
+
+    /// Interpolates the transform at the given (fractional) tick, clamped to the stored range of snapshots.
+    pub fn sample(&self, tick: f64) -> Option<PlayerTransform> {
+        let index = self.snapshots.partition_point(|&(t, _)| (t as f64) <= tick);
+        match (
+            index.checked_sub(1).map(|i| self.snapshots[i]),
+            self.snapshots.get(index),
+        ) {
+            (Some((t0, before)), Some(&(t1, after))) => {
+                Some(before.lerp(&after, ((tick - t0 as f64) / (t1 - t0) as f64) as f32))
+            }
+            (Some((_, before)), None) => Some(before),
+            (None, after) => after.map(|&(_, after)| after),
+        }
+    }
+}
+
+/// Reasons for rejecting a movement reported by a client.
+#[derive(Copy, Clone, Debug, PartialEq, Error)]
+pub enum MovementRejection {
+    /// The reported position contains NaN or infinite coordinates.
+    #[error("Non-finite position")]
+    NotFinite,
+    /// The player moved further than possible in the elapsed time.
+    #[error("Moved {distance} blocks, more than the limit of {limit}")]
+    TooFast {
+        /// The distance moved along the offending direction.
+        distance: f32,
+        /// The limit for that direction.
+        limit: f32,
+    },
+    /// The player's collision box overlaps solid blocks.
+    #[error("Inside of a solid block")]
+    InsideBlock,
+    /// The blocks around the reported position are not loaded, so it cannot be checked yet.
+    #[error("Blocks around the position are not loaded")]
+    NotLoaded,
+}
+
+fn clips_into_blocks(collision: &ChunkGroupCollision, position: Vec3) -> Option<bool> {
+    let bbox: Aabb = PlayerMovementState::new(position).collision_box();
+    collision.intersects_aabb(&bbox.expanded(Vec3::splat(-CLIPPING_TOLERANCE)))
+}
+
+fn stands_on_ground(collision: &ChunkGroupCollision, position: Vec3) -> bool {
+    clips_into_blocks(collision, position - Vec3::Y * GROUND_PROBE_DEPTH).unwrap_or(false)
+}
+
+/// How much further a player may move before getting corrected, on top of the movement possible in the elapsed time.
+/// Shrinks when the player moves faster than possible and recovers when they move slower, so that the slack given to
+/// each movement update does not add up over many updates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MovementAllowance {
+    /// Horizontal distance, in blocks.
+    horizontal: f32,
+    /// Upward distance, in blocks, only restored when standing on the ground or swimming.
+    upward: f32,
+}
+
+impl MovementAllowance {
+    /// The most upward movement a player can save up, enough to jump onto a block from the ground.
+    const MAX_UPWARD: f32 = PLAYER_JUMP_RISE + PLAYER_STEP_HEIGHT + MOVEMENT_TOLERANCE;
+}
+
+impl Default for MovementAllowance {
+    fn default() -> Self {
+        Self {
+            horizontal: MOVEMENT_TOLERANCE,
+            upward: Self::MAX_UPWARD,
+        }
+    }
+}
+
+/// Checks if a player could have moved from `from` to `to` during `ticks` simulation ticks without cheating.
+/// The accepted movement is subtracted from the player's `allowance`, which is left untouched on rejections.
+pub fn validate_player_movement<ED: GsExtraData>(
+    world: &MovementWorld<ED>,
+    allowance: &mut MovementAllowance,
+    from: Vec3,
+    to: Vec3,
+    ticks: u64,
+) -> Result<(), MovementRejection> {
+    if !to.is_finite() {
+        return Err(MovementRejection::NotFinite);
+    }
+    let collision = world.collision();
+    let seconds = ticks.max(1) as f32 * SECONDS_PER_TICK_F32;
+    let delta = to - from;
+
+    let horizontal = delta.xz().length();
+    let horizontal_limit =
+        allowance.horizontal + PLAYER_WALK_SPEED.max(PLAYER_SWIM_SPEED) * HORIZONTAL_SPEED_SLACK * seconds;
+    if horizontal > horizontal_limit {
+        return Err(MovementRejection::TooFast {
+            distance: horizontal,
+            limit: horizontal_limit,
+        });
+    }
+
+    let grounded = stands_on_ground(collision, from);
+    let swimming = world.is_water(&PlayerMovementState::new(from).submerged_box());
+    let mut upward = allowance.upward;
+    if grounded {
+        upward = MovementAllowance::MAX_UPWARD;
+    } else if swimming {
+        upward = (upward + PLAYER_SWIM_SPEED * HORIZONTAL_SPEED_SLACK * seconds).min(MovementAllowance::MAX_UPWARD);
+    }
+    let vertical_limit = if delta.y > 0.0 {
+        // Players get pushed up out of blocks placed inside of them.
+        let from_buried = clips_into_blocks(collision, from).unwrap_or(false);
+        let buried_allowance = if from_buried { 2.0 * PLAYER_HALF_EXTENTS.y } else { 0.0 };
+        let step_allowance = if grounded { PLAYER_STEP_HEIGHT } else { 0.0 };
+        upward += buried_allowance;
+        (PLAYER_JUMP_VELOCITY * seconds + step_allowance + buried_allowance + MOVEMENT_TOLERANCE).min(upward)
+    } else {
+        PLAYER_TERMINAL_VELOCITY * seconds + MOVEMENT_TOLERANCE
+    };
+    if delta.y.abs() > vertical_limit {
+        return Err(MovementRejection::TooFast {
+            distance: delta.y.abs(),
+            limit: vertical_limit,
+        });
+    }
+
+    match clips_into_blocks(collision, to) {
+        None => Err(MovementRejection::NotLoaded),
+        Some(true) => Err(MovementRejection::InsideBlock),
+        Some(false) => {
+            *allowance = MovementAllowance {
+                horizontal: (horizontal_limit - horizontal).min(MOVEMENT_TOLERANCE),
+                upward: (upward - delta.y.max(0.0)).min(MovementAllowance::MAX_UPWARD),
+            };
+            Ok(())
+        }
+    }
+}
+
+/// Server-side replication state of a connected player.
+#[derive(Component, Clone, Debug, Default)]
+pub struct ReplicatedPlayer {
+    /// Horizontal look angle in radians.
+    pub yaw: f32,
+    /// Vertical look angle in radians.
+    pub pitch: f32,
+    /// The newest state reported by the client, not processed yet.
+    pending: Option<PlayerStateUpdate>,
+    last_client_tick: Option<u64>,
+    /// The last position accepted from (or forced onto) the client, and the server tick when that happened.
+    accepted: Option<(Vec3, u64)>,
+    /// The movement slack left to the client.
+    allowance: MovementAllowance,
+    /// The position to send to the client to reset their player to.
+    correction: Option<Vec3>,
+}
+
+impl ReplicatedPlayer {
+    /// Queues a state reported by the client for the next tick, dropping it if a newer state was already received.
+    pub fn receive(&mut self, update: PlayerStateUpdate) {
+        if self.last_client_tick.is_some_and(|tick| update.tick <= tick) {
+            return;
+        }
+        self.last_client_tick = Some(update.tick);
+        self.pending = Some(update);
+    }
+}
+
+/// Decodes a player state datagram received from `peer`, and queues it for the player's entity.
+pub fn receive_player_state(engine: &GameServer, peer: PeerAddress, datagram: &[u8]) {
+    let update = match PlayerStateUpdate::read_datagram(datagram) {
+        Ok(update) => update,
+        Err(e) => {
+            warn!("Invalid player state datagram from {peer}: {e}");
+            return;
+        }
+    };
+    // The result is not awaited, a lost update is not worth reporting.
+    let _ = engine.schedule_bevy(move |world| {
+        let table = world.resource::<ConnectedPlayersTable>();
+        let Some(&entity) = table.players_by_address().get(&peer) else {
+            return Ok(());
+        };
+        if let Some(mut replicated) = world.get_mut::<ReplicatedPlayer>(entity) {
+            replicated.receive(update);
+        }
+        Ok(())
+    });
+}
+
+/// Registers the handler for player state datagrams sent by the clients.
+pub fn register_server_datagram_handlers(engine: &Arc<GameServer>) {
+    let weak_engine = Arc::downgrade(engine);
+    engine.network_thread.insert_datagram_handler(
+        PlayerStateUpdate::DATAGRAM_HEADER,
+        Box::new(move |_state, peer, payload| {
+            if let Some(engine) = weak_engine.upgrade() {
+                receive_player_state(&engine, peer, &payload);
+            }
+        }),
+    );
+}
+
+/// Registers the server-side player replication systems.
+pub struct PlayerReplicationPlugin;
+
+impl Plugin for PlayerReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            (server_apply_player_states)
+                .after(player_movement_system::<ServerData>)
+                .in_set(InGameSystemSet),
+        )
+        .add_systems(
+            FixedPostUpdate,
+            (server_broadcast_player_transforms).in_set(InGameSystemSet),
+        );
+    }
+}
+
+fn server_apply_player_states(
+    time: Res<Time>,
+    block_registry: Res<BlockRegistryHolder>,
+    voxel_q: Query<(&VoxelUniverse<ServerData>, &VoxelCollision)>,
+    mut player_q: Query<(
+        &ConnectedPlayer,
+        &mut ReplicatedPlayer,
+        &mut PlayerPhysics,
+        &mut Transform,
+    )>,
+) {
+    let Ok((voxels, collision)) = voxel_q.get_single() else {
+        return;
+    };
+    let world = MovementWorld::new(voxels.loaded_chunks(), collision, &block_registry);
+    let tick = fixed_tick(&time);
+    for (player, mut replicated, mut physics, mut transform) in player_q.iter_mut() {
+        let Some(update) = replicated.pending.take() else {
+            continue;
+        };
+        if update.yaw.is_finite() && update.pitch.is_finite() {
+            replicated.yaw = update.yaw.rem_euclid(TAU);
+            replicated.pitch = update.pitch.clamp(-FRAC_PI_2, FRAC_PI_2);
+        }
+        let (from, from_tick) = replicated.accepted.unwrap_or((physics.state.position, tick));
+        let replicated = &mut *replicated;
+        let elapsed = tick.saturating_sub(from_tick);
+        match validate_player_movement(&world, &mut replicated.allowance, from, update.position, elapsed) {
+            Ok(()) => {
+                physics.state.position = update.position;
+                if update.velocity.is_finite() {
+                    physics.state.velocity = update.velocity.clamp_length_max(PLAYER_TERMINAL_VELOCITY);
+                }
+                replicated.accepted = Some((update.position, tick));
+                replicated.correction = None;
+            }
+            Err(MovementRejection::NotLoaded) => {}
+            Err(rejection) => {
+                debug!(address = %player.address, "Rejected the movement of `{}`: {rejection}", player.nickname);
+                physics.state = PlayerMovementState::new(from);
+                replicated.accepted = Some((from, tick));
+                replicated.correction = Some(from);
+            }
+        }
+        transform.translation = physics.state.position;
+        transform.rotation = Quat::from_rotation_y(replicated.yaw);
+    }
+}
+
+fn server_broadcast_player_transforms(
+    time: Res<Time>,
+    engine: Res<GameServerResource>,
+    mut player_q: Query<(Entity, &ConnectedPlayer, &mut ReplicatedPlayer, &PlayerPhysics)>,
+) {
+    let tick = fixed_tick(&time);
+    let transforms = player_q
+        .iter()
+        .map(|(entity, player, replicated, physics)| {
+            let transform = PlayerTransform {
+                id: entity.to_bits(),
+                position: physics.state.position,
+                yaw: replicated.yaw,
+                pitch: replicated.pitch,
+            };
+            (player.address, transform)
+        })
+        .collect_vec();
+
+    let mut outgoing: Vec<(PeerAddress, Vec<Bytes>)> = Vec::new();
+    for (_, player, mut replicated, _) in player_q.iter_mut() {
+        let others = transforms
+            .iter()
+            .filter(|(address, _)| *address != player.address)
+            .map(|&(_, transform)| transform)
+            .collect_vec();
+        let datagrams = PlayerTransformsUpdate::batch(tick, &others, replicated.correction.take())
+            .iter()
+            .map(PlayerTransformsUpdate::write_datagram)
+            .collect_vec();
+        outgoing.push((player.address, datagrams));
+    }
+    if outgoing.is_empty() {
+        return;
+    }
+
+    let _ = engine.0.network_thread.schedule_task(move |state| {
+        Box::pin(async move {
+            let state = state.borrow();
+            for (address, datagrams) in outgoing {
+                let Some(client) = state.find_connected_client(address) else {
+                    continue;
+                };
+                for datagram in datagrams {
+                    if let Err(e) = client.send_datagram(&PlayerTransformsUpdate::DATAGRAM_HEADER, &datagram) {
+                        trace!("Could not send player transforms to {address}: {e}");
+                    }
+                }
+            }
+            Ok(())
+        })
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builtin_game_registries;
+    use crate::test_utils::flat_chunk_group;
+
+    #[test]
+    fn datagram_roundtrips() {
+        let state = PlayerStateUpdate {
+            tick: 17,
+            position: Vec3::new(1.5, -2.0, 3.25),
+            velocity: Vec3::new(0.0, -9.0, 1.0),
+            yaw: 1.0,
+            pitch: -0.5,
+        };
+        assert_eq!(
+            PlayerStateUpdate::read_datagram(&state.write_datagram()).unwrap(),
+            state
+        );
+
+        let players = (0..40)
+            .map(|id| PlayerTransform {
+                id,
+                position: Vec3::splat(id as f32),
+                yaw: 0.5,
+                pitch: 0.25,
+            })
+            .collect_vec();
+        let updates = PlayerTransformsUpdate::batch(5, &players, Some(Vec3::ONE));
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates.iter().filter(|u| u.correction.is_some()).count(), 1);
+        for update in &updates {
+            let datagram = update.write_datagram();
+            assert!(datagram.len() < 1200, "Datagram too large: {}", datagram.len());
+            assert_eq!(&PlayerTransformsUpdate::read_datagram(&datagram).unwrap(), update);
+        }
+        assert_eq!(updates.iter().map(|u| u.players.len()).sum::<usize>(), players.len());
+
+        assert_eq!(
+            PlayerTransformsUpdate::batch(5, &[], None),
+            vec![PlayerTransformsUpdate { tick: 5, ..default() }]
+        );
+        let correction_only = PlayerTransformsUpdate::batch(5, &[], Some(Vec3::ZERO));
+        assert_eq!(correction_only.len(), 1);
+        let roundtrip = PlayerTransformsUpdate::read_datagram(&correction_only[0].write_datagram()).unwrap();
+        assert_eq!(roundtrip.correction, Some(Vec3::ZERO));
+    }
+
+    #[test]
+    fn snapshot_interpolation() {
+        let at = |x: f32, yaw: f32| PlayerTransform {
+            id: 1,
+            position: Vec3::new(x, 0.0, 0.0),
+            yaw,
+            pitch: 0.0,
+        };
+        let mut snapshots = TransformSnapshots::default();
+        assert_eq!(snapshots.sample(0.0), None);
+        snapshots.insert(10, at(10.0, 0.1));
+        snapshots.insert(14, at(14.0, TAU - 0.1));
+        // Out of order and duplicated datagrams.
+        snapshots.insert(12, at(12.0, 0.0));
+        snapshots.insert(12, at(100.0, 0.0));
+        assert_eq!(snapshots.latest_tick(), Some(14));
+
+        assert_eq!(snapshots.sample(5.0).unwrap().position.x, 10.0);
+        assert_eq!(snapshots.sample(11.0).unwrap().position.x, 11.0);
+        assert_eq!(snapshots.sample(12.0).unwrap().position.x, 12.0);
+        assert_eq!(snapshots.sample(20.0).unwrap().position.x, 14.0);
+        // Turns through 0 instead of going around.
+        let yaw = snapshots.sample(13.0).unwrap().yaw;
+        assert!(yaw.abs() < 0.051, "yaw {yaw}");
+
+        for tick in 100..200 {
+            snapshots.insert(tick, at(tick as f32, 0.0));
+        }
+        assert_eq!(snapshots.snapshots.len(), TransformSnapshots::CAPACITY);
+        assert_eq!(snapshots.sample(0.0).unwrap().position.x, 168.0);
+    }
+
+    #[test]
+    fn movement_validation() {
+        let registries = builtin_game_registries();
+        let registry = &registries.block_types;
+        let group = flat_chunk_group(registry);
+        let mut collision = ChunkGroupCollision::new();
+        collision.update(&group, registry);
+        let world = MovementWorld::new(&group, &collision, registry);
+        let validate = |from: Vec3, to: Vec3, ticks: u64| {
+            validate_player_movement(&world, &mut MovementAllowance::default(), from, to, ticks)
+        };
+
+        let ground = Vec3::new(10.5, 16.0, 10.5);
+        let walked = ground + Vec3::X * PLAYER_WALK_SPEED * SECONDS_PER_TICK_F32;
+        assert_eq!(validate(ground, walked, 1), Ok(()));
+        assert_eq!(validate(ground, ground + Vec3::Y * 1.2, 1), Ok(()));
+        assert!(matches!(
+            validate(ground, ground + Vec3::X * 5.0, 1),
+            Err(MovementRejection::TooFast { .. })
+        ));
+        assert_eq!(validate(ground, ground + Vec3::X * 5.0, 32), Ok(()));
+        assert!(matches!(
+            validate(ground, ground + Vec3::Y * 10.0, 1),
+            Err(MovementRejection::TooFast { .. })
+        ));
+        // Stepping up is only possible from the ground.
+        let airborne = ground + Vec3::Y * 2.0;
+        assert!(matches!(
+            validate(airborne, airborne + Vec3::Y * PLAYER_STEP_HEIGHT, 1),
+            Err(MovementRejection::TooFast { .. })
+        ));
+        assert_eq!(
+            validate(ground, ground - Vec3::Y, 1),
+            Err(MovementRejection::InsideBlock)
+        );
+        assert_eq!(validate(ground, Vec3::NAN, 1), Err(MovementRejection::NotFinite));
+        assert_eq!(
+            validate(ground, Vec3::new(63.9, 16.0, 10.5), 160),
+            Err(MovementRejection::NotLoaded)
+        );
+    }
+
+    #[test]
+    fn movement_allowance_does_not_add_up() {
+        let registries = builtin_game_registries();
+        let registry = &registries.block_types;
+        let group = flat_chunk_group(registry);
+        let mut collision = ChunkGroupCollision::new();
+        collision.update(&group, registry);
+        let world = MovementWorld::new(&group, &collision, registry);
+
+        // Small upward moves, each one within the limit of a single tick, until the player gets corrected.
+        let mut allowance = MovementAllowance::default();
+        let mut position = Vec3::new(10.5, 16.0, 10.5);
+        let rejected_at = (0..100).find_map(|tick| {
+            let next = position + Vec3::Y * 0.2;
+            match validate_player_movement(&world, &mut allowance, position, next, 1) {
+                Ok(()) => {
+                    position = next;
+                    None
+                }
+                Err(rejection) => Some((tick, rejection)),
+            }
+        });
+        assert!(
+            matches!(rejected_at, Some((_, MovementRejection::TooFast { .. }))),
+            "Flew up to {position}"
+        );
+        assert!(position.y - 16.0 <= MovementAllowance::MAX_UPWARD);
+
+        // Slightly too fast walking is tolerated for a while, but not indefinitely.
+        let mut allowance = MovementAllowance::default();
+        let mut position = Vec3::new(0.5, 16.0, 10.5);
+        let step = PLAYER_WALK_SPEED * HORIZONTAL_SPEED_SLACK * SECONDS_PER_TICK_F32 + 0.1;
+        let accepted = (0..100)
+            .take_while(|_| {
+                let next = position + Vec3::X * step;
+                let result = validate_player_movement(&world, &mut allowance, position, next, 1);
+                position = next;
+                result.is_ok()
+            })
+            .count();
+        assert!((1..10).contains(&accepted), "Accepted {accepted} too fast moves");
+
+        // The slack comes back when moving slowly again.
+        let mut allowance = MovementAllowance::default();
+        let start = Vec3::new(0.5, 16.0, 10.5);
+        assert_eq!(
+            validate_player_movement(&world, &mut allowance, start, start + Vec3::X * 0.5, 1),
+            Ok(())
+        );
+        assert_eq!(
+            validate_player_movement(&world, &mut allowance, start, start, 8),
+            Ok(())
+        );
+        assert_eq!(allowance, MovementAllowance::default());
+    }
+}
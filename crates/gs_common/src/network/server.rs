@@ -2,6 +2,7 @@
 
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use bevy::ecs::component::{ComponentHooks, StorageType};
 use bevy::ecs::world::DeferredWorld;
@@ -11,31 +12,51 @@ use capnp_rpc::rpc_twoparty_capnp::Side;
 use capnp_rpc::{pry, RpcSystem};
 use futures::future::BoxFuture;
 use futures::FutureExt;
+use gs_schemas::coordinates::{AbsBlockPos, AbsChunkPos};
 use gs_schemas::dependencies::capnp::capability::Promise;
 use gs_schemas::dependencies::capnp::Error;
 use gs_schemas::dependencies::kstring::KString;
+use gs_schemas::mutwatcher::RevisionNumber;
 use gs_schemas::schemas::network_capnp::authenticated_server_connection::{
-    BootstrapGameDataParams, BootstrapGameDataResults, SendChatMessageParams, SendChatMessageResults,
+    BootstrapGameDataParams, BootstrapGameDataResults, EditBlocksParams, EditBlocksResults, QueryTerrainParams,
+    QueryTerrainResults, SendChatMessageParams, SendChatMessageResults,
 };
-use gs_schemas::schemas::{network_capnp as rpc, NetworkStreamHeader, SchemaUuidExt};
+use gs_schemas::schemas::{network_capnp as rpc, NetworkDatagramHeader, NetworkStreamType, SchemaUuidExt};
+use gs_schemas::voxel::voxeltypes::BlockEntry;
 use quinn::{Connection, EndpointConfig};
 use socket2::{Domain, Socket};
 use tokio::select;
-use tokio::task::{spawn_local, JoinHandle, JoinSet};
+use tokio::sync::Semaphore;
+use tokio::task::{spawn_blocking, spawn_local, JoinHandle, JoinSet};
 use tracing::Instrument;
 use uuid::Uuid;
 
+use crate::network::capture::CaptureDirection;
+use crate::network::chunk_queue::{ChunkSendQueueHandle, CHUNK_SEND_QUEUE_DEPTH};
+use crate::network::metrics::PeerNetworkMetrics;
+use crate::network::protocol::{
+    read_game_version, write_game_version, NegotiatedProtocol, ProtocolFeatures, ProtocolInfo,
+};
+use crate::network::replication::ReplicatedPlayer;
+use crate::network::simulator::NetworkConditions;
 use crate::network::thread::NetworkThreadState;
 use crate::network::transport::{
-    create_local_rpc_server, create_quic_rpc_server, quinn_server_config, InProcessDuplex, InProcessStream, QuicStream,
-    TransportStream,
+    create_local_rpc_server, create_quic_rpc_server, quinn_server_config, DatagramReceiver, DatagramSendError,
+    DatagramSender, InProcessDuplex, InProcessStream, QuicStream, StreamOpener, TransportStream,
+};
+use crate::network::{
+    PeerAddress, MAX_BLOCK_EDITS_PER_REQUEST, MAX_TERRAIN_QUERIES_IN_FLIGHT, MAX_TERRAIN_QUERY_DISTANCE,
 };
-use crate::network::PeerAddress;
+use crate::player::{PlayerPhysics, PLAYER_EYE_HEIGHT, PLAYER_SPAWN_POSITION};
 use crate::prelude::*;
 use crate::promises::ShutdownHandle;
-use crate::{
-    GameServer, GAME_VERSION_BUILD, GAME_VERSION_MAJOR, GAME_VERSION_MINOR, GAME_VERSION_PATCH, GAME_VERSION_PRERELEASE,
-};
+use crate::voxel::edit_ops::{validate_edit_batch, MAX_EDIT_DISTANCE};
+use crate::voxel::generator::multi_noise::{MultiNoiseGenerator, TerrainQuery};
+use crate::voxel::plugin::{BlockRegistryHolder, VoxelCollision, VoxelUniverse};
+use crate::{GameServer, ServerData};
+
+/// How long a connection refused for a protocol mismatch stays open, for the refusal to reach the client.
+pub const REFUSED_CONNECTION_CLOSE_DELAY: Duration = Duration::from_secs(1);
 
 /// The network thread game server state, accessible from network functions.
 pub struct NetworkThreadServerState {
@@ -44,43 +65,44 @@ pub struct NetworkThreadServerState {
     connected_clients: HashMap<PeerAddress, ConnectedNetClient>,
     bootstrapped_clients: HashMap<PeerAddress, Rc<RefCell<AuthenticatedServer2ClientEndpoint>>>,
     listeners: HashMap<SocketAddr, JoinHandle<()>>,
-}
-
-enum NetClientConnectionData {
-    Local {
-        stream_sender: AsyncUnboundedSender<InProcessStream>,
-    },
-    Remote {
-        connection: Connection,
-    },
+    world_generator: Option<Arc<MultiNoiseGenerator>>,
 }
 
 /// Network thread data for a live connected client.
 pub struct ConnectedNetClient {
     shutdown_handle: ShutdownHandle,
-    data: NetClientConnectionData,
-    /// The network stream for chunk data.
-    pub chunk_stream: Option<TransportStream>,
+    streams: StreamOpener,
+    datagrams: DatagramSender,
+    metrics: Arc<PeerNetworkMetrics>,
+    chunk_queue: ChunkSendQueueHandle,
 }
 
 impl ConnectedNetClient {
     /// Opens a fresh stream for sending data asynchronously to the main RPC channel.
     /// Returns a future that actually performs the work to avoid holding the state RefCell borrowed across await points.
-    pub fn open_stream(&self, header: NetworkStreamHeader) -> BoxFuture<'static, Result<TransportStream>> {
-        match &self.data {
-            NetClientConnectionData::Local { stream_sender, .. } => {
-                let stream_sender = stream_sender.clone();
-                (async move {
-                    let (local, remote) = InProcessStream::new_pair(header);
-                    stream_sender.send(remote)?;
-                    Ok(local.into())
-                })
-                .boxed()
-            }
-            NetClientConnectionData::Remote { connection, .. } => QuicStream::open(connection.clone(), header)
-                .map(|r| r.map(TransportStream::from))
-                .boxed(),
-        }
+    pub fn open_stream(&self, stream_type: NetworkStreamType) -> BoxFuture<'static, Result<TransportStream>> {
+        let metrics = Arc::clone(&self.metrics);
+        self.streams
+            .open(stream_type)
+            .map(move |stream| stream.map(|stream| stream.with_metrics(&metrics)))
+            .boxed()
+    }
+
+    /// Sends an unreliable datagram to the client.
+    pub fn send_datagram(&self, header: &NetworkDatagramHeader, payload: &[u8]) -> Result<(), DatagramSendError> {
+        self.datagrams.send(header, payload)?;
+        self.metrics.record_datagram_out(header, payload);
+        Ok(())
+    }
+
+    /// Gets the queue of chunk data waiting to be sent to this client.
+    pub fn chunk_queue(&self) -> &ChunkSendQueueHandle {
+        &self.chunk_queue
+    }
+
+    /// Gets the network statistics of this client's connection.
+    pub fn metrics(&self) -> &Arc<PeerNetworkMetrics> {
+        &self.metrics
     }
 
     /// Gets the shutdown handle for this client's connection handling task set.
@@ -168,6 +190,7 @@ impl Default for NetworkThreadServerState {
             connected_clients: Default::default(),
             bootstrapped_clients: Default::default(),
             listeners: Default::default(),
+            world_generator: None,
         }
     }
 }
@@ -196,6 +219,11 @@ impl NetworkThreadServerState {
         self.bootstrapped_clients.get(&address)
     }
 
+    /// Sets the generator answering the terrain queries of the clients, shared with the engine.
+    pub fn set_world_generator(&mut self, generator: Arc<MultiNoiseGenerator>) {
+        self.world_generator = Some(generator);
+    }
+
     /// Unblocks stream processing, call after all the handlers are registered.
     pub async fn allow_streams(this: &Rc<RefCell<Self>>) {
         this.borrow_mut().ready_to_accept_streams.send_replace(true);
@@ -212,41 +240,80 @@ impl NetworkThreadServerState {
     }
 
     /// Creates a new local server->client connection and returns the client address and stream to pass into the client object.
+    /// The connection is perfect, unless simulated network `conditions` are given.
     pub async fn accept_local_connection(
         this_ptr: &Rc<RefCell<Self>>,
         engine: Arc<GameServer>,
+        conditions: Option<NetworkConditions>,
     ) -> Result<LocalConnectionPipe> {
         let mut this = this_ptr.borrow_mut();
         let id = this.free_local_id;
         this.free_local_id += 1;
         let peer = PeerAddress::Local(id);
 
-        let (spipe, cpipe) = InProcessDuplex::new_pair();
-        let rpc_server = create_local_rpc_server(this_ptr.clone(), Arc::clone(&engine), spipe.rpc_pipe, peer);
+        let (spipe, cpipe) = match conditions {
+            Some(conditions) => InProcessDuplex::new_simulated_pair(&conditions),
+            None => InProcessDuplex::new_pair(),
+        };
+        let metrics = engine.network_metrics().register(peer);
+        let rpc_server = create_local_rpc_server(
+            this_ptr.clone(),
+            Arc::clone(&engine),
+            spipe.rpc_pipe,
+            peer,
+            Arc::clone(&metrics),
+        );
         let rpc_listener = Self::local_listener_task(peer, Arc::clone(&engine), rpc_server)
             .instrument(tracing::info_span!("server-local-rpc", address = %peer));
-        let stream_listener =
-            Self::local_stream_task(Rc::clone(this_ptr), Arc::clone(&engine), peer, spipe.incoming_streams)
-                .instrument(tracing::info_span!("server-local-stream", address = %peer));
+        let stream_listener = Self::local_stream_task(
+            Rc::clone(this_ptr),
+            Arc::clone(&engine),
+            peer,
+            Arc::clone(&metrics),
+            spipe.incoming_streams,
+        )
+        .instrument(tracing::info_span!("server-local-stream", address = %peer));
+
+        let datagram_listener = Self::datagram_task(
+            Rc::clone(this_ptr),
+            Arc::clone(&engine),
+            peer,
+            Arc::clone(&metrics),
+            DatagramReceiver::Process(spipe.incoming_datagrams),
+        )
+        .instrument(tracing::info_span!("server-local-datagram", address = %peer));
+
+        let streams = StreamOpener::Process(spipe.outgoing_streams);
+        let chunk_queue = ChunkSendQueueHandle::new(CHUNK_SEND_QUEUE_DEPTH, Arc::clone(&metrics));
+        let chunk_sender = chunk_queue
+            .clone()
+            .run_sender(streams.clone())
+            .instrument(tracing::info_span!("server-local-chunks", address = %peer));
 
         let shutdown_handle = ShutdownHandle::new();
         let mut join_set = JoinSet::new();
         join_set.spawn_local(rpc_listener);
         join_set.spawn_local(stream_listener);
+        join_set.spawn_local(datagram_listener);
+        join_set.spawn_local(chunk_sender);
         let inner_shutdown = shutdown_handle.clone();
+        let inner_state = Rc::clone(this_ptr);
         spawn_local(
-            async move { Self::player_handler_task(inner_shutdown, join_set).await }
-                .instrument(info_span!("server-player-handler", address = %peer)),
+            async move {
+                Self::player_handler_task(inner_shutdown, join_set).await;
+                Self::remove_client(&inner_state, &engine, peer);
+            }
+            .instrument(info_span!("server-player-handler", address = %peer)),
         );
 
         this.connected_clients.insert(
             peer,
             ConnectedNetClient {
                 shutdown_handle,
-                data: NetClientConnectionData::Local {
-                    stream_sender: spipe.outgoing_streams,
-                },
-                chunk_stream: None,
+                streams,
+                datagrams: DatagramSender::Process(spipe.outgoing_datagrams),
+                metrics,
+                chunk_queue,
             },
         );
 
@@ -288,6 +355,30 @@ impl NetworkThreadServerState {
         }
     }
 
+    /// Forgets a client after all its connection tasks have finished, and removes its player from the world.
+    fn remove_client(this: &Rc<RefCell<Self>>, engine: &GameServer, peer: PeerAddress) {
+        {
+            let mut this = this.borrow_mut();
+            this.connected_clients.remove(&peer);
+            this.bootstrapped_clients.remove(&peer);
+        }
+        engine.network_metrics().remove(peer);
+        info!("Client {peer} disconnected");
+        engine
+            .schedule_bevy(move |world| {
+                let player = world
+                    .resource::<ConnectedPlayersTable>()
+                    .players_by_address
+                    .get(&peer)
+                    .copied();
+                if let Some(player) = player {
+                    world.despawn(player);
+                }
+                Ok(())
+            })
+            .async_log_when_fails("Removing a disconnected player");
+    }
+
     async fn update_listeners(this: &Rc<RefCell<Self>>, engine: &Arc<GameServer>, new_listeners: &[SocketAddr]) {
         let new_set: HashSet<SocketAddr> = HashSet::from_iter(new_listeners.iter().copied());
         let old_set: HashSet<SocketAddr> = HashSet::from_iter(this.borrow().listeners.keys().copied());
@@ -374,7 +465,16 @@ impl NetworkThreadServerState {
     ) -> Result<()> {
         trace!("Awaiting the bootstrap bidi RPC channel");
         let (rpc_tx, rpc_rx) = connection.accept_bi().await?;
-        let rpc = create_quic_rpc_server(Rc::clone(&net_state), Arc::clone(&engine), rpc_tx, rpc_rx, peer_address);
+        let metrics = engine.network_metrics().register(peer_address);
+        metrics.set_quic_connection(connection.clone());
+        let rpc = create_quic_rpc_server(
+            Rc::clone(&net_state),
+            Arc::clone(&engine),
+            rpc_tx,
+            rpc_rx,
+            peer_address,
+            Arc::clone(&metrics),
+        );
         let _disconnector = rpc.get_disconnector();
 
         let mut join_set: JoinSet<Result<()>> = JoinSet::new();
@@ -386,24 +486,53 @@ impl NetworkThreadServerState {
         let stream_this = Rc::clone(&net_state);
         let stream_engine = Arc::clone(&engine);
         let stream_conn = connection.clone();
+        let stream_metrics = Arc::clone(&metrics);
+        join_set.spawn_local(
+            async move {
+                Self::remote_stream_task(stream_this, stream_engine, peer_address, stream_metrics, stream_conn).await
+            }
+            .instrument(info_span!("server-quic-stream", address = %peer_address)),
+        );
+
         join_set.spawn_local(
-            async move { Self::remote_stream_task(stream_this, stream_engine, peer_address, stream_conn).await }
-                .instrument(info_span!("server-quic-stream", address = %peer_address)),
+            Self::datagram_task(
+                Rc::clone(&net_state),
+                Arc::clone(&engine),
+                peer_address,
+                Arc::clone(&metrics),
+                DatagramReceiver::Network(connection.clone()),
+            )
+            .instrument(info_span!("server-quic-datagram", address = %peer_address)),
+        );
+
+        let streams = StreamOpener::Network(connection.clone());
+        let chunk_queue = ChunkSendQueueHandle::new(CHUNK_SEND_QUEUE_DEPTH, Arc::clone(&metrics));
+        join_set.spawn_local(
+            chunk_queue
+                .clone()
+                .run_sender(streams.clone())
+                .instrument(info_span!("server-quic-chunks", address = %peer_address)),
         );
 
         let shutdown_handle = ShutdownHandle::new();
         let inner_shutdown = shutdown_handle.clone();
+        let inner_state = Rc::clone(&net_state);
         spawn_local(
-            async move { Self::player_handler_task(inner_shutdown, join_set).await }
-                .instrument(info_span!("server-player-handler", address = %peer_address)),
+            async move {
+                Self::player_handler_task(inner_shutdown, join_set).await;
+                Self::remove_client(&inner_state, &engine, peer_address);
+            }
+            .instrument(info_span!("server-player-handler", address = %peer_address)),
         );
 
         net_state.borrow_mut().connected_clients.insert(
             peer_address,
             ConnectedNetClient {
                 shutdown_handle,
-                data: NetClientConnectionData::Remote { connection },
-                chunk_stream: None,
+                streams,
+                datagrams: DatagramSender::Network(connection),
+                metrics,
+                chunk_queue,
             },
         );
 
@@ -413,14 +542,34 @@ impl NetworkThreadServerState {
     }
 
     async fn remote_stream_task(
-        _this: Rc<RefCell<Self>>,
-        _engine: Arc<GameServer>,
-        _addr: PeerAddress,
+        this: Rc<RefCell<Self>>,
+        engine: Arc<GameServer>,
+        addr: PeerAddress,
+        metrics: Arc<PeerNetworkMetrics>,
         connection: Connection,
     ) -> Result<()> {
-        while let Ok((_tx, _rx)) = connection.accept_bi().await {
-            // TODO
-            error!("Got a stream open request from the client, currently there are no c->s streams");
+        // The listener only accepts connections once the stream handlers are ready.
+        while let Ok(stream) = QuicStream::accept(&connection).await {
+            let stream = TransportStream::from(stream).with_metrics(&metrics);
+            engine
+                .network_thread
+                .spawn_stream_handler(Rc::clone(&this), addr, stream);
+        }
+        Ok(())
+    }
+
+    async fn datagram_task(
+        this: Rc<RefCell<Self>>,
+        engine: Arc<GameServer>,
+        addr: PeerAddress,
+        metrics: Arc<PeerNetworkMetrics>,
+        mut datagrams: DatagramReceiver,
+    ) -> Result<()> {
+        while let Some((header, payload)) = datagrams.recv().await {
+            metrics.record_datagram_in(&header, &payload);
+            if let Err(header) = engine.network_thread.handle_datagram(&this, addr, header, payload) {
+                debug!("No datagram handler found for a datagram of type {header:?} from {addr}");
+            }
         }
         Ok(())
     }
@@ -439,7 +588,8 @@ impl NetworkThreadServerState {
     async fn local_stream_task(
         this: Rc<RefCell<Self>>,
         engine: Arc<GameServer>,
-        _addr: PeerAddress,
+        addr: PeerAddress,
+        metrics: Arc<PeerNetworkMetrics>,
         mut incoming_streams: AsyncUnboundedReceiver<InProcessStream>,
     ) -> Result<()> {
         let mut ready_watcher = this.borrow().ready_to_accept_streams.subscribe();
@@ -447,20 +597,10 @@ impl NetworkThreadServerState {
             ready_watcher.changed().await?;
         }
         while let Some(stream) = incoming_streams.recv().await {
-            let handler = engine
+            let stream = TransportStream::from(stream).with_metrics(&metrics);
+            engine
                 .network_thread
-                .create_stream_handler(Rc::clone(&this), stream.into());
-            match handler {
-                Ok(handler) => {
-                    spawn_local(handler);
-                }
-                Err(stream) => {
-                    error!(
-                        "No stream handler found for incoming client stream of type {:?}",
-                        stream.header()
-                    );
-                }
-            }
+                .spawn_stream_handler(Rc::clone(&this), addr, stream);
         }
         Ok(())
     }
@@ -471,16 +611,21 @@ pub struct Server2ClientEndpoint {
     net_state: Rc<RefCell<NetworkThreadServerState>>,
     server: Arc<GameServer>,
     peer: PeerAddress,
+    metrics: Arc<PeerNetworkMetrics>,
+    protocol: Option<NegotiatedProtocol>,
     auth_attempted: bool,
 }
 
 /// An authenticated RPC client<->server connection handler on the server side.
 pub struct AuthenticatedServer2ClientEndpoint {
-    _net_state: Rc<RefCell<NetworkThreadServerState>>,
+    net_state: Rc<RefCell<NetworkThreadServerState>>,
     server: Arc<GameServer>,
     peer: PeerAddress,
+    metrics: Arc<PeerNetworkMetrics>,
     username: KString,
+    protocol: NegotiatedProtocol,
     connection: rpc::authenticated_client_connection::Client,
+    terrain_queries: Arc<Semaphore>,
 }
 
 #[derive(Clone, Deref)]
@@ -489,11 +634,18 @@ struct RcAuthenticatedServer2ClientEndpoint(Rc<RefCell<AuthenticatedServer2Clien
 
 impl Server2ClientEndpoint {
     /// Constructor.
-    pub fn new(net_state: Rc<RefCell<NetworkThreadServerState>>, server: Arc<GameServer>, peer: PeerAddress) -> Self {
+    pub fn new(
+        net_state: Rc<RefCell<NetworkThreadServerState>>,
+        server: Arc<GameServer>,
+        peer: PeerAddress,
+        metrics: Arc<PeerNetworkMetrics>,
+    ) -> Self {
         Self {
             net_state,
             server,
             peer,
+            metrics,
+            protocol: None,
             auth_attempted: false,
         }
     }
@@ -507,6 +659,24 @@ impl Server2ClientEndpoint {
     pub fn peer(&self) -> PeerAddress {
         self.peer
     }
+
+    /// Closes the connection once the response to the current call had the time to reach the client.
+    fn close_after_response(&self) {
+        let Some(shutdown_handle) = self
+            .net_state
+            .borrow()
+            .find_connected_client(self.peer)
+            .map(|client| client.shutdown_handle().clone())
+        else {
+            return;
+        };
+        let peer = self.peer;
+        spawn_local(async move {
+            tokio::time::sleep(REFUSED_CONNECTION_CLOSE_DELAY).await;
+            info!("Closing the refused connection of {peer}");
+            shutdown_handle.shutdown().await;
+        });
+    }
 }
 
 impl rpc::game_server::Server for Server2ClientEndpoint {
@@ -515,14 +685,11 @@ impl rpc::game_server::Server for Server2ClientEndpoint {
         _params: rpc::game_server::GetServerMetadataParams,
         mut results: rpc::game_server::GetServerMetadataResults,
     ) -> Promise<(), Error> {
+        self.metrics.record_rpc_call();
         let config = self.server.config().borrow();
         let mut meta = results.get().init_metadata();
-        let mut ver = meta.reborrow().init_server_version();
-        ver.set_major(GAME_VERSION_MAJOR);
-        ver.set_minor(GAME_VERSION_MINOR);
-        ver.set_patch(GAME_VERSION_PATCH);
-        ver.set_build(GAME_VERSION_BUILD);
-        ver.set_prerelease(GAME_VERSION_PRERELEASE);
+        write_game_version(meta.reborrow().init_server_version());
+        ProtocolInfo::CURRENT.write_to_message(&mut meta.reborrow().init_protocol());
 
         meta.set_title(&config.server.server_title);
         meta.set_subtitle(&config.server.server_subtitle);
@@ -536,33 +703,106 @@ impl rpc::game_server::Server for Server2ClientEndpoint {
         params: rpc::game_server::PingParams,
         mut results: rpc::game_server::PingResults,
     ) -> Promise<(), Error> {
+        self.metrics.record_rpc_call();
         let input = pry!(params.get()).get_input();
         results.get().set_output(input);
         Promise::ok(())
     }
 
+    fn negotiate_protocol(
+        &mut self,
+        params: rpc::game_server::NegotiateProtocolParams,
+        mut results: rpc::game_server::NegotiateProtocolResults,
+    ) -> Promise<(), Error> {
+        self.metrics.record_rpc_call();
+        if self.protocol.is_some() {
+            return Promise::err(Error::failed("The protocol was already negotiated".to_owned()));
+        }
+        let params = pry!(params.get());
+        let client_version = pry!(read_game_version(pry!(params.get_game_version())));
+        let client_protocol = ProtocolInfo::read_from_message(&pry!(params.get_protocol()));
+
+        let server_protocol = ProtocolInfo::CURRENT;
+        let result = results.get().init_result();
+        match server_protocol.negotiate(&client_protocol) {
+            Ok(negotiated) => {
+                info!(
+                    "Negotiated protocol version {} with features {:?} with {} running version {client_version}",
+                    negotiated.version, negotiated.features, self.peer
+                );
+                negotiated.write_to_message(&mut result.init_ok());
+                self.protocol = Some(negotiated);
+                if let Some(capture) = self.metrics.capture() {
+                    capture.record_protocol(CaptureDirection::Sent, negotiated);
+                }
+                if let Some(client) = self.net_state.borrow().find_connected_client(self.peer) {
+                    client
+                        .chunk_queue()
+                        .set_compressed(negotiated.features.contains(ProtocolFeatures::COMPRESSION));
+                }
+            }
+            Err(mismatch) => {
+                warn!(
+                    "Refusing {} running version {client_version} with protocol {client_protocol:?}: {mismatch}",
+                    self.peer
+                );
+                mismatch.write_to_message(&server_protocol, &mut result.init_err());
+                self.close_after_response();
+            }
+        }
+        Promise::ok(())
+    }
+
     fn authenticate(
         &mut self,
         params: rpc::game_server::AuthenticateParams,
         mut results: rpc::game_server::AuthenticateResults,
     ) -> Promise<(), Error> {
+        self.metrics.record_rpc_call();
+        let Some(protocol) = self.protocol else {
+            return Promise::err(Error::failed(
+                "The protocol has to be negotiated before authentication".to_owned(),
+            ));
+        };
         if self.auth_attempted {
             return Promise::err(Error::failed("Authentication was already attempted once".to_owned()));
         }
         self.auth_attempted = true;
 
+        let Some(chunk_queue) = self
+            .net_state
+            .borrow()
+            .find_connected_client(self.peer)
+            .map(|client| client.chunk_queue().clone())
+        else {
+            return Promise::err(Error::failed("The connection is shutting down".to_owned()));
+        };
         let params = pry!(params.get());
         let username = KString::from_ref(pry!(pry!(params.get_username()).to_str()));
         let connection = pry!(params.get_connection());
+        let held_chunks: Vec<(AbsChunkPos, RevisionNumber)> = pry!(params.get_held_chunks())
+            .iter()
+            .filter_map(|held| {
+                let position = held.get_position().ok()?;
+                let revision = RevisionNumber::new(held.get_revision())?;
+                Some((
+                    AbsChunkPos::new(position.get_x(), position.get_y(), position.get_z()),
+                    revision,
+                ))
+            })
+            .collect();
 
         // TODO: validate username
 
         let client = Rc::new(RefCell::new(AuthenticatedServer2ClientEndpoint {
-            _net_state: self.net_state.clone(),
+            net_state: self.net_state.clone(),
             server: self.server.clone(),
             peer: self.peer,
+            metrics: Arc::clone(&self.metrics),
             username: username.clone(),
+            protocol,
             connection,
+            terrain_queries: Arc::new(Semaphore::new(MAX_TERRAIN_QUERIES_IN_FLIGHT)),
         }));
 
         let mut result = results.get().init_conn();
@@ -580,11 +820,42 @@ impl rpc::game_server::Server for Server2ClientEndpoint {
         let address = self.peer;
         self.server
             .schedule_bevy(move |world| {
+                // A player reconnecting before their old connection timed out replaces their old entity.
+                let returning = world
+                    .query::<(Entity, &ConnectedPlayer, &PlayerPhysics)>()
+                    .iter(world)
+                    .find(|(_, player, _)| player.nickname == nickname)
+                    .map(|(entity, player, physics)| (entity, player.address, physics.state.position));
+                let position = match returning {
+                    Some((old_player, old_address, position)) => {
+                        info!("Player `{nickname}` returned from {address}, replacing their connection from {old_address}");
+                        world.despawn(old_player);
+                        position
+                    }
+                    None => PLAYER_SPAWN_POSITION,
+                };
                 info!("Spawning player `{nickname}`@{address} into the world");
-                world.spawn(ConnectedPlayer {
-                    nickname: nickname.clone(),
-                    address,
-                });
+                let player = world
+                    .spawn((
+                        ConnectedPlayer {
+                            nickname: nickname.clone(),
+                            address,
+                        },
+                        PlayerPhysics::new(position),
+                        ReplicatedPlayer::default(),
+                        chunk_queue,
+                    ))
+                    .id();
+                if !held_chunks.is_empty() {
+                    let mut voxels_q = world.query::<&mut VoxelUniverse<ServerData>>();
+                    if let Ok(mut voxels) = voxels_q.get_single_mut(world) {
+                        let reused = voxels.mark_chunks_held_by(player, &held_chunks);
+                        info!(
+                            "Player `{nickname}` still holds {reused} of their {} cached chunks",
+                            held_chunks.len()
+                        );
+                    }
+                }
                 Ok(())
             })
             .async_log_when_fails("Adding player to the connection table");
@@ -598,6 +869,27 @@ impl AuthenticatedServer2ClientEndpoint {
     pub fn rpc(&self) -> &rpc::authenticated_client_connection::Client {
         &self.connection
     }
+
+    /// The protocol version and features negotiated with the client.
+    pub fn protocol(&self) -> &NegotiatedProtocol {
+        &self.protocol
+    }
+}
+
+/// Queries the generated terrain of a block column within [`MAX_TERRAIN_QUERY_DISTANCE`] of the player, on a blocking
+/// task to keep the network thread responsive.
+async fn query_terrain_near_player(
+    generator: Arc<MultiNoiseGenerator>,
+    player_position: AsyncResult<Vec3>,
+    position: AbsBlockPos,
+) -> Result<TerrainQuery> {
+    let player_position = player_position.async_wait().await?;
+    let distance = position.as_vec3().xz().distance(player_position.xz());
+    ensure!(
+        distance <= MAX_TERRAIN_QUERY_DISTANCE,
+        "The position is {distance:.0} blocks away from the player, at most {MAX_TERRAIN_QUERY_DISTANCE} are allowed"
+    );
+    Ok(spawn_blocking(move || generator.query_terrain(position)).await?)
 }
 
 impl rpc::authenticated_server_connection::Server for RcAuthenticatedServer2ClientEndpoint {
@@ -606,6 +898,7 @@ impl rpc::authenticated_server_connection::Server for RcAuthenticatedServer2Clie
         _: BootstrapGameDataParams,
         mut results: BootstrapGameDataResults,
     ) -> Promise<(), Error> {
+        self.0.borrow().metrics.record_rpc_call();
         let builder = results.get();
         let mut data = builder.init_data();
         // TODO: use saved world data here
@@ -622,6 +915,7 @@ impl rpc::authenticated_server_connection::Server for RcAuthenticatedServer2Clie
     }
 
     fn send_chat_message(&mut self, params: SendChatMessageParams, _: SendChatMessageResults) -> Promise<(), Error> {
+        self.0.borrow().metrics.record_rpc_call();
         let params = pry!(params.get());
         let text = pry!(pry!(params.get_text()).to_str());
         info!(
@@ -632,4 +926,148 @@ impl rpc::authenticated_server_connection::Server for RcAuthenticatedServer2Clie
         );
         Promise::ok(())
     }
+
+    fn edit_blocks(&mut self, params: EditBlocksParams, mut results: EditBlocksResults) -> Promise<(), Error> {
+        self.0.borrow().metrics.record_rpc_call();
+        let params = pry!(params.get());
+        let edits_reader = pry!(params.get_edits());
+        let edit_count = edits_reader.len();
+        // A rejected batch changes nothing, but the touched chunks still get their new revision below.
+        let too_many = edit_count > MAX_BLOCK_EDITS_PER_REQUEST;
+        let mut rejection = too_many
+            .then(|| format!("Too many block edits in one request: {edit_count} > {MAX_BLOCK_EDITS_PER_REQUEST}"));
+        let this = self.0.borrow();
+        let block_registry = &this.server.server_data.shared_registries.block_types;
+        // Rejected entries are kept as `None` and turned into no-op edits, so that every touched chunk still gets
+        // a new revision and the client's prediction gets corrected by the next chunk update.
+        let mut edits: Vec<(AbsBlockPos, Option<BlockEntry>)> = Vec::with_capacity(edit_count as usize);
+        for edit in edits_reader.iter() {
+            let pos = pry!(edit.get_position());
+            let pos = AbsBlockPos::new(pos.get_x(), pos.get_y(), pos.get_z());
+            if too_many {
+                edits.push((pos, None));
+                continue;
+            }
+            let block = BlockEntry::from_packed(edit.get_block()).filter(|b| b.lookup(block_registry).is_some());
+            if block.is_none() {
+                warn!(
+                    "Client {} ({:?}) requested an invalid block at {pos}",
+                    this.username, this.peer
+                );
+            }
+            edits.push((pos, block));
+        }
+        if rejection.is_none() {
+            if let Err(e) = validate_edit_batch(&edits) {
+                warn!(
+                    "Client {} ({:?}) requested an invalid block edit batch: {e:#}",
+                    this.username, this.peer
+                );
+                edits.iter_mut().for_each(|(_, block)| *block = None);
+                rejection = Some(format!("Invalid block edit batch: {e:#}"));
+            }
+        }
+        // TODO: check the player's permissions
+        let peer = this.peer;
+        let username = this.username.clone();
+        let skipped = this.server.schedule_bevy(move |world| {
+            let player = *world
+                .resource::<ConnectedPlayersTable>()
+                .players_by_address()
+                .get(&peer)
+                .context("The player is not in the world")?;
+            let eyes = world
+                .get::<PlayerPhysics>(player)
+                .context("The player has no position")?
+                .state
+                .position
+                + PLAYER_EYE_HEIGHT * Vec3::Y;
+            let mut out_of_reach = 0;
+            for (pos, block) in edits.iter_mut() {
+                let distance_squared = (pos.as_vec3() + Vec3::splat(0.5)).distance_squared(eyes);
+                if block.is_some() && distance_squared > MAX_EDIT_DISTANCE * MAX_EDIT_DISTANCE {
+                    *block = None;
+                    out_of_reach += 1;
+                }
+            }
+            if out_of_reach > 0 {
+                warn!("Client {username} ({peer:?}) requested {out_of_reach} block edits out of reach");
+            }
+            let registry = Arc::clone(&world.resource::<BlockRegistryHolder>().0);
+            let mut voxel_q = world.query::<(&mut VoxelUniverse<ServerData>, &mut VoxelCollision)>();
+            let (mut voxels, mut collision) = voxel_q.get_single_mut(world)?;
+            Ok(voxels.apply_player_block_edits(player, &edits, &mut collision, &registry))
+        });
+        Promise::from_future(async move {
+            let skipped = skipped
+                .async_wait()
+                .await
+                .map_err(|e| Error::failed(format!("Could not apply the block edits: {e:#}")))?;
+            if let Some(rejection) = rejection {
+                return Err(Error::failed(rejection));
+            }
+            let mut skipped_builder = results.get().init_skipped(skipped.len() as u32);
+            for (i, &(pos, revision)) in skipped.iter().enumerate() {
+                let mut chunk = skipped_builder.reborrow().get(i as u32);
+                let mut position = chunk.reborrow().init_position();
+                position.set_x(pos.x);
+                position.set_y(pos.y);
+                position.set_z(pos.z);
+                chunk.set_revision(revision.map_or(0, RevisionNumber::get));
+            }
+            Ok(())
+        })
+    }
+
+    fn query_terrain(&mut self, params: QueryTerrainParams, mut results: QueryTerrainResults) -> Promise<(), Error> {
+        self.0.borrow().metrics.record_rpc_call();
+        let position = pry!(pry!(params.get()).get_position());
+        let position = AbsBlockPos::new(position.get_x(), position.get_y(), position.get_z());
+        let this = self.0.borrow();
+        let Ok(permit) = Arc::clone(&this.terrain_queries).try_acquire_owned() else {
+            return Promise::err(Error::overloaded(format!(
+                "Too many terrain queries at once, at most {MAX_TERRAIN_QUERIES_IN_FLIGHT} are allowed"
+            )));
+        };
+        let Some(generator) = this.net_state.borrow().world_generator.clone() else {
+            return Promise::err(Error::failed("The world has no terrain generator".to_owned()));
+        };
+        let biome_registry = Arc::clone(&this.server.server_data.shared_registries.biome_types);
+        // Only the player's position is looked up on the engine thread, the generator is queried on a blocking task.
+        let peer = this.peer;
+        let player_position = this.server.schedule_bevy(move |world| {
+            let player = *world
+                .resource::<ConnectedPlayersTable>()
+                .players_by_address()
+                .get(&peer)
+                .context("The player is not in the world")?;
+            Ok(world
+                .get::<PlayerPhysics>(player)
+                .context("The player has no position")?
+                .state
+                .position)
+        });
+        Promise::from_future(async move {
+            let query = query_terrain_near_player(generator, player_position, position).await;
+            drop(permit);
+            let query =
+                query.map_err(|e| Error::failed(format!("Could not query the terrain at {position}: {e:#}")))?;
+            let mut info = results.get().init_info();
+            info.set_elevation(query.elevation);
+            info.set_temperature(query.temperature);
+            info.set_moisture(query.moisture);
+            info.set_surface_height(query.surface_height);
+            let mut biomes = info.init_biomes(query.biomes.len() as u32);
+            for (i, entry) in query.biomes.iter().enumerate() {
+                let mut biome = biomes.reborrow().get(i as u32);
+                biome.set_weight(entry.weight);
+                if let Some(definition) = entry.lookup(&biome_registry) {
+                    let mut name = biome.init_biome();
+                    name.set_ns(&definition.name.ns);
+                    name.set_key(&definition.name.key);
+                }
+            }
+            Ok(())
+        })
+    }
 }
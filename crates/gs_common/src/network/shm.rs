@@ -0,0 +1,883 @@
+//! A connection between two processes over shared memory, used to run the singleplayer server in a separate process
+//! so that a crash of either side cannot corrupt the other one.
+//!
+//! Both processes map the same memfd holding a single-producer single-consumer byte ring for each direction, and wake
+//! each other up through eventfds. The rings carry frames multiplexing the RPC pipe, the streams and the datagrams of
+//! an [`InProcessDuplex`], so the rest of the game uses the connection like any other in-process connection.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use bevy::log::error;
+use gs_schemas::schemas::NetworkStreamType;
+use memmap2::MmapMut;
+use quinn::VarInt;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::select;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::network::transport::{
+    InProcessDuplex, InProcessStream, StreamRejection, INPROCESS_SOCKET_BUFFER_SIZE, RPC_LOCAL_READER_OPTIONS,
+};
+use crate::prelude::*;
+
+/// Size of the ring buffer for each direction, a power of two.
+pub const SHM_RING_CAPACITY: usize = 1 << 20;
+/// How often each side signals that it is still alive.
+pub const SHM_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+/// The other side is considered dead after not signalling for this long.
+pub const SHM_PEER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for the other side to start up and signal for the first time.
+pub const SHM_STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Identifies memory set up by [`ShmEndpoint::create`].
+const SHM_MAGIC: u64 = u64::from_le_bytes(*b"GSSHM001");
+/// Size of the chunks the RPC pipe is split into.
+const RPC_FRAME_SIZE: usize = 16 * 1024;
+/// Kind (u8), stream ID (u32) and payload length (u32).
+const FRAME_HEADER_SIZE: usize = 9;
+/// Largest accepted frame payload, so that the other side cannot make the receive buffer grow without bounds.
+const MAX_FRAME_PAYLOAD: usize = 64 * 1024 * 1024;
+/// Frames waiting to be encoded for the outgoing ring, the local senders wait once the queue is full.
+const FRAME_QUEUE_DEPTH: usize = 64;
+
+/// Which of the two processes sharing the memory an endpoint belongs to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ShmSide {
+    /// The process that created the memory, e.g. the game client.
+    Host,
+    /// The process that inherited the memory from the host, e.g. the server.
+    Guest,
+}
+
+impl ShmSide {
+    fn index(self) -> usize {
+        match self {
+            Self::Host => 0,
+            Self::Guest => 1,
+        }
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            Self::Host => Self::Guest,
+            Self::Guest => Self::Host,
+        }
+    }
+}
+
+#[repr(C, align(64))]
+struct CachePadded<T>(T);
+
+/// Positions in a ring, counted in bytes since the creation of the connection.
+#[repr(C)]
+struct RingHeader {
+    /// Only advanced by the writing side.
+    head: CachePadded<AtomicU64>,
+    /// Only advanced by the reading side.
+    tail: CachePadded<AtomicU64>,
+}
+
+/// The beginning of the shared memory, followed by the ring data of the host and then of the guest.
+#[repr(C)]
+struct SharedHeader {
+    magic: AtomicU64,
+    /// Incremented periodically by each side while it is alive.
+    heartbeats: [AtomicU64; 2],
+    /// Set by each side when it closes the connection.
+    closed: [AtomicU32; 2],
+    /// The ring written by each side.
+    rings: [RingHeader; 2],
+}
+
+const SHM_HEADER_SIZE: usize = size_of::<SharedHeader>().next_multiple_of(64);
+const SHM_SIZE: usize = SHM_HEADER_SIZE + 2 * SHM_RING_CAPACITY;
+
+/// The file descriptors making up a shared memory connection, passed on to the guest process.
+pub struct ShmConnectionFds {
+    /// The memfd of the shared memory.
+    pub memory: OwnedFd,
+    /// The eventfd waking up the host.
+    pub host_wakeup: OwnedFd,
+    /// The eventfd waking up the guest.
+    pub guest_wakeup: OwnedFd,
+}
+
+impl ShmConnectionFds {
+    /// Formats the descriptor numbers for the command line of a child process inheriting them.
+    pub fn to_arg(&self) -> String {
+        format!(
+            "{},{},{}",
+            self.memory.as_raw_fd(),
+            self.host_wakeup.as_raw_fd(),
+            self.guest_wakeup.as_raw_fd()
+        )
+    }
+
+    /// Takes ownership of the descriptors inherited from the parent process, described by [`Self::to_arg`].
+    ///
+    /// # Safety
+    /// The descriptors have to be open, and must not be used or closed by anything else in this process.
+    pub unsafe fn from_inherited_arg(arg: &str) -> Result<Self> {
+        let fds: Vec<RawFd> = arg
+            .split(',')
+            .map(|fd| fd.trim().parse())
+            .collect::<Result<_, _>>()
+            .context("Invalid shared memory descriptor list")?;
+        let &[memory, host_wakeup, guest_wakeup] = fds.as_slice() else {
+            bail!("Expected 3 shared memory descriptors, got {}", fds.len());
+        };
+        // Safety: guaranteed by the caller.
+        unsafe {
+            Ok(Self {
+                memory: OwnedFd::from_raw_fd(memory),
+                host_wakeup: OwnedFd::from_raw_fd(host_wakeup),
+                guest_wakeup: OwnedFd::from_raw_fd(guest_wakeup),
+            })
+        }
+    }
+
+    /// Makes the descriptors survive executing the command, they are closed on exec by default.
+    pub fn inherit_into(&self, command: &mut Command) {
+        let fds = [
+            self.memory.as_raw_fd(),
+            self.host_wakeup.as_raw_fd(),
+            self.guest_wakeup.as_raw_fd(),
+        ];
+        // Safety: fcntl is async-signal-safe, and only changes the flags of descriptors owned by this struct.
+        unsafe {
+            command.pre_exec(move || {
+                for fd in fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// The number of bytes stored in a ring between its read and write positions.
+/// Both positions are in memory the other process can write to, so they are checked before being used for copying.
+fn ring_len(head: u64, tail: u64) -> Result<usize> {
+    match head.checked_sub(tail) {
+        Some(len) if len <= SHM_RING_CAPACITY as u64 => Ok(len as usize),
+        _ => bail!("Corrupted shared memory ring positions (head {head}, tail {tail})"),
+    }
+}
+
+/// One side of a shared memory connection, not connected to anything yet, see [`bridge_connection`].
+pub struct ShmEndpoint {
+    side: ShmSide,
+    memory: MmapMut,
+    wakeup: File,
+    peer_wakeup: File,
+}
+
+fn eventfd() -> io::Result<OwnedFd> {
+    // Safety: creates a new descriptor, checked for errors before taking ownership.
+    unsafe {
+        let fd = libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn memfd() -> io::Result<OwnedFd> {
+    // Safety: creates a new descriptor, checked for errors before taking ownership.
+    unsafe {
+        let fd = libc::memfd_create(c"geosia-connection".as_ptr(), libc::MFD_CLOEXEC);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn map_memory(memory: &File) -> io::Result<MmapMut> {
+    // Safety: the memfd is only ever mapped by the two sides of the connection, which access it through atomics and
+    // the ring buffer protocol.
+    unsafe { MmapMut::map_mut(memory) }
+}
+
+impl ShmEndpoint {
+    /// Sets up new shared memory, returns the host endpoint and the descriptors to pass on to the guest process.
+    pub fn create() -> Result<(Self, ShmConnectionFds)> {
+        let memory = File::from(memfd().context("Creating the shared memory")?);
+        memory.set_len(SHM_SIZE as u64)?;
+        let fds = ShmConnectionFds {
+            memory: memory.try_clone()?.into(),
+            host_wakeup: eventfd()?,
+            guest_wakeup: eventfd()?,
+        };
+        let endpoint = Self {
+            side: ShmSide::Host,
+            memory: map_memory(&memory)?,
+            wakeup: File::from(fds.host_wakeup.try_clone()?),
+            peer_wakeup: File::from(fds.guest_wakeup.try_clone()?),
+        };
+        endpoint.header().magic.store(SHM_MAGIC, AtomicOrdering::Release);
+        Ok((endpoint, fds))
+    }
+
+    /// Opens the guest endpoint from the descriptors set up by the host.
+    pub fn open(fds: ShmConnectionFds) -> Result<Self> {
+        let memory = File::from(fds.memory);
+        ensure!(
+            memory.metadata()?.len() == SHM_SIZE as u64,
+            "Shared memory has the wrong size, was it created by a different game version?"
+        );
+        let endpoint = Self {
+            side: ShmSide::Guest,
+            memory: map_memory(&memory)?,
+            wakeup: File::from(fds.guest_wakeup),
+            peer_wakeup: File::from(fds.host_wakeup),
+        };
+        ensure!(
+            endpoint.header().magic.load(AtomicOrdering::Acquire) == SHM_MAGIC,
+            "Shared memory was not set up for a game connection"
+        );
+        Ok(endpoint)
+    }
+
+    /// Which side of the connection this is.
+    pub fn side(&self) -> ShmSide {
+        self.side
+    }
+
+    fn header(&self) -> &SharedHeader {
+        // Safety: the mapping is page-aligned and larger than the header, which only contains atomics.
+        unsafe { &*(self.memory.as_ptr() as *const SharedHeader) }
+    }
+
+    /// Returns the header, and the start of the data of the given side's ring.
+    fn ring_parts(&mut self, side: ShmSide) -> (&SharedHeader, *mut u8) {
+        let base = self.memory.as_mut_ptr();
+        // Safety: see header(), the ring data is within the mapping.
+        unsafe {
+            let header = &*(base as *const SharedHeader);
+            (header, base.add(SHM_HEADER_SIZE + side.index() * SHM_RING_CAPACITY))
+        }
+    }
+
+    /// Copies as much of the data into the outgoing ring as fits, returns the number of bytes written.
+    fn write_some(&mut self, data: &[u8]) -> Result<usize> {
+        let side = self.side;
+        let (header, ring_data) = self.ring_parts(side);
+        let ring = &header.rings[side.index()];
+        let head = ring.head.0.load(AtomicOrdering::Relaxed);
+        let tail = ring.tail.0.load(AtomicOrdering::Acquire);
+        let free = SHM_RING_CAPACITY - ring_len(head, tail)?;
+        let len = data.len().min(free);
+        let start = head as usize % SHM_RING_CAPACITY;
+        let first = len.min(SHM_RING_CAPACITY - start);
+        // Safety: the bytes between head and tail + capacity are not read by the other side until head is advanced.
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ring_data.add(start), first);
+            std::ptr::copy_nonoverlapping(data.as_ptr().add(first), ring_data, len - first);
+        }
+        ring.head.0.store(head + len as u64, AtomicOrdering::Release);
+        Ok(len)
+    }
+
+    /// Moves all the bytes available in the incoming ring to the buffer, returns their number.
+    fn read_available(&mut self, buffer: &mut BytesMut) -> Result<usize> {
+        let side = self.side.peer();
+        let (header, ring_data) = self.ring_parts(side);
+        let ring = &header.rings[side.index()];
+        let head = ring.head.0.load(AtomicOrdering::Acquire);
+        let tail = ring.tail.0.load(AtomicOrdering::Relaxed);
+        let len = ring_len(head, tail)?;
+        let start = tail as usize % SHM_RING_CAPACITY;
+        let first = len.min(SHM_RING_CAPACITY - start);
+        // Safety: the bytes between tail and head are not written by the other side until tail is advanced.
+        unsafe {
+            buffer.extend_from_slice(std::slice::from_raw_parts(ring_data.add(start), first));
+            buffer.extend_from_slice(std::slice::from_raw_parts(ring_data, len - first));
+        }
+        ring.tail.0.store(head, AtomicOrdering::Release);
+        Ok(len)
+    }
+
+    fn notify_peer(&self) {
+        // Only fails if the counter would overflow, in which case the other side has a pending wakeup anyway.
+        let _ = (&self.peer_wakeup).write(&1u64.to_ne_bytes());
+    }
+
+    fn heartbeat(&self) {
+        self.header().heartbeats[self.side.index()].fetch_add(1, AtomicOrdering::Release);
+    }
+
+    fn peer_heartbeat(&self) -> u64 {
+        self.header().heartbeats[self.side.peer().index()].load(AtomicOrdering::Acquire)
+    }
+
+    fn close(&self) {
+        self.header().closed[self.side.index()].store(1, AtomicOrdering::Release);
+        self.notify_peer();
+    }
+
+    fn peer_closed(&self) -> bool {
+        self.header().closed[self.side.peer().index()].load(AtomicOrdering::Acquire) != 0
+    }
+}
+
+/// A unit of the multiplexing protocol carried by the rings.
+#[derive(Clone, Debug, PartialEq)]
+enum Frame {
+    Rpc(Bytes),
+    StreamOpen {
+        id: u32,
+        stream_type: NetworkStreamType,
+    },
+    StreamData {
+        id: u32,
+        payload: Bytes,
+    },
+    /// The sending side will not send more data on the stream.
+    StreamClose {
+        id: u32,
+    },
+    StreamReject {
+        id: u32,
+        reason: StreamRejection,
+    },
+    Datagram(Bytes),
+}
+
+impl Frame {
+    fn encode(&self) -> Bytes {
+        let (kind, id, payload) = match self {
+            Self::Rpc(payload) => (0, 0, payload.clone()),
+            Self::StreamOpen { id, stream_type } => (1, *id, Bytes::from(stream_type.write_to_bytes().into_vec())),
+            Self::StreamData { id, payload } => (2, *id, payload.clone()),
+            Self::StreamClose { id } => (3, *id, Bytes::new()),
+            Self::StreamReject { id, reason } => {
+                let code = reason.code().into_inner() as u32;
+                (4, *id, Bytes::copy_from_slice(&code.to_le_bytes()))
+            }
+            Self::Datagram(payload) => (5, 0, payload.clone()),
+        };
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.put_u8(kind);
+        frame.put_u32_le(id);
+        frame.put_u32_le(payload.len() as u32);
+        frame.put_slice(&payload);
+        frame.freeze()
+    }
+
+    /// Takes the first complete frame out of the buffer, if there is one.
+    fn decode(buffer: &mut BytesMut) -> Result<Option<Self>> {
+        if buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(buffer[5..9].try_into().unwrap()) as usize;
+        ensure!(
+            len <= MAX_FRAME_PAYLOAD,
+            "Shared memory frame of {len} bytes is larger than the limit of {MAX_FRAME_PAYLOAD}"
+        );
+        if buffer.len() < FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let mut frame = buffer.split_to(FRAME_HEADER_SIZE + len).freeze();
+        let kind = frame.get_u8();
+        let id = frame.get_u32_le();
+        frame.advance(4);
+        Ok(Some(match kind {
+            0 => Self::Rpc(frame),
+            1 => {
+                // Copied out of the frame, capnp needs the message aligned.
+                let header: Box<[u8]> = frame.to_vec().into_boxed_slice();
+                Self::StreamOpen {
+                    id,
+                    stream_type: NetworkStreamType::read_from_bytes(&header, RPC_LOCAL_READER_OPTIONS)?,
+                }
+            }
+            2 => Self::StreamData { id, payload: frame },
+            3 => Self::StreamClose { id },
+            4 => {
+                ensure!(frame.len() == 4, "Invalid stream rejection frame");
+                let code = VarInt::from_u32(frame.get_u32_le());
+                let reason = StreamRejection::from_code(code).unwrap_or(StreamRejection::UnknownType);
+                Self::StreamReject { id, reason }
+            }
+            5 => Self::Datagram(frame),
+            _ => bail!("Unknown shared memory frame kind {kind}"),
+        }))
+    }
+}
+
+/// A stream crossing the process boundary.
+struct BridgedStream {
+    /// Passes on the data received from the other process, None after the other process finished sending.
+    tx: Option<AsyncUnboundedSender<Bytes>>,
+    rejection: Arc<OnceLock<StreamRejection>>,
+    /// Stops forwarding the local data, None after the local side finished sending.
+    stop: Option<AsyncOneshotSender<()>>,
+}
+
+impl BridgedStream {
+    /// Starts forwarding the data sent by the local side of the stream as frames.
+    fn forward(id: u32, stream: InProcessStream, frames: AsyncBoundedSender<Frame>) -> Self {
+        let (stop_tx, mut stop_rx) = async_oneshot_channel();
+        let source = Arc::clone(&stream.rx);
+        let rejection = Arc::clone(&stream.rejection);
+        tokio::spawn(async move {
+            let mut source = source.lock().await;
+            loop {
+                select! {
+                    message = source.recv() => {
+                        let Some(payload) = message else { break };
+                        if payload.len() > MAX_FRAME_PAYLOAD {
+                            error!("Closing stream {id}, message of {} bytes is too large to bridge", payload.len());
+                            source.close();
+                            break;
+                        }
+                        if frames.send(Frame::StreamData { id, payload }).await.is_err() {
+                            return;
+                        }
+                    }
+                    _ = &mut stop_rx => {
+                        source.close();
+                        return;
+                    }
+                }
+            }
+            // The local side stops sending after rejecting the stream.
+            let _ = match rejection.get() {
+                Some(&reason) => frames.send(Frame::StreamReject { id, reason }).await,
+                None => frames.send(Frame::StreamClose { id }).await,
+            };
+        });
+        Self {
+            tx: Some(stream.tx),
+            rejection: stream.rejection,
+            stop: Some(stop_tx),
+        }
+    }
+}
+
+/// The state of a running [`bridge_connection`].
+struct Bridge {
+    endpoint: ShmEndpoint,
+    streams: HashMap<u32, BridgedStream>,
+    next_stream_id: u32,
+    frames: AsyncBoundedSender<Frame>,
+    rpc: AsyncUnboundedSender<Bytes>,
+    outgoing_streams: AsyncUnboundedSender<InProcessStream>,
+    outgoing_datagrams: AsyncUnboundedSender<Bytes>,
+    /// Encoded frames waiting for space in the outgoing ring, no new frames are taken in until it empties.
+    unsent: VecDeque<Bytes>,
+    received: BytesMut,
+}
+
+impl Bridge {
+    fn open_local_stream(&mut self, stream: InProcessStream) {
+        let id = self.next_stream_id * 2 + self.endpoint.side().index() as u32;
+        self.next_stream_id += 1;
+        self.send(Frame::StreamOpen {
+            id,
+            stream_type: stream.stream_type.clone(),
+        });
+        let bridged = BridgedStream::forward(id, stream, self.frames.clone());
+        self.streams.insert(id, bridged);
+    }
+
+    /// Queues a frame for sending, updating the state of the stream it finishes.
+    fn send(&mut self, frame: Frame) {
+        match &frame {
+            Frame::StreamClose { id } => {
+                if let Some(stream) = self.streams.get_mut(id) {
+                    stream.stop = None;
+                    if stream.tx.is_none() {
+                        self.streams.remove(id);
+                    }
+                }
+            }
+            Frame::StreamReject { id, .. } => {
+                self.streams.remove(id);
+            }
+            _ => {}
+        }
+        self.unsent.push_back(frame.encode());
+    }
+
+    fn receive(&mut self, frame: Frame) {
+        match frame {
+            Frame::Rpc(data) => {
+                let _ = self.rpc.send(data);
+            }
+            Frame::StreamOpen { id, stream_type } => {
+                let (local, bridged) = InProcessStream::new_pair(stream_type);
+                if self.outgoing_streams.send(local).is_ok() {
+                    let bridged = BridgedStream::forward(id, bridged, self.frames.clone());
+                    self.streams.insert(id, bridged);
+                }
+            }
+            Frame::StreamData { id, payload } => {
+                let Some(stream) = self.streams.get(&id) else {
+                    return;
+                };
+                let delivered = stream.tx.as_ref().is_some_and(|tx| tx.send(payload).is_ok());
+                if !delivered {
+                    let reason = stream.rejection.get().copied().unwrap_or(StreamRejection::UnknownType);
+                    self.send(Frame::StreamReject { id, reason });
+                }
+            }
+            Frame::StreamClose { id } => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.tx = None;
+                    if stream.stop.is_none() {
+                        self.streams.remove(&id);
+                    }
+                }
+            }
+            Frame::StreamReject { id, reason } => {
+                if let Some(mut stream) = self.streams.remove(&id) {
+                    let _ = stream.rejection.set(reason);
+                    if let Some(stop) = stream.stop.take() {
+                        let _ = stop.send(());
+                    }
+                }
+            }
+            Frame::Datagram(datagram) => {
+                let _ = self.outgoing_datagrams.send(datagram);
+            }
+        }
+    }
+
+    /// Moves data through the rings as far as possible without waiting, returns if anything was moved.
+    fn transfer(&mut self) -> Result<bool> {
+        let mut progress = false;
+        while let Some(front) = self.unsent.front_mut() {
+            let written = self.endpoint.write_some(front)?;
+            progress |= written > 0;
+            if written < front.len() {
+                front.advance(written);
+                break;
+            }
+            self.unsent.pop_front();
+        }
+        if self.endpoint.read_available(&mut self.received)? > 0 {
+            progress = true;
+            while let Some(frame) = Frame::decode(&mut self.received)? {
+                self.receive(frame);
+            }
+        }
+        Ok(progress)
+    }
+}
+
+/// Connects an in-process connection end to the other process, until either side closes the connection or the other
+/// process stops responding. Has to be called within a tokio runtime.
+pub async fn bridge_connection(endpoint: ShmEndpoint, pipe: InProcessDuplex) -> Result<()> {
+    let InProcessDuplex {
+        rpc_pipe,
+        mut incoming_streams,
+        outgoing_streams,
+        mut incoming_datagrams,
+        outgoing_datagrams,
+    } = pipe;
+    let wakeup = AsyncFd::new(endpoint.wakeup.try_clone()?)?;
+    let (frames_tx, mut frames_rx) = async_bounded_channel(FRAME_QUEUE_DEPTH);
+    let (rpc_tx, mut rpc_rx) = async_unbounded_channel::<Bytes>();
+    let (mut rpc_read, mut rpc_write) = tokio::io::split(rpc_pipe);
+
+    let rpc_frames = frames_tx.clone();
+    let rpc_reader = tokio::spawn(async move {
+        let mut buffer = vec![0u8; RPC_FRAME_SIZE];
+        while let Ok(len @ 1..) = rpc_read.read(&mut buffer).await {
+            if rpc_frames
+                .send(Frame::Rpc(Bytes::copy_from_slice(&buffer[..len])))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    let rpc_writer = tokio::spawn(async move {
+        while let Some(data) = rpc_rx.recv().await {
+            if rpc_write.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = rpc_write.shutdown().await;
+    });
+
+    let mut bridge = Bridge {
+        endpoint,
+        streams: HashMap::new(),
+        next_stream_id: 0,
+        frames: frames_tx,
+        rpc: rpc_tx,
+        outgoing_streams,
+        outgoing_datagrams,
+        unsent: VecDeque::new(),
+        received: BytesMut::with_capacity(INPROCESS_SOCKET_BUFFER_SIZE),
+    };
+    let mut heartbeat = interval(SHM_HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut peer_heartbeat = (bridge.endpoint.peer_heartbeat(), Instant::now());
+
+    let result = loop {
+        match bridge.transfer() {
+            Ok(true) => bridge.endpoint.notify_peer(),
+            Ok(false) => {}
+            Err(e) => break Err(e.context("Invalid data received from the other process")),
+        }
+        if bridge.endpoint.peer_closed() {
+            break Ok(());
+        }
+        // Leaves the frames in the bounded queue while the ring is full, so that the senders wait instead of the
+        // unsent frames growing without bounds.
+        let ring_free = bridge.unsent.is_empty();
+        select! {
+            Some(frame) = frames_rx.recv(), if ring_free => bridge.send(frame),
+            stream = incoming_streams.recv(), if ring_free => match stream {
+                Some(stream) => bridge.open_local_stream(stream),
+                None => break Ok(()),
+            },
+            datagram = incoming_datagrams.recv(), if ring_free => match datagram {
+                Some(datagram) => bridge.send(Frame::Datagram(datagram)),
+                None => break Ok(()),
+            },
+            guard = wakeup.readable() => {
+                let mut guard = match guard {
+                    Ok(guard) => guard,
+                    Err(e) => break Err(e.into()),
+                };
+                let mut counter = [0u8; 8];
+                // Resets the eventfd, the rings get checked at the start of the next iteration.
+                let _ = guard.try_io(|fd| fd.get_ref().read(&mut counter));
+            }
+            _ = heartbeat.tick() => {
+                bridge.endpoint.heartbeat();
+                let now = Instant::now();
+                let beat = bridge.endpoint.peer_heartbeat();
+                if beat != peer_heartbeat.0 {
+                    peer_heartbeat = (beat, now);
+                }
+                let timeout = if beat == 0 { SHM_STARTUP_TIMEOUT } else { SHM_PEER_TIMEOUT };
+                if now - peer_heartbeat.1 > timeout {
+                    break Err(anyhow!("The other process stopped responding for {timeout:?}"));
+                }
+            }
+        }
+    };
+
+    // Best effort delivery of the last messages before closing.
+    let _ = bridge.transfer();
+    bridge.endpoint.close();
+    rpc_reader.abort();
+    rpc_writer.abort();
+    result
+}
+
+/// Runs [`bridge_connection`] on a new thread with its own tokio runtime.
+pub fn spawn_bridge_thread(endpoint: ShmEndpoint, pipe: InProcessDuplex) -> JoinHandle<Result<()>> {
+    std::thread::Builder::new()
+        .name(String::from("GS Shared Memory Bridge"))
+        .spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(bridge_connection(endpoint, pipe))
+        })
+        .expect("Could not start the shared memory bridge thread")
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::schemas::NetworkStreamHeader;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::network::transport::TransportStream;
+
+    const TEST_STREAM: NetworkStreamType = NetworkStreamType::new(
+        NetworkStreamHeader::Standard(rpc::stream_header::StandardTypes::ChunkData),
+        1,
+    );
+
+    fn run<F: std::future::Future<Output = ()>>(future: F) {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future);
+    }
+
+    /// Connects two in-process connection ends through shared memory within this process.
+    fn bridged_pair() -> (
+        InProcessDuplex,
+        InProcessDuplex,
+        JoinHandle<Result<()>>,
+        JoinHandle<Result<()>>,
+    ) {
+        let (host, fds) = ShmEndpoint::create().unwrap();
+        let guest = ShmEndpoint::open(fds).unwrap();
+        let (host_local, host_bridged) = InProcessDuplex::new_pair();
+        let (guest_local, guest_bridged) = InProcessDuplex::new_pair();
+        (
+            host_local,
+            guest_local,
+            tokio::spawn(bridge_connection(host, host_bridged)),
+            tokio::spawn(bridge_connection(guest, guest_bridged)),
+        )
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frames = [
+            Frame::Rpc(Bytes::from_static(b"rpc")),
+            Frame::StreamOpen {
+                id: 3,
+                stream_type: TEST_STREAM,
+            },
+            Frame::StreamData {
+                id: 3,
+                payload: Bytes::from_static(b"data"),
+            },
+            Frame::StreamClose { id: 3 },
+            Frame::StreamReject {
+                id: 4,
+                reason: StreamRejection::UnsupportedVersion,
+            },
+            Frame::Datagram(Bytes::new()),
+        ];
+        let mut buffer = BytesMut::new();
+        for frame in &frames {
+            buffer.extend_from_slice(&frame.encode());
+        }
+        let last = buffer.split_off(buffer.len() - 2);
+        for frame in &frames[..frames.len() - 1] {
+            assert_eq!(Frame::decode(&mut buffer).unwrap().as_ref(), Some(frame));
+        }
+        assert_eq!(Frame::decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&last);
+        assert_eq!(Frame::decode(&mut buffer).unwrap().as_ref(), frames.last());
+    }
+
+    #[test]
+    fn rejects_corrupted_data() {
+        assert_eq!(ring_len(10, 4).unwrap(), 6);
+        assert_eq!(ring_len(SHM_RING_CAPACITY as u64 + 4, 4).unwrap(), SHM_RING_CAPACITY);
+        assert!(ring_len(4, 10).is_err());
+        assert!(ring_len(SHM_RING_CAPACITY as u64 + 5, 4).is_err());
+
+        let (mut host, fds) = ShmEndpoint::create().unwrap();
+        let mut guest = ShmEndpoint::open(fds).unwrap();
+        host.header().rings[ShmSide::Host.index()]
+            .head
+            .0
+            .store(u64::MAX, AtomicOrdering::Release);
+        assert!(guest.read_available(&mut BytesMut::new()).is_err());
+        assert!(host.write_some(b"data").is_err());
+
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(5);
+        buffer.put_u32_le(0);
+        buffer.put_u32_le(u32::MAX);
+        assert!(Frame::decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn bridge_carries_all_traffic() {
+        run(async {
+            let (mut host, mut guest, host_bridge, guest_bridge) = bridged_pair();
+
+            host.rpc_pipe.write_all(b"hello").await.unwrap();
+            let mut greeting = [0u8; 5];
+            guest.rpc_pipe.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(&greeting, b"hello");
+
+            let (opened, sent) = InProcessStream::new_pair(TEST_STREAM);
+            host.outgoing_streams.send(sent).unwrap();
+            let opened = TransportStream::from(opened);
+            let accepted = TransportStream::from(guest.incoming_streams.recv().await.unwrap());
+            assert_eq!(accepted.stream_type(), &TEST_STREAM);
+            // Larger than the rings, to exercise wrapping around and partial writes.
+            let large = Bytes::from((0..3 * SHM_RING_CAPACITY).map(|i| i as u8).collect::<Vec<u8>>());
+            opened.send(large.clone()).await.unwrap();
+            accepted.send(Bytes::from_static(b"reply")).await.unwrap();
+            assert_eq!(accepted.recv().await.unwrap(), large);
+            assert_eq!(opened.recv().await.unwrap(), Bytes::from_static(b"reply"));
+
+            guest.outgoing_datagrams.send(Bytes::from_static(b"datagram")).unwrap();
+            assert_eq!(
+                host.incoming_datagrams.recv().await.unwrap(),
+                Bytes::from_static(b"datagram")
+            );
+
+            // Closing one side ends both bridges cleanly.
+            drop(host);
+            host_bridge.await.unwrap().unwrap();
+            guest_bridge.await.unwrap().unwrap();
+            assert!(guest.incoming_streams.recv().await.is_none());
+        });
+    }
+
+    #[test]
+    fn slow_readers_make_the_writers_wait() {
+        run(async {
+            let (host, fds) = ShmEndpoint::create().unwrap();
+            let guest = ShmEndpoint::open(fds).unwrap();
+            let (mut host_local, host_bridged) = InProcessDuplex::new_pair();
+            let (mut guest_local, guest_bridged) = InProcessDuplex::new_pair();
+            tokio::spawn(bridge_connection(host, host_bridged));
+
+            // Nothing reads the ring yet, so the writer stalls once the ring and the bounded queues are full.
+            let data: Vec<u8> = (0..16 * SHM_RING_CAPACITY).map(|i| i as u8).collect();
+            let write = tokio::spawn(async move {
+                host_local.rpc_pipe.write_all(&data).await.unwrap();
+                (host_local, data)
+            });
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(!write.is_finished());
+
+            tokio::spawn(bridge_connection(guest, guest_bridged));
+            let mut received = vec![0u8; 16 * SHM_RING_CAPACITY];
+            guest_local.rpc_pipe.read_exact(&mut received).await.unwrap();
+            let (_host_local, data) = write.await.unwrap();
+            assert!(received == data);
+        });
+    }
+
+    #[test]
+    fn rejections_reach_the_other_process() {
+        run(async {
+            let (host, mut guest, _host_bridge, _guest_bridge) = bridged_pair();
+
+            let (opened, sent) = InProcessStream::new_pair(TEST_STREAM);
+            host.outgoing_streams.send(sent).unwrap();
+            let opened = TransportStream::from(opened);
+            let accepted = TransportStream::from(guest.incoming_streams.recv().await.unwrap());
+            accepted.reject(StreamRejection::UnsupportedVersion).await;
+
+            let error = loop {
+                if let Err(e) = opened.send(Bytes::from_static(b"data")).await {
+                    break e;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            };
+            assert_eq!(
+                error.downcast_ref::<StreamRejection>(),
+                Some(&StreamRejection::UnsupportedVersion)
+            );
+        });
+    }
+}
@@ -0,0 +1,393 @@
+//! Simulated network conditions for in-process connections, to test the networking code under latency,
+//! limited bandwidth, packet loss and reordering without real sockets.
+
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use tokio::io::{AsyncReadExt as TokioAsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::select;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::bytes::Bytes;
+
+use crate::network::transport::{InProcessDuplex, InProcessStream, INPROCESS_SOCKET_BUFFER_SIZE};
+use crate::prelude::*;
+
+/// Size of the chunks the in-process RPC pipe is split into when sent over a simulated link.
+const PIPE_PACKET_SIZE: usize = 1200;
+
+/// Parameters of a simulated one-way network link.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkConditions {
+    /// Fixed delay of every packet.
+    pub latency: Duration,
+    /// Maximum random extra delay of every packet.
+    pub jitter: Duration,
+    /// Bandwidth limit in bytes per second, packets queue up behind each other when it is exceeded.
+    pub bandwidth: Option<u64>,
+    /// Probability of losing a datagram, or of a stream packet getting retransmitted after a round trip.
+    pub packet_loss: f64,
+    /// Probability of delaying a datagram by an extra latency, so that it arrives after the following datagrams.
+    pub reordering: f64,
+    /// Seed of the random number generator, to make the simulation reproducible.
+    pub seed: u64,
+}
+
+impl Default for NetworkConditions {
+    /// A perfect link.
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            packet_loss: 0.0,
+            reordering: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl NetworkConditions {
+    /// A congested mobile connection.
+    pub fn poor() -> Self {
+        Self {
+            latency: Duration::from_millis(150),
+            jitter: Duration::from_millis(50),
+            bandwidth: Some(256 * 1024),
+            packet_loss: 0.05,
+            reordering: 0.02,
+            seed: 0,
+        }
+    }
+}
+
+/// The state of one direction of a simulated connection, shared by all the traffic competing for its bandwidth.
+struct SimulatedLink {
+    conditions: NetworkConditions,
+    rng: Xoshiro256PlusPlus,
+    busy_until: Instant,
+}
+
+type SharedLink = Arc<Mutex<SimulatedLink>>;
+
+impl SimulatedLink {
+    fn new_shared(conditions: &NetworkConditions, seed: u64) -> SharedLink {
+        Arc::new(Mutex::new(Self {
+            conditions: conditions.clone(),
+            rng: Xoshiro256PlusPlus::seed_from_u64(seed),
+            busy_until: Instant::now(),
+        }))
+    }
+
+    /// Computes the arrival time of a packet of `size` bytes sent now, or None if it gets lost.
+    /// Lost reliable packets arrive late instead, after a retransmission.
+    fn schedule(&mut self, size: usize, reliable: bool) -> Option<Instant> {
+        let conditions = &self.conditions;
+        let start = self.busy_until.max(Instant::now());
+        let transmission = match conditions.bandwidth {
+            Some(bandwidth) => Duration::from_secs_f64(size as f64 / bandwidth.max(1) as f64),
+            None => Duration::ZERO,
+        };
+        self.busy_until = start + transmission;
+
+        let mut delay = conditions.latency + conditions.jitter.mul_f64(self.rng.gen::<f64>());
+        if self.rng.gen_bool(conditions.packet_loss.clamp(0.0, 1.0)) {
+            if !reliable {
+                return None;
+            }
+            delay += 2 * conditions.latency;
+        }
+        if !reliable && self.rng.gen_bool(conditions.reordering.clamp(0.0, 1.0)) {
+            delay += conditions.latency.max(Duration::from_millis(1));
+        }
+        Some(self.busy_until + delay)
+    }
+}
+
+/// Forwards messages in order, delaying them as if they were sent over the link.
+/// Closing either end propagates to the other one, after all the messages in flight are delivered.
+fn relay_reliable(link: SharedLink, mut rx: AsyncUnboundedReceiver<Bytes>, tx: AsyncUnboundedSender<Bytes>) {
+    let (queue_tx, mut queue_rx) = async_unbounded_channel::<(Instant, Bytes)>();
+    tokio::spawn(async move {
+        let mut last_arrival = Instant::now();
+        loop {
+            let message = select! {
+                message = rx.recv() => message,
+                _ = queue_tx.closed() => None,
+            };
+            let Some(message) = message else {
+                break;
+            };
+            let arrival = link.lock().unwrap().schedule(message.len(), true).unwrap();
+            last_arrival = last_arrival.max(arrival);
+            if queue_tx.send((last_arrival, message)).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        loop {
+            let entry = select! {
+                entry = queue_rx.recv() => entry,
+                _ = tx.closed() => None,
+            };
+            let Some((arrival, message)) = entry else {
+                break;
+            };
+            sleep_until(arrival).await;
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Forwards messages as if they were sent over the link, they can get lost or reordered.
+fn relay_unreliable(link: SharedLink, mut rx: AsyncUnboundedReceiver<Bytes>, tx: AsyncUnboundedSender<Bytes>) {
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            let Some(arrival) = link.lock().unwrap().schedule(message.len(), false) else {
+                continue;
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                sleep_until(arrival).await;
+                let _ = tx.send(message);
+            });
+        }
+    });
+}
+
+/// Wraps an in-process byte pipe, delaying the bytes it receives by sending them over the `incoming` link.
+fn simulate_pipe(incoming: SharedLink, pipe: DuplexStream) -> DuplexStream {
+    let (inner, outer) = tokio::io::duplex(INPROCESS_SOCKET_BUFFER_SIZE);
+    let (mut pipe_read, mut pipe_write) = tokio::io::split(pipe);
+    let (mut outer_read, mut outer_write) = tokio::io::split(outer);
+    let (sent_tx, sent_rx) = async_unbounded_channel();
+    let (received_tx, mut received_rx) = async_unbounded_channel::<Bytes>();
+    relay_reliable(incoming, sent_rx, received_tx);
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; PIPE_PACKET_SIZE];
+        while let Ok(len @ 1..) = pipe_read.read(&mut buffer).await {
+            if sent_tx.send(Bytes::copy_from_slice(&buffer[..len])).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(packet) = received_rx.recv().await {
+            if outer_write.write_all(&packet).await.is_err() {
+                break;
+            }
+        }
+        let _ = outer_write.shutdown().await;
+    });
+    tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut outer_read, &mut pipe_write).await;
+        let _ = pipe_write.shutdown().await;
+    });
+    inner
+}
+
+/// Wraps an accepted in-process stream, sending its traffic over the given links.
+fn simulate_stream(stream: InProcessStream, incoming: SharedLink, outgoing: SharedLink) -> InProcessStream {
+    let (incoming_tx, incoming_rx) = async_unbounded_channel();
+    let (outgoing_tx, outgoing_rx) = async_unbounded_channel();
+    let (forward_tx, forward_rx) = async_unbounded_channel();
+    let source = Arc::clone(&stream.rx);
+    tokio::spawn(async move {
+        let mut source = source.lock().await;
+        loop {
+            let message = select! {
+                message = source.recv() => message,
+                _ = forward_tx.closed() => None,
+            };
+            let Some(message) = message else {
+                break;
+            };
+            if forward_tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    relay_reliable(incoming, forward_rx, incoming_tx);
+    relay_reliable(outgoing, outgoing_rx, stream.tx);
+    InProcessStream {
+        stream_type: stream.stream_type,
+        tx: outgoing_tx,
+        rx: Arc::new(AsyncMutex::new(incoming_rx)),
+        rejection: stream.rejection,
+        counters: stream.counters,
+        capture: stream.capture,
+    }
+}
+
+/// Wraps one side of an in-process connection, simulating the links to (`outgoing`) and from (`incoming`) it.
+fn simulate_side(side: InProcessDuplex, incoming: SharedLink, outgoing: SharedLink) -> InProcessDuplex {
+    let InProcessDuplex {
+        rpc_pipe,
+        mut incoming_streams,
+        outgoing_streams,
+        incoming_datagrams,
+        outgoing_datagrams,
+    } = side;
+
+    let (streams_tx, streams_rx) = async_unbounded_channel();
+    let (stream_incoming, stream_outgoing) = (Arc::clone(&incoming), Arc::clone(&outgoing));
+    tokio::spawn(async move {
+        while let Some(stream) = incoming_streams.recv().await {
+            let stream = simulate_stream(stream, Arc::clone(&stream_incoming), Arc::clone(&stream_outgoing));
+            if streams_tx.send(stream).is_err() {
+                break;
+            }
+        }
+    });
+
+    let (datagrams_tx, datagrams_rx) = async_unbounded_channel();
+    relay_unreliable(Arc::clone(&incoming), incoming_datagrams, datagrams_tx);
+
+    InProcessDuplex {
+        rpc_pipe: simulate_pipe(incoming, rpc_pipe),
+        incoming_streams: streams_rx,
+        outgoing_streams,
+        incoming_datagrams: datagrams_rx,
+        outgoing_datagrams,
+    }
+}
+
+/// Wraps both ends of an in-process connection, making all the traffic between them follow the given conditions.
+/// Has to be called within a tokio runtime, which runs the simulation.
+pub fn simulate_connection(
+    (first, second): (InProcessDuplex, InProcessDuplex),
+    conditions: &NetworkConditions,
+) -> (InProcessDuplex, InProcessDuplex) {
+    let to_first = SimulatedLink::new_shared(conditions, conditions.seed);
+    let to_second = SimulatedLink::new_shared(conditions, conditions.seed.wrapping_add(1));
+    (
+        simulate_side(first, Arc::clone(&to_first), Arc::clone(&to_second)),
+        simulate_side(second, to_second, to_first),
+    )
+}
+
+/// Wraps both ends of an in-process byte pipe, e.g. one used for a bare RPC connection in tests.
+/// Has to be called within a tokio runtime, which runs the simulation.
+pub fn simulate_pipes(
+    (first, second): (DuplexStream, DuplexStream),
+    conditions: &NetworkConditions,
+) -> (DuplexStream, DuplexStream) {
+    let to_first = SimulatedLink::new_shared(conditions, conditions.seed);
+    let to_second = SimulatedLink::new_shared(conditions, conditions.seed.wrapping_add(1));
+    (simulate_pipe(to_first, first), simulate_pipe(to_second, second))
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::schemas::{NetworkStreamHeader, NetworkStreamType};
+
+    use super::*;
+
+    fn run<F: std::future::Future<Output = ()>>(future: F) {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future);
+    }
+
+    const TEST_STREAM: NetworkStreamType = NetworkStreamType::new(
+        NetworkStreamHeader::Standard(rpc::stream_header::StandardTypes::ChunkData),
+        1,
+    );
+
+    #[test]
+    fn streams_keep_order_under_loss() {
+        run(async {
+            let conditions = NetworkConditions {
+                latency: Duration::from_millis(20),
+                jitter: Duration::from_millis(10),
+                packet_loss: 0.2,
+                seed: 7,
+                ..Default::default()
+            };
+            let (client, mut server) = InProcessDuplex::new_simulated_pair(&conditions);
+            let (local, remote) = InProcessStream::new_pair(TEST_STREAM);
+            client.outgoing_streams.send(remote).unwrap();
+            let accepted = server.incoming_streams.recv().await.unwrap();
+
+            let start = Instant::now();
+            for i in 0..50u8 {
+                local.tx.send(Bytes::from(vec![i])).unwrap();
+            }
+            drop(local.tx);
+            let mut rx = accepted.rx.lock().await;
+            for i in 0..50u8 {
+                assert_eq!(rx.recv().await.unwrap(), Bytes::from(vec![i]));
+            }
+            assert!(start.elapsed() >= conditions.latency);
+            assert!(rx.recv().await.is_none());
+
+            accepted.tx.send(Bytes::from_static(b"reply")).unwrap();
+            assert_eq!(
+                local.rx.lock().await.recv().await.unwrap(),
+                Bytes::from_static(b"reply")
+            );
+        });
+    }
+
+    #[test]
+    fn datagrams_get_lost_and_reordered() {
+        run(async {
+            let conditions = NetworkConditions {
+                latency: Duration::from_millis(5),
+                packet_loss: 0.25,
+                reordering: 0.25,
+                seed: 3,
+                ..Default::default()
+            };
+            let (client, server) = InProcessDuplex::new_simulated_pair(&conditions);
+            let mut incoming = server.incoming_datagrams;
+            for i in 0..200u8 {
+                client.outgoing_datagrams.send(Bytes::from(vec![i])).unwrap();
+            }
+            drop(client.outgoing_datagrams);
+
+            let mut received = Vec::new();
+            while let Some(datagram) = incoming.recv().await {
+                received.push(datagram[0]);
+            }
+            assert!(
+                received.len() > 100 && received.len() < 190,
+                "{} datagrams arrived",
+                received.len()
+            );
+            assert!(
+                received.windows(2).any(|pair| pair[0] > pair[1]),
+                "No datagrams were reordered"
+            );
+        });
+    }
+
+    #[test]
+    fn rpc_pipe_bandwidth_limit() {
+        run(async {
+            let conditions = NetworkConditions {
+                bandwidth: Some(100 * 1024),
+                ..Default::default()
+            };
+            let (mut first, mut second) = simulate_pipes(tokio::io::duplex(64 * 1024), &conditions);
+            let data = vec![0xA5u8; 20 * 1024];
+            let start = Instant::now();
+            first.write_all(&data).await.unwrap();
+            first.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            second.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, data);
+            // 20 KiB at 100 KiB/s takes at least 200 ms.
+            assert!(
+                start.elapsed() >= Duration::from_millis(190),
+                "Took {:?}",
+                start.elapsed()
+            );
+        });
+    }
+}
@@ -5,13 +5,16 @@ use std::pin::Pin;
 use std::thread::JoinHandle;
 
 use futures::FutureExt;
-use gs_schemas::schemas::NetworkStreamHeader;
+use gs_schemas::schemas::{NetworkDatagramHeader, NetworkStreamHeader, NetworkStreamType};
 use gs_schemas::GameSide;
 use hashbrown::HashMap;
 use thiserror::Error;
-use tokio::task::LocalSet;
+use tokio::task::{spawn_local, LocalSet};
+use tokio_util::bytes::Bytes;
 
-use super::transport::TransportStream;
+use super::protocol::NegotiatedProtocol;
+use super::transport::{StreamRejection, TransportStream};
+use super::PeerAddress;
 use crate::prelude::*;
 
 /// A wrapper for a tokio runtime, allowing for easy scheduling of tasks to run within the context of the network thread.
@@ -20,13 +23,19 @@ pub struct NetworkThread<State> {
     side: GameSide,
     tokio_thread: JoinHandle<()>,
     channel: AsyncUnboundedSender<NetworkThreadCommand<State>>,
-    new_stream_handler: Mutex<HashMap<NetworkStreamHeader, Box<NetworkThreadStreamHandler<State>>>>,
+    new_stream_handler: Mutex<HashMap<NetworkStreamHeader, RegisteredStreamHandler<State>>>,
+    datagram_handler: Mutex<HashMap<NetworkDatagramHeader, Arc<NetworkThreadDatagramHandler<State>>>>,
 }
 
 /// Trait that needs to be implemented for the state object of the network thread.
 pub trait NetworkThreadState: 'static {
     /// Performs a clean shutdown of the network subsystem.
     fn shutdown(this: Rc<RefCell<Self>>) -> impl Future<Output = ()>;
+
+    /// The protocol negotiated with the server on the client side, which decides how the streams it opens are encoded.
+    fn server_protocol(&self) -> Option<NegotiatedProtocol> {
+        None
+    }
 }
 
 /// A boxed future that can be queued on the network thread's tokio LocalSet.
@@ -36,7 +45,13 @@ pub type NetworkThreadAsyncFunction<State> =
     dyn for<'state> FnOnce(&'state Rc<RefCell<State>>) -> NetworkThreadAsyncFuture<'state> + Send + 'static;
 /// Handler for newly opened async streams.
 pub type NetworkThreadStreamHandler<State> =
-    dyn FnMut(Rc<RefCell<State>>, TransportStream) -> NetworkThreadAsyncFuture<'static> + Send + 'static;
+    dyn Fn(Rc<RefCell<State>>, TransportStream) -> NetworkThreadAsyncFuture<'static> + Send + Sync + 'static;
+struct RegisteredStreamHandler<State> {
+    version: u32,
+    factory: Arc<NetworkThreadStreamHandler<State>>,
+}
+/// Handler for received datagrams, called with the address of the sender and the datagram payload.
+pub type NetworkThreadDatagramHandler<State> = dyn Fn(&Rc<RefCell<State>>, PeerAddress, Bytes) + Send + Sync + 'static;
 
 enum NetworkThreadCommand<State> {
     Shutdown(AsyncOneshotSender<()>),
@@ -71,6 +86,7 @@ impl<State: NetworkThreadState> NetworkThread<State> {
             tokio_thread,
             channel: net_tx,
             new_stream_handler: Mutex::new(HashMap::with_capacity(32)),
+            datagram_handler: Mutex::new(HashMap::with_capacity(32)),
         }
     }
 
@@ -123,23 +139,88 @@ impl<State: NetworkThreadState> NetworkThread<State> {
             .or(Err(NetworkThreadCommandError::NetworkThreadTerminated(self.side)))
     }
 
-    /// Registers a new stream type handler for the given header, overwrites any previous handler with the same header.
-    pub fn insert_stream_handler(&self, header: NetworkStreamHeader, function: Box<NetworkThreadStreamHandler<State>>) {
+    /// Registers a new stream type handler for the given stream type and version.
+    /// Overwrites any previous handler with the same header, regardless of its version.
+    pub fn insert_stream_handler(
+        &self,
+        stream_type: NetworkStreamType,
+        function: Box<NetworkThreadStreamHandler<State>>,
+    ) {
         let mut map = self.new_stream_handler.lock().unwrap();
-        map.insert(header, function);
+        let handler = RegisteredStreamHandler {
+            version: stream_type.version,
+            factory: Arc::from(function),
+        };
+        map.insert(stream_type.header, handler);
+    }
+
+    /// Returns the stream types with registered handlers.
+    pub fn registered_stream_types(&self) -> Vec<NetworkStreamType> {
+        let map = self.new_stream_handler.lock().unwrap();
+        map.iter()
+            .map(|(header, handler)| NetworkStreamType::new(header.clone(), handler.version))
+            .collect()
     }
 
-    /// Creates a stream handler future by looking up the matching factory function, or returns Err if none were registered for this header.
+    /// Creates a stream handler future by looking up the matching factory function.
+    /// Returns Err with the reason for rejecting the stream if no handler was registered for its header and version.
     pub fn create_stream_handler(
         &self,
         state: Rc<RefCell<State>>,
         stream: TransportStream,
-    ) -> Result<NetworkThreadAsyncFuture<'static>, TransportStream> {
-        let mut factory = self.new_stream_handler.lock().unwrap();
-        let factory = factory.get_mut(stream.header());
-        match factory {
-            Some(factory) => Ok(factory(state, stream)),
-            None => Err(stream),
+    ) -> Result<NetworkThreadAsyncFuture<'static>, (TransportStream, StreamRejection)> {
+        // Don't hold the lock while the factory runs, so that it can register handlers itself.
+        let factory = match self.new_stream_handler.lock().unwrap().get(stream.header()) {
+            Some(handler) if handler.version == stream.stream_type().version => Arc::clone(&handler.factory),
+            Some(_) => return Err((stream, StreamRejection::UnsupportedVersion)),
+            None => return Err((stream, StreamRejection::UnknownType)),
+        };
+        Ok(factory(state, stream))
+    }
+
+    /// Spawns the handler for a stream opened by `peer` on the current LocalSet, or rejects the stream if there is none.
+    pub fn spawn_stream_handler(&self, state: Rc<RefCell<State>>, peer: PeerAddress, stream: TransportStream) {
+        match self.create_stream_handler(state, stream) {
+            Ok(handler) => {
+                spawn_local(handler);
+            }
+            Err((stream, reason)) => {
+                tracing::warn!(
+                    "Rejecting a stream of type {:?} from {peer}: {reason}",
+                    stream.stream_type()
+                );
+                spawn_local(stream.reject(reason));
+            }
+        }
+    }
+
+    /// Registers a new datagram type handler for the given header, overwrites any previous handler with the same header.
+    /// Register the handlers before connecting, datagrams arriving without a handler are dropped.
+    pub fn insert_datagram_handler(
+        &self,
+        header: NetworkDatagramHeader,
+        function: Box<NetworkThreadDatagramHandler<State>>,
+    ) {
+        let mut map = self.datagram_handler.lock().unwrap();
+        map.insert(header, Arc::from(function));
+    }
+
+    /// Passes a received datagram to the handler registered for its header, or returns Err if none were registered.
+    pub fn handle_datagram(
+        &self,
+        state: &Rc<RefCell<State>>,
+        peer: PeerAddress,
+        header: NetworkDatagramHeader,
+        payload: Bytes,
+    ) -> Result<(), NetworkDatagramHeader> {
+        // Don't hold the lock while the handler runs, so that it can register handlers itself.
+        let handler = self.datagram_handler.lock().unwrap().get(&header).map(Arc::clone);
+        match handler {
+            Some(handler) => {
+                handler(state, peer, payload);
+                Ok(())
+            }
+            None => Err(header),
         }
     }
 
@@ -177,3 +258,90 @@ impl<State: NetworkThreadState> NetworkThread<State> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::registry::RegistryName;
+
+    use super::*;
+    use crate::network::server::NetworkThreadServerState;
+    use crate::network::transport::InProcessStream;
+
+    #[test]
+    fn datagram_handlers_can_replace_themselves() {
+        let header = NetworkDatagramHeader::Standard(rpc::datagram_header::StandardTypes::PlayerState);
+        let net_thread = Arc::new(NetworkThread::new(GameSide::Server, NetworkThreadServerState::new));
+        let (received_tx, received_rx) = std_unbounded_channel();
+        let weak_thread = Arc::downgrade(&net_thread);
+        let replaced_header = header.clone();
+        net_thread.insert_datagram_handler(
+            header.clone(),
+            Box::new(move |_state, _peer, payload| {
+                received_tx.send(payload).unwrap();
+                if let Some(thread) = weak_thread.upgrade() {
+                    thread.insert_datagram_handler(replaced_header.clone(), Box::new(|_state, _peer, _payload| {}));
+                }
+            }),
+        );
+
+        let thread = Arc::clone(&net_thread);
+        let handled = net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    Ok([&b"first"[..], &b"second"[..]].map(|payload| {
+                        thread
+                            .handle_datagram(
+                                state,
+                                PeerAddress::Local(0),
+                                header.clone(),
+                                Bytes::from_static(payload),
+                            )
+                            .is_ok()
+                    }))
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        assert_eq!(handled, [true, true]);
+        assert_eq!(
+            received_rx.try_iter().collect::<Vec<_>>(),
+            vec![Bytes::from_static(b"first")]
+        );
+        net_thread.sync_shutdown();
+    }
+
+    #[test]
+    fn stream_handlers_can_register_handlers() {
+        const FIRST: NetworkStreamType =
+            NetworkStreamType::new(NetworkStreamHeader::Custom(RegistryName::new_const("test", "first")), 1);
+        const SECOND: NetworkStreamType = NetworkStreamType::new(
+            NetworkStreamHeader::Custom(RegistryName::new_const("test", "second")),
+            1,
+        );
+        let net_thread = Arc::new(NetworkThread::new(GameSide::Server, NetworkThreadServerState::new));
+        let weak_thread = Arc::downgrade(&net_thread);
+        net_thread.insert_stream_handler(
+            FIRST,
+            Box::new(move |_state, _stream| {
+                if let Some(thread) = weak_thread.upgrade() {
+                    thread.insert_stream_handler(SECOND, Box::new(|_state, _stream| Box::pin(async {})));
+                }
+                Box::pin(async {})
+            }),
+        );
+
+        let thread = Arc::clone(&net_thread);
+        let created = net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    let (_local, remote) = InProcessStream::new_pair(FIRST);
+                    Ok(thread.create_stream_handler(Rc::clone(state), remote.into()).is_ok())
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        assert!(created);
+        assert!(net_thread.registered_stream_types().contains(&SECOND));
+        net_thread.sync_shutdown();
+    }
+}
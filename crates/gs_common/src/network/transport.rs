@@ -1,23 +1,33 @@
 //! Network transport implementations - local message passing for singleplayer&unit tests and QUIC for multiplayer
 
 use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
+use std::task::Poll;
+use std::time::Duration;
 
 use capnp::message::ReaderOptions;
 use capnp::Word;
 use capnp_rpc::rpc_twoparty_capnp::Side;
 use capnp_rpc::twoparty::VatNetwork;
 use capnp_rpc::RpcSystem;
+use futures::future::BoxFuture;
+use futures::FutureExt;
 use gs_schemas::dependencies::itertools::Itertools;
-use gs_schemas::schemas::{network_capnp as rpc, read_leb128, write_leb128, NetworkStreamHeader};
+use gs_schemas::schemas::{
+    network_capnp as rpc, read_leb128, write_leb128, NetworkDatagramHeader, NetworkStreamHeader, NetworkStreamType,
+};
 use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
-use quinn::{Connection, RecvStream, SendStream};
+use quinn::{Connection, RecvStream, SendDatagramError, SendStream, TransportConfig, VarInt, WriteError};
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
 use rustls::version::TLS13;
 use rustls::{DigitallySignedStruct, Error, SignatureScheme, SupportedProtocolVersion};
 use tokio_util::bytes::Bytes;
 
+use crate::network::capture::{CaptureDirection, StreamCapture};
+use crate::network::metrics::{CountingIo, PeerNetworkMetrics, TrafficCounters};
 use crate::network::server::{NetworkThreadServerState, Server2ClientEndpoint};
+use crate::network::simulator::{simulate_connection, NetworkConditions};
 use crate::network::PeerAddress;
 use crate::prelude::*;
 use crate::GameServer;
@@ -86,7 +96,7 @@ pub static RPC_CLIENT_READER_OPTIONS: ReaderOptions = ReaderOptions {
 };
 
 /// Size in bytes of the in-process client-server "socket" buffer.
-const INPROCESS_SOCKET_BUFFER_SIZE: usize = 1024 * 1024;
+pub(crate) const INPROCESS_SOCKET_BUFFER_SIZE: usize = 1024 * 1024;
 
 /// A byte array over-aligned to Cap'n proto requirements.
 #[derive(Clone, Eq, PartialEq, Debug)]
@@ -136,56 +146,118 @@ impl From<AlignedBytesMut> for Bytes {
     }
 }
 
+/// Reasons for rejecting a newly opened stream, sent to the opening side as the stream reset error code.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, thiserror::Error)]
+pub enum StreamRejection {
+    /// No handler is registered for the stream type, or the header could not be read.
+    #[error("unknown stream type")]
+    UnknownType,
+    /// The handler registered for the stream type expects a different protocol version.
+    #[error("unsupported stream version")]
+    UnsupportedVersion,
+}
+
+impl StreamRejection {
+    /// The QUIC application error code used for resetting the stream.
+    pub fn code(self) -> VarInt {
+        match self {
+            Self::UnknownType => VarInt::from_u32(1),
+            Self::UnsupportedVersion => VarInt::from_u32(2),
+        }
+    }
+
+    /// Looks up the rejection reason from a QUIC application error code.
+    pub fn from_code(code: VarInt) -> Option<Self> {
+        match code.into_inner() {
+            1 => Some(Self::UnknownType),
+            2 => Some(Self::UnsupportedVersion),
+            _ => None,
+        }
+    }
+}
+
 /// A QUIC network stream for communication asynchronous to the main RPC channel.
 #[derive(Clone)]
 pub struct QuicStream {
-    /// The stream header, determining its type.
-    pub header: NetworkStreamHeader,
+    /// The stream type and version, determining its handler.
+    pub stream_type: NetworkStreamType,
     /// The outgoing QUIC stream handle.
     pub tx: Arc<AsyncMutex<SendStream>>,
     /// The incoming QUIC stream handle.
     pub rx: Arc<AsyncMutex<RecvStream>>,
+    /// Traffic statistics of this stream.
+    pub counters: Arc<TrafficCounters>,
+    /// Records the packets of this stream, if the connection is captured.
+    pub capture: Option<StreamCapture>,
 }
 
 impl QuicStream {
     /// Opens a new stream on an existing QUIC connection.
-    pub async fn open(connection: Connection, header: NetworkStreamHeader) -> Result<Self> {
+    pub async fn open(connection: Connection, stream_type: NetworkStreamType) -> Result<Self> {
         let (mut tx, rx) = connection.open_bi().await?;
-        let header_bytes = header.write_to_bytes();
+        let header_bytes = stream_type.write_to_bytes();
         let len_bytes = write_leb128(header_bytes.len() as u64);
         tx.write_all(&len_bytes).await?;
         tx.write_all(&header_bytes).await?;
         Ok(Self {
-            header,
+            stream_type,
             tx: Arc::new(AsyncMutex::new(tx)),
             rx: Arc::new(AsyncMutex::new(rx)),
+            counters: Default::default(),
+            capture: None,
         })
     }
 
     /// Listens for a single new stream on an existing QUIC connection.
+    /// Streams with unreadable headers (e.g. unknown standard types) are rejected and skipped.
     pub async fn accept(connection: &Connection) -> Result<Self> {
-        let (tx, mut rx) = connection.accept_bi().await?;
-        let len_bytes: usize = read_leb128(&mut rx).await?.try_into()?;
+        loop {
+            let (mut tx, mut rx) = connection.accept_bi().await?;
+            match Self::read_stream_type(&mut rx).await {
+                Ok(stream_type) => {
+                    return Ok(Self {
+                        stream_type,
+                        tx: Arc::new(AsyncMutex::new(tx)),
+                        rx: Arc::new(AsyncMutex::new(rx)),
+                        counters: Default::default(),
+                        capture: None,
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!("Rejecting a stream with an unreadable header: {e}");
+                    let _ = tx.reset(StreamRejection::UnknownType.code());
+                    let _ = rx.stop(StreamRejection::UnknownType.code());
+                }
+            }
+        }
+    }
+
+    async fn read_stream_type(rx: &mut RecvStream) -> Result<NetworkStreamType> {
+        let len_bytes: usize = read_leb128(&mut *rx).await?.try_into()?;
         let mut header_bytes: Box<[u8]> = vec![0u8; len_bytes].into_boxed_slice();
         rx.read_exact(&mut header_bytes).await?;
-        let header = NetworkStreamHeader::read_from_bytes(&header_bytes, RPC_SERVER_READER_OPTIONS)?;
-        Ok(Self {
-            header,
-            tx: Arc::new(AsyncMutex::new(tx)),
-            rx: Arc::new(AsyncMutex::new(rx)),
-        })
+        Ok(NetworkStreamType::read_from_bytes(
+            &header_bytes,
+            RPC_SERVER_READER_OPTIONS,
+        )?)
     }
 }
 
 /// An in-process stream, modelling QUIC streams when using in-process communication.
 #[derive(Clone)]
 pub struct InProcessStream {
-    /// The stream header, determining its type.
-    pub header: NetworkStreamHeader,
+    /// The stream type and version, determining its handler.
+    pub stream_type: NetworkStreamType,
     /// The sender "socket" for this stream side.
     pub tx: AsyncUnboundedSender<Bytes>,
     /// The receiver "socket" for this stream side.
     pub rx: Arc<AsyncMutex<AsyncUnboundedReceiver<Bytes>>>,
+    /// The reason the receiving side rejected this stream, shared by both sides.
+    pub rejection: Arc<OnceLock<StreamRejection>>,
+    /// Traffic statistics of this stream side.
+    pub counters: Arc<TrafficCounters>,
+    /// Records the packets of this stream side, if the connection is captured.
+    pub capture: Option<StreamCapture>,
 }
 
 /// An abstraction over the two stream kinds (in-process and network).
@@ -199,20 +271,26 @@ pub enum TransportStream {
 
 impl InProcessStream {
     /// Constructs a new, pre-connected bidirectional stream for in-process communication.
-    pub fn new_pair(header: NetworkStreamHeader) -> (Self, Self) {
+    pub fn new_pair(stream_type: NetworkStreamType) -> (Self, Self) {
         let (tx12, rx12) = async_unbounded_channel();
         let (tx21, rx21) = async_unbounded_channel();
-        let header2 = header.clone();
+        let rejection = Arc::new(OnceLock::new());
         (
             Self {
-                header,
+                stream_type: stream_type.clone(),
                 tx: tx12,
                 rx: Arc::new(AsyncMutex::new(rx21)),
+                rejection: Arc::clone(&rejection),
+                counters: Default::default(),
+                capture: None,
             },
             Self {
-                header: header2,
+                stream_type,
                 tx: tx21,
                 rx: Arc::new(AsyncMutex::new(rx12)),
+                rejection,
+                counters: Default::default(),
+                capture: None,
             },
         )
     }
@@ -231,42 +309,272 @@ impl From<QuicStream> for TransportStream {
 }
 
 impl TransportStream {
+    /// Returns the stream type and version set at stream opening time.
+    pub fn stream_type(&self) -> &NetworkStreamType {
+        match self {
+            TransportStream::Network(quic) => &quic.stream_type,
+            TransportStream::Process(ipc) => &ipc.stream_type,
+        }
+    }
+
     /// Returns the header information set at stream opening time.
     pub fn header(&self) -> &NetworkStreamHeader {
+        &self.stream_type().header
+    }
+
+    /// Returns the traffic statistics of this stream.
+    pub fn counters(&self) -> &Arc<TrafficCounters> {
         match self {
-            TransportStream::Network(quic) => &quic.header,
-            TransportStream::Process(ipc) => &ipc.header,
+            TransportStream::Network(quic) => &quic.counters,
+            TransportStream::Process(ipc) => &ipc.counters,
         }
     }
 
+    /// Returns the recorder of this stream's packets, if the connection is captured.
+    pub fn capture(&self) -> Option<&StreamCapture> {
+        match self {
+            TransportStream::Network(quic) => quic.capture.as_ref(),
+            TransportStream::Process(ipc) => ipc.capture.as_ref(),
+        }
+    }
+
+    /// Records the traffic of this stream in the connection's per-stream-type statistics, and its capture if any.
+    pub fn with_metrics(mut self, metrics: &PeerNetworkMetrics) -> Self {
+        let counters = metrics.stream_counters(self.header());
+        let capture = metrics.capture().map(|capture| capture.stream(self.stream_type()));
+        match &mut self {
+            TransportStream::Network(quic) => {
+                quic.counters = counters;
+                quic.capture = capture;
+            }
+            TransportStream::Process(ipc) => {
+                ipc.counters = counters;
+                ipc.capture = capture;
+            }
+        }
+        self
+    }
+
     /// Wraps the given message in a length-prefixed frame if needed and sends it over the stream.
+    /// Fails with a [`StreamRejection`] if the other side rejected the stream.
     pub async fn send(&self, message: Bytes) -> Result<()> {
+        let size = message.len();
+        if let Some(capture) = self.capture() {
+            capture.record(CaptureDirection::Sent, &message);
+        }
         match self {
             Self::Process(ipc) => {
-                ipc.tx.send(message)?;
-                Ok(())
+                if ipc.tx.send(message).is_err() {
+                    return match ipc.rejection.get() {
+                        Some(&rejection) => Err(anyhow::Error::from(rejection).context("Stream rejected by the peer")),
+                        None => Err(anyhow!("In-process stream closed")),
+                    };
+                }
             }
             Self::Network(quic) => {
-                let len_bytes = write_leb128(message.len() as u64);
-                let mut tx = quic.tx.lock().await;
-                tx.write_all(&len_bytes).await?;
-                tx.write_all(&message).await?;
-                Ok(())
+                let write = async {
+                    let len_bytes = write_leb128(message.len() as u64);
+                    let mut tx = quic.tx.lock().await;
+                    tx.write_all(&len_bytes).await?;
+                    tx.write_all(&message).await
+                };
+                let mut write = std::pin::pin!(write);
+                // A send that can't complete right away is waiting on flow control (or a concurrent send).
+                let result = match futures::poll!(write.as_mut()) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => {
+                        quic.counters.record_stall();
+                        write.await
+                    }
+                };
+                result.map_err(Self::write_error)?;
+            }
+        }
+        self.counters().record_out(size);
+        Ok(())
+    }
+
+    fn write_error(error: WriteError) -> anyhow::Error {
+        if let WriteError::Stopped(code) = error {
+            if let Some(rejection) = StreamRejection::from_code(code) {
+                return anyhow::Error::from(rejection).context("Stream rejected by the peer");
+            }
+        }
+        error.into()
+    }
+
+    /// Rejects a stream opened by the other side, resetting it with the given reason.
+    pub async fn reject(self, reason: StreamRejection) {
+        match self {
+            Self::Process(ipc) => {
+                let _ = ipc.rejection.set(reason);
+                ipc.rx.lock().await.close();
+            }
+            Self::Network(quic) => {
+                let _ = quic.tx.lock().await.reset(reason.code());
+                let _ = quic.rx.lock().await.stop(reason.code());
             }
         }
     }
 
     /// Reads an incoming message from this stream. Returns None if no further messages can be read.
     pub async fn recv(&self) -> Option<Bytes> {
-        match self {
-            Self::Process(ipc) => ipc.rx.lock().await.recv().await,
+        let message = match self {
+            Self::Process(ipc) => ipc.rx.lock().await.recv().await?,
             Self::Network(quic) => {
                 let mut rx = quic.rx.lock().await;
                 let len = read_leb128(&mut *rx).await.ok()? as usize;
                 let mut buf = AlignedBytesMut::new(len);
                 rx.read_exact(&mut buf).await.ok()?;
                 assert_eq!(len, buf.len());
-                Some(buf.into())
+                buf.into()
+            }
+        };
+        self.counters().record_in(message.len());
+        if let Some(capture) = self.capture() {
+            capture.record(CaptureDirection::Received, &message);
+        }
+        Some(message)
+    }
+}
+
+/// Opens new streams to the other side of a connection.
+#[derive(Clone)]
+pub enum StreamOpener {
+    /// Remote QUIC connection.
+    Network(Connection),
+    /// Local in-process connection.
+    Process(AsyncUnboundedSender<InProcessStream>),
+}
+
+impl StreamOpener {
+    /// Opens a fresh stream for sending data asynchronously to the main RPC channel.
+    /// Returns a future that actually performs the work to avoid holding the state RefCell borrowed across await points.
+    pub fn open(&self, stream_type: NetworkStreamType) -> BoxFuture<'static, Result<TransportStream>> {
+        match self {
+            Self::Network(connection) => QuicStream::open(connection.clone(), stream_type)
+                .map(|r| r.map(TransportStream::from))
+                .boxed(),
+            Self::Process(stream_sender) => {
+                let stream_sender = stream_sender.clone();
+                (async move {
+                    let (local, remote) = InProcessStream::new_pair(stream_type);
+                    stream_sender.send(remote)?;
+                    Ok(local.into())
+                })
+                .boxed()
+            }
+        }
+    }
+
+    /// Waits until the connection is closed and no more streams can be opened.
+    pub async fn closed(&self) {
+        match self {
+            Self::Network(connection) => {
+                connection.closed().await;
+            }
+            Self::Process(stream_sender) => stream_sender.closed().await,
+        }
+    }
+}
+
+/// Maximum size of in-process datagrams, matching the minimum QUIC datagram size to catch oversized datagrams in singleplayer.
+pub const INPROCESS_MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Errors returned when a datagram cannot be sent.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+pub enum DatagramSendError {
+    /// The datagram would not fit in a single network packet.
+    #[error("Datagram of {size} bytes is larger than the limit of {limit} bytes")]
+    TooLarge {
+        /// Size of the datagram, including the header.
+        size: usize,
+        /// Maximum size of datagrams on the connection.
+        limit: usize,
+    },
+    /// The other side of the connection does not accept datagrams.
+    #[error("Datagrams are not supported by the peer")]
+    Unsupported,
+    /// The connection was closed.
+    #[error("Connection closed")]
+    ConnectionLost,
+}
+
+/// Prepends the header to the datagram payload.
+fn encode_datagram(header: &NetworkDatagramHeader, payload: &[u8]) -> Bytes {
+    let header_bytes = header.write_to_bytes();
+    let mut datagram = Vec::with_capacity(header_bytes.len() + payload.len());
+    datagram.extend_from_slice(&header_bytes);
+    datagram.extend_from_slice(payload);
+    Bytes::from(datagram)
+}
+
+/// Splits a datagram into its header and payload.
+fn decode_datagram(datagram: Bytes) -> Result<(NetworkDatagramHeader, Bytes)> {
+    let mut rest: &[u8] = &datagram;
+    let header = NetworkDatagramHeader::read_from_bytes(&mut rest, RPC_SERVER_READER_OPTIONS)?;
+    let payload = datagram.slice(datagram.len() - rest.len()..);
+    Ok((header, payload))
+}
+
+/// The sending side of an unreliable datagram channel, for frequently changing data where losing an update is fine.
+#[derive(Clone)]
+pub enum DatagramSender {
+    /// Remote QUIC datagrams.
+    Network(Connection),
+    /// Local in-process datagrams, these are never lost.
+    Process(AsyncUnboundedSender<Bytes>),
+}
+
+impl DatagramSender {
+    /// The maximum size of a datagram (including its header) that can currently be sent, None if unsupported.
+    /// Can change over the lifetime of a network connection.
+    pub fn max_size(&self) -> Option<usize> {
+        match self {
+            Self::Network(connection) => connection.max_datagram_size(),
+            Self::Process(_) => Some(INPROCESS_MAX_DATAGRAM_SIZE),
+        }
+    }
+
+    /// Queues a datagram for sending, it might never arrive or arrive out of order.
+    pub fn send(&self, header: &NetworkDatagramHeader, payload: &[u8]) -> Result<(), DatagramSendError> {
+        let datagram = encode_datagram(header, payload);
+        let size = datagram.len();
+        let limit = self.max_size().ok_or(DatagramSendError::Unsupported)?;
+        if size > limit {
+            return Err(DatagramSendError::TooLarge { size, limit });
+        }
+        match self {
+            Self::Network(connection) => connection.send_datagram(datagram).map_err(|e| match e {
+                SendDatagramError::TooLarge => DatagramSendError::TooLarge { size, limit },
+                SendDatagramError::UnsupportedByPeer | SendDatagramError::Disabled => DatagramSendError::Unsupported,
+                SendDatagramError::ConnectionLost(_) => DatagramSendError::ConnectionLost,
+            }),
+            Self::Process(tx) => tx.send(datagram).map_err(|_| DatagramSendError::ConnectionLost),
+        }
+    }
+}
+
+/// The receiving side of an unreliable datagram channel.
+pub enum DatagramReceiver {
+    /// Remote QUIC datagrams.
+    Network(Connection),
+    /// Local in-process datagrams.
+    Process(AsyncUnboundedReceiver<Bytes>),
+}
+
+impl DatagramReceiver {
+    /// Waits for the next well-formed datagram, and returns its header and payload.
+    /// Returns None if the connection was closed.
+    pub async fn recv(&mut self) -> Option<(NetworkDatagramHeader, Bytes)> {
+        loop {
+            let datagram = match self {
+                Self::Network(connection) => connection.read_datagram().await.ok()?,
+                Self::Process(rx) => rx.recv().await?,
+            };
+            match decode_datagram(datagram) {
+                Ok(decoded) => return Some(decoded),
+                Err(e) => tracing::warn!("Dropping a datagram with an invalid header: {e}"),
             }
         }
     }
@@ -280,6 +588,10 @@ pub struct InProcessDuplex {
     pub incoming_streams: AsyncUnboundedReceiver<InProcessStream>,
     /// Stream for sending new in-process streams to the other side.
     pub outgoing_streams: AsyncUnboundedSender<InProcessStream>,
+    /// Datagrams sent by the other side.
+    pub incoming_datagrams: AsyncUnboundedReceiver<Bytes>,
+    /// Datagrams for the other side.
+    pub outgoing_datagrams: AsyncUnboundedSender<Bytes>,
 }
 
 impl InProcessDuplex {
@@ -288,19 +600,31 @@ impl InProcessDuplex {
         let (duplex1, duplex2) = tokio::io::duplex(INPROCESS_SOCKET_BUFFER_SIZE);
         let (streams12_tx, streams12_rx) = async_unbounded_channel();
         let (streams21_tx, streams21_rx) = async_unbounded_channel();
+        let (datagrams12_tx, datagrams12_rx) = async_unbounded_channel();
+        let (datagrams21_tx, datagrams21_rx) = async_unbounded_channel();
         (
             Self {
                 rpc_pipe: duplex1,
                 incoming_streams: streams21_rx,
                 outgoing_streams: streams12_tx,
+                incoming_datagrams: datagrams21_rx,
+                outgoing_datagrams: datagrams12_tx,
             },
             Self {
                 rpc_pipe: duplex2,
                 incoming_streams: streams12_rx,
                 outgoing_streams: streams21_tx,
+                incoming_datagrams: datagrams12_rx,
+                outgoing_datagrams: datagrams21_tx,
             },
         )
     }
+
+    /// Makes a new pair of connected in-process "sockets", with all the traffic between them subject to the given network conditions.
+    /// Must be called within a tokio runtime, which runs the simulation.
+    pub fn new_simulated_pair(conditions: &NetworkConditions) -> (Self, Self) {
+        simulate_connection(Self::new_pair(), conditions)
+    }
 }
 
 /// Create a Future that will handle in-memory messages coming into a [`Server2ClientEndpoint`] and any child RPC objects on the given `server`&`id`.
@@ -309,10 +633,13 @@ pub fn create_local_rpc_server(
     server: Arc<GameServer>,
     pipe: tokio::io::DuplexStream,
     id: PeerAddress,
+    metrics: Arc<PeerNetworkMetrics>,
 ) -> RpcSystem<Side> {
-    let (read, write) = pipe.compat().split();
+    let (read, write) = CountingIo::new(pipe.compat(), Arc::clone(metrics.rpc_counters()))
+        .with_capture(metrics.capture())
+        .split();
     let network = VatNetwork::new(read, write, Side::Server, RPC_LOCAL_READER_OPTIONS);
-    let bootstrap_object = Server2ClientEndpoint::new(net_state, server, id);
+    let bootstrap_object = Server2ClientEndpoint::new(net_state, server, id, metrics);
     let bootstrap_client: rpc::game_server::Client = capnp_rpc::new_client(bootstrap_object);
     RpcSystem::new(Box::new(network), Some(bootstrap_client.clone().client))
 }
@@ -324,16 +651,35 @@ pub fn create_quic_rpc_server(
     tx: SendStream,
     rx: RecvStream,
     id: PeerAddress,
+    metrics: Arc<PeerNetworkMetrics>,
 ) -> RpcSystem<Side> {
+    let rx = CountingIo::new(rx, Arc::clone(metrics.rpc_counters())).with_capture(metrics.capture());
+    let tx = CountingIo::new(tx, Arc::clone(metrics.rpc_counters())).with_capture(metrics.capture());
     let network = VatNetwork::new(rx, tx, Side::Server, RPC_SERVER_READER_OPTIONS);
-    let bootstrap_object = Server2ClientEndpoint::new(net_state, server, id);
+    let bootstrap_object = Server2ClientEndpoint::new(net_state, server, id, metrics);
     let bootstrap_client: rpc::game_server::Client = capnp_rpc::new_client(bootstrap_object);
     RpcSystem::new(Box::new(network), Some(bootstrap_client.clone().client))
 }
 
+/// Identifies the bootstrap `GameServer` RPC interface, only changed if it breaks `negotiateProtocol`.
+/// The rest of the protocol is versioned by [`crate::network::protocol::PROTOCOL_VERSION`] instead,
+/// so that incompatible clients get a clear error instead of a failed TLS handshake.
 static ALPN_GEOSIA: &[&[u8]] = &[b"game-geosia/1"];
 static TLS_PROTO_VERSIONS: &[&SupportedProtocolVersion] = &[&TLS13];
 
+/// How often an idle QUIC connection sends keep-alive packets.
+pub const QUIC_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a QUIC connection can go without receiving any packets before it's considered lost.
+pub const QUIC_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection-level settings shared by the client and the server, detecting lost connections.
+fn quinn_transport_config() -> Arc<TransportConfig> {
+    let mut config = TransportConfig::default();
+    config.keep_alive_interval(Some(QUIC_KEEP_ALIVE_INTERVAL));
+    config.max_idle_timeout(Some(QUIC_IDLE_TIMEOUT.try_into().unwrap()));
+    Arc::new(config)
+}
+
 /// Makes a simple QUINN endpoint client config object.
 pub fn quinn_client_config() -> quinn::ClientConfig {
     let mut crypto = rustls::ClientConfig::builder_with_protocol_versions(TLS_PROTO_VERSIONS)
@@ -341,7 +687,9 @@ pub fn quinn_client_config() -> quinn::ClientConfig {
         .with_custom_certificate_verifier(NoopServerTlsVerification::new())
         .with_no_client_auth();
     crypto.alpn_protocols = ALPN_GEOSIA.iter().map(|a| a.to_vec()).collect_vec();
-    quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).unwrap()))
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).unwrap()));
+    config.transport_config(quinn_transport_config());
+    config
 }
 
 /// Makes a simple QUINN endpoint server config object.
@@ -355,15 +703,25 @@ pub fn quinn_server_config() -> quinn::ServerConfig {
         .with_single_cert(vec![cert], key)
         .unwrap();
     crypto.alpn_protocols = ALPN_GEOSIA.iter().map(|a| a.to_vec()).collect_vec();
-    quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto).unwrap()))
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto).unwrap()));
+    config.transport_config(quinn_transport_config());
+    config
 }
 
 /// Unit test utilities
 #[cfg(test)]
 pub mod test {
 
+    use std::time::Duration;
+
     use capnp_rpc::twoparty::VatId;
+    use gs_schemas::registry::RegistryName;
+    use gs_schemas::GameSide;
 
+    use crate::network::capture::{CaptureDecoder, CaptureWriter, SharedBuffer};
+    use crate::network::protocol::{write_game_version, ProtocolFeatures, ProtocolInfo};
+    use crate::network::simulator::simulate_pipes;
+    use crate::network::thread::NetworkThread;
     use crate::network::transport::*;
     use crate::GameServerControlCommand;
 
@@ -407,8 +765,23 @@ pub mod test {
 
     #[test]
     fn test_server_metadata() {
+        check_server_metadata(None);
+    }
+
+    #[test]
+    fn test_server_metadata_simulated() {
+        check_server_metadata(Some(NetworkConditions {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(5),
+            bandwidth: Some(1024 * 1024),
+            packet_loss: 0.1,
+            ..Default::default()
+        }));
+    }
+
+    fn check_server_metadata(conditions: Option<NetworkConditions>) {
         tokio::runtime::Builder::new_current_thread()
-            .enable_io()
+            .enable_all()
             .build()
             .unwrap()
             .block_on(async move {
@@ -416,9 +789,17 @@ pub mod test {
                     .run_until(async move {
                         let dummy_state = Rc::new(RefCell::new(NetworkThreadServerState::new()));
                         let addr = PeerAddress::Local(0);
-                        let (cpipe, spipe) = tokio::io::duplex(1024 * 1024);
+                        let pipes = tokio::io::duplex(1024 * 1024);
+                        let (cpipe, spipe) = match &conditions {
+                            Some(conditions) => simulate_pipes(pipes, conditions),
+                            None => pipes,
+                        };
                         let server = GameServer::new_test();
-                        let rpc_server = create_local_rpc_server(dummy_state, server.clone(), spipe, addr);
+                        let metrics = Arc::new(PeerNetworkMetrics::default());
+                        let capture = SharedBuffer::default();
+                        metrics.set_capture(CaptureWriter::new(capture.clone()).peer(addr));
+                        let rpc_server =
+                            create_local_rpc_server(dummy_state, server.clone(), spipe, addr, Arc::clone(&metrics));
                         let s_disconnector = rpc_server.get_disconnector();
                         let rpc_server = tokio::task::spawn_local(rpc_server);
                         let (rpc_client, c_server) = create_test_rpc_client(cpipe, addr);
@@ -443,6 +824,43 @@ pub mod test {
                             "Metadata: {:?}",
                             metadata.get_metadata().expect("metadata nested get failed")
                         );
+                        let server_protocol =
+                            ProtocolInfo::read_from_message(&metadata.get_metadata().unwrap().get_protocol().unwrap());
+                        assert_eq!(server_protocol, ProtocolInfo::CURRENT);
+                        let rpc_metrics = metrics.snapshot();
+                        assert_eq!(rpc_metrics.rpc_calls, 2);
+                        assert!(rpc_metrics.rpc.bytes_in > 0 && rpc_metrics.rpc.bytes_out > 0);
+                        let mut decoder = CaptureDecoder::new(None);
+                        let decoded = capture
+                            .messages()
+                            .iter()
+                            .flat_map(|message| decoder.decode(message))
+                            .collect_vec();
+                        assert!(decoded
+                            .iter()
+                            .any(|line| line.contains("<- rpc call") && line.contains("GameServer.ping")));
+                        assert!(decoded.iter().any(
+                            |line| line.contains("-> rpc return") && line.contains("GameServer.getServerMetadata")
+                        ));
+
+                        for (client_protocol, accepted) in [
+                            (
+                                ProtocolInfo {
+                                    version: 0,
+                                    min_version: 0,
+                                    features: ProtocolFeatures::empty(),
+                                },
+                                false,
+                            ),
+                            (ProtocolInfo::CURRENT, true),
+                        ] {
+                            let mut request = c_server.server_rpc.negotiate_protocol_request();
+                            write_game_version(request.get().init_game_version());
+                            client_protocol.write_to_message(&mut request.get().init_protocol());
+                            let response = request.send().promise.await.expect("negotiation request failed");
+                            let result = response.get().unwrap().get_result().unwrap();
+                            assert_eq!(result.has_ok(), accepted);
+                        }
 
                         // Disconnect the RPC endpoint, then await graceful shutdown.
                         let _ = s_disconnector.await;
@@ -459,4 +877,94 @@ pub mod test {
                     .await;
             });
     }
+
+    #[test]
+    fn test_datagram_channel() {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                let (tx, rx) = async_unbounded_channel();
+                let sender = DatagramSender::Process(tx);
+                let mut receiver = DatagramReceiver::Process(rx);
+                let standard = NetworkDatagramHeader::Standard(rpc::datagram_header::StandardTypes::PlayerState);
+                let custom = NetworkDatagramHeader::Custom(RegistryName::new("test", "datagram"));
+
+                sender.send(&standard, &[1, 2, 3]).unwrap();
+                sender.send(&custom, &[]).unwrap();
+                let err = sender.send(&standard, &[0; INPROCESS_MAX_DATAGRAM_SIZE]).unwrap_err();
+                assert!(matches!(
+                    err,
+                    DatagramSendError::TooLarge {
+                        limit: INPROCESS_MAX_DATAGRAM_SIZE,
+                        ..
+                    }
+                ));
+
+                assert_eq!(receiver.recv().await, Some((standard, Bytes::from_static(&[1, 2, 3]))));
+                assert_eq!(receiver.recv().await, Some((custom, Bytes::new())));
+                drop(sender);
+                assert_eq!(receiver.recv().await, None);
+            });
+    }
+
+    #[test]
+    fn test_custom_stream_handlers() {
+        const ECHO_V1: NetworkStreamType =
+            NetworkStreamType::new(NetworkStreamHeader::Custom(RegistryName::new_const("test", "echo")), 1);
+        const ECHO_V2: NetworkStreamType =
+            NetworkStreamType::new(NetworkStreamHeader::Custom(RegistryName::new_const("test", "echo")), 2);
+        const UNKNOWN: NetworkStreamType = NetworkStreamType::new(
+            NetworkStreamHeader::Custom(RegistryName::new_const("test", "unknown")),
+            1,
+        );
+
+        let header_bytes = ECHO_V2.write_to_bytes();
+        assert_eq!(
+            NetworkStreamType::read_from_bytes(&header_bytes, RPC_SERVER_READER_OPTIONS).unwrap(),
+            ECHO_V2
+        );
+
+        let net_thread = Arc::new(NetworkThread::new(GameSide::Server, NetworkThreadServerState::new));
+        net_thread.insert_stream_handler(
+            ECHO_V1,
+            Box::new(|_state, stream| {
+                Box::pin(async move {
+                    while let Some(message) = stream.recv().await {
+                        stream.send(message).await.unwrap();
+                    }
+                })
+            }),
+        );
+        assert_eq!(net_thread.registered_stream_types(), vec![ECHO_V1]);
+
+        let thread = Arc::clone(&net_thread);
+        net_thread
+            .schedule_task(move |state| {
+                Box::pin(async move {
+                    let peer = PeerAddress::Local(0);
+                    let (local, remote) = InProcessStream::new_pair(ECHO_V1);
+                    thread.spawn_stream_handler(Rc::clone(state), peer, remote.into());
+                    let local = TransportStream::from(local);
+                    local.send(Bytes::from_static(b"ping")).await?;
+                    assert_eq!(local.recv().await, Some(Bytes::from_static(b"ping")));
+
+                    for (stream_type, reason) in [
+                        (ECHO_V2, StreamRejection::UnsupportedVersion),
+                        (UNKNOWN, StreamRejection::UnknownType),
+                    ] {
+                        let (local, remote) = InProcessStream::new_pair(stream_type);
+                        thread.spawn_stream_handler(Rc::clone(state), peer, remote.into());
+                        let local = TransportStream::from(local);
+                        assert_eq!(local.recv().await, None);
+                        let err = local.send(Bytes::from_static(b"ping")).await.unwrap_err();
+                        assert_eq!(err.downcast_ref::<StreamRejection>(), Some(&reason));
+                    }
+                    Ok(())
+                })
+            })
+            .blocking_wait()
+            .unwrap();
+        net_thread.sync_shutdown();
+    }
 }
@@ -0,0 +1,428 @@
+//! The player entity and its collision-aware movement, simulated authoritatively on the server and predictively on the client.
+
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+use gs_schemas::coordinates::{AbsBlockPos, BLOCK_DIM};
+use gs_schemas::dependencies::itertools::iproduct;
+use gs_schemas::physics::Aabb;
+use gs_schemas::registry::RegistryId;
+use gs_schemas::voxel::chunk_group::ChunkGroup;
+use gs_schemas::voxel::collision::{ChunkGroupCollision, CollisionHull};
+use gs_schemas::voxel::voxeltypes::BlockRegistry;
+use gs_schemas::GsExtraData;
+
+use crate::voxel::blocks::WATER_BLOCK_NAME;
+use crate::voxel::plugin::{voxel_collision_update_system, BlockRegistryHolder, VoxelCollision, VoxelUniverse};
+use crate::InGameSystemSet;
+
+/// Where new players are placed, they get pushed out on top of the terrain if it is there.
+pub const PLAYER_SPAWN_POSITION: Vec3 = Vec3::new(0.5, 0.0, 0.5);
+/// Half of the size of the player's collision box, 0.6m wide and 1.8m tall.
+pub const PLAYER_HALF_EXTENTS: Vec3 = Vec3::new(0.3 / BLOCK_DIM, 0.9 / BLOCK_DIM, 0.3 / BLOCK_DIM);
+/// Height of the player's eyes above their feet.
+pub const PLAYER_EYE_HEIGHT: f32 = 1.6 / BLOCK_DIM;
+/// Maximum horizontal movement speed on land.
+pub const PLAYER_WALK_SPEED: f32 = 4.5 / BLOCK_DIM;
+/// Maximum movement speed in water.
+pub const PLAYER_SWIM_SPEED: f32 = 2.0 / BLOCK_DIM;
+/// Vertical velocity gained from a jump, enough to get on top of a single block.
+pub const PLAYER_JUMP_VELOCITY: f32 = 3.5 / BLOCK_DIM;
+/// Maximum height of an obstacle the player can walk onto without jumping.
+pub const PLAYER_STEP_HEIGHT: f32 = 1.0;
+/// Gravitational acceleration.
+pub const GRAVITY: f32 = 9.81 / BLOCK_DIM;
+/// Maximum falling speed.
+pub const PLAYER_TERMINAL_VELOCITY: f32 = 60.0 / BLOCK_DIM;
+
+const WATER_SINK_SPEED: f32 = 0.5 / BLOCK_DIM;
+// Rates (per second) at which the velocity approaches the desired one.
+const GROUND_ACCELERATION: f32 = 12.0;
+const AIR_ACCELERATION: f32 = 2.0;
+const WATER_ACCELERATION: f32 = 4.0;
+/// Movements blocked by less than this are not considered collisions.
+const COLLISION_EPSILON: f32 = 1.0e-4;
+
+/// How the player is currently moving.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub enum PlayerMovementMode {
+    /// Falling or jumping.
+    #[default]
+    Airborne,
+    /// Standing on top of a block.
+    Grounded,
+    /// Submerged in water.
+    Swimming,
+}
+
+/// The movement intent of a player for a simulation step.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PlayerInput {
+    /// The desired horizontal direction of movement in world space, the Y component is ignored.
+    /// Vectors longer than 1 are normalized.
+    pub movement: Vec3,
+    /// Jump when on the ground, swim up when in water.
+    pub jump: bool,
+    /// Swim down when in water.
+    pub descend: bool,
+}
+
+/// The physical state of a player, advanced by [`step_player_movement`].
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PlayerMovementState {
+    /// The center of the bottom face of the player's collision box.
+    pub position: Vec3,
+    /// Current velocity, in blocks per second.
+    pub velocity: Vec3,
+    /// Current movement mode.
+    pub mode: PlayerMovementMode,
+}
+
+impl PlayerMovementState {
+    /// A stationary player with their feet at `position`.
+    pub fn new(position: Vec3) -> Self {
+        Self { position, ..default() }
+    }
+
+    /// The space occupied by the player.
+    pub fn collision_box(&self) -> Aabb {
+        Aabb::from_center_half_extents(self.position + PLAYER_HALF_EXTENTS.y * Vec3::Y, PLAYER_HALF_EXTENTS)
+    }
+
+    /// The lower half of the collision box, the player swims while any of it is in water.
+    pub fn submerged_box(&self) -> Aabb {
+        let bbox = self.collision_box();
+        Aabb::new(bbox.min, Vec3::new(bbox.max.x, bbox.center().y, bbox.max.z))
+    }
+
+    /// The position of the player's eyes, where the camera should be placed.
+    pub fn eye_position(&self) -> Vec3 {
+        self.position + PLAYER_EYE_HEIGHT * Vec3::Y
+    }
+}
+
+/// A physically simulated player entity.
+#[derive(Component, Clone, Debug, Default)]
+#[require(Transform)]
+pub struct PlayerPhysics {
+    /// The current state of the simulation.
+    pub state: PlayerMovementState,
+    /// The position before the last simulation step, used for rendering interpolation.
+    pub previous_position: Vec3,
+    /// The input used for the following simulation steps.
+    pub input: PlayerInput,
+}
+
+impl PlayerPhysics {
+    /// A stationary player with their feet at `position`.
+    pub fn new(position: Vec3) -> Self {
+        Self {
+            state: PlayerMovementState::new(position),
+            previous_position: position,
+            input: default(),
+        }
+    }
+}
+
+/// A read-only view of the voxel world for resolving player collisions.
+pub struct MovementWorld<'a, ED: GsExtraData> {
+    chunks: &'a ChunkGroup<ED>,
+    collision: &'a ChunkGroupCollision,
+    water: Option<RegistryId>,
+}
+
+impl<'a, ED: GsExtraData> MovementWorld<'a, ED> {
+    /// Constructor, `collision` should be up to date with `chunks`.
+    pub fn new(chunks: &'a ChunkGroup<ED>, collision: &'a ChunkGroupCollision, registry: &BlockRegistry) -> Self {
+        let water = registry
+            .lookup_name_to_object(WATER_BLOCK_NAME.as_ref())
+            .map(|(id, _)| id);
+        Self {
+            chunks,
+            collision,
+            water,
+        }
+    }
+
+    /// The collision shapes of the loaded blocks.
+    pub fn collision(&self) -> &'a ChunkGroupCollision {
+        self.collision
+    }
+
+    /// Checks if any block touching `region` is water.
+    pub fn is_water(&self, region: &Aabb) -> bool {
+        let Some(water) = self.water else {
+            return false;
+        };
+        let (min, max) = region.block_range();
+        iproduct!(min.x..=max.x, min.y..=max.y, min.z..=max.z).any(|(x, y, z)| {
+            self.chunks
+                .get_block(AbsBlockPos::new(x, y, z))
+                .is_some_and(|block| block.id == water)
+        })
+    }
+}
+
+/// Advances the player simulation by `dt` seconds.
+/// The player does not move while any block they could collide with is not loaded yet.
+pub fn step_player_movement<ED: GsExtraData>(
+    world: &MovementWorld<ED>,
+    state: &mut PlayerMovementState,
+    input: &PlayerInput,
+    dt: f32,
+) {
+    let bbox = state.collision_box();
+    let in_water = world.is_water(&state.submerged_box());
+
+    let (speed, acceleration) = match state.mode {
+        _ if in_water => (PLAYER_SWIM_SPEED, WATER_ACCELERATION),
+        PlayerMovementMode::Grounded => (PLAYER_WALK_SPEED, GROUND_ACCELERATION),
+        _ => (PLAYER_WALK_SPEED, AIR_ACCELERATION),
+    };
+    let blend = 1.0 - (-acceleration * dt).exp();
+    let wish = Vec3::new(input.movement.x, 0.0, input.movement.z).clamp_length_max(1.0) * speed;
+    let mut velocity = state.velocity;
+    velocity.x += (wish.x - velocity.x) * blend;
+    velocity.z += (wish.z - velocity.z) * blend;
+    if in_water {
+        let wish_y = match (input.jump, input.descend) {
+            (true, false) => PLAYER_SWIM_SPEED,
+            (false, true) => -PLAYER_SWIM_SPEED,
+            _ => -WATER_SINK_SPEED,
+        };
+        velocity.y += (wish_y - velocity.y) * blend;
+    } else {
+        if input.jump && state.mode == PlayerMovementMode::Grounded {
+            velocity.y = PLAYER_JUMP_VELOCITY;
+        }
+        velocity.y = (velocity.y - GRAVITY * dt).max(-PLAYER_TERMINAL_VELOCITY);
+    }
+
+    let wanted = velocity * dt;
+    let mut colliders = Vec::new();
+    let region = bbox
+        .swept(wanted)
+        .expanded(Vec3::ONE)
+        .swept(PLAYER_STEP_HEIGHT * Vec3::Y);
+    if world.collision.collect_hulls(&region, &mut colliders).is_none() {
+        return;
+    }
+
+    // Push the player out of blocks placed inside of them, or terrain they spawned in.
+    let inner_box = bbox.expanded(Vec3::splat(-COLLISION_EPSILON));
+    if let Some(top) = colliders
+        .iter()
+        .filter(|(offset, hull)| hull.intersects_aabb(*offset, &inner_box))
+        .map(|(offset, hull)| offset.y + hull.bounds().max.y)
+        .reduce(f32::max)
+    {
+        state.position.y = top;
+        state.velocity = Vec3::ZERO;
+        state.mode = PlayerMovementMode::Grounded;
+        return;
+    }
+
+    let mut moved = move_and_collide(bbox, wanted, &colliders);
+    let horizontal_blocked =
+        (moved.x - wanted.x).abs() > COLLISION_EPSILON || (moved.z - wanted.z).abs() > COLLISION_EPSILON;
+    if horizontal_blocked && !in_water && state.mode == PlayerMovementMode::Grounded {
+        // Try stepping up onto the obstacle, and back down as far as possible.
+        let up = move_and_collide(bbox, PLAYER_STEP_HEIGHT * Vec3::Y, &colliders);
+        let raised = bbox.translated(up);
+        let across = move_and_collide(raised, Vec3::new(wanted.x, 0.0, wanted.z), &colliders);
+        let down = move_and_collide(
+            raised.translated(across),
+            Vec3::new(0.0, wanted.y.min(0.0) - up.y, 0.0),
+            &colliders,
+        );
+        if across.xz().length_squared() > moved.xz().length_squared() + COLLISION_EPSILON {
+            moved = up + across + down;
+        }
+    }
+
+    state.position += moved;
+    let blocked = (moved - wanted).abs().cmpgt(Vec3::splat(COLLISION_EPSILON));
+    velocity = Vec3::select(blocked, Vec3::ZERO, velocity);
+    state.velocity = velocity;
+    state.mode = if world.is_water(&state.submerged_box()) {
+        PlayerMovementMode::Swimming
+    } else if blocked.y && wanted.y < 0.0 {
+        PlayerMovementMode::Grounded
+    } else {
+        PlayerMovementMode::Airborne
+    };
+}
+
+/// Moves the box by `offset` one axis at a time, stopping at the colliders, and returns the actual offset moved.
+fn move_and_collide(mut bbox: Aabb, offset: Vec3, colliders: &[(Vec3, &CollisionHull)]) -> Vec3 {
+    let mut moved = Vec3::ZERO;
+    for axis in [1, 0, 2] {
+        let distance = colliders.iter().fold(offset[axis], |distance, (hull_offset, hull)| {
+            hull.clip_axis_offset(*hull_offset, &bbox, axis, distance)
+        });
+        let mut step = Vec3::ZERO;
+        step[axis] = distance;
+        bbox = bbox.translated(step);
+        moved[axis] = distance;
+    }
+    moved
+}
+
+/// Registers the player simulation systems for the given side.
+#[derive(Default)]
+pub struct PlayerMovementPlugin<ExtraData: GsExtraData> {
+    _extra_data: PhantomData<ExtraData>,
+}
+
+impl<ExtraData: GsExtraData> PlayerMovementPlugin<ExtraData> {
+    /// Constructor.
+    pub fn new() -> Self {
+        Self {
+            _extra_data: Default::default(),
+        }
+    }
+}
+
+impl<ExtraData: GsExtraData> Plugin for PlayerMovementPlugin<ExtraData> {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            (player_movement_system::<ExtraData>)
+                .after(voxel_collision_update_system::<ExtraData>)
+                .in_set(InGameSystemSet),
+        );
+    }
+
+    fn name(&self) -> &str {
+        "common::PlayerMovementPlugin"
+    }
+
+    fn is_unique(&self) -> bool {
+        true
+    }
+}
+
+/// Advances the simulation of all players by a tick.
+pub fn player_movement_system<ED: GsExtraData>(
+    time: Res<Time>,
+    block_registry: Res<BlockRegistryHolder>,
+    voxel_q: Query<(&VoxelUniverse<ED>, &VoxelCollision)>,
+    mut player_q: Query<(&mut PlayerPhysics, &mut Transform)>,
+) {
+    let Ok((voxels, collision)) = voxel_q.get_single() else {
+        return;
+    };
+    let world = MovementWorld::new(voxels.loaded_chunks(), collision, &block_registry);
+    let dt = time.delta_secs();
+    for (mut physics, mut transform) in player_q.iter_mut() {
+        let physics = &mut *physics;
+        physics.previous_position = physics.state.position;
+        step_player_movement(&world, &mut physics.state, &physics.input, dt);
+        transform.translation = physics.state.position;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::GameSide;
+
+    use super::*;
+    use crate::test_utils::{block, flat_chunk_group};
+    use crate::voxel::blocks::STONE_BLOCK_NAME;
+    use crate::{builtin_game_registries, ServerData};
+
+    const DT: f32 = 1.0 / 32.0;
+
+    fn simulate(group: &ChunkGroup<ServerData>, state: &mut PlayerMovementState, input: PlayerInput, steps: usize) {
+        let registry = builtin_game_registries().block_types;
+        let mut collision = ChunkGroupCollision::new();
+        collision.update(group, &registry);
+        let world = MovementWorld::new(group, &collision, &registry);
+        for _ in 0..steps {
+            step_player_movement(&world, state, &input, DT);
+        }
+    }
+
+    #[test]
+    fn falls_onto_ground_and_jumps() {
+        let registry = builtin_game_registries().block_types;
+        let group = flat_chunk_group(&registry);
+        let mut state = PlayerMovementState::new(Vec3::new(0.5, 20.0, 0.5));
+        simulate(&group, &mut state, default(), 64);
+        assert!(state.position.abs_diff_eq(Vec3::new(0.5, 16.0, 0.5), 1.0e-3));
+        assert_eq!(state.mode, PlayerMovementMode::Grounded);
+
+        let jump = PlayerInput {
+            jump: true,
+            ..default()
+        };
+        simulate(&group, &mut state, jump, 1);
+        assert_eq!(state.mode, PlayerMovementMode::Airborne);
+        assert!(state.position.y > 16.0);
+        simulate(&group, &mut state, default(), 64);
+        assert!((state.position.y - 16.0).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn walls_and_steps() {
+        let registry = builtin_game_registries().block_types;
+        let mut group = flat_chunk_group(&registry);
+        let stone = block(&registry, &STONE_BLOCK_NAME);
+        let forward = PlayerInput {
+            movement: Vec3::X,
+            ..default()
+        };
+
+        // A single block is stepped over.
+        group.apply_block_edits(&[(AbsBlockPos::new(3, 16, 0), stone)], GameSide::Server);
+        let mut state = PlayerMovementState::new(Vec3::new(0.5, 16.0, 0.5));
+        simulate(&group, &mut state, forward, 64);
+        assert!(state.position.x > 4.0);
+        assert_eq!(state.mode, PlayerMovementMode::Grounded);
+
+        // A two blocks high wall is not.
+        group.apply_block_edits(
+            &[
+                (AbsBlockPos::new(40, 16, 0), stone),
+                (AbsBlockPos::new(40, 17, 0), stone),
+            ],
+            GameSide::Server,
+        );
+        simulate(&group, &mut state, forward, 256);
+        assert!((state.position.x - (40.0 - PLAYER_HALF_EXTENTS.x)).abs() < 1.0e-3);
+        assert_eq!(state.velocity.x, 0.0);
+    }
+
+    #[test]
+    fn swims_when_touching_water() {
+        let registry = builtin_game_registries().block_types;
+        let mut group = flat_chunk_group(&registry);
+        let water = block(&registry, &WATER_BLOCK_NAME);
+        let pool: Vec<_> = iproduct!(4..8, 16..20, -4..4)
+            .map(|(x, y, z)| (AbsBlockPos::new(x, y, z), water))
+            .collect();
+        group.apply_block_edits(&pool, GameSide::Server);
+
+        // Only the side of the player is in the pool, not its center.
+        let mut state = PlayerMovementState::new(Vec3::new(3.8, 16.0, 0.5));
+        simulate(&group, &mut state, default(), 1);
+        assert_eq!(state.mode, PlayerMovementMode::Swimming);
+        let mut state = PlayerMovementState::new(Vec3::new(3.2, 16.0, 0.5));
+        simulate(&group, &mut state, default(), 1);
+        assert_eq!(state.mode, PlayerMovementMode::Grounded);
+    }
+
+    #[test]
+    fn unloaded_and_buried() {
+        let registry = builtin_game_registries().block_types;
+        let group = flat_chunk_group(&registry);
+        // Outside of the loaded area, nothing happens.
+        let mut state = PlayerMovementState::new(Vec3::new(100.5, 20.0, 0.5));
+        simulate(&group, &mut state, default(), 8);
+        assert_eq!(state.position, Vec3::new(100.5, 20.0, 0.5));
+        // Players stuck in the terrain get pushed out on top.
+        let mut state = PlayerMovementState::new(Vec3::new(0.5, 10.0, 0.5));
+        simulate(&group, &mut state, default(), 8);
+        assert!((state.position.y - 16.0).abs() < 1.0e-3);
+    }
+}
@@ -0,0 +1,33 @@
+//! Fixtures shared by the unit tests of multiple modules.
+
+use bevy::prelude::default;
+use gs_schemas::coordinates::{AbsChunkPos, InChunkPos, InChunkRange};
+use gs_schemas::dependencies::itertools::iproduct;
+use gs_schemas::mutwatcher::MutWatcher;
+use gs_schemas::registry::RegistryName;
+use gs_schemas::voxel::chunk::Chunk;
+use gs_schemas::voxel::chunk_group::ChunkGroup;
+use gs_schemas::voxel::chunk_storage::ChunkStorage;
+use gs_schemas::voxel::voxeltypes::{BlockEntry, BlockRegistry, EMPTY_BLOCK_NAME};
+
+use crate::voxel::blocks::STONE_BLOCK_NAME;
+use crate::ServerData;
+
+/// The default state of the registered block with the given name.
+pub fn block(registry: &BlockRegistry, name: &RegistryName) -> BlockEntry {
+    BlockEntry::new(registry.lookup_name_to_object(name.as_ref()).unwrap().0, 0)
+}
+
+/// Stone up to y=15 and empty space above, in the 3x3 chunks around the origin.
+pub fn flat_chunk_group(registry: &BlockRegistry) -> ChunkGroup<ServerData> {
+    let mut group = ChunkGroup::with_data(());
+    for (x, z) in iproduct!(-1..=1, -1..=1) {
+        let mut chunk = Chunk::new(block(registry, &EMPTY_BLOCK_NAME), default());
+        chunk.blocks.fill(
+            InChunkRange::from_corners(InChunkPos::ZERO, InChunkPos::try_new(31, 15, 31).unwrap()),
+            block(registry, &STONE_BLOCK_NAME),
+        );
+        group.chunks.insert(AbsChunkPos::new(x, 0, z), MutWatcher::new(chunk));
+    }
+    group
+}
@@ -1,22 +1,33 @@
-//! The builtin biome types.
+//! The builtin biome types, and loading of biome definitions from data files.
 //! Most of this will be moved to a "base" mod at some point in the future.
+//!
+//! Biomes are described with [`SurfaceRules`] and noise graphs, so they can be stored in
+//! `<data directory>/<namespace>/biomes/<name>.ron` files. Biomes that need more than that can still use
+//! [`BiomeRules::Code`] and [`SurfaceNoise::Code`], but can't be stored in files.
 
-use gs_schemas::voxel::generation::fbm_noise::Fbm;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gs_schemas::voxel::biome::feature::{BiomeFeature, FeaturePlacement, FeatureShape};
+use gs_schemas::voxel::biome::{BiomeRules, CaveCarving, SurfaceNoise, SurfaceRules};
+use gs_schemas::voxel::generation::noise_graph::{NodeId, NoiseGraphDefinition, NoiseNode};
 use gs_schemas::{
     dependencies::rgb::RGBA8,
     range::range,
     registry::RegistryName,
     voxel::{
         biome::{BiomeDefinition, BiomeRegistry, VOID_BIOME_NAME},
-        generation::{Context, NoiseNDTo2D},
-        voxeltypes::{BlockEntry, BlockRegistry},
+        voxeltypes::BlockRegistry,
     },
 };
-use noise::OpenSimplex;
+use ron::extensions::Extensions;
+use ron::ser::PrettyConfig;
 
 use super::blocks::{
-    DIRT_BLOCK_NAME, GRASS_BLOCK_NAME, SAND_BLOCK_NAME, SNOWY_GRASS_BLOCK_NAME, STONE_BLOCK_NAME, WATER_BLOCK_NAME,
+    COAL_ORE_BLOCK_NAME, DIRT_BLOCK_NAME, GRASS_BLOCK_NAME, IRON_ORE_BLOCK_NAME, LEAVES_BLOCK_NAME, LOG_BLOCK_NAME,
+    SAND_BLOCK_NAME, SNOWY_GRASS_BLOCK_NAME, STONE_BLOCK_NAME, TALL_GRASS_BLOCK_NAME, WATER_BLOCK_NAME,
 };
+use crate::prelude::*;
 
 /// Registry name for plains.
 pub const PLAINS_BIOME_NAME: RegistryName = RegistryName::gs_const("plains");
@@ -31,6 +42,156 @@ pub const BEACH_BIOME_NAME: RegistryName = RegistryName::gs_const("beach");
 /// Registry name for river.
 pub const RIVER_BIOME_NAME: RegistryName = RegistryName::gs_const("river");
 
+/// The subdirectory of a data namespace directory holding its biome definitions.
+pub const BIOME_DATA_SUBDIRECTORY: &str = "biomes";
+/// The file extension of biome definition files.
+pub const BIOME_FILE_EXTENSION: &str = "ron";
+
+/// The ground level above which the grassy biomes are covered with snow.
+const SNOW_LINE: i32 = 80;
+
+/// How far above the sea level the grassy biomes have sandy shores.
+const SHORE_HEIGHT: i32 = 1;
+
+/// Grass with dirt under it, snowy above the snow line and sandy along the sea shore.
+fn grassy_surface() -> SurfaceRules {
+    SurfaceRules {
+        top_block: Some(GRASS_BLOCK_NAME),
+        snow_block: Some(SNOWY_GRASS_BLOCK_NAME),
+        snow_line: SNOW_LINE,
+        filler_block: Some(DIRT_BLOCK_NAME),
+        filler_depth: 4,
+        stone_block: Some(STONE_BLOCK_NAME),
+        shore_block: Some(SAND_BLOCK_NAME),
+        shore_height: SHORE_HEIGHT,
+        fluid_block: Some(WATER_BLOCK_NAME),
+    }
+}
+
+/// Sand over stone, flooded up to the sea level.
+fn sandy_surface(sand_depth: i32) -> SurfaceRules {
+    SurfaceRules {
+        top_block: Some(SAND_BLOCK_NAME),
+        filler_block: Some(SAND_BLOCK_NAME),
+        filler_depth: sand_depth,
+        stone_block: Some(STONE_BLOCK_NAME),
+        fluid_block: Some(WATER_BLOCK_NAME),
+        ..Default::default()
+    }
+}
+
+/// Coal and iron veins in the stone.
+fn ores() -> Vec<BiomeFeature> {
+    let vein = |block, size, frequency, height| BiomeFeature {
+        frequency,
+        height,
+        placement: FeaturePlacement::Buried(vec![STONE_BLOCK_NAME]),
+        shape: FeatureShape::OreVein { block, size },
+    };
+    vec![
+        vein(COAL_ORE_BLOCK_NAME, 12, 4.0, range(..64)),
+        vein(IRON_ORE_BLOCK_NAME, 8, 2.0, range(..0)),
+    ]
+}
+
+/// Trees and tall grass on grass, with the given number of trees per chunk.
+fn vegetation(trees: f64) -> Vec<BiomeFeature> {
+    vec![
+        BiomeFeature {
+            frequency: trees,
+            height: range(..SNOW_LINE),
+            placement: FeaturePlacement::Surface(vec![GRASS_BLOCK_NAME]),
+            shape: FeatureShape::Tree {
+                trunk: LOG_BLOCK_NAME,
+                leaves: LEAVES_BLOCK_NAME,
+                min_height: 4,
+                max_height: 7,
+                canopy_radius: 2,
+            },
+        },
+        BiomeFeature {
+            frequency: 24.0,
+            height: range(..SNOW_LINE),
+            placement: FeaturePlacement::Surface(vec![GRASS_BLOCK_NAME]),
+            shape: FeatureShape::Vegetation {
+                block: TALL_GRASS_BLOCK_NAME,
+            },
+        },
+    ]
+}
+
+/// Stone boulders lying on the ground, with the given number of boulders per chunk.
+fn boulders(frequency: f64) -> BiomeFeature {
+    BiomeFeature {
+        frequency,
+        height: range(..),
+        placement: FeaturePlacement::Surface(vec![GRASS_BLOCK_NAME, SNOWY_GRASS_BLOCK_NAME, STONE_BLOCK_NAME]),
+        shape: FeatureShape::Boulder {
+            block: STONE_BLOCK_NAME,
+            radius: 2.5,
+        },
+    }
+}
+
+/// Pushes the base terrain noise at each of the given `(frequency, scale)` pairs, returning the scaled nodes.
+fn push_terrain_layers(graph: &mut NoiseGraphDefinition, layers: &[(f64, f64)]) -> Vec<NodeId> {
+    layers
+        .iter()
+        .map(|&(frequency, scale)| {
+            let terrain = graph.push(NoiseNode::Terrain { frequency });
+            graph.push(NoiseNode::Affine {
+                input: terrain,
+                scale,
+                offset: 0.0,
+            })
+        })
+        .collect()
+}
+
+/// Pushes the ridges of the base terrain noise at the given frequency, `1 - |noise|`, multiplied by `scale`.
+fn push_ridges(graph: &mut NoiseGraphDefinition, frequency: f64, scale: f64) -> NodeId {
+    let terrain = graph.push(NoiseNode::Terrain { frequency });
+    let abs = graph.push(NoiseNode::Abs(terrain));
+    let ridges = graph.push(NoiseNode::Affine {
+        input: abs,
+        scale: -1.0,
+        offset: 1.0,
+    });
+    graph.push(NoiseNode::Affine {
+        input: ridges,
+        scale,
+        offset: 0.0,
+    })
+}
+
+/// A surface height summing the base terrain noise at each of the given `(frequency, scale)` pairs, and an offset.
+pub(crate) fn layered_terrain_surface(layers: &[(f64, f64)], offset: f64) -> SurfaceNoise {
+    let mut graph = NoiseGraphDefinition::default();
+    let mut terms = push_terrain_layers(&mut graph, layers);
+    terms.push(graph.push(NoiseNode::Constant(offset)));
+    graph.push(NoiseNode::Add(terms));
+    SurfaceNoise::Graph(graph)
+}
+
+/// The surface height of mountains: two octaves of ridges, with finer detail added in proportion to their height.
+fn mountain_surface() -> SurfaceNoise {
+    let mut graph = NoiseGraphDefinition::default();
+    let ridges = vec![push_ridges(&mut graph, 0.25, 0.5), push_ridges(&mut graph, 0.5, 0.25)];
+    let ridges = graph.push(NoiseNode::Add(ridges));
+    let mut detail = vec![graph.push(NoiseNode::Constant(1.1))];
+    detail.extend(push_terrain_layers(&mut graph, &[(1.25, 0.1)]));
+    detail.push(push_ridges(&mut graph, 2.25, 1.0 / 15.0));
+    let detail = graph.push(NoiseNode::Add(detail));
+    let height = graph.push(NoiseNode::Multiply(vec![ridges, detail]));
+    let height = graph.push(NoiseNode::Abs(height));
+    graph.push(NoiseNode::Affine {
+        input: height,
+        scale: 100.0,
+        offset: 40.0,
+    });
+    SurfaceNoise::Graph(graph)
+}
+
 /// Installs the base set of biomes into the given block registry.
 pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
     biome_registry
@@ -40,11 +201,13 @@ pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
             elevation: range(-1.0..-1.0),
             temperature: range(-1.0..-1.0),
             moisture: range(-1.0..-1.0),
-            rule_source: |_pos: &bevy_math::IVec3, _ctx: &Context, _reg: &BlockRegistry| None,
-            surface_noise: |_point, _noise| 0.0,
+            rule_source: BiomeRules::Layered(Box::default()),
+            surface_noise: layered_terrain_surface(&[], 0.0),
             blend_influence: 0.0,
             block_influence: 0.0,
             can_generate: false,
+            cave_carving: CaveCarving::None,
+            features: Vec::new(),
         })
         .unwrap();
 
@@ -55,39 +218,13 @@ pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
             elevation: range(0.5..1.5),
             temperature: range(..),
             moisture: range(..),
-            rule_source: |pos: &bevy_math::IVec3, context: &Context, block_registry: &BlockRegistry| {
-                let (i_grass, _) = block_registry.lookup_name_to_object(GRASS_BLOCK_NAME.as_ref()).unwrap();
-                let (i_dirt, _) = block_registry.lookup_name_to_object(DIRT_BLOCK_NAME.as_ref()).unwrap();
-                let (i_stone, _) = block_registry.lookup_name_to_object(STONE_BLOCK_NAME.as_ref()).unwrap();
-                let (i_snow_grass, _) = block_registry
-                    .lookup_name_to_object(SNOWY_GRASS_BLOCK_NAME.as_ref())
-                    .unwrap();
-
-                if context.ground_y == pos.y {
-                    return if pos.y >= 80 {
-                        Some(BlockEntry::new(i_snow_grass, 0))
-                    } else {
-                        Some(BlockEntry::new(i_grass, 0))
-                    };
-                } else if pos.y <= context.ground_y && pos.y > context.ground_y - 5 {
-                    return Some(BlockEntry::new(i_dirt, 0));
-                } else if context.ground_y > pos.y {
-                    return Some(BlockEntry::new(i_stone, 0));
-                }
-                None
-            },
-            surface_noise: |point, noise| {
-                let new_point = point * 1.5;
-
-                let mut value = <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, new_point.to_array()) * 0.75;
-                value += <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, (new_point * 2.0).to_array()) * 0.25;
-                value *= 5.0;
-                value += 10.0;
-                value
-            },
+            rule_source: BiomeRules::Layered(Box::new(grassy_surface())),
+            surface_noise: layered_terrain_surface(&[(1.5, 3.75), (3.0, 1.25)], 10.0),
             blend_influence: 0.5,
             block_influence: 1.0,
             can_generate: true,
+            cave_carving: CaveCarving::Dry,
+            features: [vegetation(1.0), vec![boulders(0.1)], ores()].concat(),
         })
         .unwrap();
 
@@ -98,41 +235,13 @@ pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
             elevation: range(1.5..3.0),
             temperature: range(..),
             moisture: range(..),
-            rule_source: |pos: &bevy_math::IVec3, context: &Context, block_registry: &BlockRegistry| {
-                let (i_grass, _) = block_registry.lookup_name_to_object(GRASS_BLOCK_NAME.as_ref()).unwrap();
-                let (i_dirt, _) = block_registry.lookup_name_to_object(DIRT_BLOCK_NAME.as_ref()).unwrap();
-                let (i_stone, _) = block_registry.lookup_name_to_object(STONE_BLOCK_NAME.as_ref()).unwrap();
-                let (i_snow_grass, _) = block_registry
-                    .lookup_name_to_object(SNOWY_GRASS_BLOCK_NAME.as_ref())
-                    .unwrap();
-
-                if context.ground_y == pos.y {
-                    return if pos.y >= 80 {
-                        Some(BlockEntry::new(i_snow_grass, 0))
-                    } else {
-                        Some(BlockEntry::new(i_grass, 0))
-                    };
-                } else if pos.y <= context.ground_y && pos.y > context.ground_y - 5 {
-                    return Some(BlockEntry::new(i_dirt, 0));
-                } else if context.ground_y > pos.y {
-                    return Some(BlockEntry::new(i_stone, 0));
-                }
-                None
-            },
-            surface_noise: |point, noise| {
-                let new_point = point / 3.0;
-                let new_point_arr = new_point.to_array();
-
-                let mut value = <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, new_point_arr) * 0.6;
-                value += <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, (new_point * 1.5).to_array()) * 0.25;
-                value += <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, (new_point * 3.0).to_array()) * 0.15;
-                value *= 8.0;
-                value += 15.0;
-                value
-            },
+            rule_source: BiomeRules::Layered(Box::new(grassy_surface())),
+            surface_noise: layered_terrain_surface(&[(1.0 / 3.0, 4.8), (0.5, 2.0), (1.0, 1.2)], 15.0),
             blend_influence: 1.0,
             block_influence: 1.0,
             can_generate: true,
+            cave_carving: CaveCarving::Dry,
+            features: [vegetation(3.0), vec![boulders(0.3)], ores()].concat(),
         })
         .unwrap();
 
@@ -143,45 +252,13 @@ pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
             elevation: range(3.0..),
             temperature: range(..),
             moisture: range(..),
-            rule_source: |pos: &bevy_math::IVec3, context: &Context, block_registry: &BlockRegistry| {
-                let (i_grass, _) = block_registry.lookup_name_to_object(GRASS_BLOCK_NAME.as_ref()).unwrap();
-                let (i_dirt, _) = block_registry.lookup_name_to_object(DIRT_BLOCK_NAME.as_ref()).unwrap();
-                let (i_stone, _) = block_registry.lookup_name_to_object(STONE_BLOCK_NAME.as_ref()).unwrap();
-                let (i_snow_grass, _) = block_registry
-                    .lookup_name_to_object(SNOWY_GRASS_BLOCK_NAME.as_ref())
-                    .unwrap();
-
-                if context.ground_y == pos.y {
-                    return if pos.y >= 80 {
-                        Some(BlockEntry::new(i_snow_grass, 0))
-                    } else {
-                        Some(BlockEntry::new(i_grass, 0))
-                    };
-                } else if context.ground_y >= pos.y && pos.y > context.ground_y - 5 {
-                    return Some(BlockEntry::new(i_dirt, 0));
-                } else if context.ground_y > pos.y {
-                    return Some(BlockEntry::new(i_stone, 0));
-                }
-                None
-            },
-            surface_noise: |point, noise| {
-                let new_point = point / 4.0;
-                let new_point_arr = new_point.to_array();
-                let h_n = |p| (<Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, p) + 1.0) / 2.0;
-                let h_rn = |p| (0.5 - (0.5 - h_n(p)).abs()) * 2.0;
-
-                let h0 = 0.50 * h_rn(new_point_arr);
-                let h01 = 0.25 * h_rn((new_point * 2.0).to_array()) + h0;
-
-                (h01 + (h01 / 0.75) * 0.15 * h_n((new_point * 5.0).to_array())
-                    + (h01 / 0.75) * 0.05 * h_rn((new_point * 9.0).to_array()))
-                .abs()
-                    * 100.0
-                    + 40.0
-            },
+            rule_source: BiomeRules::Layered(Box::new(grassy_surface())),
+            surface_noise: mountain_surface(),
             blend_influence: 1.0,
             block_influence: 1.0,
             can_generate: true,
+            cave_carving: CaveCarving::Dry,
+            features: [vec![boulders(1.0)], ores()].concat(),
         })
         .unwrap();
 
@@ -192,25 +269,13 @@ pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
             elevation: range(..1.0),
             temperature: range(..),
             moisture: range(2.5..),
-            rule_source: |pos: &bevy_math::IVec3, context: &Context, block_registry: &BlockRegistry| {
-                let (i_stone, _) = block_registry.lookup_name_to_object(STONE_BLOCK_NAME.as_ref()).unwrap();
-                let (i_water, _) = block_registry.lookup_name_to_object(WATER_BLOCK_NAME.as_ref()).unwrap();
-
-                if context.sea_level > pos.y {
-                    return if context.ground_y > pos.y {
-                        Some(BlockEntry::new(i_stone, 0))
-                    } else {
-                        Some(BlockEntry::new(i_water, 0))
-                    };
-                }
-                None
-            },
-            surface_noise: |point, noise| {
-                <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, (point / 25.0).to_array()) * -7.5 + 1.0
-            },
+            rule_source: BiomeRules::Layered(Box::new(sandy_surface(3))),
+            surface_noise: layered_terrain_surface(&[(1.0 / 25.0, -7.5)], -12.0),
             blend_influence: 1.0,
             block_influence: 1.0,
             can_generate: true,
+            cave_carving: CaveCarving::Flooded,
+            features: ores(),
         })
         .unwrap();
 
@@ -221,23 +286,13 @@ pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
             elevation: range(1.0..),
             temperature: range(..),
             moisture: range(2.5..),
-            rule_source: |pos: &bevy_math::IVec3, context: &Context, block_registry: &BlockRegistry| {
-                let (i_stone, _) = block_registry.lookup_name_to_object(STONE_BLOCK_NAME.as_ref()).unwrap();
-                let (i_sand, _) = block_registry.lookup_name_to_object(SAND_BLOCK_NAME.as_ref()).unwrap();
-
-                if context.ground_y > pos.y - 1 {
-                    return Some(BlockEntry::new(i_stone, 0));
-                } else if context.ground_y == pos.y {
-                    return Some(BlockEntry::new(i_sand, 0));
-                }
-                None
-            },
-            surface_noise: |point, noise| {
-                <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, point.to_array()) * 1.0 + 1.0
-            },
+            rule_source: BiomeRules::Layered(Box::new(sandy_surface(3))),
+            surface_noise: layered_terrain_surface(&[(1.0, 1.0)], 1.0),
             blend_influence: 1.0,
             block_influence: 1.0,
             can_generate: false,
+            cave_carving: CaveCarving::Flooded,
+            features: ores(),
         })
         .unwrap();
 
@@ -248,26 +303,287 @@ pub fn setup_basic_biomes(biome_registry: &mut BiomeRegistry) {
             elevation: range(..),
             temperature: range(..),
             moisture: range(..),
-            rule_source: |pos: &bevy_math::IVec3, context: &Context, block_registry: &BlockRegistry| {
-                let (i_stone, _) = block_registry.lookup_name_to_object(STONE_BLOCK_NAME.as_ref()).unwrap();
-                let (i_sand, _) = block_registry.lookup_name_to_object(SAND_BLOCK_NAME.as_ref()).unwrap();
-                let (i_water, _) = block_registry.lookup_name_to_object(WATER_BLOCK_NAME.as_ref()).unwrap();
-
-                if context.ground_y == pos.y {
-                    return Some(BlockEntry::new(i_sand, 0));
-                } else if pos.y <= context.ground_y && context.ground_y > pos.y - 3 {
-                    return Some(BlockEntry::new(i_water, 0));
-                } else if context.ground_y > pos.y {
-                    return Some(BlockEntry::new(i_stone, 0));
-                }
-                None
-            },
-            surface_noise: |point, noise| {
-                <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(noise, point.to_array()) * -1.5 + 1.0
-            },
+            rule_source: BiomeRules::Layered(Box::new(sandy_surface(2))),
+            surface_noise: layered_terrain_surface(&[(1.0, -1.5)], 1.0),
             blend_influence: 1.0,
             block_influence: 1.0,
             can_generate: false,
+            cave_carving: CaveCarving::Flooded,
+            features: ores(),
         })
         .unwrap();
 }
+
+/// Parses a biome definition file, checking that all the blocks it uses exist.
+/// The biome gets the given name, which the file may repeat but not contradict.
+pub fn parse_biome_definition(
+    source: &str,
+    name: RegistryName,
+    block_registry: &BlockRegistry,
+) -> Result<BiomeDefinition> {
+    let mut definition: BiomeDefinition = ron::Options::default()
+        .with_default_extension(Extensions::IMPLICIT_SOME)
+        .from_str(source)?;
+    ensure!(
+        definition.name == RegistryName::default() || definition.name == name,
+        "The biome is named {:?} in the file, but {:?} by its path",
+        definition.name,
+        name
+    );
+    definition.name = name;
+    if let BiomeRules::Layered(rules) = &definition.rule_source {
+        for block in rules.blocks() {
+            ensure!(
+                block_registry.lookup_name_to_object(block.as_ref()).is_some(),
+                "Unknown block {block:?}"
+            );
+        }
+    }
+    if let SurfaceNoise::Graph(graph) = &definition.surface_noise {
+        graph.validate()?;
+    }
+    for feature in &definition.features {
+        feature.validate()?;
+        for block in feature.blocks() {
+            ensure!(
+                block_registry.lookup_name_to_object(block.as_ref()).is_some(),
+                "Unknown block {block:?}"
+            );
+        }
+    }
+    Ok(definition)
+}
+
+/// Writes a biome definition in the format read by [`parse_biome_definition`].
+/// Fails for biomes with parts defined in code.
+pub fn serialize_biome_definition(definition: &BiomeDefinition) -> Result<String> {
+    definition.ensure_serializable()?;
+    Ok(ron::ser::to_string_pretty(definition, PrettyConfig::default())?)
+}
+
+/// Loads the biomes defined in `<root>/<namespace>/biomes/<name>.ron` files into the registry, as `namespace:name`.
+/// Files are loaded in the order of their paths, so that all sides loading the same files agree on the biome IDs.
+/// The registry is only changed if all the files load, returns the number of biomes loaded.
+pub fn load_biome_directory(
+    biome_registry: &mut BiomeRegistry,
+    block_registry: &BlockRegistry,
+    root: &Path,
+) -> Result<usize> {
+    let mut staged = biome_registry.clone();
+    let mut loaded = 0;
+    for namespace_dir in sorted_directory(root)? {
+        let biome_dir = namespace_dir.join(BIOME_DATA_SUBDIRECTORY);
+        if !biome_dir.is_dir() {
+            continue;
+        }
+        let namespace = namespace_dir
+            .file_name()
+            .and_then(|ns| ns.to_str())
+            .with_context(|| format!("Invalid namespace directory name {}", namespace_dir.display()))?;
+        for path in sorted_directory(&biome_dir)? {
+            if path.extension().and_then(|ext| ext.to_str()) != Some(BIOME_FILE_EXTENSION) {
+                continue;
+            }
+            let key = path
+                .file_stem()
+                .and_then(|key| key.to_str())
+                .with_context(|| format!("Invalid biome file name {}", path.display()))?;
+            let name = RegistryName::new(namespace, key);
+            let definition = fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|source| parse_biome_definition(&source, name, block_registry))
+                .with_context(|| format!("Could not load the biome {}", path.display()))?;
+            staged.push_object(definition)?;
+            loaded += 1;
+        }
+    }
+    *biome_registry = staged;
+    Ok(loaded)
+}
+
+/// The entries of a directory, sorted by path.
+pub(crate) fn sorted_directory(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(path)
+        .with_context(|| format!("Could not read {}", path.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::registry::Registry;
+    use gs_schemas::voxel::biome::SurfaceRules;
+
+    use super::*;
+    use crate::voxel::blocks::setup_basic_blocks;
+
+    fn block_registry() -> BlockRegistry {
+        let mut blocks = Registry::default();
+        setup_basic_blocks(&mut blocks);
+        blocks
+    }
+
+    #[test]
+    fn builtin_biomes_round_trip() {
+        let blocks = block_registry();
+        let mut biomes = Registry::default();
+        setup_basic_biomes(&mut biomes);
+        for (_, name, biome) in biomes.iter() {
+            let source = serialize_biome_definition(biome).unwrap();
+            let parsed = parse_biome_definition(&source, name.to_owned(), &blocks).unwrap();
+            assert_eq!(parsed.name, biome.name);
+            let (BiomeRules::Layered(parsed_rules), BiomeRules::Layered(rules)) =
+                (&parsed.rule_source, &biome.rule_source)
+            else {
+                panic!("Builtin biomes should use layered rules");
+            };
+            assert_eq!(parsed_rules, rules);
+            assert_eq!(parsed.features, biome.features);
+        }
+    }
+
+    #[test]
+    fn code_defined_biomes_are_not_serialized() {
+        let mut biomes = Registry::default();
+        setup_basic_biomes(&mut biomes);
+        let mut biome = biomes
+            .lookup_name_to_object(PLAINS_BIOME_NAME.as_ref())
+            .unwrap()
+            .1
+            .clone();
+        biome.rule_source = BiomeRules::Code(|_, _, _| None);
+        let error = biome.ensure_serializable().unwrap_err();
+        assert_eq!(error.name, PLAINS_BIOME_NAME);
+        assert!(serialize_biome_definition(&biome).is_err());
+    }
+
+    #[test]
+    fn layered_rules() {
+        let rules = grassy_surface();
+        let at = |y| rules.block_name_at(y, 10, 0);
+        assert_eq!(at(11), None);
+        assert_eq!(at(10), Some(&GRASS_BLOCK_NAME));
+        assert_eq!(at(9), Some(&DIRT_BLOCK_NAME));
+        assert_eq!(at(6), Some(&DIRT_BLOCK_NAME));
+        assert_eq!(at(5), Some(&STONE_BLOCK_NAME));
+        assert_eq!(
+            rules.block_name_at(SNOW_LINE, SNOW_LINE, 0),
+            Some(&SNOWY_GRASS_BLOCK_NAME)
+        );
+
+        // Grassy biomes get sandy shores, and are flooded below the sea level.
+        assert_eq!(rules.block_name_at(1, 1, 0), Some(&SAND_BLOCK_NAME));
+        assert_eq!(rules.block_name_at(0, 1, 0), Some(&SAND_BLOCK_NAME));
+        assert_eq!(rules.block_name_at(2, 2, 0), Some(&GRASS_BLOCK_NAME));
+        assert_eq!(rules.block_name_at(-1, -3, 0), Some(&WATER_BLOCK_NAME));
+
+        let rules = sandy_surface(1);
+        assert_eq!(rules.block_name_at(-1, -5, 0), Some(&WATER_BLOCK_NAME));
+        assert_eq!(rules.block_name_at(0, -5, 0), None);
+        assert_eq!(rules.block_name_at(-6, -5, 0), Some(&SAND_BLOCK_NAME));
+        assert_eq!(SurfaceRules::default().block_name_at(-6, -5, 0), None);
+    }
+
+    #[test]
+    fn graph_surface() {
+        let source = |output: u32| {
+            format!(
+                r#"(
+                    representative_color: (r: 0, g: 0, b: 0, a: 255),
+                    can_generate: true,
+                    elevation: Full,
+                    temperature: Full,
+                    moisture: Full,
+                    rule_source: Layered((filler_depth: 0)),
+                    surface_noise: Graph((
+                        nodes: [
+                            OpenSimplex(seed: 7, frequency: 0.5),
+                            Affine(input: 0, scale: 8.0, offset: 20.0),
+                        ],
+                        output: {output},
+                    )),
+                    blend_influence: 1.0,
+                    block_influence: 1.0,
+                )"#
+            )
+        };
+        let blocks = block_registry();
+        let name = RegistryName::new("test", "hills");
+        let biome = parse_biome_definition(&source(1), name.clone(), &blocks).unwrap();
+        let SurfaceNoise::Graph(graph) = &biome.surface_noise else {
+            panic!("Expected a noise graph");
+        };
+        assert_eq!(graph.nodes.len(), 2);
+        assert!(parse_biome_definition(&source(2), name, &blocks).is_err());
+    }
+
+    #[test]
+    fn load_from_directory() {
+        let root = std::env::temp_dir().join(format!("gs-biome-test-{}", std::process::id()));
+        let biome_dir = root.join("test").join(BIOME_DATA_SUBDIRECTORY);
+        fs::create_dir_all(&biome_dir).unwrap();
+        fs::write(
+            biome_dir.join("desert.ron"),
+            r#"(
+                representative_color: (r: 230, g: 210, b: 140, a: 255),
+                can_generate: true,
+                elevation: Closed(0.5, 1.5),
+                temperature: Left(3.0),
+                moisture: Right(1.0),
+                rule_source: Layered((
+                    top_block: (ns: "gs", key: "sand"),
+                    filler_block: (ns: "gs", key: "sand"),
+                    filler_depth: 6,
+                    stone_block: (ns: "gs", key: "stone"),
+                )),
+                surface_noise: Graph((
+                    nodes: [Terrain(frequency: 0.5), Affine(input: 0, scale: 4.0, offset: 12.0)],
+                )),
+                blend_influence: 0.5,
+                block_influence: 1.0,
+            )"#,
+        )
+        .unwrap();
+        fs::write(biome_dir.join("notes.txt"), "Not a biome").unwrap();
+
+        let blocks = block_registry();
+        let mut biomes = Registry::default();
+        setup_basic_biomes(&mut biomes);
+        let loaded = load_biome_directory(&mut biomes, &blocks, &root);
+
+        fs::write(
+            biome_dir.join("swamp.ron"),
+            r#"(
+                representative_color: (r: 0, g: 0, b: 0, a: 255),
+                can_generate: true,
+                elevation: Full,
+                temperature: Full,
+                moisture: Full,
+                rule_source: Layered((top_block: (ns: "test", key: "mud"))),
+                surface_noise: Graph((nodes: [Constant(0.0)])),
+                blend_influence: 1.0,
+                block_influence: 1.0,
+            )"#,
+        )
+        .unwrap();
+        let mut partial = Registry::default();
+        let unknown_block = load_biome_directory(&mut partial, &blocks, &root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(loaded.unwrap(), 1);
+        let (_, desert) = biomes
+            .lookup_name_to_object(RegistryName::new("test", "desert").as_ref())
+            .unwrap();
+        assert!(desert.can_generate);
+        assert!(desert.temperature.contains(4.0));
+        let BiomeRules::Layered(rules) = &desert.rule_source else {
+            panic!("Expected layered rules");
+        };
+        assert_eq!(rules.filler_depth, 6);
+        assert!(format!("{:#}", unknown_block.unwrap_err()).contains("Unknown block"));
+        // The desert loaded before the failing swamp isn't registered either.
+        assert!(partial.is_empty());
+    }
+}
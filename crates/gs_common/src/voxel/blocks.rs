@@ -18,6 +18,16 @@ pub const SNOWY_GRASS_BLOCK_NAME: RegistryName = RegistryName::gs_const("snowy_g
 pub const WATER_BLOCK_NAME: RegistryName = RegistryName::gs_const("water");
 /// Registry name for sand.
 pub const SAND_BLOCK_NAME: RegistryName = RegistryName::gs_const("sand");
+/// Registry name for coal ore.
+pub const COAL_ORE_BLOCK_NAME: RegistryName = RegistryName::gs_const("coal_ore");
+/// Registry name for iron ore.
+pub const IRON_ORE_BLOCK_NAME: RegistryName = RegistryName::gs_const("iron_ore");
+/// Registry name for logs.
+pub const LOG_BLOCK_NAME: RegistryName = RegistryName::gs_const("log");
+/// Registry name for leaves.
+pub const LEAVES_BLOCK_NAME: RegistryName = RegistryName::gs_const("leaves");
+/// Registry name for tall grass.
+pub const TALL_GRASS_BLOCK_NAME: RegistryName = RegistryName::gs_const("tall_grass");
 
 /// Installs the base set of blocks into the given block registry.
 pub fn setup_basic_blocks(registry: &mut BlockRegistry) {
@@ -76,4 +86,49 @@ pub fn setup_basic_blocks(registry: &mut BlockRegistry) {
             has_drawable_mesh: true,
         })
         .unwrap();
+    registry
+        .push_object(BlockDefinition {
+            name: COAL_ORE_BLOCK_NAME,
+            shape_set: StandardShapedMaterial,
+            representative_color: RGBA8::new(40, 40, 40, 255),
+            has_collision_box: true,
+            has_drawable_mesh: true,
+        })
+        .unwrap();
+    registry
+        .push_object(BlockDefinition {
+            name: IRON_ORE_BLOCK_NAME,
+            shape_set: StandardShapedMaterial,
+            representative_color: RGBA8::new(140, 110, 90, 255),
+            has_collision_box: true,
+            has_drawable_mesh: true,
+        })
+        .unwrap();
+    registry
+        .push_object(BlockDefinition {
+            name: LOG_BLOCK_NAME,
+            shape_set: StandardShapedMaterial,
+            representative_color: RGBA8::new(90, 60, 30, 255),
+            has_collision_box: true,
+            has_drawable_mesh: true,
+        })
+        .unwrap();
+    registry
+        .push_object(BlockDefinition {
+            name: LEAVES_BLOCK_NAME,
+            shape_set: StandardShapedMaterial,
+            representative_color: RGBA8::new(20, 120, 20, 255),
+            has_collision_box: true,
+            has_drawable_mesh: true,
+        })
+        .unwrap();
+    registry
+        .push_object(BlockDefinition {
+            name: TALL_GRASS_BLOCK_NAME,
+            shape_set: StandardShapedMaterial,
+            representative_color: RGBA8::new(60, 200, 40, 255),
+            has_collision_box: false,
+            has_drawable_mesh: true,
+        })
+        .unwrap();
 }
@@ -0,0 +1,420 @@
+//! Multi-block edit tools, shared between the client (for prediction) and the server.
+//!
+//! An [`EditOperation`] is turned into a plain batch of block changes by [`EditOperation::plan`], which only depends on
+//! the operation and the current contents of the [`ChunkGroup`], so both sides compute the same result.
+
+use std::ops::RangeInclusive;
+
+use bevy::math::IVec3;
+use gs_schemas::coordinates::AbsBlockPos;
+use gs_schemas::dependencies::itertools::iproduct;
+use gs_schemas::direction::{Direction, OctahedralOrientation};
+use gs_schemas::voxel::chunk_group::ChunkGroup;
+use gs_schemas::voxel::standard_shapes::{
+    StandardShapeMetadata, STANDARD_SHAPE_CORNER, STANDARD_SHAPE_CUBE, STANDARD_SHAPE_INNER_CORNER,
+    STANDARD_SHAPE_SLOPE,
+};
+use gs_schemas::voxel::voxeltypes::{BlockEntry, BlockRegistry, BlockShapeSet};
+use gs_schemas::GsExtraData;
+use smallvec::SmallVec;
+
+use crate::prelude::*;
+
+/// The maximum distance (in blocks) from the player's eyes at which blocks can be targeted.
+pub const BLOCK_REACH: f32 = 16.0;
+/// The maximum distance (in blocks) from the player's eyes of a block changed by an edit, covering the longest tool.
+pub const MAX_EDIT_DISTANCE: f32 = BLOCK_REACH + *LINE_SIZES.end() as f32 + 1.0;
+
+/// Supported edge lengths of the cubic brush.
+pub const BRUSH_SIZES: RangeInclusive<u8> = 2..=4;
+/// Supported lengths of the line tool.
+pub const LINE_SIZES: RangeInclusive<u8> = 1..=64;
+/// Supported edge lengths of the plane tool.
+pub const PLANE_SIZES: RangeInclusive<u8> = 1..=16;
+/// Supported radii of the smoothed area, counting the anchor column.
+pub const SMOOTH_SIZES: RangeInclusive<u8> = 1..=5;
+/// The largest number of blocks a single [`EditOperation`] can change.
+pub const MAX_OPERATION_EDITS: usize = *PLANE_SIZES.end() as usize * *PLANE_SIZES.end() as usize;
+/// The largest distance along any axis between two blocks changed by a single [`EditOperation`].
+pub const MAX_OPERATION_EXTENT: i32 = *LINE_SIZES.end() as i32 - 1;
+
+/// The horizontal directions considered when smoothing, in a fixed order.
+const HORIZONTAL_DIRECTIONS: [Direction; 4] =
+    [Direction::XMinus, Direction::XPlus, Direction::ZMinus, Direction::ZPlus];
+
+/// A multi-block editing tool.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub enum EditTool {
+    /// A cube of `size`³ blocks, extending from the anchor along the orientation's right, up and front directions.
+    #[default]
+    CubicBrush,
+    /// A line of `size` blocks, extending from the anchor along the orientation's front direction.
+    Line,
+    /// A `size`×`size` square, extending from the anchor along the orientation's right and front directions.
+    Plane,
+    /// Reshapes the top surface of the terrain with slopes and corners, in a square area centered on the anchor
+    /// with `2 * size - 1` blocks long edges.
+    /// Keeps the materials of the existing blocks, ignoring the block of the operation.
+    Smooth,
+}
+
+impl EditTool {
+    /// All the tools, in cycling order.
+    pub const ALL: [EditTool; 4] = [Self::CubicBrush, Self::Line, Self::Plane, Self::Smooth];
+
+    /// The range of sizes supported by the tool.
+    pub fn sizes(self) -> RangeInclusive<u8> {
+        match self {
+            Self::CubicBrush => BRUSH_SIZES,
+            Self::Line => LINE_SIZES,
+            Self::Plane => PLANE_SIZES,
+            Self::Smooth => SMOOTH_SIZES,
+        }
+    }
+}
+
+/// A single application of an [`EditTool`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct EditOperation {
+    /// The tool used.
+    pub tool: EditTool,
+    /// The size of the tool in blocks, see [`EditTool::sizes`].
+    pub size: u8,
+    /// The block the tool is applied at.
+    pub anchor: AbsBlockPos,
+    /// The orientation of the tool's extent, and of the placed standard-shaped blocks.
+    pub orientation: OctahedralOrientation,
+    /// The block to fill with, its metadata is used as-is.
+    pub block: BlockEntry,
+}
+
+impl EditOperation {
+    /// Computes the batch of block changes for this operation, in a deterministic order.
+    /// Positions in chunks that are not loaded and changes that would not modify the block are left out.
+    pub fn plan<ED: GsExtraData>(
+        &self,
+        group: &ChunkGroup<ED>,
+        registry: &BlockRegistry,
+    ) -> Result<Vec<(AbsBlockPos, BlockEntry)>> {
+        let sizes = self.tool.sizes();
+        ensure!(
+            sizes.contains(&self.size),
+            "Size {} is not in the supported range {sizes:?} of {:?}",
+            self.size,
+            self.tool
+        );
+        let size = self.size as i32;
+        let right = self.orientation.right().to_ivec();
+        let up = self.orientation.up().to_ivec();
+        let front = self.orientation.front().to_ivec();
+
+        let offsets: Vec<IVec3> = match self.tool {
+            EditTool::CubicBrush => iproduct!(0..size, 0..size, 0..size)
+                .map(|(u, f, r)| u * up + f * front + r * right)
+                .collect(),
+            EditTool::Line => (0..size).map(|f| f * front).collect(),
+            EditTool::Plane => iproduct!(0..size, 0..size)
+                .map(|(f, r)| f * front + r * right)
+                .collect(),
+            EditTool::Smooth => return Ok(self.plan_smoothing(group, registry)),
+        };
+
+        Ok(offsets
+            .into_iter()
+            .map(|offset| self.anchor + offset.into())
+            .filter(|&pos| group.get_block(pos).is_some_and(|old| old != self.block))
+            .map(|pos| (pos, self.block))
+            .collect())
+    }
+
+    fn plan_smoothing<ED: GsExtraData>(
+        &self,
+        group: &ChunkGroup<ED>,
+        registry: &BlockRegistry,
+    ) -> Vec<(AbsBlockPos, BlockEntry)> {
+        let size = self.size as i32;
+        let edge = 2 * size - 1;
+        let min = self.anchor.into_ivec3() - IVec3::new(size - 1, 0, size - 1);
+        let is_shapeable = |block: BlockEntry| {
+            block
+                .lookup(registry)
+                .is_some_and(|def| def.has_collision_box && def.shape_set == BlockShapeSet::StandardShapedMaterial)
+        };
+        let is_solid = |pos: IVec3| {
+            group
+                .get_block(pos.into())
+                .and_then(|block| block.lookup(registry))
+                .is_some_and(|def| def.has_collision_box)
+        };
+        // Height of the topmost solid block of a column within the vertical search range around the anchor,
+        // or the range limits for columns that are fully solid or fully empty.
+        let search_low = self.anchor.y - size - 1;
+        let search_high = self.anchor.y + size + 1;
+        let height = |x: i32, z: i32| -> i32 {
+            (search_low..=search_high)
+                .rev()
+                .find(|&y| is_solid(IVec3::new(x, y, z)))
+                .map(|y| if y == search_high { i32::MAX } else { y })
+                .unwrap_or(i32::MIN)
+        };
+
+        let mut edits = Vec::new();
+        for (z, x) in iproduct!(min.z..min.z + edge, min.x..min.x + edge) {
+            let h = height(x, z);
+            if h == i32::MAX || h == i32::MIN {
+                continue;
+            }
+            let pos = AbsBlockPos::new(x, h, z);
+            let Some(old) = group.get_block(pos).filter(|&b| is_shapeable(b)) else {
+                continue;
+            };
+            let is_lower = |offset: IVec3| height(x + offset.x, z + offset.z) < h;
+            let lower: SmallVec<[Direction; 4]> = HORIZONTAL_DIRECTIONS
+                .into_iter()
+                .filter(|d| is_lower(d.to_ivec()))
+                .collect();
+            let (shape, orientation) = match lower.as_slice() {
+                [] => {
+                    let lower_diagonals: SmallVec<[(Direction, Direction); 4]> = iproduct!(
+                        [Direction::XMinus, Direction::XPlus],
+                        [Direction::ZMinus, Direction::ZPlus]
+                    )
+                    .filter(|(a, b)| is_lower(a.to_ivec() + b.to_ivec()))
+                    .collect();
+                    match lower_diagonals.as_slice() {
+                        &[(a, b)] => (STANDARD_SHAPE_INNER_CORNER, inner_corner_orientation(a, b)),
+                        _ => (STANDARD_SHAPE_CUBE, OctahedralOrientation::default()),
+                    }
+                }
+                &[d] => (STANDARD_SHAPE_SLOPE, slope_orientation(d)),
+                &[a, b] if a.to_axis_index() != b.to_axis_index() => (STANDARD_SHAPE_CORNER, corner_orientation(a, b)),
+                _ => (STANDARD_SHAPE_CUBE, OctahedralOrientation::default()),
+            };
+            let meta = StandardShapeMetadata::from_parts(shape, orientation.to_index() as u16)
+                .unwrap()
+                .to_meta();
+            let new = BlockEntry::new(old.id, meta);
+            if new != old {
+                edits.push((pos, new));
+            }
+        }
+        edits
+    }
+}
+
+/// Checks that a batch of block changes received from a client could have been planned by a single [`EditOperation`]:
+/// it is not larger than the largest tool, changes every block at most once and fits in the extent of the longest tool.
+pub fn validate_edit_batch<B>(edits: &[(AbsBlockPos, B)]) -> Result<()> {
+    ensure!(
+        edits.len() <= MAX_OPERATION_EDITS,
+        "{} block changes are more than any tool makes ({MAX_OPERATION_EDITS})",
+        edits.len()
+    );
+    let Some(&(first, _)) = edits.first() else {
+        return Ok(());
+    };
+    let (min, max) = edits
+        .iter()
+        .fold((first.into_ivec3(), first.into_ivec3()), |(min, max), (pos, _)| {
+            (min.min(pos.into_ivec3()), max.max(pos.into_ivec3()))
+        });
+    let extent = (max - min).max_element();
+    ensure!(
+        extent <= MAX_OPERATION_EXTENT,
+        "The block changes span {extent} blocks, more than any tool reaches ({MAX_OPERATION_EXTENT})"
+    );
+    let unique = edits.iter().map(|(pos, _)| *pos).collect::<HashSet<_>>().len();
+    ensure!(
+        unique == edits.len(),
+        "{} block changes target an already changed block",
+        edits.len() - unique
+    );
+    Ok(())
+}
+
+/// The orientation of a slope descending towards the given horizontal direction.
+pub fn slope_orientation(descending: Direction) -> OctahedralOrientation {
+    // The unrotated slope descends towards Z-, i.e. away from its front.
+    OctahedralOrientation::from_up_front(Direction::UP, descending.opposite()).unwrap_or_default()
+}
+
+/// The orientation of an outer corner descending towards both given perpendicular horizontal directions.
+pub fn corner_orientation(a: Direction, b: Direction) -> OctahedralOrientation {
+    // The unrotated corner descends towards X+ and Z-, i.e. towards its right and away from its front.
+    OctahedralOrientation::try_from_directions(a, Direction::UP, b.opposite())
+        .or_else(|| OctahedralOrientation::try_from_directions(b, Direction::UP, a.opposite()))
+        .unwrap_or_default()
+}
+
+/// The orientation of an inner corner with its lowest point towards both given perpendicular horizontal directions.
+pub fn inner_corner_orientation(a: Direction, b: Direction) -> OctahedralOrientation {
+    // The unrotated inner corner is lowest at X- Z+, i.e. away from its right and towards its front.
+    OctahedralOrientation::try_from_directions(a.opposite(), Direction::UP, b)
+        .or_else(|| OctahedralOrientation::try_from_directions(b.opposite(), Direction::UP, a))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::direction::ALL_DIRECTIONS;
+    use gs_schemas::voxel::standard_shapes::{VOXEL_CORNER_SHAPE, VOXEL_INNER_CORNER_SHAPE, VOXEL_SLOPE_SHAPE};
+    use gs_schemas::voxel::voxeltypes::EMPTY_BLOCK_NAME;
+    use gs_schemas::GameSide;
+
+    use super::*;
+    use crate::builtin_game_registries;
+    use crate::test_utils::{block, flat_chunk_group};
+    use crate::voxel::blocks::{DIRT_BLOCK_NAME, STONE_BLOCK_NAME};
+
+    fn operation(tool: EditTool, size: u8, anchor: AbsBlockPos, block: BlockEntry) -> EditOperation {
+        EditOperation {
+            tool,
+            size,
+            anchor,
+            orientation: OctahedralOrientation::default(),
+            block,
+        }
+    }
+
+    #[test]
+    fn brush_sizes() {
+        let registry = builtin_game_registries().block_types;
+        let group = flat_chunk_group(&registry);
+        let stone = block(&registry, &STONE_BLOCK_NAME);
+        for size in BRUSH_SIZES {
+            let edits = operation(EditTool::CubicBrush, size, AbsBlockPos::new(0, 16, 0), stone)
+                .plan(&group, &registry)
+                .unwrap();
+            assert_eq!(edits.len(), (size as usize).pow(3));
+            assert_eq!(edits.iter().map(|e| e.0).collect::<HashSet<_>>().len(), edits.len());
+        }
+        assert!(operation(EditTool::CubicBrush, 5, AbsBlockPos::ZERO, stone)
+            .plan(&group, &registry)
+            .is_err());
+    }
+
+    #[test]
+    fn line_and_plane() {
+        let registry = builtin_game_registries().block_types;
+        let group = flat_chunk_group(&registry);
+        let stone = block(&registry, &STONE_BLOCK_NAME);
+        let dirt = block(&registry, &DIRT_BLOCK_NAME);
+
+        let mut line = operation(EditTool::Line, 4, AbsBlockPos::new(0, 16, 0), stone);
+        let edits = line.plan(&group, &registry).unwrap();
+        let expected: Vec<_> = (0..4).map(|z| (AbsBlockPos::new(0, 16, z), stone)).collect();
+        assert_eq!(edits, expected);
+        line.orientation = OctahedralOrientation::from_up_front(Direction::UP, Direction::XMinus).unwrap();
+        let edits = line.plan(&group, &registry).unwrap();
+        let expected: Vec<_> = (0..4).map(|x| (AbsBlockPos::new(-x, 16, 0), stone)).collect();
+        assert_eq!(edits, expected);
+
+        // Unchanged blocks are left out.
+        let plane = operation(EditTool::Plane, 5, AbsBlockPos::new(-2, 15, -2), stone);
+        assert!(plane.plan(&group, &registry).unwrap().is_empty());
+        let plane = EditOperation { block: dirt, ..plane };
+        let edits = plane.plan(&group, &registry).unwrap();
+        assert_eq!(edits.len(), 25);
+        assert!(edits.iter().all(|&(pos, _)| pos.y == 15));
+    }
+
+    #[test]
+    fn smoothing_a_ledge() {
+        let registry = builtin_game_registries().block_types;
+        let mut group = flat_chunk_group(&registry);
+        let stone = block(&registry, &STONE_BLOCK_NAME);
+        // A one block high ledge covering x >= 0.
+        let ledge: Vec<_> = iproduct!(0..8, -8..8)
+            .map(|(x, z)| (AbsBlockPos::new(x, 16, z), stone))
+            .collect();
+        group.apply_block_edits(&ledge, GameSide::Server);
+
+        let smooth = operation(EditTool::Smooth, 2, AbsBlockPos::new(0, 16, 0), stone);
+        let edits = smooth.plan(&group, &registry).unwrap();
+        assert_eq!(edits, smooth.plan(&group, &registry).unwrap());
+        let slope_meta = StandardShapeMetadata::from_parts(
+            STANDARD_SHAPE_SLOPE,
+            slope_orientation(Direction::XMinus).to_index() as u16,
+        )
+        .unwrap()
+        .to_meta();
+        let expected: Vec<_> = (-1..=1)
+            .map(|z| (AbsBlockPos::new(0, 16, z), BlockEntry::new(stone.id, slope_meta)))
+            .collect();
+        assert_eq!(edits, expected);
+    }
+
+    #[test]
+    fn smoothing_is_centered() {
+        let registry = builtin_game_registries().block_types;
+        let mut group = flat_chunk_group(&registry);
+        let stone = block(&registry, &STONE_BLOCK_NAME);
+        let empty = block(&registry, &EMPTY_BLOCK_NAME);
+        // A staircase rising towards X+, where every column gets smoothed into a slope.
+        let stairs: Vec<_> = iproduct!(-8..8, -8..8, 8..24)
+            .map(|(x, z, y)| (AbsBlockPos::new(x, y, z), if y <= 16 + x { stone } else { empty }))
+            .collect();
+        group.apply_block_edits(&stairs, GameSide::Server);
+        for size in SMOOTH_SIZES {
+            let smooth = operation(EditTool::Smooth, size, AbsBlockPos::new(0, 16, 0), stone);
+            let edits = smooth.plan(&group, &registry).unwrap();
+            let reach = size as i32 - 1;
+            assert_eq!(edits.len(), (2 * reach as usize + 1).pow(2));
+            assert!(edits
+                .iter()
+                .all(|(pos, _)| pos.x.abs() <= reach && pos.z.abs() <= reach));
+        }
+    }
+
+    #[test]
+    fn planned_batches_are_valid() {
+        let registry = builtin_game_registries().block_types;
+        let group = flat_chunk_group(&registry);
+        let stone = block(&registry, &STONE_BLOCK_NAME);
+        let dirt = block(&registry, &DIRT_BLOCK_NAME);
+        for tool in EditTool::ALL {
+            let size = *tool.sizes().end();
+            let edits = operation(tool, size, AbsBlockPos::new(-8, 15, -8), dirt)
+                .plan(&group, &registry)
+                .unwrap();
+            validate_edit_batch(&edits).unwrap();
+        }
+
+        let far = [(AbsBlockPos::new(0, 0, 0), stone), (AbsBlockPos::new(0, 200, 0), stone)];
+        assert!(validate_edit_batch(&far).is_err());
+        let repeated = [(AbsBlockPos::new(0, 0, 0), stone), (AbsBlockPos::new(0, 0, 0), dirt)];
+        assert!(validate_edit_batch(&repeated).is_err());
+        let many: Vec<_> = (0..MAX_OPERATION_EDITS as i32 + 1)
+            .map(|i| (AbsBlockPos::new(i % 32, 0, i / 32), stone))
+            .collect();
+        assert!(validate_edit_batch(&many).is_err());
+    }
+
+    #[test]
+    fn shape_orientations_match_meshes() {
+        for dir in ALL_DIRECTIONS.into_iter().filter(|d| d.to_ivec().y == 0) {
+            let o = slope_orientation(dir);
+            assert!(VOXEL_SLOPE_SHAPE.sides[o.unapply_to_dir(dir.opposite()).to_index()].can_clip);
+            assert!(!VOXEL_SLOPE_SHAPE.sides[o.unapply_to_dir(dir).to_index()].can_clip);
+            assert_eq!(o.up(), Direction::UP);
+        }
+        for (a, b) in iproduct!(
+            [Direction::XMinus, Direction::XPlus],
+            [Direction::ZMinus, Direction::ZPlus]
+        ) {
+            let o = corner_orientation(a, b);
+            assert_eq!(o.up(), Direction::UP);
+            assert!(VOXEL_CORNER_SHAPE.sides[o.unapply_to_dir(a).to_index()]
+                .indices
+                .is_empty());
+            assert!(VOXEL_CORNER_SHAPE.sides[o.unapply_to_dir(b).to_index()]
+                .indices
+                .is_empty());
+            let o = inner_corner_orientation(a, b);
+            assert_eq!(o.up(), Direction::UP);
+            assert!(VOXEL_INNER_CORNER_SHAPE.sides[o.unapply_to_dir(a.opposite()).to_index()].can_clip);
+            assert!(VOXEL_INNER_CORNER_SHAPE.sides[o.unapply_to_dir(b.opposite()).to_index()].can_clip);
+            assert!(!VOXEL_INNER_CORNER_SHAPE.sides[o.unapply_to_dir(a).to_index()].can_clip);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! The optional 3D density stage of the world generator, turning the biome heightmap into terrain with overhangs,
+//! and carving caves out of it.
+//!
+//! Density is positive in solid ground: the distance below the heightmap surface, displaced by 3D noise.
+//! Caves are carved in two styles: large "cheese" caverns where a 3D noise exceeds a threshold, and long "noodle"
+//! tunnels along the intersections of the zero surfaces of two 3D noises.
+
+use std::ops::RangeInclusive;
+
+use bevy_math::{IVec2, IVec3};
+use gs_schemas::coordinates::{CHUNK_DIM, CHUNK_DIMZ};
+use gs_schemas::voxel::generation::noise_graph::{
+    CurvePoint, NoiseGraph, NoiseGraphCache, NoiseGraphDefinition, NoiseGraphError, NoiseNode,
+};
+use serde::{Deserialize, Serialize};
+
+/// How many blocks above a chunk are sampled to find the surface over its topmost blocks.
+const SURFACE_LOOKAHEAD: i32 = 8;
+
+/// 3D noise displacing the heightmap surface, creating overhangs and arches.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OverhangSettings {
+    /// The displacement noise, clamped to the -1..1 range.
+    pub noise: NoiseGraphDefinition,
+    /// The largest displacement of the surface, in blocks.
+    pub strength: f64,
+}
+
+/// Large caverns, carved where the noise exceeds the threshold.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CheeseCaveSettings {
+    /// The cavern noise.
+    pub noise: NoiseGraphDefinition,
+    /// The noise value above which the ground is carved out.
+    pub threshold: f64,
+}
+
+/// Long tunnels, carved where both noises are close to zero.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NoodleCaveSettings {
+    /// The first tunnel noise.
+    pub first: NoiseGraphDefinition,
+    /// The second tunnel noise.
+    pub second: NoiseGraphDefinition,
+    /// How close to zero both noises have to be for the ground to be carved out, controlling the tunnel width.
+    pub thickness: f64,
+}
+
+/// Configuration of the 3D density stage.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TerrainDensitySettings {
+    /// Surface displacement, or `None` for a pure heightmap.
+    pub overhangs: Option<OverhangSettings>,
+    /// Cavern carver, if any.
+    pub cheese_caves: Option<CheeseCaveSettings>,
+    /// Tunnel carver, if any.
+    pub noodle_caves: Option<NoodleCaveSettings>,
+    /// How many blocks under the heightmap surface caves start, so that they only occasionally break through it.
+    pub cave_roof_depth: i32,
+}
+
+fn volumetric_fbm(seed: u32, frequency: f64) -> NoiseGraphDefinition {
+    let mut graph = NoiseGraphDefinition::default();
+    graph.push(NoiseNode::Fbm {
+        seed,
+        octaves: vec![1.0, 1.0, 0.5],
+        frequency,
+        lacunarity: 2.0,
+        persistence: 0.5,
+        volumetric: true,
+    });
+    graph
+}
+
+/// Tunnel noise, domain warped so that the tunnels wind around.
+fn tunnel_noise(seed: u32) -> NoiseGraphDefinition {
+    let mut graph = NoiseGraphDefinition::default();
+    let tunnels = graph.push(NoiseNode::OpenSimplex {
+        seed,
+        frequency: 0.012,
+        volumetric: true,
+    });
+    let warp = graph.push(NoiseNode::OpenSimplex {
+        seed: seed.wrapping_add(1),
+        frequency: 0.02,
+        volumetric: true,
+    });
+    graph.push(NoiseNode::DomainWarp {
+        input: tunnels,
+        x: warp,
+        y: None,
+        z: warp,
+        strength: 8.0,
+    });
+    graph
+}
+
+impl Default for TerrainDensitySettings {
+    fn default() -> Self {
+        let mut overhang_noise = volumetric_fbm(0x0be4, 0.02);
+        let base = overhang_noise.output().unwrap();
+        // Keep most of the surface close to the heightmap, with occasional strong displacement.
+        overhang_noise.push(NoiseNode::Curve {
+            input: base,
+            points: vec![
+                CurvePoint { x: -1.0, y: -1.0 },
+                CurvePoint { x: -0.3, y: -0.1 },
+                CurvePoint { x: 0.3, y: 0.1 },
+                CurvePoint { x: 1.0, y: 1.0 },
+            ],
+        });
+        Self {
+            overhangs: Some(OverhangSettings {
+                noise: overhang_noise,
+                strength: 12.0,
+            }),
+            cheese_caves: Some(CheeseCaveSettings {
+                noise: volumetric_fbm(0xc4ee5e, 0.015),
+                threshold: 0.55,
+            }),
+            noodle_caves: Some(NoodleCaveSettings {
+                first: tunnel_noise(0x0d1e),
+                second: tunnel_noise(0x0d2e),
+                thickness: 0.06,
+            }),
+            cave_roof_depth: 6,
+        }
+    }
+}
+
+/// The 3D density stage, with its noise graphs built for a world seed.
+pub struct TerrainDensity {
+    overhangs: Option<(NoiseGraph, f64)>,
+    cheese_caves: Option<(NoiseGraph, f64)>,
+    noodle_caves: Option<(NoiseGraph, NoiseGraph, f64)>,
+    cave_roof_depth: i32,
+}
+
+impl TerrainDensity {
+    /// Builds all the noise graphs of the settings with the given world seed.
+    pub fn new(settings: &TerrainDensitySettings, seed: u32) -> Result<Self, NoiseGraphError> {
+        Ok(Self {
+            overhangs: match &settings.overhangs {
+                Some(o) => Some((NoiseGraph::new(&o.noise, seed)?, o.strength)),
+                None => None,
+            },
+            cheese_caves: match &settings.cheese_caves {
+                Some(c) => Some((NoiseGraph::new(&c.noise, seed)?, c.threshold)),
+                None => None,
+            },
+            noodle_caves: match &settings.noodle_caves {
+                Some(n) => Some((
+                    NoiseGraph::new(&n.first, seed)?,
+                    NoiseGraph::new(&n.second, seed)?,
+                    n.thickness,
+                )),
+                None => None,
+            },
+            cave_roof_depth: settings.cave_roof_depth,
+        })
+    }
+
+    /// Is the block at the given position solid ground, in a column with the given heightmap surface?
+    pub fn is_solid(&self, pos: IVec3, ground_y: i32) -> bool {
+        self.column_solidity(IVec2::new(pos.x, pos.z), pos.y..=pos.y, ground_y)[0]
+    }
+
+    /// Whether each block of the Y range of a column is solid ground, in a column with the given heightmap surface.
+    /// The overhang noise is sampled for the whole range in one batch.
+    fn column_solidity(&self, column: IVec2, ys: RangeInclusive<i32>, ground_y: i32) -> Vec<bool> {
+        let Some((noise, strength)) = &self.overhangs else {
+            return ys.map(|y| y <= ground_y).collect();
+        };
+        // The clamped noise can't flip the sign of the density further than `strength` from the surface.
+        let reach = strength.floor() as i32;
+        let displaced = (ground_y - reach).max(*ys.start())..=(ground_y + reach).min(*ys.end());
+        let displacements = noise.sample_column(
+            column.as_dvec2(),
+            displaced.clone().map(f64::from),
+            &mut NoiseGraphCache::default(),
+        );
+        ys.map(|y| {
+            let depth = (ground_y - y) as f64;
+            if displaced.contains(&y) {
+                depth + displacements[(y - displaced.start()) as usize].clamp(-1.0, 1.0) * strength >= 0.0
+            } else {
+                depth >= 0.0
+            }
+        })
+        .collect()
+    }
+
+    /// The local surface level for each block of the column in the chunk starting at `bottom_y`, indexed by the Y
+    /// coordinate in the chunk: the top of the run of solid blocks containing the block, or the Y of the block
+    /// under it for empty blocks.
+    pub fn column_ground_levels(&self, column: IVec2, bottom_y: i32, ground_y: i32) -> [i32; CHUNK_DIMZ] {
+        let mut levels = [0; CHUNK_DIMZ];
+        let top_y = bottom_y + CHUNK_DIM - 1;
+        // Solid runs reaching above the lookahead are treated as reaching up to the heightmap surface or above.
+        let mut surface = ground_y.max(top_y + SURFACE_LOOKAHEAD);
+        let mut above_solid = true;
+        let solidity = self.column_solidity(column, bottom_y..=top_y + SURFACE_LOOKAHEAD, ground_y);
+        for y in (bottom_y..=top_y + SURFACE_LOOKAHEAD).rev() {
+            let solid = solidity[(y - bottom_y) as usize];
+            if solid && !above_solid {
+                surface = y;
+            }
+            above_solid = solid;
+            if y <= top_y {
+                levels[(y - bottom_y) as usize] = if solid { surface } else { y - 1 };
+            }
+        }
+        levels
+    }
+
+    /// Is the block at the given position inside a cave, under the given local ground level?
+    pub fn is_cave(&self, pos: IVec3, ground_y: i32) -> bool {
+        pos.y <= ground_y - self.cave_roof_depth && self.carved(IVec2::new(pos.x, pos.z), &[pos.y])[0]
+    }
+
+    /// Whether each block of the column in the chunk starting at `bottom_y` is inside a cave, indexed by the Y
+    /// coordinate in the chunk, given the local ground levels from [`Self::column_ground_levels`].
+    pub fn column_caves(&self, column: IVec2, bottom_y: i32, ground_levels: &[i32; CHUNK_DIMZ]) -> [bool; CHUNK_DIMZ] {
+        let mut caves = [false; CHUNK_DIMZ];
+        let (indices, ys): (Vec<usize>, Vec<i32>) = ground_levels
+            .iter()
+            .enumerate()
+            .map(|(i, &ground_y)| (i, bottom_y + i as i32, ground_y))
+            .filter(|&(_, y, ground_y)| y <= ground_y - self.cave_roof_depth)
+            .map(|(i, y, _)| (i, y))
+            .unzip();
+        for (i, carved) in indices.into_iter().zip(self.carved(column, &ys)) {
+            caves[i] = carved;
+        }
+        caves
+    }
+
+    /// Whether the cave noises carve out each of the given blocks of a column, ignoring the cave roof.
+    /// Each noise is sampled for all the blocks in one batch.
+    fn carved(&self, column: IVec2, ys: &[i32]) -> Vec<bool> {
+        let mut carved = vec![false; ys.len()];
+        let column_pos = column.as_dvec2();
+        let heights = || ys.iter().map(|&y| f64::from(y));
+        if let Some((noise, threshold)) = &self.cheese_caves {
+            let values = noise.sample_column(column_pos, heights(), &mut NoiseGraphCache::default());
+            for (carved, value) in carved.iter_mut().zip(values) {
+                *carved |= value > *threshold;
+            }
+        }
+        if let Some((first, second, thickness)) = &self.noodle_caves {
+            let first = first.sample_column(column_pos, heights(), &mut NoiseGraphCache::default());
+            let second = second.sample_column(column_pos, heights(), &mut NoiseGraphCache::default());
+            for (carved, (a, b)) in carved.iter_mut().zip(first.into_iter().zip(second)) {
+                *carved |= a.abs() < *thickness && b.abs() < *thickness;
+            }
+        }
+        carved
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn heightmap_without_overhangs() {
+        let density = TerrainDensity::new(
+            &TerrainDensitySettings {
+                overhangs: None,
+                cheese_caves: None,
+                noodle_caves: None,
+                cave_roof_depth: 0,
+            },
+            1,
+        )
+        .unwrap();
+        assert!(density.is_solid(IVec3::new(0, 10, 0), 10));
+        assert!(!density.is_solid(IVec3::new(0, 11, 0), 10));
+        assert!(!density.is_cave(IVec3::new(0, -100, 0), 10));
+
+        let levels = density.column_ground_levels(IVec2::ZERO, 0, 10);
+        assert_eq!(levels[10], 10);
+        assert_eq!(levels[0], 10);
+        assert_eq!(levels[11], 10);
+        assert_eq!(levels[20], 19);
+        let buried = density.column_ground_levels(IVec2::ZERO, -64, 10);
+        assert!(buried.iter().all(|&level| level >= 10));
+    }
+
+    #[test]
+    fn flat_overhangs_keep_the_heightmap() {
+        let mut noise = NoiseGraphDefinition::default();
+        noise.push(NoiseNode::Constant(0.0));
+        let density = TerrainDensity::new(
+            &TerrainDensitySettings {
+                overhangs: Some(OverhangSettings { noise, strength: 4.0 }),
+                cheese_caves: None,
+                noodle_caves: None,
+                cave_roof_depth: 0,
+            },
+            1,
+        )
+        .unwrap();
+        // The surface block stays solid just like without overhangs.
+        assert!(density.is_solid(IVec3::new(0, 10, 0), 10));
+        assert!(!density.is_solid(IVec3::new(0, 11, 0), 10));
+    }
+
+    #[test]
+    fn column_levels_match_single_blocks() {
+        let density = TerrainDensity::new(&TerrainDensitySettings::default(), 3).unwrap();
+        for x in 0..8 {
+            let column = IVec2::new(x * 7, -x);
+            let levels = density.column_ground_levels(column, -8, 0);
+            for (i, &level) in levels.iter().enumerate() {
+                let y = i as i32 - 8;
+                let solid = density.is_solid(IVec3::new(column.x, y, column.y), 0);
+                assert_eq!(solid, level >= y, "column {column} y {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn deterministic_per_seed() {
+        let settings = TerrainDensitySettings::default();
+        let a = TerrainDensity::new(&settings, 42).unwrap();
+        let b = TerrainDensity::new(&settings, 42).unwrap();
+        let c = TerrainDensity::new(&settings, 43).unwrap();
+        let mut differs = false;
+        for x in 0..32 {
+            for y in -12..12 {
+                let pos = IVec3::new(x * 3, y, x * 5);
+                assert_eq!(a.is_solid(pos, 0), b.is_solid(pos, 0));
+                assert_eq!(a.is_cave(pos - IVec3::Y * 32, 0), b.is_cave(pos - IVec3::Y * 32, 0));
+                differs |= a.is_solid(pos, 0) != c.is_solid(pos, 0);
+            }
+        }
+        assert!(differs);
+    }
+
+    #[test]
+    fn cave_roofs_follow_the_local_ground() {
+        let mut noise = NoiseGraphDefinition::default();
+        noise.push(NoiseNode::Constant(1.0));
+        let density = TerrainDensity::new(
+            &TerrainDensitySettings {
+                overhangs: None,
+                cheese_caves: Some(CheeseCaveSettings { noise, threshold: 0.5 }),
+                noodle_caves: None,
+                cave_roof_depth: 2,
+            },
+            1,
+        )
+        .unwrap();
+        // An overhang with its underside at Y 8, over ground at Y 3.
+        let levels: [i32; CHUNK_DIMZ] = std::array::from_fn(|y| if y < 8 { 3 } else { 12 });
+        let caves = density.column_caves(IVec2::ZERO, 0, &levels);
+        for (y, &cave) in caves.iter().enumerate() {
+            assert_eq!(cave, y as i32 <= levels[y] - 2, "y {y}");
+            assert_eq!(cave, density.is_cave(IVec3::new(0, y as i32, 0), levels[y]));
+        }
+    }
+
+    #[test]
+    fn caves_stay_under_the_roof() {
+        let density = TerrainDensity::new(&TerrainDensitySettings::default(), 7).unwrap();
+        for x in 0..64 {
+            for y in -5..20 {
+                assert!(!density.is_cave(IVec3::new(x, y, 0), 0));
+            }
+        }
+    }
+}
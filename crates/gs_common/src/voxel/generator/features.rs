@@ -0,0 +1,754 @@
+//! The decoration stage of world generation, placing the biome features and structures on top of the generated
+//! terrain.
+//!
+//! Features are placed per origin chunk, from that chunk's terrain and a positional random source only, so the result
+//! doesn't depend on the order chunks are generated in. Each feature of a biome has its own random source, salted with
+//! the biome and the feature's index, so that adding a feature doesn't move the others. Blocks of features spilling
+//! into the neighbouring chunks are kept as pending writes until those chunks are generated.
+
+use std::num::NonZeroUsize;
+
+use bevy_math::IVec3;
+use gs_schemas::coordinates::{AbsBlockPos, AbsChunkPos, InChunkPos, CHUNK_DIM};
+use gs_schemas::dependencies::itertools::iproduct;
+use gs_schemas::direction::{Direction, OctahedralOrientation};
+use gs_schemas::range::Range;
+use gs_schemas::registry::{RegistryId, RegistryName};
+use gs_schemas::voxel::biome::feature::{BiomeFeature, FeaturePlacement, FeatureShape, MAX_FEATURE_REACH};
+use gs_schemas::voxel::biome::BiomeRegistry;
+use gs_schemas::voxel::chunk_storage::{ChunkStorage, PaletteStorage};
+use gs_schemas::voxel::generation::positional_random::PositionalRandomFactory;
+use gs_schemas::voxel::structure::StructureTemplate;
+use gs_schemas::voxel::voxeltypes::{BlockEntry, BlockRegistry};
+use lru::LruCache;
+use rand::Rng;
+use rand_xoshiro::Xoshiro256StarStar;
+use tracing::warn;
+
+use crate::prelude::*;
+
+/// The terrain of a chunk before decoration.
+pub struct TerrainChunk {
+    /// The terrain blocks.
+    pub blocks: PaletteStorage<BlockEntry>,
+    /// The biome with the strongest influence on each column, indexed by `x + z * CHUNK_DIM`.
+    pub biomes: Vec<RegistryId>,
+}
+
+/// Which blocks a feature block can replace.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplaceRule {
+    /// Only empty space.
+    Empty,
+    /// Only the given blocks.
+    AnyOf(Arc<[BlockEntry]>),
+    /// Any block.
+    Always,
+}
+
+/// A single block placed by a feature.
+#[derive(Clone, Debug)]
+pub struct FeatureWrite {
+    /// The position of the block.
+    pub pos: AbsBlockPos,
+    /// The placed block.
+    pub block: BlockEntry,
+    /// The blocks it can replace.
+    pub replace: ReplaceRule,
+    /// The chunk with the feature's origin and the index of the write among the writes of that chunk,
+    /// to apply overlapping writes in the same order regardless of the generation order.
+    order: (AbsChunkPos, u32),
+}
+
+struct ResolvedFeature {
+    /// The salt of the feature's positional random source.
+    salt: u64,
+    frequency: f64,
+    height: Range<i32>,
+    surface: bool,
+    anchors: Arc<[BlockEntry]>,
+    shape: ResolvedShape,
+}
+
+enum ResolvedShape {
+    OreVein {
+        block: BlockEntry,
+        size: u32,
+    },
+    Boulder {
+        block: BlockEntry,
+        radius: f64,
+    },
+    Tree {
+        trunk: BlockEntry,
+        leaves: BlockEntry,
+        min_height: i32,
+        max_height: i32,
+        canopy_radius: i32,
+    },
+    Vegetation {
+        block: BlockEntry,
+    },
+    Structure {
+        /// The blocks of each rotation the structure can be placed with, relative to its origin.
+        variants: Vec<Arc<[(IVec3, BlockEntry)]>>,
+    },
+}
+
+/// Places the features of all the biomes of a registry.
+pub struct FeaturePlacer {
+    features: HashMap<RegistryId, Vec<ResolvedFeature>>,
+    random: PositionalRandomFactory<Xoshiro256StarStar>,
+    air: BlockEntry,
+}
+
+impl FeaturePlacer {
+    /// Resolves the features of all the biomes, skipping invalid ones with a warning.
+    /// Structure features refer to the given templates by name.
+    pub fn new(
+        seed: u64,
+        biome_registry: &BiomeRegistry,
+        block_registry: &BlockRegistry,
+        templates: &HashMap<RegistryName, StructureTemplate>,
+        air: BlockEntry,
+    ) -> Self {
+        let mut features = HashMap::new();
+        for (id, name, biome) in biome_registry.iter() {
+            let resolved: Vec<_> = biome
+                .features
+                .iter()
+                .enumerate()
+                .filter_map(
+                    |(index, feature)| match resolve_feature(feature, block_registry, templates) {
+                        Ok(resolved) => Some(ResolvedFeature {
+                            salt: (u64::from(id.0.get()) << 32) | index as u64,
+                            ..resolved
+                        }),
+                        Err(e) => {
+                            warn!("Skipping an invalid feature of biome {name}: {e:#}");
+                            None
+                        }
+                    },
+                )
+                .collect();
+            if !resolved.is_empty() {
+                features.insert(id, resolved);
+            }
+        }
+        Self {
+            features,
+            random: PositionalRandomFactory::new(seed),
+            air,
+        }
+    }
+
+    /// Are there no features to place at all?
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Places the features originating in the given chunk, returning all of their blocks, including the ones outside
+    /// of the chunk.
+    pub fn place(&self, chunk: AbsChunkPos, terrain: &TerrainChunk) -> Vec<FeatureWrite> {
+        let chunk_origin = IVec3::from(AbsBlockPos::from(chunk));
+        let mut biomes = terrain.biomes.clone();
+        biomes.sort();
+        biomes.dedup();
+
+        let mut writes = Vec::new();
+        let mut write = |pos: IVec3, block: BlockEntry, replace: ReplaceRule| {
+            let order = (chunk, writes.len() as u32);
+            writes.push(FeatureWrite {
+                pos: AbsBlockPos::from(pos),
+                block,
+                replace,
+                order,
+            });
+        };
+        for biome in biomes {
+            let Some(features) = self.features.get(&biome) else {
+                continue;
+            };
+            for feature in features {
+                let mut rand = self.random.get_at_pos(feature.salt, chunk_origin);
+                let attempts = feature.frequency.floor() as u32 + u32::from(rand.gen_bool(feature.frequency.fract()));
+                for _ in 0..attempts {
+                    let (x, y, z) = (
+                        rand.gen_range(0..CHUNK_DIM),
+                        rand.gen_range(0..CHUNK_DIM),
+                        rand.gen_range(0..CHUNK_DIM),
+                    );
+                    if terrain.biomes[(x + z * CHUNK_DIM) as usize] != biome {
+                        continue;
+                    }
+                    let Some(y) = self.find_origin(feature, terrain, x, y, z) else {
+                        continue;
+                    };
+                    let origin = chunk_origin + IVec3::new(x, y, z);
+                    if feature.height.contains(origin.y) {
+                        build_shape(feature, origin, &mut rand, &mut write);
+                    }
+                }
+            }
+        }
+        writes
+    }
+
+    /// The Y coordinate in the chunk of a valid origin of the feature in the given column, if there is one.
+    /// Surface features use the topmost surface of the column, buried features use the given Y coordinate.
+    fn find_origin(&self, feature: &ResolvedFeature, terrain: &TerrainChunk, x: i32, y: i32, z: i32) -> Option<i32> {
+        let block_at = |y: i32| *terrain.blocks.get(InChunkPos::try_new(x, y, z).unwrap());
+        if feature.surface {
+            // The block above the chunk is assumed to be empty, as it's not known yet.
+            (0..CHUNK_DIM)
+                .rev()
+                .find(|&y| {
+                    feature.anchors.contains(&block_at(y)) && (y + 1 == CHUNK_DIM || block_at(y + 1) == self.air)
+                })
+                .map(|y| y + 1)
+        } else {
+            feature.anchors.contains(&block_at(y)).then_some(y)
+        }
+    }
+
+    /// Applies the writes to the blocks of a chunk, skipping the ones that can't replace the existing blocks.
+    pub fn apply(&self, blocks: &mut PaletteStorage<BlockEntry>, writes: Vec<FeatureWrite>) {
+        for write in writes {
+            let (_, pos) = write.pos.split_chunk_component();
+            let current = *blocks.get(pos);
+            let replaceable = match &write.replace {
+                ReplaceRule::Empty => current == self.air,
+                ReplaceRule::AnyOf(replaced) => replaced.contains(&current),
+                ReplaceRule::Always => true,
+            };
+            if replaceable {
+                blocks.put(pos, write.block);
+            }
+        }
+    }
+}
+
+fn resolve_feature(
+    feature: &BiomeFeature,
+    block_registry: &BlockRegistry,
+    templates: &HashMap<RegistryName, StructureTemplate>,
+) -> Result<ResolvedFeature> {
+    feature.validate()?;
+    let block = |name: &RegistryName| {
+        block_registry
+            .lookup_name_to_object(name.as_ref())
+            .map(|(id, _)| BlockEntry::new(id, 0))
+            .with_context(|| format!("Unknown block {name}"))
+    };
+    let anchors = feature
+        .placement
+        .blocks()
+        .iter()
+        .map(&block)
+        .collect::<Result<Vec<_>>>()?;
+    let shape = match &feature.shape {
+        FeatureShape::OreVein { block: ore, size } => ResolvedShape::OreVein {
+            block: block(ore)?,
+            size: *size,
+        },
+        FeatureShape::Boulder { block: rock, radius } => ResolvedShape::Boulder {
+            block: block(rock)?,
+            radius: *radius,
+        },
+        FeatureShape::Tree {
+            trunk,
+            leaves,
+            min_height,
+            max_height,
+            canopy_radius,
+        } => ResolvedShape::Tree {
+            trunk: block(trunk)?,
+            leaves: block(leaves)?,
+            min_height: *min_height,
+            max_height: *max_height,
+            canopy_radius: *canopy_radius,
+        },
+        FeatureShape::Vegetation { block: plant } => ResolvedShape::Vegetation { block: block(plant)? },
+        FeatureShape::Structure {
+            template: name,
+            random_rotation,
+        } => {
+            let template = templates
+                .get(name)
+                .with_context(|| format!("Unknown structure template {name}"))?;
+            ensure!(
+                template.reach() <= MAX_FEATURE_REACH,
+                "Structure template {name} reaches {} blocks from its origin, more than the maximum of \
+                 {MAX_FEATURE_REACH}",
+                template.reach()
+            );
+            let rights: &[Direction] = if *random_rotation {
+                &[Direction::XPlus, Direction::ZPlus, Direction::XMinus, Direction::ZMinus]
+            } else {
+                &[Direction::XPlus]
+            };
+            let variants = rights
+                .iter()
+                .map(|&right| -> Result<Arc<[_]>> {
+                    let orientation = OctahedralOrientation::from_right_up(right, Direction::UP).unwrap();
+                    let rotated = template.rotated(orientation, block_registry);
+                    let blocks = rotated.placement_edits(AbsBlockPos::ZERO, block_registry)?;
+                    Ok(blocks
+                        .into_iter()
+                        .map(|(pos, block)| (IVec3::from(pos), block))
+                        .collect())
+                })
+                .collect::<Result<_>>()?;
+            ResolvedShape::Structure { variants }
+        }
+    };
+    Ok(ResolvedFeature {
+        salt: 0,
+        frequency: feature.frequency,
+        height: feature.height,
+        surface: matches!(feature.placement, FeaturePlacement::Surface(_)),
+        anchors: anchors.into(),
+        shape,
+    })
+}
+
+const VEIN_STEPS: [IVec3; 6] = [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z];
+
+fn build_shape(
+    feature: &ResolvedFeature,
+    origin: IVec3,
+    rand: &mut impl Rng,
+    write: &mut impl FnMut(IVec3, BlockEntry, ReplaceRule),
+) {
+    match feature.shape {
+        ResolvedShape::OreVein { block, size } => {
+            let mut pos = origin;
+            for _ in 0..size {
+                write(pos, block, ReplaceRule::AnyOf(Arc::clone(&feature.anchors)));
+                pos += VEIN_STEPS[rand.gen_range(0..VEIN_STEPS.len())];
+            }
+        }
+        ResolvedShape::Boulder { block, radius } => {
+            let radius = rand.gen_range(radius * 0.6..=radius);
+            let extent = radius.ceil() as i32;
+            for (x, y, z) in iproduct!(-extent..=extent, -extent..=extent, -extent..=extent) {
+                if IVec3::new(x, y, z).as_dvec3().length() <= radius {
+                    write(origin + IVec3::new(x, y, z), block, ReplaceRule::Always);
+                }
+            }
+        }
+        ResolvedShape::Tree {
+            trunk,
+            leaves,
+            min_height,
+            max_height,
+            canopy_radius,
+        } => {
+            let height = rand.gen_range(min_height..=max_height);
+            for y in 0..height {
+                write(origin + IVec3::new(0, y, 0), trunk, ReplaceRule::Empty);
+            }
+            let canopy = origin + IVec3::new(0, height - 1, 0);
+            let r = canopy_radius;
+            for (x, y, z) in iproduct!(-r..=r, -r..=r, -r..=r) {
+                if x * x + y * y + z * z <= r * r {
+                    write(canopy + IVec3::new(x, y, z), leaves, ReplaceRule::Empty);
+                }
+            }
+        }
+        ResolvedShape::Vegetation { block } => write(origin, block, ReplaceRule::Empty),
+        ResolvedShape::Structure { ref variants } => {
+            let variant = &variants[rand.gen_range(0..variants.len())];
+            for &(offset, block) in variant.iter() {
+                write(origin + offset, block, ReplaceRule::Always);
+            }
+        }
+    }
+}
+
+/// The bits of [`PendingState::placed`] set once all the chunks around an origin took their writes.
+const ALL_NEIGHBORS_TAKEN: u32 = (1 << 27) - 1;
+/// How many origins with submitted features are kept, the least recently used ones are dropped first.
+/// Origins at the edge of the explored area never get all their neighbours generated, so they would pile up otherwise.
+const PENDING_FEATURE_ORIGINS: NonZeroUsize = NonZeroUsize::new(4096).unwrap();
+
+/// The chunks features originating in the given chunk can write into, including itself.
+fn feature_neighborhood(origin: AbsChunkPos) -> impl Iterator<Item = AbsChunkPos> {
+    iproduct!(-1..=1, -1..=1, -1..=1).map(move |(x, y, z)| AbsChunkPos::from(IVec3::from(origin) + IVec3::new(x, y, z)))
+}
+
+/// The bit of a chunk in the [`PendingState::placed`] entry of a neighbouring origin.
+fn neighbor_bit(origin: AbsChunkPos, chunk: AbsChunkPos) -> u32 {
+    let offset = IVec3::from(chunk) - IVec3::from(origin) + IVec3::ONE;
+    1 << (offset.x * 9 + offset.y * 3 + offset.z)
+}
+
+struct PendingState {
+    /// The origins with submitted features, with a bit set for each of the chunks around them that took their writes.
+    placed: LruCache<AbsChunkPos, u32>,
+    writes: HashMap<AbsChunkPos, Vec<FeatureWrite>>,
+}
+
+impl PendingState {
+    /// Forgets the writes of the features originating in the given chunk.
+    fn drop_writes_of(&mut self, origin: AbsChunkPos) {
+        for target in feature_neighborhood(origin) {
+            if let Some(target_writes) = self.writes.get_mut(&target) {
+                target_writes.retain(|write| write.order.0 != origin);
+                if target_writes.is_empty() {
+                    self.writes.remove(&target);
+                }
+            }
+        }
+    }
+}
+
+/// Feature blocks waiting for the chunks they are in, shared by all the generation tasks.
+///
+/// The writes of an origin are dropped once all the chunks around it took them, or when it's the least recently used
+/// of too many origins. If one of the chunks around a dropped origin is generated again, the features of the origin
+/// are placed again, giving the same result.
+pub struct PendingFeatureWrites {
+    state: Mutex<PendingState>,
+}
+
+impl Default for PendingFeatureWrites {
+    fn default() -> Self {
+        Self::with_capacity(PENDING_FEATURE_ORIGINS)
+    }
+}
+
+impl PendingFeatureWrites {
+    /// Keeps the features of at most `origins` origins.
+    pub fn with_capacity(origins: NonZeroUsize) -> Self {
+        Self {
+            state: Mutex::new(PendingState {
+                placed: LruCache::new(origins),
+                writes: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Were the features originating in the given chunk already submitted?
+    pub fn is_placed(&self, origin: AbsChunkPos) -> bool {
+        self.state.lock().unwrap().placed.contains(&origin)
+    }
+
+    /// Records the writes of the features originating in the given chunk, unless they were already recorded by
+    /// another generation task.
+    pub fn submit(&self, origin: AbsChunkPos, writes: Vec<FeatureWrite>) {
+        let mut state = self.state.lock().unwrap();
+        if state.placed.contains(&origin) {
+            return;
+        }
+        if let Some((evicted, _)) = state.placed.push(origin, 0) {
+            state.drop_writes_of(evicted);
+        }
+        for write in writes {
+            let (chunk, _) = write.pos.split_chunk_component();
+            state.writes.entry(chunk).or_default().push(write);
+        }
+    }
+
+    /// Takes all the recorded writes into the given chunk, in a deterministic order.
+    /// Returns `None` if the features of a chunk around it are not submitted, which can happen when another
+    /// generation task dropped them in the meantime.
+    pub fn take_writes_into(&self, chunk: AbsChunkPos) -> Option<Vec<FeatureWrite>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if !feature_neighborhood(chunk).all(|origin| state.placed.contains(&origin)) {
+            return None;
+        }
+        let mut writes = state.writes.get(&chunk).cloned().unwrap_or_default();
+        writes.sort_by_key(|write| write.order);
+
+        for origin in feature_neighborhood(chunk) {
+            let taken = state.placed.get_mut(&origin).unwrap();
+            *taken |= neighbor_bit(origin, chunk);
+            if *taken != ALL_NEIGHBORS_TAKEN {
+                continue;
+            }
+            state.placed.pop(&origin);
+            state.drop_writes_of(origin);
+        }
+        Some(writes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::range::range;
+    use gs_schemas::registry::Registry;
+    use gs_schemas::voxel::biome::feature::{FeatureError, MAX_FEATURE_FREQUENCY};
+    use gs_schemas::voxel::structure::{StructureBlock, StructureBlocks};
+    use gs_schemas::voxel::voxeltypes::EMPTY_BLOCK_NAME;
+
+    use super::*;
+    use crate::voxel::biomes::{setup_basic_biomes, PLAINS_BIOME_NAME};
+    use crate::voxel::blocks::{
+        setup_basic_blocks, GRASS_BLOCK_NAME, LEAVES_BLOCK_NAME, LOG_BLOCK_NAME, STONE_BLOCK_NAME,
+    };
+
+    const GROUND_Y: i32 = CHUNK_DIM / 2;
+
+    struct Setup {
+        blocks: BlockRegistry,
+        plains: RegistryId,
+        placer: FeaturePlacer,
+    }
+
+    /// The builtin plains, densely covered with trees.
+    fn setup() -> Setup {
+        setup_with(
+            FeatureShape::Tree {
+                trunk: LOG_BLOCK_NAME,
+                leaves: LEAVES_BLOCK_NAME,
+                min_height: 4,
+                max_height: 6,
+                canopy_radius: 2,
+            },
+            &HashMap::new(),
+        )
+    }
+
+    /// The builtin plains, densely covered with the given feature.
+    fn setup_with(shape: FeatureShape, templates: &HashMap<RegistryName, StructureTemplate>) -> Setup {
+        let mut blocks = Registry::default();
+        setup_basic_blocks(&mut blocks);
+        let mut builtin = Registry::default();
+        setup_basic_biomes(&mut builtin);
+        let (_, plains) = builtin.lookup_name_to_object(PLAINS_BIOME_NAME.as_ref()).unwrap();
+        let mut plains = plains.clone();
+        plains.features = vec![BiomeFeature {
+            frequency: 32.0,
+            height: range(..),
+            placement: FeaturePlacement::Surface(vec![GRASS_BLOCK_NAME]),
+            shape,
+        }];
+        let mut biomes = Registry::default();
+        let plains = biomes.push_object(plains).unwrap();
+        let air = block(&blocks, &EMPTY_BLOCK_NAME);
+        let placer = FeaturePlacer::new(1, &biomes, &blocks, templates, air);
+        Setup { blocks, plains, placer }
+    }
+
+    fn block(registry: &BlockRegistry, name: &RegistryName) -> BlockEntry {
+        BlockEntry::new(registry.lookup_name_to_object(name.as_ref()).unwrap().0, 0)
+    }
+
+    /// Flat stone ground covered with grass at [`GROUND_Y`].
+    fn flat_terrain(setup: &Setup) -> TerrainChunk {
+        let mut blocks = PaletteStorage::new(block(&setup.blocks, &EMPTY_BLOCK_NAME));
+        for (x, y, z) in iproduct!(0..CHUNK_DIM, 0..=GROUND_Y, 0..CHUNK_DIM) {
+            let name = if y == GROUND_Y {
+                &GRASS_BLOCK_NAME
+            } else {
+                &STONE_BLOCK_NAME
+            };
+            blocks.put(InChunkPos::try_new(x, y, z).unwrap(), block(&setup.blocks, name));
+        }
+        TerrainChunk {
+            blocks,
+            biomes: vec![setup.plains; (CHUNK_DIM * CHUNK_DIM) as usize],
+        }
+    }
+
+    fn summary(writes: &[FeatureWrite]) -> Vec<(AbsBlockPos, BlockEntry)> {
+        writes.iter().map(|write| (write.pos, write.block)).collect()
+    }
+
+    #[test]
+    fn trees_grow_on_grass() {
+        let setup = setup();
+        let terrain = flat_terrain(&setup);
+        let chunk = AbsChunkPos::new(0, 0, 0);
+        let writes = setup.placer.place(chunk, &terrain);
+        assert_eq!(summary(&writes), summary(&setup.placer.place(chunk, &terrain)));
+
+        let log = block(&setup.blocks, &LOG_BLOCK_NAME);
+        let leaves = block(&setup.blocks, &LEAVES_BLOCK_NAME);
+        assert!(writes
+            .iter()
+            .any(|write| write.block == log && IVec3::from(write.pos).y == GROUND_Y + 1));
+        assert!(
+            writes.iter().any(|write| write.pos.split_chunk_component().0 != chunk),
+            "No tree reaching into the neighbouring chunks"
+        );
+
+        let mut blocks = terrain.blocks.clone();
+        let own_writes = writes
+            .into_iter()
+            .filter(|write| write.pos.split_chunk_component().0 == chunk)
+            .collect();
+        setup.placer.apply(&mut blocks, own_writes);
+        for (x, y, z) in iproduct!(0..CHUNK_DIM, 0..=GROUND_Y, 0..CHUNK_DIM) {
+            let pos = InChunkPos::try_new(x, y, z).unwrap();
+            assert_eq!(blocks.get(pos), terrain.blocks.get(pos), "A tree replaced the ground");
+        }
+        assert!(iproduct!(0..CHUNK_DIM, 0..CHUNK_DIM, 0..CHUNK_DIM)
+            .any(|(x, y, z)| *blocks.get(InChunkPos::try_new(x, y, z).unwrap()) == leaves));
+    }
+
+    #[test]
+    fn structures_are_rotated() {
+        // An L shape of logs: a pillar with an arm towards X+.
+        let log = StructureBlock {
+            name: LOG_BLOCK_NAME,
+            metadata: 0,
+        };
+        let template = StructureTemplate::new(
+            IVec3::new(2, 2, 1),
+            IVec3::ZERO,
+            vec![log],
+            StructureBlocks::Sparse(vec![(0, 0), (2, 0), (3, 0)]),
+        )
+        .unwrap();
+        let name = RegistryName::new("test", "corner");
+        let templates = HashMap::from([(name.clone(), template)]);
+        let shape = |random_rotation| FeatureShape::Structure {
+            template: name.clone(),
+            random_rotation,
+        };
+
+        let arms = |setup: &Setup| {
+            let log = block(&setup.blocks, &LOG_BLOCK_NAME);
+            let writes = setup.placer.place(AbsChunkPos::ZERO, &flat_terrain(setup));
+            // Rotating the structure turns the logs without changing their type.
+            assert!(writes.iter().all(|write| write.block.id == log.id));
+            // Each placed structure writes its three blocks in a row.
+            let mut arms = HashSet::new();
+            for placed in writes.chunks_exact(3) {
+                let mut blocks: Vec<IVec3> = placed.iter().map(|write| IVec3::from(write.pos)).collect();
+                blocks.sort_by_key(|pos| pos.y);
+                assert_eq!(blocks[0].y, GROUND_Y + 1);
+                let (pillar_top, arm) = if blocks[1] == blocks[0] + IVec3::Y {
+                    (blocks[1], blocks[2])
+                } else {
+                    (blocks[2], blocks[1])
+                };
+                assert_eq!(pillar_top, blocks[0] + IVec3::Y);
+                arms.insert(arm - pillar_top);
+            }
+            arms
+        };
+        assert_eq!(arms(&setup_with(shape(false), &templates)), HashSet::from([IVec3::X]));
+        assert!(arms(&setup_with(shape(true), &templates)).len() > 1);
+
+        let missing = setup_with(shape(false), &HashMap::new());
+        assert!(missing.placer.is_empty());
+    }
+
+    #[test]
+    fn frequencies_are_bounded() {
+        let mut feature = BiomeFeature {
+            frequency: f64::INFINITY,
+            height: range(..),
+            placement: FeaturePlacement::Surface(vec![GRASS_BLOCK_NAME]),
+            shape: FeatureShape::Vegetation {
+                block: GRASS_BLOCK_NAME,
+            },
+        };
+        assert_eq!(feature.validate(), Err(FeatureError::InvalidFrequency(f64::INFINITY)));
+        feature.frequency = f64::NAN;
+        assert!(feature.validate().is_err());
+        feature.frequency = MAX_FEATURE_FREQUENCY + 1.0;
+        assert!(feature.validate().is_err());
+        feature.frequency = MAX_FEATURE_FREQUENCY;
+        assert_eq!(feature.validate(), Ok(()));
+    }
+
+    #[test]
+    fn pending_writes_ignore_generation_order() {
+        let setup = setup();
+        let terrain = flat_terrain(&setup);
+        let (a, b) = (AbsChunkPos::new(0, 0, 0), AbsChunkPos::new(1, 0, 0));
+        let origins: Vec<_> = feature_neighborhood(a).chain(feature_neighborhood(b)).collect();
+        let placed: Vec<_> = origins
+            .iter()
+            .map(|&origin| (origin, setup.placer.place(origin, &terrain)))
+            .collect();
+
+        let forward = PendingFeatureWrites::default();
+        for (origin, writes) in placed.iter().cloned() {
+            forward.submit(origin, writes);
+        }
+        let backward = PendingFeatureWrites::default();
+        for (origin, writes) in placed.iter().rev().cloned() {
+            backward.submit(origin, writes);
+        }
+        // Submitting the same origin again is ignored.
+        let (_, writes_a) = placed.iter().find(|(origin, _)| *origin == a).unwrap();
+        backward.submit(a, writes_a.clone());
+
+        assert!(forward.is_placed(a) && forward.is_placed(b));
+        assert!(!forward.is_placed(AbsChunkPos::new(3, 0, 0)));
+        assert!(forward.take_writes_into(AbsChunkPos::new(2, 0, 0)).is_none());
+        for chunk in [a, b] {
+            let writes = forward.take_writes_into(chunk).unwrap();
+            assert!(!writes.is_empty());
+            assert_eq!(summary(&writes), summary(&backward.take_writes_into(chunk).unwrap()));
+        }
+    }
+
+    #[test]
+    fn pending_writes_are_dropped_once_taken() {
+        let setup = setup();
+        let terrain = flat_terrain(&setup);
+        let center = AbsChunkPos::new(0, 0, 0);
+        let pending = PendingFeatureWrites::default();
+        let submit_around = |chunk| {
+            for origin in feature_neighborhood(chunk) {
+                if !pending.is_placed(origin) {
+                    pending.submit(origin, setup.placer.place(origin, &terrain));
+                }
+            }
+        };
+
+        submit_around(center);
+        let first = pending.take_writes_into(center).unwrap();
+        for chunk in feature_neighborhood(center).filter(|&chunk| chunk != center) {
+            assert!(pending.is_placed(center));
+            submit_around(chunk);
+            pending.take_writes_into(chunk).unwrap();
+        }
+        // Every chunk around the center took its writes.
+        assert!(!pending.is_placed(center));
+        let state = pending.state.lock().unwrap();
+        assert!(state.writes.values().flatten().all(|write| write.order.0 != center));
+        drop(state);
+
+        // Generating the center chunk again places its features again, with the same result.
+        submit_around(center);
+        assert_eq!(summary(&pending.take_writes_into(center).unwrap()), summary(&first));
+    }
+
+    #[test]
+    fn pending_writes_stay_bounded_while_exploring() {
+        let setup = setup();
+        let terrain = flat_terrain(&setup);
+        let capacity = NonZeroUsize::new(64).unwrap();
+        let pending = PendingFeatureWrites::with_capacity(capacity);
+        // A long walk leaves a trail of origins above, below and to the sides that never get all their neighbours.
+        for x in 0..500 {
+            let chunk = AbsChunkPos::new(x, 0, 0);
+            let writes = loop {
+                for origin in feature_neighborhood(chunk) {
+                    if !pending.is_placed(origin) {
+                        pending.submit(origin, setup.placer.place(origin, &terrain));
+                    }
+                }
+                if let Some(writes) = pending.take_writes_into(chunk) {
+                    break writes;
+                }
+            };
+            assert!(!writes.is_empty());
+            let state = pending.state.lock().unwrap();
+            assert!(state.placed.len() <= capacity.get());
+            assert!(state
+                .writes
+                .values()
+                .flatten()
+                .all(|write| state.placed.contains(&write.order.0)));
+        }
+    }
+}
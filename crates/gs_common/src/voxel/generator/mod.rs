@@ -4,6 +4,8 @@ use gs_schemas::coordinates::AbsChunkPos;
 use gs_schemas::voxel::chunk::Chunk;
 use gs_schemas::GsExtraData;
 
+pub mod density;
+pub mod features;
 pub mod flat;
 pub mod multi_noise;
 
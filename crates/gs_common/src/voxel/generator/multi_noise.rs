@@ -2,38 +2,49 @@
 
 use std::ops::{Add, AddAssign, Sub, SubAssign};
 use std::sync::Arc;
-use std::{cell::RefCell, cmp::Ordering, mem::MaybeUninit, ops::Deref, rc::Rc};
+use std::{cell::RefCell, cmp::Ordering, num::NonZeroUsize, ops::Deref, rc::Rc};
 
-use bevy_math::{DVec2, FloatExt, IVec2, IVec3, Vec3Swizzles};
+use bevy_math::{DVec2, FloatExt, IVec2, IVec3};
 use gs_schemas::{
-    coordinates::{AbsChunkPos, InChunkPos, CHUNK_DIM, CHUNK_DIM2Z, CHUNK_DIM3V, CHUNK_DIMD, CHUNK_DIMZ},
+    coordinates::{AbsBlockPos, AbsChunkPos, InChunkPos, CHUNK_DIM, CHUNK_DIM2Z, CHUNK_DIM3V, CHUNK_DIMD, CHUNK_DIMZ},
     dependencies::{
         itertools::{iproduct, Itertools},
         smallvec::SmallVec,
     },
-    registry::RegistryId,
+    registry::{RegistryId, RegistryName},
     voxel::{
         biome::{
             biome_map::{EXPECTED_BIOME_COUNT, GLOBAL_BIOME_SCALE, GLOBAL_SCALE_MOD},
-            BiomeDefinition, BiomeEntry, BiomeRegistry, Noises, VOID_BIOME_NAME,
+            BiomeDefinition, BiomeEntry, BiomeRegistry, CaveCarving, Noises, SurfaceNoise, VOID_BIOME_NAME,
         },
         chunk::Chunk,
-        chunk_storage::ChunkStorage,
-        generation::{fbm_noise::Fbm, positional_random::PositionalRandomFactory, Context, NoiseNDTo2D},
+        chunk_storage::{ChunkStorage, PaletteStorage},
+        generation::{
+            fbm_noise::Fbm,
+            noise_graph::{base_terrain_noise, NoiseGraph, NoiseGraphCache},
+            positional_random::PositionalRandomFactory,
+            Context, NoiseNDTo2D,
+        },
+        structure::StructureTemplate,
         voxeltypes::{BlockEntry, BlockRegistry, EMPTY_BLOCK_NAME},
     },
     GsExtraData,
 };
 use hashbrown::HashMap;
+use lru::LruCache;
 use noise::OpenSimplex;
-use rand::{Rng, SeedableRng};
+use rand::Rng;
 use rand_xoshiro::Xoshiro128StarStar;
 use serde::{Deserialize, Serialize};
 use spade::handles::FixedVertexHandle;
 use spade::{DelaunayTriangulation, HasPosition, Point2, Triangulation};
 use tracing::warn;
 
+use crate::prelude::*;
 use crate::voxel::biomes::{BEACH_BIOME_NAME, OCEAN_BIOME_NAME};
+use crate::voxel::blocks::WATER_BLOCK_NAME;
+use crate::voxel::generator::density::{TerrainDensity, TerrainDensitySettings};
+use crate::voxel::generator::features::{FeaturePlacer, PendingFeatureWrites, TerrainChunk};
 use crate::voxel::generator::VoxelGenerator;
 
 /// Biome size in chunks
@@ -43,6 +54,23 @@ pub const BIOME_SIZE: f64 = 1.0;
 
 const BIOME_BLEND_RADIUS: f64 = 32.0;
 
+/// The sea level of planets that don't specify one.
+pub const DEFAULT_SEA_LEVEL: i32 = 0;
+
+/// How many chunks of terrain generated only for placing the features of neighbouring chunks are kept.
+const TERRAIN_CACHE_CAPACITY: usize = 256;
+
+/// The side of the square regions of chunks whose biome cell graphs are built at once, in chunks.
+const BIOME_REGION_CHUNKS: i32 = 8;
+/// How many chunks away the biome cells blended into a chunk's columns are.
+const BIOME_CELL_REACH: i32 = 2;
+/// How many biome regions are kept.
+const BIOME_REGION_CACHE_CAPACITY: NonZeroUsize = NonZeroUsize::new(64).unwrap();
+/// How many chunk columns' biomes and heightmaps are kept.
+const COLUMN_CACHE_CAPACITY: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+/// The positional random salt of the fallback biome choice of cells matching no biome.
+const BIOME_FALLBACK_SALT: u64 = 0xB10E;
+
 /// Standard world generator implementation
 pub struct MultiNoiseGenerator {
     biome_registry: Arc<BiomeRegistry>,
@@ -54,24 +82,276 @@ pub struct MultiNoiseGenerator {
     point_offset_noise: OpenSimplex,
 
     generatable_biomes: Vec<(RegistryId, BiomeDefinition)>,
+    /// Built noise graphs of the biomes with [`SurfaceNoise::Graph`] surfaces.
+    surface_graphs: HashMap<RegistryId, NoiseGraph>,
+    /// The optional 3D density stage, `None` for pure heightmap terrain.
+    density: Option<TerrainDensity>,
+    /// The Y level below which open space is flooded with [`Self::sea_fluid`].
+    sea_level: i32,
+    sea_fluid: BlockEntry,
+    air: BlockEntry,
+
+    features: FeaturePlacer,
+    pending_features: PendingFeatureWrites,
+    terrain_cache: Mutex<HashMap<AbsChunkPos, TerrainChunk>>,
+    region_cache: Mutex<LruCache<IVec2, Arc<BiomeRegion>>>,
+    column_cache: Mutex<LruCache<IVec2, Arc<ChunkColumn>>>,
+}
+
+/// The biome cells of a square region of chunks, with one cell per chunk.
+struct BiomeRegion {
+    /// The chunk of the first cell.
+    min: IVec2,
+    /// The number of cells along each side.
+    side: i32,
+    centers: Vec<Center>,
+    /// The center index of each cell, in XZ order.
+    cells: Vec<usize>,
+}
+
+impl BiomeRegion {
+    /// The center of the cell of the given chunk column, which has to be in the region or its margin.
+    fn cell(&self, chunk: IVec2) -> &Center {
+        let local = chunk - self.min;
+        &self.centers[self.cells[(local.x + local.y * self.side) as usize]]
+    }
+}
+
+/// The biomes and heightmap of a column of chunks, indexed by `x + z * CHUNK_DIM`.
+struct ChunkColumn {
+    blended: Vec<SmallVec<[BiomeEntry; EXPECTED_BIOME_COUNT]>>,
+    heights: [i32; CHUNK_DIM2Z],
+    /// The biome with the strongest influence on each column.
+    dominant_biomes: Vec<RegistryId>,
+}
+
+/// The generated terrain parameters of a block column, see [`MultiNoiseGenerator::query_terrain`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct TerrainQuery {
+    /// The biomes blended in the column, strongest first, with weights adding up to 1.
+    pub biomes: SmallVec<[BiomeEntry; EXPECTED_BIOME_COUNT]>,
+    /// The blended elevation noise of the biome cells, between 0 and 5.
+    pub elevation: f64,
+    /// The blended temperature noise of the biome cells, between 0 and 5.
+    pub temperature: f64,
+    /// The blended moisture noise of the biome cells, between 0 and 5.
+    pub moisture: f64,
+    /// The Y level of the top block of the heightmap ground, before caves and overhangs are carved into it.
+    pub surface_height: i32,
 }
 
 impl<ED: GsExtraData> VoxelGenerator<ED> for MultiNoiseGenerator {
     /// Generate a single chunk's blocks for the world.
     fn generate_chunk(&self, position: AbsChunkPos, extra_data: <ED as GsExtraData>::ChunkData) -> Chunk<ED> {
+        let cached = self.terrain_cache.lock().unwrap().remove(&position);
+        let mut terrain = cached.unwrap_or_else(|| self.generate_terrain(position));
+
+        if !self.features.is_empty() {
+            // Features can spill over from the neighbouring chunks, so all of them have to be placed before this
+            // chunk's pending writes are complete.
+            let writes = loop {
+                for offset in iproduct!(-1..=1, -1..=1, -1..=1) {
+                    let origin = AbsChunkPos::from(IVec3::from(position) + IVec3::from(offset));
+                    if self.pending_features.is_placed(origin) {
+                        continue;
+                    }
+                    let writes = if origin == position {
+                        self.features.place(origin, &terrain)
+                    } else {
+                        // The neighbour's terrain might still be cached from placing the features around another chunk.
+                        let cached = self.terrain_cache.lock().unwrap().remove(&origin);
+                        let neighbor = cached.unwrap_or_else(|| self.generate_terrain(origin));
+                        let writes = self.features.place(origin, &neighbor);
+                        self.cache_terrain(origin, neighbor);
+                        writes
+                    };
+                    self.pending_features.submit(origin, writes);
+                }
+                if let Some(writes) = self.pending_features.take_writes_into(position) {
+                    break writes;
+                }
+            };
+            self.features.apply(&mut terrain.blocks, writes);
+        }
+
+        let mut chunk = Chunk::new(self.air, extra_data);
+        chunk.blocks = terrain.blocks;
+        chunk
+    }
+}
+
+impl MultiNoiseGenerator {
+    /// Generates the terrain of a chunk, without any features.
+    fn generate_terrain(&self, position: AbsChunkPos) -> TerrainChunk {
         let point: IVec3 = <IVec3>::from(position) * CHUNK_DIM3V;
-        let offset_point = DVec2Wrapper::new((point.x + CHUNK_DIM / 2) as f64, (point.z + CHUNK_DIM / 2) as f64);
+        let column = self.chunk_column(IVec2::new(position.x, position.z));
+        let blended = &column.blended;
+        let vparams = &column.heights;
+
+        let air = self.air;
+        let mut blocks = PaletteStorage::new(air);
+
+        // Local ground levels and caves of every block, indexed by column and then by Y, when terrain isn't a pure
+        // heightmap.
+        let density_columns: Option<Vec<([i32; CHUNK_DIMZ], [bool; CHUNK_DIMZ])>> =
+            self.density.as_ref().map(|density| {
+                (0..CHUNK_DIM2Z)
+                    .map(|i| {
+                        let column = IVec2::new(point.x + i as i32 % CHUNK_DIM, point.z + i as i32 / CHUNK_DIM);
+                        let levels = density.column_ground_levels(column, point.y, vparams[i]);
+                        let caves = density.column_caves(column, point.y, &levels);
+                        (levels, caves)
+                    })
+                    .collect()
+            });
+        for (pos_x, pos_y, pos_z) in iproduct!(0..CHUNK_DIM, 0..CHUNK_DIM, 0..CHUNK_DIM) {
+            let b_pos = InChunkPos::try_new(pos_x, pos_y, pos_z).unwrap();
+
+            let g_pos = <IVec3>::from(b_pos) + (<IVec3>::from(position) * CHUNK_DIM);
+            let height = vparams[(pos_x + pos_z * CHUNK_DIM) as usize];
+
+            let mut biomes: SmallVec<[(&BiomeDefinition, f64); 3]> = SmallVec::new();
+            for b in blended[(pos_x + pos_z * CHUNK_DIM) as usize].iter() {
+                let e = b.lookup(&self.biome_registry).unwrap();
+                let w = b.weight * e.block_influence;
+                biomes.push((e, w));
+            }
+            // sort by block influence, then registry id if influence is same
+            biomes.sort_by(|a, b| {
+                a.1.partial_cmp(&b.1).unwrap_or_else(|| {
+                    self.biome_registry
+                        .search_object_to_id(a.0)
+                        .cmp(&self.biome_registry.search_object_to_id(b.0))
+                })
+            });
+
+            for (biome, _) in biomes.iter() {
+                let ctx = Context {
+                    seed: self.seed,
+                    chunk: &blocks,
+                    random: PositionalRandomFactory::new(self.seed),
+                    ground_y: density_columns.as_ref().map_or(height, |columns| {
+                        columns[(pos_x + pos_z * CHUNK_DIM) as usize].0[pos_y as usize]
+                    }),
+                    sea_level: self.sea_level,
+                };
+                let result = biome.rule_source.place_block(&g_pos, &ctx, &self.block_registry);
+                if let Some(result) = result {
+                    blocks.put(b_pos, result);
+                }
+            }
+
+            // Carve caves according to the biome with the strongest influence on the block.
+            let mut carved = false;
+            if let (Some(columns), Some((biome, _))) = (&density_columns, biomes.last()) {
+                if biome.cave_carving != CaveCarving::None
+                    && *blocks.get(b_pos) != air
+                    && columns[(pos_x + pos_z * CHUNK_DIM) as usize].1[pos_y as usize]
+                {
+                    let fluid = match biome.cave_carving {
+                        CaveCarving::Flooded if g_pos.y < self.sea_level => biome
+                            .rule_source
+                            .fluid_block()
+                            .and_then(|name| self.block_registry.lookup_name_to_object(name.as_ref()))
+                            .map(|(id, _)| BlockEntry::new(id, 0)),
+                        _ => None,
+                    };
+                    blocks.put(b_pos, fluid.unwrap_or(air));
+                    carved = true;
+                }
+            }
 
-        let seed_bytes_be = self.seed.to_be_bytes();
-        let seed_bytes_le = self.seed.to_le_bytes();
-        let x = offset_point.x.to_le_bytes();
-        let y = offset_point.y.to_be_bytes();
-        let mut seed = [0_u8; 16];
-        for i in 0..8 {
-            seed[i] = x[i].wrapping_mul(seed_bytes_be[i]);
-            seed[i + 8] = y[i].wrapping_mul(seed_bytes_le[i]);
+            // Flood the open space below the sea level, leaving dry caves dry.
+            if !carved && g_pos.y < self.sea_level && *blocks.get(b_pos) == air {
+                blocks.put(b_pos, self.sea_fluid);
+            }
+        }
+        TerrainChunk {
+            blocks,
+            biomes: column.dominant_biomes.clone(),
         }
-        let mut rand = Xoshiro128StarStar::from_seed(seed);
+    }
+
+    /// The biomes and heightmap of a column of chunks, shared by all the chunks of the column.
+    fn chunk_column(&self, column: IVec2) -> Arc<ChunkColumn> {
+        if let Some(cached) = self.column_cache.lock().unwrap().get(&column) {
+            return Arc::clone(cached);
+        }
+        // Generated without holding the lock, a column requested by two tasks at once is just generated twice.
+        let generated = Arc::new(self.generate_column(column));
+        self.column_cache.lock().unwrap().put(column, Arc::clone(&generated));
+        generated
+    }
+
+    fn generate_column(&self, column: IVec2) -> ChunkColumn {
+        let origin = column * CHUNK_DIM;
+        let centers = self.cells_around(column);
+        let void_id = self.void_biome();
+        let points: Vec<IVec2> = (0..CHUNK_DIM2Z)
+            .map(|i| IVec2::new((i % CHUNK_DIMZ) as i32, ((i / CHUNK_DIMZ) % CHUNK_DIMZ) as i32) + origin)
+            .collect();
+        let blended: Vec<_> = points
+            .iter()
+            .map(|point| self.find_biomes_at_point(point.as_dvec2(), void_id, &centers).0)
+            .collect();
+        let mut heights = [0; CHUNK_DIM2Z];
+        for (height, elevation) in heights.iter_mut().zip(self.elevation_noise(&points, &blended)) {
+            *height = elevation.round() as i32;
+        }
+
+        let dominant_biomes = blended
+            .iter()
+            .map(|blend| {
+                let influence = |entry: &&BiomeEntry| {
+                    entry.weight * entry.lookup(&self.biome_registry).map_or(0.0, |b| b.block_influence)
+                };
+                blend
+                    .iter()
+                    .max_by(|a, b| influence(a).total_cmp(&influence(b)))
+                    .map_or(void_id, |entry| entry.id)
+            })
+            .collect();
+
+        ChunkColumn {
+            blended,
+            heights,
+            dominant_biomes,
+        }
+    }
+
+    /// The biome cells blended into the given chunk column.
+    fn cells_around(&self, column: IVec2) -> Vec<Center> {
+        let region = self.biome_region(column.div_euclid(IVec2::splat(BIOME_REGION_CHUNKS)));
+        iproduct!(
+            -BIOME_CELL_REACH..=BIOME_CELL_REACH,
+            -BIOME_CELL_REACH..=BIOME_CELL_REACH
+        )
+        .map(|(x, z)| region.cell(column + IVec2::new(x, z)).clone())
+        .collect()
+    }
+
+    fn void_biome(&self) -> RegistryId {
+        self.biome_registry
+            .lookup_name_to_object(VOID_BIOME_NAME.as_ref())
+            .unwrap()
+            .0
+    }
+
+    /// The biome cell graph of a region of chunks, shared by all the columns of the region.
+    fn biome_region(&self, region: IVec2) -> Arc<BiomeRegion> {
+        if let Some(cached) = self.region_cache.lock().unwrap().get(&region) {
+            return Arc::clone(cached);
+        }
+        let generated = Arc::new(self.generate_biome_region(region));
+        self.region_cache.lock().unwrap().put(region, Arc::clone(&generated));
+        generated
+    }
+
+    /// Builds the Voronoi graph of the biome cells of a region, with one jittered cell point per chunk, including the
+    /// cells within [`BIOME_CELL_REACH`] chunks around the region so that the columns at its border can be blended.
+    fn generate_biome_region(&self, region: IVec2) -> BiomeRegion {
+        let min = region * BIOME_REGION_CHUNKS - IVec2::splat(BIOME_CELL_REACH);
+        let side = BIOME_REGION_CHUNKS + 2 * BIOME_CELL_REACH;
 
         let mut centers: Vec<Center> = Vec::new();
         let mut center_lookup: HashMap<[i32; 2], usize> = HashMap::new();
@@ -79,12 +359,11 @@ impl<ED: GsExtraData> VoxelGenerator<ED> for MultiNoiseGenerator {
         let mut corner_map: HashMap<[i32; 2], usize> = HashMap::new();
         let mut edges: Vec<Edge> = Vec::new();
 
-        // Construct a new triangulation for this zone only.
         let mut delaunay = DelaunayTriangulation::new();
-        let mut vertex_point = None;
         let mut points = Vec::new();
-        for (x, z) in iproduct!(-2..=2, -2..=2) {
-            let mut position: DVec2 = (point.xz() + IVec2::new(x * CHUNK_DIM, z * CHUNK_DIM)).into();
+        for (z, x) in iproduct!(0..side, 0..side) {
+            let chunk = min + IVec2::new(x, z);
+            let mut position: DVec2 = (chunk * CHUNK_DIM).into();
             let noise = CHUNK_DIMD
                 * 0.75
                 * <OpenSimplex as NoiseNDTo2D<4>>::get_2d(&self.point_offset_noise, (position * BIOME_SIZE).to_array());
@@ -92,13 +371,12 @@ impl<ED: GsExtraData> VoxelGenerator<ED> for MultiNoiseGenerator {
             let point = delaunay
                 .insert(DVec2Wrapper(position))
                 .unwrap_or_else(|_| panic!("failed to insert point {position:?} into delaunay triangulation"));
-            if x == 0 && z == 0 {
-                vertex_point = Some(point);
-            } else {
-                points.push(point);
-            }
+            points.push((chunk, point));
         }
-        for point in points {
+
+        let random = PositionalRandomFactory::<Xoshiro128StarStar>::new(self.seed);
+        let mut cells = Vec::with_capacity(points.len());
+        for (chunk, point) in points {
             let center = self.make_edge_center_corner(
                 point,
                 &delaunay,
@@ -108,106 +386,49 @@ impl<ED: GsExtraData> VoxelGenerator<ED> for MultiNoiseGenerator {
                 &mut corner_map,
                 &mut edges,
             );
-            self.assign_biome(center, &mut centers, &mut rand);
+            self.assign_biome(
+                center,
+                &mut centers,
+                &mut random.get_at_column(BIOME_FALLBACK_SALT, chunk),
+            );
+            cells.push(center);
         }
 
-        let center = self.make_edge_center_corner(
-            vertex_point.unwrap(),
-            &delaunay,
-            &mut centers,
-            &mut center_lookup,
-            &mut corners,
-            &mut corner_map,
-            &mut edges,
-        );
-        self.assign_biome(center, &mut centers, &mut rand);
-
-        let mut blended = vec![SmallVec::new(); CHUNK_DIM2Z];
-
-        let void_id = self
-            .biome_registry
-            .lookup_name_to_object(VOID_BIOME_NAME.as_ref())
-            .unwrap()
-            .0;
-        let vparams: [i32; CHUNK_DIM2Z] = {
-            let mut vparams: [MaybeUninit<i32>; CHUNK_DIM2Z] = unsafe { MaybeUninit::uninit().assume_init() };
-            for (i, v) in vparams[..].iter_mut().enumerate() {
-                let ix = (i % CHUNK_DIMZ) as i32;
-                let iz = ((i / CHUNK_DIMZ) % CHUNK_DIMZ) as i32;
-                let (biomes, _) = self.find_biomes_at_point(
-                    DVec2::new((ix + point.x) as f64, (iz + point.z) as f64),
-                    void_id,
-                    &centers,
-                );
-                biomes.clone_into(&mut blended[(ix + iz * CHUNK_DIM) as usize]);
-
-                let p = Self::elevation_noise(
-                    IVec2::new(ix, iz),
-                    IVec2::new(position.x, position.z),
-                    &self.biome_registry,
-                    &blended,
-                    &self.noises,
-                )
-                .round() as i32;
-                unsafe {
-                    std::ptr::write(v.as_mut_ptr(), p);
-                }
-            }
-            unsafe { std::mem::transmute(vparams) }
-        };
-
-        let air = self
-            .block_registry
-            .lookup_name_to_object(EMPTY_BLOCK_NAME.as_ref())
-            .unwrap()
-            .0;
-        let mut chunk = Chunk::new(BlockEntry::new(air, 0), extra_data);
-
-        for (pos_x, pos_y, pos_z) in iproduct!(0..CHUNK_DIM, 0..CHUNK_DIM, 0..CHUNK_DIM) {
-            let b_pos = InChunkPos::try_new(pos_x, pos_y, pos_z).unwrap();
-
-            let g_pos = <IVec3>::from(b_pos) + (<IVec3>::from(position) * CHUNK_DIM);
-            let height = vparams[(pos_x + pos_z * CHUNK_DIM) as usize];
-
-            let mut biomes: SmallVec<[(&BiomeDefinition, f64); 3]> = SmallVec::new();
-            for b in blended[(pos_x + pos_z * CHUNK_DIM) as usize].iter() {
-                let e = b.lookup(&self.biome_registry).unwrap();
-                let w = b.weight * e.block_influence;
-                biomes.push((e, w));
-            }
-            // sort by block influence, then registry id if influence is same
-            biomes.sort_by(|a, b| {
-                a.1.partial_cmp(&b.1).unwrap_or_else(|| {
-                    self.biome_registry
-                        .search_object_to_id(a.0)
-                        .cmp(&self.biome_registry.search_object_to_id(b.0))
-                })
-            });
+        // The corners and edges are only needed to link the centers together.
+        BiomeRegion {
+            min,
+            side,
+            centers,
+            cells,
+        }
+    }
 
-            for (biome, _) in biomes.iter() {
-                let ctx = Context {
-                    seed: self.seed,
-                    chunk: &chunk.blocks,
-                    random: PositionalRandomFactory::default(),
-                    ground_y: height,
-                    sea_level: 0, /* hardcoded for now... */
-                };
-                let result = (biome.rule_source)(&g_pos, &ctx, &self.block_registry);
-                if let Some(result) = result {
-                    chunk.blocks.put(b_pos, result);
-                }
+    /// Keeps the terrain of a chunk generated for placing features, for when the chunk itself is generated.
+    fn cache_terrain(&self, position: AbsChunkPos, terrain: TerrainChunk) {
+        let mut cache = self.terrain_cache.lock().unwrap();
+        if cache.len() >= TERRAIN_CACHE_CAPACITY {
+            // Terrain of chunks at the edge of the generated area might never be requested, drop an arbitrary entry.
+            if let Some(&evicted) = cache.keys().next() {
+                cache.remove(&evicted);
             }
         }
-        chunk
+        cache.insert(position, terrain);
     }
-}
 
-impl MultiNoiseGenerator {
-    /// create a new StdGenerator.
-    pub fn new(seed: u64, biome_registry: Arc<BiomeRegistry>, block_registry: Arc<BlockRegistry>) -> Self {
+    /// Creates a new generator, failing if the block registry is missing the water or empty blocks.
+    pub fn new(seed: u64, biome_registry: Arc<BiomeRegistry>, block_registry: Arc<BlockRegistry>) -> Result<Self> {
         let seed_int = seed as u32;
+        let (water, _) = block_registry
+            .lookup_name_to_object(WATER_BLOCK_NAME.as_ref())
+            .ok_or_else(|| anyhow!("The sea fluid block {WATER_BLOCK_NAME} is not registered"))?;
+        let sea_fluid = BlockEntry::new(water, 0);
+        let (air, _) = block_registry
+            .lookup_name_to_object(EMPTY_BLOCK_NAME.as_ref())
+            .ok_or_else(|| anyhow!("The empty block {EMPTY_BLOCK_NAME} is not registered"))?;
+        let air = BlockEntry::new(air, 0);
+        let features = FeaturePlacer::new(seed, &biome_registry, &block_registry, &HashMap::new(), air);
 
-        Self {
+        Ok(Self {
             generatable_biomes: {
                 let mut biomes: Vec<(RegistryId, BiomeDefinition)> = Vec::new();
                 for (id, _name, def) in biome_registry.iter() {
@@ -217,6 +438,19 @@ impl MultiNoiseGenerator {
                 }
                 biomes
             },
+            surface_graphs: biome_registry
+                .iter()
+                .filter_map(|(id, name, def)| match &def.surface_noise {
+                    SurfaceNoise::Graph(definition) => match NoiseGraph::new(definition, seed_int) {
+                        Ok(graph) => Some((id, graph)),
+                        Err(e) => {
+                            warn!("Invalid surface noise graph of biome {name}: {e}");
+                            None
+                        }
+                    },
+                    _ => None,
+                })
+                .collect(),
 
             biome_registry,
             block_registry,
@@ -224,7 +458,7 @@ impl MultiNoiseGenerator {
             seed,
 
             noises: Noises {
-                base_terrain_noise: Fbm::<OpenSimplex>::new(seed_int).set_octaves(vec![-4.0, 1.0, 1.0, 0.0]),
+                base_terrain_noise: base_terrain_noise(seed_int),
                 elevation_noise: Fbm::<OpenSimplex>::new(seed_int.wrapping_pow(1347))
                     .set_octaves(vec![1.0, 2.0, 2.0, 1.0]),
                 temperature_noise: Fbm::<OpenSimplex>::new(seed_int.wrapping_pow(2349))
@@ -233,34 +467,115 @@ impl MultiNoiseGenerator {
                     .set_octaves(vec![1.0, 2.0, 2.0, 1.0]),
             },
             point_offset_noise: OpenSimplex::new(seed_int.wrapping_mul(5463)),
-        }
+            density: None,
+            sea_level: DEFAULT_SEA_LEVEL,
+            sea_fluid,
+            air,
+
+            features,
+            pending_features: PendingFeatureWrites::default(),
+            terrain_cache: Mutex::new(HashMap::new()),
+            region_cache: Mutex::new(LruCache::new(BIOME_REGION_CACHE_CAPACITY)),
+            column_cache: Mutex::new(LruCache::new(COLUMN_CACHE_CAPACITY)),
+        })
     }
 
-    fn elevation_noise(
-        in_chunk_pos: IVec2,
-        chunk_pos: IVec2,
-        biome_registry: &BiomeRegistry,
-        blended: &[SmallVec<[BiomeEntry; EXPECTED_BIOME_COUNT]>],
-        noises: &Noises,
-    ) -> f64 {
-        let nf = |p: DVec2, b: &BiomeDefinition| ((b.surface_noise)(p, &noises.base_terrain_noise) + 1.0) / 2.0;
-        let scale_factor = GLOBAL_BIOME_SCALE * GLOBAL_SCALE_MOD;
-        let blend = &blended[(in_chunk_pos.x + in_chunk_pos.y * CHUNK_DIM) as usize];
-        let global_pos = DVec2::new(
-            (in_chunk_pos.x + (chunk_pos.x * CHUNK_DIM)) as f64,
-            (in_chunk_pos.y + (chunk_pos.y * CHUNK_DIM)) as f64,
+    /// Sets the Y level below which open space is flooded with water.
+    pub fn with_sea_level(mut self, sea_level: i32) -> Self {
+        self.sea_level = sea_level;
+        self
+    }
+
+    /// The Y level below which open space is flooded with water.
+    pub fn sea_level(&self) -> i32 {
+        self.sea_level
+    }
+
+    /// Makes the given structure templates available to the structure features of the biomes.
+    pub fn with_structure_templates(mut self, templates: &HashMap<RegistryName, StructureTemplate>) -> Self {
+        self.features = FeaturePlacer::new(
+            self.seed,
+            &self.biome_registry,
+            &self.block_registry,
+            templates,
+            self.air,
         );
+        self
+    }
 
-        let mut heights = 0.0;
-        let mut weights = 0.0;
-        for entry in blend {
-            let biome = entry.lookup(biome_registry).unwrap();
-            let noise = nf(global_pos / scale_factor, biome);
-            let strength = entry.weight * biome.blend_influence;
-            heights += noise * strength;
-            weights += strength;
+    /// Enables the 3D density stage with the given settings, adding overhangs and caves to the terrain.
+    pub fn with_terrain_density(mut self, settings: &TerrainDensitySettings) -> Result<Self> {
+        self.density = Some(TerrainDensity::new(settings, self.seed as u32)?);
+        Ok(self)
+    }
+
+    /// Computes the biomes and terrain parameters of the block column of the given position, without generating any
+    /// chunk. They only depend on the X and Z coordinates, and match the ones used when generating the column.
+    pub fn query_terrain(&self, position: AbsBlockPos) -> TerrainQuery {
+        let column = IVec2::new(position.x, position.z);
+        let centers = self.cells_around(column.div_euclid(IVec2::splat(CHUNK_DIM)));
+        let (mut biomes, (elevation, temperature, moisture)) =
+            self.find_biomes_at_point(column.as_dvec2(), self.void_biome(), &centers);
+        let surface_height = self.elevation_noise(&[column], std::slice::from_ref(&biomes))[0].round() as i32;
+
+        // The blending weights of the cells don't add up to 1 on their own.
+        let total: f64 = biomes.iter().map(|entry| entry.weight).sum();
+        biomes.iter_mut().for_each(|entry| entry.weight /= total);
+        biomes.sort_by(|a, b| b.weight.total_cmp(&a.weight).then(a.id.cmp(&b.id)));
+        TerrainQuery {
+            biomes,
+            elevation: elevation / total,
+            temperature: temperature / total,
+            moisture: moisture / total,
+            surface_height,
         }
-        heights / weights
+    }
+
+    /// The heights of the terrain surface of block columns, blended between the biomes of each column.
+    /// Each surface graph is sampled for all the columns blending it in one batch.
+    fn elevation_noise(&self, columns: &[IVec2], blends: &[SmallVec<[BiomeEntry; EXPECTED_BIOME_COUNT]>]) -> Vec<f64> {
+        let scale_factor = GLOBAL_BIOME_SCALE * GLOBAL_SCALE_MOD;
+        let mut graph_points: HashMap<RegistryId, Vec<DVec2>> = HashMap::new();
+        for (column, blend) in columns.iter().zip(blends) {
+            for entry in blend.iter().filter(|entry| self.surface_graphs.contains_key(&entry.id)) {
+                graph_points
+                    .entry(entry.id)
+                    .or_default()
+                    .push(column.as_dvec2() / scale_factor);
+            }
+        }
+        // Consumed in the same order as the points were collected.
+        let mut graph_heights: HashMap<RegistryId, std::vec::IntoIter<f64>> = graph_points
+            .into_iter()
+            .map(|(id, points)| {
+                let heights = self.surface_graphs[&id].sample_2d_batch(points, &mut NoiseGraphCache::default());
+                (id, heights.into_iter())
+            })
+            .collect();
+
+        columns
+            .iter()
+            .zip(blends)
+            .map(|(column, blend)| {
+                let mut heights = 0.0;
+                let mut weights = 0.0;
+                for entry in blend {
+                    let biome = entry.lookup(&self.biome_registry).unwrap();
+                    let height = match (&biome.surface_noise, graph_heights.get_mut(&entry.id)) {
+                        (_, Some(graph_heights)) => graph_heights.next().unwrap(),
+                        (SurfaceNoise::Code(function), None) => {
+                            function(column.as_dvec2() / scale_factor, &self.noises.base_terrain_noise)
+                        }
+                        // Invalid graphs are reported when the generator is created.
+                        (SurfaceNoise::Graph(_), None) => 0.0,
+                    };
+                    let strength = entry.weight * biome.blend_influence;
+                    heights += (height + 1.0) / 2.0 * strength;
+                    weights += strength;
+                }
+                heights / weights
+            })
+            .collect()
     }
 
     fn add_to_list(v: &mut Vec<usize>, x: &Option<usize>) {
@@ -460,7 +775,7 @@ impl MultiNoiseGenerator {
         }
     }
 
-    fn assign_biome(&self, center: usize, centers: &mut [Center], rand: &mut Xoshiro128StarStar) {
+    fn assign_biome(&self, center: usize, centers: &mut [Center], rand: &mut impl Rng) {
         // go over all centers and assign biomes to them based on noise & other parameters.
         let center = &mut centers[center];
         if center.biome.is_some() {
@@ -772,3 +1087,164 @@ impl From<DVec2Wrapper> for DVec2 {
         value.0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::registry::{Registry, RegistryName};
+
+    use super::*;
+    use crate::voxel::biomes::{layered_terrain_surface, setup_basic_biomes};
+    use crate::voxel::blocks::{setup_basic_blocks, SAND_BLOCK_NAME};
+    use crate::{ServerData, DEFAULT_WORLD_SEED};
+
+    /// How many chunks away from the spawn, in each direction, to look for a coast.
+    const COAST_SEARCH_CHUNKS: i32 = 16;
+
+    /// The builtin biomes, with only the ocean generating, over a floor that rises above the sea level every 64 blocks.
+    fn shallow_ocean_biomes() -> BiomeRegistry {
+        let mut builtin = Registry::default();
+        setup_basic_biomes(&mut builtin);
+        let mut biomes = Registry::default();
+        for (_, name, biome) in builtin.iter() {
+            let mut biome = biome.clone();
+            biome.can_generate = name == OCEAN_BIOME_NAME.as_ref();
+            if biome.can_generate {
+                biome.surface_noise = layered_terrain_surface(&[(1.0, 8.0)], 0.0);
+            }
+            biomes.push_object(biome).unwrap();
+        }
+        biomes
+    }
+
+    fn block_id(registry: &BlockRegistry, name: &RegistryName) -> BlockEntry {
+        BlockEntry::new(registry.lookup_name_to_object(name.as_ref()).unwrap().0, 0)
+    }
+
+    #[test]
+    fn coastlines_appear() {
+        let mut blocks = Registry::default();
+        setup_basic_blocks(&mut blocks);
+        let blocks = Arc::new(blocks);
+        let mut biomes = Registry::default();
+        setup_basic_biomes(&mut biomes);
+        let generator = MultiNoiseGenerator::new(DEFAULT_WORLD_SEED, Arc::new(biomes), Arc::clone(&blocks)).unwrap();
+        let (water, sand) = (
+            block_id(&blocks, &WATER_BLOCK_NAME),
+            block_id(&blocks, &SAND_BLOCK_NAME),
+        );
+        let sea_level = generator.sea_level();
+
+        // Look for a column of the heightmap under the sea next to one at the shore, nearest to the spawn first.
+        let mut columns = iproduct!(
+            -COAST_SEARCH_CHUNKS..COAST_SEARCH_CHUNKS,
+            -COAST_SEARCH_CHUNKS..COAST_SEARCH_CHUNKS
+        )
+        .map(|(x, z)| IVec2::new(x, z))
+        .sorted_by_key(|column| column.length_squared());
+        let coast = columns.find_map(|column| {
+            let heights = &generator.chunk_column(column).heights;
+            let height = |x: i32, z: i32| heights[(x + z * CHUNK_DIM) as usize];
+            iproduct!(0..CHUNK_DIM - 1, 0..CHUNK_DIM).find_map(|(x, z)| {
+                let (sea, shore) = match (height(x, z), height(x + 1, z)) {
+                    (a, b) if a < sea_level - 1 && (sea_level - 1..=sea_level).contains(&b) => (x, x + 1),
+                    (a, b) if b < sea_level - 1 && (sea_level - 1..=sea_level).contains(&a) => (x + 1, x),
+                    _ => return None,
+                };
+                Some((column, sea, shore, z))
+            })
+        });
+        let (column, sea, shore, z) = coast.expect("No coast near the spawn");
+
+        // The ground is sandy right under the sea level at the shore, and flooded in the sea.
+        let chunk: Chunk<ServerData> = generator.generate_chunk(
+            AbsBlockPos::new(column.x * CHUNK_DIM, sea_level - 1, column.y * CHUNK_DIM).into(),
+            Default::default(),
+        );
+        let y = (sea_level - 1).rem_euclid(CHUNK_DIM);
+        let at = |x| *chunk.blocks.get(InChunkPos::try_new(x, y, z).unwrap());
+        assert_eq!(at(sea), water, "No water below the sea level");
+        assert_eq!(at(shore), sand, "No sandy shore next to the water");
+    }
+
+    #[test]
+    fn missing_sea_fluid_is_an_error() {
+        let mut biomes = Registry::default();
+        setup_basic_biomes(&mut biomes);
+        let generator = MultiNoiseGenerator::new(1, Arc::new(biomes), Arc::new(Registry::default()));
+        assert!(generator.is_err());
+    }
+
+    #[test]
+    fn biome_regions_agree_on_shared_cells() {
+        let mut blocks = Registry::default();
+        setup_basic_blocks(&mut blocks);
+        let mut biomes = Registry::default();
+        setup_basic_biomes(&mut biomes);
+        let generator = MultiNoiseGenerator::new(7, Arc::new(biomes), Arc::new(blocks)).unwrap();
+
+        let (left, right) = (generator.biome_region(IVec2::ZERO), generator.biome_region(IVec2::X));
+        // The margins of neighbouring regions overlap, with the same cells in both.
+        for (x, z) in iproduct!(
+            BIOME_REGION_CHUNKS - BIOME_CELL_REACH..BIOME_REGION_CHUNKS + BIOME_CELL_REACH,
+            0..BIOME_REGION_CHUNKS
+        ) {
+            let (a, b) = (left.cell(IVec2::new(x, z)), right.cell(IVec2::new(x, z)));
+            assert_eq!((a.point, a.biome), (b.point, b.biome));
+            assert!(a.biome.is_some());
+        }
+
+        let column = generator.chunk_column(IVec2::new(BIOME_REGION_CHUNKS - 1, 2));
+        assert!(Arc::ptr_eq(
+            &column,
+            &generator.chunk_column(IVec2::new(BIOME_REGION_CHUNKS - 1, 2))
+        ));
+        assert_eq!(column.heights.len(), CHUNK_DIM2Z);
+        assert!(column.blended.iter().all(|blend| !blend.is_empty()));
+    }
+
+    #[test]
+    fn terrain_queries_match_generated_columns() {
+        let mut blocks = Registry::default();
+        setup_basic_blocks(&mut blocks);
+        let mut biomes = Registry::default();
+        setup_basic_biomes(&mut biomes);
+        let generator = MultiNoiseGenerator::new(99, Arc::new(biomes), Arc::new(blocks)).unwrap();
+
+        let chunk = IVec2::new(-3, 5);
+        let column = generator.chunk_column(chunk);
+        for (x, z) in iproduct!([0, 7, CHUNK_DIM - 1], [0, 12, CHUNK_DIM - 1]) {
+            let block = chunk * CHUNK_DIM + IVec2::new(x, z);
+            let i = (x + z * CHUNK_DIM) as usize;
+            let query = generator.query_terrain(AbsBlockPos::new(block.x, 40, block.y));
+            assert_eq!(query, generator.query_terrain(AbsBlockPos::new(block.x, -200, block.y)));
+            assert_eq!(query.surface_height, column.heights[i]);
+
+            let mut queried: Vec<RegistryId> = query.biomes.iter().map(|entry| entry.id).collect();
+            let mut generated: Vec<RegistryId> = column.blended[i].iter().map(|entry| entry.id).collect();
+            queried.sort();
+            generated.sort();
+            assert_eq!(queried, generated);
+            let total: f64 = query.biomes.iter().map(|entry| entry.weight).sum();
+            assert!((total - 1.0).abs() < 1e-9);
+            assert!(query.biomes.windows(2).all(|pair| pair[0].weight >= pair[1].weight));
+            for noise in [query.elevation, query.temperature, query.moisture] {
+                assert!((0.0..=5.0).contains(&noise), "{noise} out of range");
+            }
+        }
+    }
+
+    #[test]
+    fn sea_level_floods_open_space() {
+        let mut blocks = Registry::default();
+        setup_basic_blocks(&mut blocks);
+        let blocks = Arc::new(blocks);
+        let generator = MultiNoiseGenerator::new(42, Arc::new(shallow_ocean_biomes()), Arc::clone(&blocks))
+            .unwrap()
+            .with_sea_level(100);
+        let water = block_id(&blocks, &WATER_BLOCK_NAME);
+        let chunk: Chunk<ServerData> = generator.generate_chunk(AbsChunkPos::new(0, 2, 0), Default::default());
+        for (x, y, z) in iproduct!(0..CHUNK_DIM, 0..CHUNK_DIM, 0..CHUNK_DIM) {
+            assert_eq!(*chunk.blocks.get(InChunkPos::try_new(x, y, z).unwrap()), water);
+        }
+    }
+}
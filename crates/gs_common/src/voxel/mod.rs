@@ -2,6 +2,8 @@
 
 pub mod biomes;
 pub mod blocks;
+pub mod edit_ops;
 pub mod generator;
 pub mod persistence;
 pub mod plugin;
+pub mod structures;
@@ -1,35 +1,43 @@
 //! The Bevy plugin for voxel universe handling.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::marker::PhantomData;
 use std::sync::Arc;
 
 use bevy::prelude::*;
 use capnp::message::TypedBuilder;
-use gs_schemas::coordinates::{AbsBlockPos, AbsChunkPos, AbsChunkRange, RelChunkPos};
+use gs_schemas::coordinates::{AbsBlockPos, AbsChunkPos, AbsChunkRange, InChunkPos, RelChunkPos};
 use gs_schemas::dependencies::itertools::Itertools;
 use gs_schemas::mutwatcher::{MutWatcher, RevisionNumber};
 use gs_schemas::schemas::network_capnp::stream_header::StandardTypes;
-use gs_schemas::schemas::NetworkStreamHeader;
+use gs_schemas::schemas::{NetworkStreamHeader, NetworkStreamType};
 use gs_schemas::voxel::biome::BiomeRegistry;
 use gs_schemas::voxel::chunk::Chunk;
 use gs_schemas::voxel::chunk_group::ChunkGroup;
-use gs_schemas::voxel::voxeltypes::BlockRegistry;
+use gs_schemas::voxel::chunk_storage::ChunkStorage;
+use gs_schemas::voxel::collision::ChunkGroupCollision;
+use gs_schemas::voxel::voxeltypes::{BlockEntry, BlockRegistry};
 use gs_schemas::{GameSide, GsExtraData};
 use smallvec::SmallVec;
-use tokio::task::JoinSet;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
 use tokio_util::bytes::Bytes;
 
+use crate::network::chunk_queue::{ChunkQueuePush, ChunkSendQueueHandle};
+use crate::network::protocol::{unpack_stream_packet, write_stream_packet, ProtocolFeatures};
 use crate::network::server::ConnectedPlayer;
 use crate::network::thread::{NetworkThread, NetworkThreadState};
 use crate::network::transport::TransportStream;
-use crate::network::PeerAddress;
+use crate::player::PlayerPhysics;
+use crate::prelude::*;
+use crate::voxel::generator::multi_noise::MultiNoiseGenerator;
 use crate::voxel::persistence::ChunkPersistenceLayer;
-use crate::{prelude::*, GameServer, GameServerResource};
 use crate::{InGameSystemSet, ServerData};
 
 /// The maximum number of stored chunk packets before applying stream backpressure.
 pub const CHUNK_PACKET_QUEUE_LENGTH: usize = 20;
+/// The stream carrying chunk data from the server to the client.
+pub const CHUNK_DATA_STREAM: NetworkStreamType =
+    NetworkStreamType::new(NetworkStreamHeader::Standard(StandardTypes::ChunkData), 1);
 
 /// Initializes the settings related to the voxel universe.
 #[derive(Default)]
@@ -39,6 +47,10 @@ pub struct VoxelUniversePlugin<ExtraData: GsExtraData> {
 
 impl<ExtraData: GsExtraData> Plugin for VoxelUniversePlugin<ExtraData> {
     fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            (voxel_collision_update_system::<ExtraData>).in_set(InGameSystemSet),
+        );
         if ExtraData::SIDE == GameSide::Server {
             app.add_systems(
                 FixedPreUpdate,
@@ -87,6 +99,10 @@ pub struct VoxelUniverse<ExtraData: GsExtraData> {
     _extra_data: PhantomData<ExtraData>,
 }
 
+/// Block collision data of a voxel universe, exists alongside VoxelUniverse and follows the changes of its chunks.
+#[derive(Component, Default, Deref, DerefMut)]
+pub struct VoxelCollision(pub ChunkGroupCollision);
+
 /// Persistent storage for chunks, exists alongside VoxelUniverse on servers.
 #[derive(Component)]
 pub struct PersistentVoxelStorage<ExtraData: GsExtraData> {
@@ -110,6 +126,10 @@ pub struct BlockRegistryHolder(pub Arc<BlockRegistry>);
 #[derive(Resource, Clone, Deref)]
 pub struct BiomeRegistryHolder(pub Arc<BiomeRegistry>);
 
+/// The bevy [`Resource`] for access to the server's world generator, for terrain queries outside of chunk generation.
+#[derive(Resource, Clone, Deref)]
+pub struct WorldGeneratorHolder(pub Arc<MultiNoiseGenerator>);
+
 /// Component for entities anchored in the voxel grid.
 #[derive(Component, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Deref, DerefMut)]
 pub struct VoxelPosition(pub AbsBlockPos);
@@ -152,7 +172,11 @@ impl<'world, ED: GsExtraData> VoxelUniverseBuilder<'world, ED> {
 
         world.insert_resource(BlockRegistryHolder(Arc::clone(&block_registry)));
         world.insert_resource(BiomeRegistryHolder(Arc::clone(&biome_registry)));
-        let bundle = world.spawn((VoxelUniverseTag, VoxelUniverse::<ED>::new(default())));
+        let bundle = world.spawn((
+            VoxelUniverseTag,
+            VoxelUniverse::<ED>::new(default()),
+            VoxelCollision::default(),
+        ));
 
         Ok(Self {
             _block_registry: block_registry,
@@ -191,9 +215,17 @@ impl<'world, ED: GsExtraData> VoxelUniverseBuilder<'world, ED> {
             chunk_packet_receiver: rx,
         });
         net_thread.insert_stream_handler(
-            NetworkStreamHeader::Standard(StandardTypes::ChunkData),
-            Box::new(move |_state, stream| {
-                Box::pin(NetworkVoxelClient::<ED>::chunk_stream_handler(stream, tx.clone()))
+            CHUNK_DATA_STREAM,
+            Box::new(move |state, stream| {
+                let compressed = state
+                    .borrow()
+                    .server_protocol()
+                    .is_some_and(|protocol| protocol.features.contains(ProtocolFeatures::COMPRESSION));
+                Box::pin(NetworkVoxelClient::<ED>::chunk_stream_handler(
+                    stream,
+                    compressed,
+                    tx.clone(),
+                ))
             }),
         );
         Ok(self)
@@ -227,10 +259,95 @@ impl<ExtraData: GsExtraData> VoxelUniverse<ExtraData> {
     }
 }
 
+impl VoxelUniverse<ServerData> {
+    /// Records the chunk revisions a reconnecting player still has cached, so that they aren't sent again.
+    /// Only revisions matching the loaded chunks are trusted, everything else gets sent as usual.
+    /// Returns the number of reused chunks.
+    pub fn mark_chunks_held_by(&mut self, player: Entity, held_chunks: &[(AbsChunkPos, RevisionNumber)]) -> usize {
+        let mut reused = 0;
+        for &(position, revision) in held_chunks {
+            let Some(chunk) = self.loaded_chunks.chunks.get_mut(&position) else {
+                continue;
+            };
+            if chunk.local_revision() != revision {
+                continue;
+            }
+            chunk
+                .mutate_without_revision()
+                .extra_data
+                .player_held_revisions
+                .insert(player, revision);
+            reused += 1;
+        }
+        reused
+    }
+
+    /// Applies a block edit request of a player, edits with a `None` block were rejected and change nothing.
+    /// Each touched chunk held by the player gets exactly one new revision even if all of its edits were rejected,
+    /// so that the chunk update sent afterwards resolves the player's prediction.
+    /// Edits in chunks the player does not hold are skipped, returns those chunks with their revision if loaded.
+    /// The collision data of the edited blocks is updated right away, instead of rebuilding the whole chunks.
+    pub fn apply_player_block_edits(
+        &mut self,
+        player: Entity,
+        edits: &[(AbsBlockPos, Option<BlockEntry>)],
+        collision: &mut ChunkGroupCollision,
+        registry: &BlockRegistry,
+    ) -> Vec<(AbsChunkPos, Option<RevisionNumber>)> {
+        let mut by_chunk: BTreeMap<AbsChunkPos, SmallVec<[(InChunkPos, Option<BlockEntry>); 8]>> = BTreeMap::new();
+        for &(pos, block) in edits {
+            let (cpos, ipos) = pos.split_chunk_component();
+            by_chunk.entry(cpos).or_default().push((ipos, block));
+        }
+        let mut skipped = Vec::new();
+        for (cpos, chunk_edits) in by_chunk {
+            let Some(chunk) = self.loaded_chunks.chunks.get_mut(&cpos) else {
+                skipped.push((cpos, None));
+                continue;
+            };
+            if !chunk.extra_data.player_held_revisions.contains_key(&player) {
+                skipped.push((cpos, Some(chunk.local_revision())));
+                continue;
+            }
+            let previous = chunk.new_with_same_revision(());
+            let applied: SmallVec<[(InChunkPos, BlockEntry); 8]> = chunk_edits
+                .into_iter()
+                .filter_map(|(ipos, block)| Some((ipos, block?)))
+                .collect();
+            let blocks = &mut chunk.mutate_stored().blocks;
+            for &(ipos, block) in applied.iter() {
+                blocks.put(ipos, block);
+            }
+            collision.update_edited_blocks(cpos, &previous, chunk, applied, registry);
+        }
+        skipped
+    }
+}
+
 impl<ED: GsExtraData> NetworkVoxelClient<ED> {
-    async fn chunk_stream_handler(stream: TransportStream, packet_queue: AsyncBoundedSender<Bytes>) {
+    async fn chunk_stream_handler(stream: TransportStream, compressed: bool, packet_queue: AsyncBoundedSender<Bytes>) {
         while let Some(raw_packet) = stream.recv().await {
-            if let Err(e) = packet_queue.send(raw_packet).await {
+            let raw_packet = if compressed {
+                match unpack_stream_packet(&raw_packet) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        error!("Error while unpacking chunk data packet: {e}");
+                        break;
+                    }
+                }
+            } else {
+                raw_packet
+            };
+            let result = match packet_queue.try_send(raw_packet) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(raw_packet)) => {
+                    // The game can't keep up, stop reading to push the backpressure onto the server.
+                    stream.counters().record_stall();
+                    packet_queue.send(raw_packet).await
+                }
+                Err(TrySendError::Closed(raw_packet)) => Err(SendError(raw_packet)),
+            };
+            if let Err(e) = result {
                 error!("Error while queueing chunk data packet: {e}");
                 break;
             }
@@ -238,6 +355,19 @@ impl<ED: GsExtraData> NetworkVoxelClient<ED> {
     }
 }
 
+/// Rebuilds the collision data of chunks changed since the last tick.
+pub fn voxel_collision_update_system<ED: GsExtraData>(
+    block_registry: Res<BlockRegistryHolder>,
+    mut voxel_q: Query<(&VoxelUniverse<ED>, &mut VoxelCollision)>,
+) {
+    for (voxels, mut collision) in voxel_q.iter_mut() {
+        let rebuilt = collision.update(voxels.loaded_chunks(), &block_registry);
+        if rebuilt > 0 {
+            trace!(rebuilt, "Rebuilt chunk collision data");
+        }
+    }
+}
+
 fn server_system_process_chunk_loading(
     mut voxel_q: Query<(
         &mut VoxelUniverse<ServerData>,
@@ -307,9 +437,8 @@ fn server_system_process_chunk_loading(
 }
 
 fn server_system_process_chunk_sending(
-    engine: Res<GameServerResource>,
     mut voxel_q: Query<&mut VoxelUniverse<ServerData>>,
-    connected_players_q: Query<(Entity, &ConnectedPlayer)>,
+    connected_players_q: Query<(Entity, &ChunkSendQueueHandle, &PlayerPhysics), With<ConnectedPlayer>>,
 ) {
     // TODO: send only nearby chunks, not everything. Also don't iterate every chunk every tick.
     if connected_players_q.is_empty() {
@@ -319,46 +448,71 @@ fn server_system_process_chunk_sending(
     let Ok(mut voxels) = voxel_q.get_single_mut() else {
         return;
     };
-    let voxels = &mut *voxels;
+    let chunks = &mut voxels.loaded_chunks_mut().chunks;
 
-    let engine = &engine.0 as &GameServer;
+    let mut send_list: SmallVec<[(Entity, &ChunkSendQueueHandle, i64); 8]> = SmallVec::new();
+    let mut evicted: Vec<(AbsChunkPos, Entity)> = Vec::new();
 
-    let mut send_list: SmallVec<[PeerAddress; 8]> = SmallVec::new();
-
-    for (&position, loaded_chunk) in voxels.loaded_chunks_mut().chunks.iter_mut() {
+    for (&position, loaded_chunk) in chunks.iter_mut() {
         send_list.clear();
         let chunk_rev = loaded_chunk.local_revision();
         let chunk_player_list = &mut loaded_chunk.mutate_without_revision().extra_data.player_held_revisions;
         // remove disconnected players
         chunk_player_list.retain(|&player, _rev| connected_players_q.contains(player));
-        // find players with outdated revisions
-        for (pid, player) in connected_players_q.iter() {
-            let peer = player.address;
-            let entry = chunk_player_list.entry(pid).or_insert_with(|| {
-                send_list.push(peer);
-                chunk_rev
-            });
-            if *entry < chunk_rev {
-                send_list.push(peer);
-                *entry = chunk_rev;
+        // find players with outdated revisions and room in their queues
+        for (pid, queue, physics) in connected_players_q.iter() {
+            if chunk_player_list.get(&pid).is_some_and(|&held| held >= chunk_rev) {
+                continue;
+            }
+            let player_chunk = AbsChunkPos::from(AbsBlockPos::from(physics.state.position.floor().as_ivec3()));
+            let distance_squared = player_chunk
+                .into_ivec3()
+                .as_i64vec3()
+                .distance_squared(position.into_ivec3().as_i64vec3());
+            if queue.accepts(position, distance_squared) {
+                send_list.push((pid, queue, distance_squared));
             }
         }
         if send_list.is_empty() {
             continue;
         }
-        // serialize chunk once and send to all players
+        // serialize chunk once and queue it for all players, encoding it at most once per packet encoding
         // TODO: tick counter system
-        send_chunk_to_players(0, engine, position, loaded_chunk, &send_list);
+        let message = serialize_chunk_packet(0, position, loaded_chunk);
+        let mut packets: [Option<Bytes>; 2] = [None, None];
+        let chunk_player_list = &mut loaded_chunk.mutate_without_revision().extra_data.player_held_revisions;
+        for &(pid, queue, distance_squared) in &send_list {
+            let compressed = queue.is_compressed();
+            let packet = packets[compressed as usize]
+                .get_or_insert_with(|| write_stream_packet(message.borrow_inner(), compressed))
+                .clone();
+            let result = queue.push(position, chunk_rev, distance_squared, packet);
+            if let ChunkQueuePush::Evicted(evicted_position) = result {
+                evicted.push((evicted_position, pid));
+            }
+            if result.is_accepted() {
+                chunk_player_list.insert(pid, chunk_rev);
+            }
+        }
+    }
+
+    // Evicted chunks were never sent, forget about them to offer them again once there's room in the queue.
+    for (position, pid) in evicted {
+        if let Some(chunk) = chunks.get_mut(&position) {
+            chunk
+                .mutate_without_revision()
+                .extra_data
+                .player_held_revisions
+                .remove(&pid);
+        }
     }
 }
 
-fn send_chunk_to_players(
+fn serialize_chunk_packet(
     tick: u64,
-    engine: &GameServer,
     pos: AbsChunkPos,
     chunk: &MutWatcher<Chunk<ServerData>>,
-    peers: &[PeerAddress],
-) {
+) -> TypedBuilder<rpc::chunk_data_stream_packet::Owned> {
     let mut builder = TypedBuilder::<rpc::chunk_data_stream_packet::Owned>::new_default();
     let mut root = builder.init_root();
     root.set_tick(tick);
@@ -368,68 +522,101 @@ fn send_chunk_to_players(
     position.set_y(pos.y);
     position.set_z(pos.z);
     chunk.write_full(&mut root.reborrow().init_data());
-    let mut buffer = Vec::new();
-    capnp::serialize::write_message(&mut buffer, builder.borrow_inner()).unwrap();
-    let buffer = Bytes::from(buffer);
-
-    // TODO: error handling, throttling
-    let peers: SmallVec<[_; 8]> = peers.into();
-    let _ = engine.network_thread.schedule_task(move |rstate| {
-        Box::pin(async move {
-            let mut joiner: JoinSet<Result<()>> = JoinSet::new();
-            for addr in peers {
-                let rstate_inner = rstate.clone();
-                let state = rstate.borrow();
-                let my_buffer = buffer.clone();
-                let Some(peer) = state.find_connected_client(addr) else {
-                    bail!("Cannot find connected client {addr:?} anymore");
-                };
-                match &peer.chunk_stream {
-                    Some(chunk_stream) => {
-                        let chunk_stream = chunk_stream.clone();
-                        joiner.spawn_local(async move {
-                            chunk_stream.send(my_buffer).await?;
-                            Ok(())
-                        });
-                    }
-                    None => {
-                        // TODO: encapsulate safe concurrent stream opening
-                        let open_stream = peer.open_stream(NetworkStreamHeader::Standard(StandardTypes::ChunkData));
-                        joiner.spawn_local(async move {
-                            let mut open_stream = open_stream.await?;
-                            {
-                                let mut state = rstate_inner.borrow_mut();
-                                let client = state.find_connected_client_mut(addr).context("Client went missing")?;
-                                if let Some(already_open_stream) = &client.chunk_stream {
-                                    open_stream = already_open_stream.clone();
-                                } else {
-                                    client.chunk_stream = Some(open_stream.clone());
-                                }
-                            }
-                            open_stream.send(my_buffer).await?;
-                            Ok(())
-                        });
-                    }
-                }
-            }
-            while let Some(result) = joiner.join_next().await {
-                match result {
-                    Err(join_error) => {
-                        if join_error.is_cancelled() {
-                            continue;
-                        } else if join_error.is_panic() {
-                            std::panic::resume_unwind(join_error.into_panic())
-                        } else {
-                            unreachable!()
-                        }
-                    }
-                    Ok(Err(error)) => {
-                        error!("Error while sending chunk data to player: {error}");
-                    }
-                    Ok(Ok(())) => {}
-                }
-            }
-            Ok(())
-        })
-    });
+    builder
+}
+
+#[cfg(test)]
+mod test {
+    use gs_schemas::voxel::voxeltypes::EMPTY_BLOCK_NAME;
+
+    use super::*;
+    use crate::builtin_game_registries;
+    use crate::voxel::blocks::STONE_BLOCK_NAME;
+
+    #[test]
+    fn held_chunks_are_not_resent() {
+        let registry = builtin_game_registries().block_types;
+        let empty = BlockEntry::new(registry.lookup_name_to_object(EMPTY_BLOCK_NAME.as_ref()).unwrap().0, 0);
+        let mut voxels = VoxelUniverse::<ServerData>::new(());
+        let rev = |n| RevisionNumber::new(n).unwrap();
+        for x in 0..3 {
+            let chunk = Chunk::new(empty, Default::default());
+            voxels
+                .loaded_chunks_mut()
+                .chunks
+                .insert(AbsChunkPos::new(x, 0, 0), MutWatcher::new_saved(chunk, rev(5)));
+        }
+
+        let player = Entity::from_raw(7);
+        let held = [
+            (AbsChunkPos::new(0, 0, 0), rev(5)),
+            (AbsChunkPos::new(1, 0, 0), rev(4)),
+            (AbsChunkPos::new(9, 0, 0), rev(5)),
+        ];
+        assert_eq!(voxels.mark_chunks_held_by(player, &held), 1);
+        let held_revision = |x| {
+            voxels.loaded_chunks().chunks[&AbsChunkPos::new(x, 0, 0)]
+                .extra_data
+                .player_held_revisions
+                .get(&player)
+                .copied()
+        };
+        assert_eq!(held_revision(0), Some(rev(5)));
+        assert_eq!(held_revision(1), None);
+        assert_eq!(held_revision(2), None);
+    }
+
+    #[test]
+    fn edits_apply_to_held_chunks_only() {
+        let registry = builtin_game_registries().block_types;
+        let empty = BlockEntry::new(registry.lookup_name_to_object(EMPTY_BLOCK_NAME.as_ref()).unwrap().0, 0);
+        let stone = BlockEntry::new(registry.lookup_name_to_object(STONE_BLOCK_NAME.as_ref()).unwrap().0, 0);
+        let mut voxels = VoxelUniverse::<ServerData>::new(());
+        let rev = |n| RevisionNumber::new(n).unwrap();
+        for x in 0..2 {
+            let chunk = Chunk::new(empty, Default::default());
+            voxels
+                .loaded_chunks_mut()
+                .chunks
+                .insert(AbsChunkPos::new(x, 0, 0), MutWatcher::new_saved(chunk, rev(5)));
+        }
+        let player = Entity::from_raw(7);
+        voxels.mark_chunks_held_by(player, &[(AbsChunkPos::new(0, 0, 0), rev(5))]);
+
+        let mut collision = ChunkGroupCollision::new();
+        collision.update(voxels.loaded_chunks(), &registry);
+        let skipped = voxels.apply_player_block_edits(
+            player,
+            &[
+                (AbsBlockPos::new(1, 2, 3), Some(stone)),
+                (AbsBlockPos::new(40, 2, 3), Some(stone)),
+                (AbsBlockPos::new(1, 2, 300), Some(stone)),
+            ],
+            &mut collision,
+            &registry,
+        );
+        assert_eq!(
+            skipped,
+            vec![
+                (AbsChunkPos::new(1, 0, 0), Some(rev(5))),
+                (AbsChunkPos::new(0, 0, 9), None)
+            ]
+        );
+        let chunks = voxels.loaded_chunks();
+        assert_eq!(chunks.get_block(AbsBlockPos::new(1, 2, 3)), Some(stone));
+        assert_eq!(chunks.get_block(AbsBlockPos::new(40, 2, 3)), Some(empty));
+        assert_eq!(chunks.chunks[&AbsChunkPos::new(0, 0, 0)].local_revision(), rev(6));
+        // The collision data follows the edit without a rebuild.
+        let edited = collision.get_chunk(AbsChunkPos::ZERO).unwrap();
+        assert!(edited.hull_at(InChunkPos::try_new(1, 2, 3).unwrap()).is_some());
+        assert_eq!(collision.update(chunks, &registry), 0);
+
+        // Rejected edits still create the revision the client predicted.
+        let skipped =
+            voxels.apply_player_block_edits(player, &[(AbsBlockPos::new(2, 2, 3), None)], &mut collision, &registry);
+        assert!(skipped.is_empty());
+        let chunks = voxels.loaded_chunks();
+        assert_eq!(chunks.get_block(AbsBlockPos::new(2, 2, 3)), Some(empty));
+        assert_eq!(chunks.chunks[&AbsChunkPos::new(0, 0, 0)].local_revision(), rev(7));
+    }
 }
@@ -0,0 +1,88 @@
+//! Loading of structure templates from data files.
+//!
+//! Templates are stored in `<data directory>/<namespace>/structures/<name>.structure` files, in the capnp format of
+//! [`StructureTemplate::write_to_bytes`], and are referred to by the structure features of biomes as `namespace:name`.
+
+use std::fs;
+use std::path::Path;
+
+use capnp::message::ReaderOptions;
+use gs_schemas::registry::RegistryName;
+use gs_schemas::voxel::structure::StructureTemplate;
+
+use crate::prelude::*;
+use crate::voxel::biomes::sorted_directory;
+
+/// The subdirectory of a data namespace directory holding its structure templates.
+pub const STRUCTURE_DATA_SUBDIRECTORY: &str = "structures";
+/// The file extension of structure template files.
+pub const STRUCTURE_FILE_EXTENSION: &str = "structure";
+
+/// Loads the structure templates stored in `<root>/<namespace>/structures/<name>.structure` files, as
+/// `namespace:name`.
+pub fn load_structure_directory(root: &Path) -> Result<HashMap<RegistryName, StructureTemplate>> {
+    let mut templates = HashMap::new();
+    for namespace_dir in sorted_directory(root)? {
+        let structure_dir = namespace_dir.join(STRUCTURE_DATA_SUBDIRECTORY);
+        if !structure_dir.is_dir() {
+            continue;
+        }
+        let namespace = namespace_dir
+            .file_name()
+            .and_then(|ns| ns.to_str())
+            .with_context(|| format!("Invalid namespace directory name {}", namespace_dir.display()))?;
+        for path in sorted_directory(&structure_dir)? {
+            if path.extension().and_then(|ext| ext.to_str()) != Some(STRUCTURE_FILE_EXTENSION) {
+                continue;
+            }
+            let key = path
+                .file_stem()
+                .and_then(|key| key.to_str())
+                .with_context(|| format!("Invalid structure file name {}", path.display()))?;
+            let template = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(StructureTemplate::read_from_bytes(&bytes, ReaderOptions::new())?))
+                .with_context(|| format!("Could not load the structure {}", path.display()))?;
+            templates.insert(RegistryName::new(namespace, key), template);
+        }
+    }
+    Ok(templates)
+}
+
+#[cfg(test)]
+mod test {
+    use bevy_math::IVec3;
+    use gs_schemas::voxel::structure::{StructureBlock, StructureBlocks};
+
+    use super::*;
+    use crate::voxel::blocks::LOG_BLOCK_NAME;
+
+    #[test]
+    fn load_from_directory() {
+        let root = std::env::temp_dir().join(format!("gs-structure-test-{}", std::process::id()));
+        let structure_dir = root.join("test").join(STRUCTURE_DATA_SUBDIRECTORY);
+        fs::create_dir_all(&structure_dir).unwrap();
+        let pillar = StructureTemplate::new(
+            IVec3::new(1, 3, 1),
+            IVec3::ZERO,
+            vec![StructureBlock {
+                name: LOG_BLOCK_NAME,
+                metadata: 0,
+            }],
+            StructureBlocks::Dense(vec![0; 3]),
+        )
+        .unwrap();
+        fs::write(structure_dir.join("pillar.structure"), pillar.write_to_bytes()).unwrap();
+        fs::write(structure_dir.join("notes.txt"), "Not a structure").unwrap();
+        let loaded = load_structure_directory(&root);
+
+        fs::write(structure_dir.join("broken.structure"), "Not a structure either").unwrap();
+        let broken = load_structure_directory(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&RegistryName::new("test", "pillar")], pillar);
+        assert!(format!("{:#}", broken.unwrap_err()).contains("broken.structure"));
+    }
+}
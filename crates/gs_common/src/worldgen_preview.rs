@@ -0,0 +1,366 @@
+//! The offline world generation preview tool main() implementation
+//!
+//! Generates an area of the world without running the game, and writes top-down PNG maps of it. In the maps, the X
+//! axis of the images goes towards +X in the world, and the Y axis of the images towards +Z.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use bevy_math::IVec2;
+use clap::{Parser, ValueEnum};
+use gs_schemas::coordinates::{AbsBlockPos, AbsChunkPos, InChunkPos, CHUNK_DIM};
+use gs_schemas::registries::GameRegistries;
+use gs_schemas::registry::RegistryName;
+use gs_schemas::voxel::chunk_storage::ChunkStorage;
+use gs_schemas::voxel::voxeltypes::{BlockEntry, EMPTY_BLOCK_NAME};
+use image::{GrayImage, Luma, Rgb, RgbImage};
+
+use crate::config::WorldGenConfig;
+use crate::prelude::*;
+use crate::voxel::blocks::{DIRT_BLOCK_NAME, GRASS_BLOCK_NAME, STONE_BLOCK_NAME};
+use crate::voxel::generator::flat::{FlatGenerator, FlatLayer};
+use crate::voxel::generator::multi_noise::{MultiNoiseGenerator, TerrainQuery, DEFAULT_SEA_LEVEL};
+use crate::voxel::generator::VoxelGenerator;
+use crate::{builtin_game_registries, builtin_world_generator, ServerData, DEFAULT_WORLD_SEED};
+
+/// How far above the highest surface of a chunk column its top blocks are looked for, to find trees and overhangs.
+const SURFACE_SCAN_MARGIN: i32 = 2 * CHUNK_DIM;
+/// How many chunks below the start of the scan the top blocks of a chunk column are looked for at most.
+const MAX_SCAN_CHUNKS: i32 = 16;
+/// The highest Y of the previewed flat world.
+const FLAT_TOP_Y: i32 = 0;
+
+#[derive(Parser)]
+#[command(name = "gs_worldgen", about = "Geosia offline world generation preview tool")]
+struct CliOptions {
+    /// The world generator to preview.
+    #[arg(long, value_enum, default_value_t = GeneratorKind::MultiNoise)]
+    generator: GeneratorKind,
+    /// The world seed.
+    #[arg(long, default_value_t = DEFAULT_WORLD_SEED)]
+    seed: u64,
+    /// X coordinate of the center of the previewed area, in blocks.
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    x: i32,
+    /// Z coordinate of the center of the previewed area, in blocks.
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    z: i32,
+    /// Side of the square previewed area, in blocks, rounded up to whole chunks.
+    #[arg(long, default_value_t = 512)]
+    size: u32,
+    /// Only write the maps computed from the biome noises, without generating chunks for the block map.
+    #[arg(long)]
+    skip_blocks: bool,
+    /// Experimental: generate overhangs and caves through the terrain.
+    #[arg(long)]
+    terrain_density: bool,
+    /// The Y level below which open space is flooded with water.
+    #[arg(long, value_name = "Y", default_value_t = DEFAULT_SEA_LEVEL, allow_negative_numbers = true)]
+    sea_level: i32,
+    /// The directory the maps are written to.
+    #[arg(long, short, value_name = "DIR", default_value = "worldgen_preview")]
+    output: PathBuf,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+enum GeneratorKind {
+    /// The standard world generator, with the builtin biomes and the ones from the game data directory.
+    MultiNoise,
+    /// A flat world of stone, dirt and grass layers, without biomes.
+    Flat,
+}
+
+/// A square area of whole chunk columns.
+#[derive(Copy, Clone, Debug)]
+struct PreviewArea {
+    /// The chunk column of the top-left corner of the maps.
+    min_chunk: IVec2,
+    /// The number of chunk columns along each side.
+    chunks: i32,
+}
+
+impl PreviewArea {
+    fn new(center: IVec2, size: u32) -> Self {
+        let chunks = (size.max(1) as i32 + CHUNK_DIM - 1) / CHUNK_DIM;
+        let min_block = center - IVec2::splat(chunks * CHUNK_DIM / 2);
+        Self {
+            min_chunk: IVec2::new(min_block.x.div_euclid(CHUNK_DIM), min_block.y.div_euclid(CHUNK_DIM)),
+            chunks,
+        }
+    }
+
+    /// The number of blocks along each side.
+    fn side(&self) -> u32 {
+        (self.chunks * CHUNK_DIM) as u32
+    }
+
+    /// The block column of the given map pixel.
+    fn block_column(&self, x: u32, y: u32) -> IVec2 {
+        self.min_chunk * CHUNK_DIM + IVec2::new(x as i32, y as i32)
+    }
+
+    /// The chunk column of the given chunk index, in XZ order.
+    fn chunk_column(&self, index: usize) -> IVec2 {
+        self.min_chunk + IVec2::new(index as i32 % self.chunks, index as i32 / self.chunks)
+    }
+}
+
+/// Starts the world generation preview tool CLI
+pub fn run_worldgen_preview() -> Result<()> {
+    let cli = CliOptions::parse();
+    let area = PreviewArea::new(IVec2::new(cli.x, cli.z), cli.size);
+    let registries = builtin_game_registries();
+    fs::create_dir_all(&cli.output)
+        .with_context(|| format!("Could not create the output directory {}", cli.output.display()))?;
+    let first_block = area.block_column(0, 0);
+    println!(
+        "Previewing the {} blocks wide area from X={} Z={} with seed {}",
+        area.side(),
+        first_block.x,
+        first_block.y,
+        cli.seed
+    );
+
+    match cli.generator {
+        GeneratorKind::MultiNoise => {
+            let config = WorldGenConfig {
+                terrain_density: cli.terrain_density,
+                sea_level: cli.sea_level,
+            };
+            let generator = builtin_world_generator(cli.seed, &registries, &config)?;
+            let terrain = query_area(&generator, area);
+            write_climate_maps(&cli.output, &registries, area, &terrain)?;
+            if !cli.skip_blocks {
+                let side = area.side() as usize;
+                let top_y = |column: IVec2| {
+                    let min = (column - area.min_chunk) * CHUNK_DIM;
+                    let surface = (0..CHUNK_DIM * CHUNK_DIM)
+                        .map(|i| {
+                            let pixel = min + IVec2::new(i % CHUNK_DIM, i / CHUNK_DIM);
+                            terrain[pixel.x as usize + pixel.y as usize * side].surface_height
+                        })
+                        .max()
+                        .unwrap_or_default();
+                    surface.max(generator.sea_level()) + SURFACE_SCAN_MARGIN
+                };
+                write_block_map(&cli.output, &registries, area, &generator, top_y)?;
+            }
+        }
+        GeneratorKind::Flat => {
+            let layer = |name: &RegistryName, thickness| -> Result<FlatLayer> {
+                let (id, _) = registries
+                    .block_types
+                    .lookup_name_to_object(name.as_ref())
+                    .with_context(|| format!("The {name} block is not registered"))?;
+                Ok(FlatLayer {
+                    block_type: BlockEntry::new(id, 0),
+                    thickness,
+                })
+            };
+            let generator = FlatGenerator::new(
+                FLAT_TOP_Y - 8,
+                vec![
+                    layer(&STONE_BLOCK_NAME, 4)?,
+                    layer(&DIRT_BLOCK_NAME, 3)?,
+                    layer(&GRASS_BLOCK_NAME, 1)?,
+                    layer(&EMPTY_BLOCK_NAME, 1)?,
+                ],
+            )?;
+            if !cli.skip_blocks {
+                write_block_map(&cli.output, &registries, area, &generator, |_| FLAT_TOP_Y)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs `task` for every index below `count` on all the available threads, returning the results in order.
+fn parallel_map<T: Send>(count: usize, task: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(count.max(1));
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, T)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, AtomicOrdering::Relaxed);
+                        if index >= count {
+                            break results;
+                        }
+                        results.push((index, task(index)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("A preview worker thread panicked"))
+            .collect()
+    });
+    results.sort_unstable_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Queries the terrain of every block column of the area, in XZ order.
+fn query_area(generator: &MultiNoiseGenerator, area: PreviewArea) -> Vec<TerrainQuery> {
+    let side = area.side();
+    parallel_map(side as usize, |y| {
+        (0..side)
+            .map(|x| {
+                let column = area.block_column(x, y as u32);
+                generator.query_terrain(AbsBlockPos::new(column.x, 0, column.y))
+            })
+            .collect::<Vec<_>>()
+    })
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn save(output: &Path, name: &str, image: impl FnOnce(&Path) -> image::ImageResult<()>) -> Result<()> {
+    let path = output.join(name);
+    image(&path).with_context(|| format!("Could not write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// Writes the biome, height, temperature and moisture maps.
+fn write_climate_maps(
+    output: &Path,
+    registries: &GameRegistries,
+    area: PreviewArea,
+    terrain: &[TerrainQuery],
+) -> Result<()> {
+    let side = area.side();
+    let at = |x: u32, y: u32| &terrain[(x + y * side) as usize];
+
+    let mut coverage: HashMap<_, u32> = HashMap::new();
+    for query in terrain {
+        if let Some(strongest) = query.biomes.first() {
+            *coverage.entry(strongest.id).or_default() += 1;
+        }
+    }
+    let biomes = RgbImage::from_fn(side, side, |x, y| {
+        let color = at(x, y)
+            .biomes
+            .first()
+            .and_then(|entry| entry.lookup(&registries.biome_types))
+            .map_or(Default::default(), |biome| biome.representative_color);
+        Rgb([color.r, color.g, color.b])
+    });
+    save(output, "biomes.png", |path| biomes.save(path))?;
+    let mut coverage: Vec<_> = coverage.into_iter().collect();
+    coverage.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    for (id, count) in coverage {
+        let Some(biome) = registries.biome_types.lookup_id_to_object(id) else {
+            continue;
+        };
+        let color = biome.representative_color;
+        println!(
+            "  {:>5.1}% {} (#{:02x}{:02x}{:02x})",
+            count as f64 * 100.0 / terrain.len() as f64,
+            biome.name,
+            color.r,
+            color.g,
+            color.b
+        );
+    }
+
+    let (min_height, max_height) = terrain.iter().fold((i32::MAX, i32::MIN), |(min, max), query| {
+        (min.min(query.surface_height), max.max(query.surface_height))
+    });
+    let height_range = (max_height - min_height).max(1) as f64;
+    let heights = GrayImage::from_fn(side, side, |x, y| {
+        Luma([((at(x, y).surface_height - min_height) as f64 * 255.0 / height_range) as u8])
+    });
+    save(output, "height.png", |path| heights.save(path))?;
+    println!("  Surface heights from Y={min_height} (black) to Y={max_height} (white)");
+
+    // The climate noises are all between 0 and 5, kept on the same scale so that maps of different areas compare.
+    let noise_map = |noise: fn(&TerrainQuery) -> f64| {
+        GrayImage::from_fn(side, side, |x, y| {
+            Luma([(noise(at(x, y)).clamp(0.0, 5.0) * 51.0) as u8])
+        })
+    };
+    let temperature = noise_map(|query: &TerrainQuery| query.temperature);
+    save(output, "temperature.png", |path| temperature.save(path))?;
+    let moisture = noise_map(|query: &TerrainQuery| query.moisture);
+    save(output, "moisture.png", |path| moisture.save(path))?;
+    Ok(())
+}
+
+/// Generates the chunks with the top blocks of the area, and writes their colors as seen from above.
+/// The top blocks of each chunk column are looked for downwards from the Y given by `top_y`.
+fn write_block_map(
+    output: &Path,
+    registries: &GameRegistries,
+    area: PreviewArea,
+    generator: &dyn VoxelGenerator<ServerData>,
+    top_y: impl Fn(IVec2) -> i32 + Sync,
+) -> Result<()> {
+    let (air, _) = registries
+        .block_types
+        .lookup_name_to_object(EMPTY_BLOCK_NAME.as_ref())
+        .context("The empty block is registered")?;
+    let columns = parallel_map((area.chunks * area.chunks) as usize, |index| {
+        let column = area.chunk_column(index);
+        let top_chunk = top_y(column).div_euclid(CHUNK_DIM);
+        let mut top_blocks: Vec<Option<BlockEntry>> = vec![None; (CHUNK_DIM * CHUNK_DIM) as usize];
+        for chunk_y in (top_chunk - MAX_SCAN_CHUNKS..=top_chunk).rev() {
+            let chunk = generator.generate_chunk(AbsChunkPos::new(column.x, chunk_y, column.y), Default::default());
+            for (i, top) in top_blocks.iter_mut().enumerate().filter(|(_, top)| top.is_none()) {
+                let (x, z) = (i as i32 % CHUNK_DIM, i as i32 / CHUNK_DIM);
+                *top = (0..CHUNK_DIM)
+                    .rev()
+                    .map(|y| *chunk.blocks.get(InChunkPos::try_new(x, y, z).unwrap()))
+                    .find(|block| block.id != air);
+            }
+            if top_blocks.iter().all(Option::is_some) {
+                break;
+            }
+        }
+        top_blocks
+    });
+
+    let side = area.side();
+    let blocks = RgbImage::from_fn(side, side, |x, y| {
+        let (chunk, block) = (
+            IVec2::new(x as i32, y as i32) / CHUNK_DIM,
+            IVec2::new(x as i32, y as i32) % CHUNK_DIM,
+        );
+        let color = columns[(chunk.x + chunk.y * area.chunks) as usize][(block.x + block.y * CHUNK_DIM) as usize]
+            .and_then(|block| block.lookup(&registries.block_types))
+            .map_or(Default::default(), |definition| definition.representative_color);
+        Rgb([color.r, color.g, color.b])
+    });
+    save(output, "blocks.png", |path| blocks.save(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn preview_area_covers_whole_chunks() {
+        let area = PreviewArea::new(IVec2::new(-40, 100), 100);
+        assert_eq!(area.chunks, 4);
+        assert_eq!(area.side(), 4 * CHUNK_DIM as u32);
+        let first = area.block_column(0, 0);
+        assert_eq!(first.x.rem_euclid(CHUNK_DIM), 0);
+        assert_eq!(first.y.rem_euclid(CHUNK_DIM), 0);
+        let last = area.block_column(area.side() - 1, area.side() - 1);
+        assert!(first.x <= -40 - 50 + CHUNK_DIM && last.x >= -40 + 50 - CHUNK_DIM);
+        assert_eq!(area.chunk_column(area.chunks as usize + 1), area.min_chunk + IVec2::ONE);
+    }
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        assert_eq!(
+            parallel_map(1000, |i| i * 2),
+            (0..1000).map(|i| i * 2).collect::<Vec<_>>()
+        );
+        assert!(parallel_map(0, |i| i).is_empty());
+    }
+}
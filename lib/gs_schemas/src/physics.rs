@@ -1,5 +1,6 @@
 //! Physics-related types
 
+use bevy_math::{IVec3, Vec3};
 use bitflags::bitflags;
 
 bitflags! {
@@ -21,3 +22,124 @@ bitflags! {
         const BLOCKS_TRANSPARENT = 0x40;
     }
 }
+
+/// An axis-aligned bounding box, in world (block) units.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Aabb {
+    /// The corner with the smallest coordinates.
+    pub min: Vec3,
+    /// The corner with the largest coordinates.
+    pub max: Vec3,
+}
+
+impl Aabb {
+    /// The space occupied by a single full block at the origin.
+    pub const UNIT_CUBE: Aabb = Aabb::new(Vec3::ZERO, Vec3::ONE);
+
+    /// Constructs a box from its two corners, `min` must not be larger than `max` in any axis.
+    pub const fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    /// Constructs a box centered around `center`, extending by `half_extents` in both directions of each axis.
+    pub fn from_center_half_extents(center: Vec3, half_extents: Vec3) -> Self {
+        Self::new(center - half_extents, center + half_extents)
+    }
+
+    /// The center point of the box.
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// The size of the box in each axis.
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    /// Returns the box moved by `offset`.
+    pub fn translated(&self, offset: Vec3) -> Self {
+        Self::new(self.min + offset, self.max + offset)
+    }
+
+    /// Returns the box grown by `margin` in both directions of each axis.
+    pub fn expanded(&self, margin: Vec3) -> Self {
+        Self::new(self.min - margin, self.max + margin)
+    }
+
+    /// Returns the smallest box containing both `self` and `other`.
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    /// Returns the smallest box containing all the space swept by `self` while moving by `offset`.
+    pub fn swept(&self, offset: Vec3) -> Self {
+        self.union(&self.translated(offset))
+    }
+
+    /// Checks if the interiors of the two boxes overlap, boxes merely touching at their faces do not intersect.
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.cmplt(other.max).all() && other.min.cmplt(self.max).all()
+    }
+
+    /// The range of block positions the box touches, as inclusive `(min, max)` corners.
+    pub fn block_range(&self) -> (IVec3, IVec3) {
+        (self.min.floor().as_ivec3(), self.max.ceil().as_ivec3() - IVec3::ONE)
+    }
+
+    /// Clips a movement of `self` along a single `axis` (0=X, 1=Y, 2=Z) by `offset`, so that it stops at the face of `obstacle`.
+    /// Obstacles that do not overlap with `self` in the two other axes, or lie behind the movement, do not affect it.
+    pub fn clip_axis_offset(&self, obstacle: &Aabb, axis: usize, offset: f32) -> f32 {
+        let (a1, a2) = ((axis + 1) % 3, (axis + 2) % 3);
+        if self.max[a1] <= obstacle.min[a1]
+            || self.min[a1] >= obstacle.max[a1]
+            || self.max[a2] <= obstacle.min[a2]
+            || self.min[a2] >= obstacle.max[a2]
+        {
+            return offset;
+        }
+        if offset > 0.0 && obstacle.min[axis] >= self.max[axis] - CLIP_EPSILON {
+            offset.min(obstacle.min[axis] - self.max[axis]).max(0.0)
+        } else if offset < 0.0 && obstacle.max[axis] <= self.min[axis] + CLIP_EPSILON {
+            offset.max(obstacle.max[axis] - self.min[axis]).min(0.0)
+        } else {
+            offset
+        }
+    }
+}
+
+/// Tolerance for boxes sunk slightly into an obstacle due to floating point errors to still be stopped by it.
+const CLIP_EPSILON: f32 = 1.0e-4;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aabb_intersections() {
+        let a = Aabb::UNIT_CUBE;
+        assert!(a.intersects(&a.translated(Vec3::splat(0.5))));
+        assert!(!a.intersects(&a.translated(Vec3::X)));
+        assert_eq!(
+            a.swept(Vec3::new(-1.0, 2.0, 0.0)),
+            Aabb::new(Vec3::NEG_X, Vec3::new(1.0, 3.0, 1.0))
+        );
+        assert_eq!(
+            Aabb::new(Vec3::splat(-0.5), Vec3::splat(1.0)).block_range(),
+            (IVec3::splat(-1), IVec3::ZERO)
+        );
+    }
+
+    #[test]
+    fn aabb_axis_clipping() {
+        let player = Aabb::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 3.0, 1.0));
+        let floor = Aabb::UNIT_CUBE;
+        assert_eq!(player.clip_axis_offset(&floor, 1, -0.5), 0.0);
+        assert_eq!(player.translated(Vec3::Y).clip_axis_offset(&floor, 1, -2.0), -1.0);
+        assert_eq!(player.clip_axis_offset(&floor, 1, 0.5), 0.5);
+        // Not overlapping in the other axes
+        assert_eq!(player.clip_axis_offset(&floor.translated(Vec3::X), 1, -0.5), -0.5);
+        let wall = Aabb::UNIT_CUBE.translated(Vec3::new(2.0, 1.0, 0.0));
+        assert_eq!(player.clip_axis_offset(&wall, 0, 3.0), 1.0);
+        assert_eq!(player.clip_axis_offset(&wall, 0, -3.0), -3.0);
+    }
+}
@@ -124,16 +124,6 @@ impl NetworkStreamHeader {
         }
     }
 
-    /// Serializes the capnp message into a byte array.
-    pub fn write_to_bytes(&self) -> Box<[u8]> {
-        let mut builder = TypedBuilder::<network_capnp::stream_header::Owned>::new_default();
-        let mut root = builder.init_root();
-        self.write_to_message(&mut root);
-        let mut buffer = Vec::new();
-        capnp::serialize::write_message(&mut buffer, builder.borrow_inner()).unwrap();
-        buffer.into_boxed_slice()
-    }
-
     /// Deserializes a stream header from a capnp message.
     pub fn read_from_message(reader: &network_capnp::stream_header::Reader) -> capnp::Result<Self> {
         match reader.which()? {
@@ -149,8 +139,46 @@ impl NetworkStreamHeader {
             }
         }
     }
+}
 
-    /// Deserializes a stream header from a serialized capnp message.
+/// A stream type together with the version of its protocol, sent when opening a new stream.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct NetworkStreamType {
+    /// The stream type, determining the handler on the receiving side.
+    pub header: NetworkStreamHeader,
+    /// Version of the stream's protocol, streams are only accepted by handlers registered for the same version.
+    pub version: u32,
+}
+
+impl NetworkStreamType {
+    /// Constructor.
+    pub const fn new(header: NetworkStreamHeader, version: u32) -> Self {
+        Self { header, version }
+    }
+
+    /// Serializes the stream type into a capnp message.
+    pub fn write_to_message(&self, builder: &mut network_capnp::stream_header::Builder) {
+        self.header.write_to_message(builder);
+        builder.set_version(self.version);
+    }
+
+    /// Serializes the capnp message into a byte array.
+    pub fn write_to_bytes(&self) -> Box<[u8]> {
+        let mut builder = TypedBuilder::<network_capnp::stream_header::Owned>::new_default();
+        let mut root = builder.init_root();
+        self.write_to_message(&mut root);
+        let mut buffer = Vec::new();
+        capnp::serialize::write_message(&mut buffer, builder.borrow_inner()).unwrap();
+        buffer.into_boxed_slice()
+    }
+
+    /// Deserializes the stream type from a capnp message.
+    pub fn read_from_message(reader: &network_capnp::stream_header::Reader) -> capnp::Result<Self> {
+        let header = NetworkStreamHeader::read_from_message(reader)?;
+        Ok(Self::new(header, reader.get_version()))
+    }
+
+    /// Deserializes the stream type from a serialized capnp message.
     pub fn read_from_bytes(bytes: &[u8], options: ReaderOptions) -> capnp::Result<Self> {
         let mut bytes_ref = bytes;
         let msg = capnp::serialize::read_message_from_flat_slice_no_alloc(&mut bytes_ref, options)?;
@@ -159,3 +187,72 @@ impl NetworkStreamHeader {
         Self::read_from_message(&root)
     }
 }
+
+/// Helpers for network datagram headers.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum NetworkDatagramHeader {
+    /// A builtin datagram type.
+    Standard(network_capnp::datagram_header::StandardTypes),
+    /// A custom (modded) datagram type.
+    Custom(RegistryName),
+}
+
+impl Hash for NetworkDatagramHeader {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
+        match self {
+            Self::Standard(ty) => {
+                core::mem::discriminant(ty).hash(state);
+            }
+            Self::Custom(nm) => {
+                nm.hash(state);
+            }
+        }
+    }
+}
+
+impl NetworkDatagramHeader {
+    /// Serializes a datagram header into a capnp message.
+    pub fn write_to_message(&self, builder: &mut network_capnp::datagram_header::Builder) {
+        match self {
+            Self::Standard(standard) => {
+                builder.set_standard_type(*standard);
+            }
+            Self::Custom(custom) => {
+                let mut ser = builder.reborrow().init_custom_type();
+                ser.set_ns(&custom.ns);
+                ser.set_key(&custom.key);
+            }
+        }
+    }
+
+    /// Serializes the capnp message into a byte array.
+    pub fn write_to_bytes(&self) -> Box<[u8]> {
+        let mut builder = TypedBuilder::<network_capnp::datagram_header::Owned>::new_default();
+        let mut root = builder.init_root();
+        self.write_to_message(&mut root);
+        let mut buffer = Vec::new();
+        capnp::serialize::write_message(&mut buffer, builder.borrow_inner()).unwrap();
+        buffer.into_boxed_slice()
+    }
+
+    /// Deserializes a datagram header from a capnp message.
+    pub fn read_from_message(reader: &network_capnp::datagram_header::Reader) -> capnp::Result<Self> {
+        match reader.which()? {
+            network_capnp::datagram_header::WhichReader::StandardType(standard) => Ok(Self::Standard(standard?)),
+            network_capnp::datagram_header::WhichReader::CustomType(custom) => {
+                let custom = custom?;
+                let ns = custom.get_ns()?.to_str()?;
+                let key = custom.get_key()?.to_str()?;
+                Ok(Self::Custom(RegistryName::new(ns, key)))
+            }
+        }
+    }
+
+    /// Deserializes a datagram header from the start of `bytes`, and advances it to the data following the header.
+    pub fn read_from_bytes(bytes: &mut &[u8], options: ReaderOptions) -> capnp::Result<Self> {
+        let msg = capnp::serialize::read_message(bytes, options)?;
+        let root = msg.get_root::<network_capnp::datagram_header::Reader>()?;
+        Self::read_from_message(&root)
+    }
+}
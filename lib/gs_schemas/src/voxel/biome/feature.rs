@@ -0,0 +1,165 @@
+//! Decorative features placed by biomes after the terrain is generated: ore veins, boulders, trees, vegetation and
+//! structure templates.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::coordinates::CHUNK_DIM;
+use crate::range::Range;
+use crate::registry::RegistryName;
+
+/// The furthest a feature can reach from its origin block along any axis, so that features only ever spill into the
+/// directly neighbouring chunks. Surface origins can be right above the top of their chunk, hence the one block less.
+pub const MAX_FEATURE_REACH: i32 = CHUNK_DIM - 1;
+/// The largest number of placement attempts per chunk of a feature, one per block column.
+pub const MAX_FEATURE_FREQUENCY: f64 = (CHUNK_DIM * CHUNK_DIM) as f64;
+
+/// A feature placed by a biome, with the constraints on where it can be placed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BiomeFeature {
+    /// The average number of placement attempts per chunk, fractional parts are rolled as a chance of one more.
+    pub frequency: f64,
+    /// The Y levels where the feature's origin can be.
+    pub height: Range<i32>,
+    /// Where the feature's origin can be placed relative to the neighbouring blocks.
+    pub placement: FeaturePlacement,
+    /// The shape of the feature.
+    pub shape: FeatureShape,
+}
+
+/// The constraints on the blocks around a feature's origin.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FeaturePlacement {
+    /// On top of the ground: the origin is an empty block right above one of the given blocks.
+    Surface(Vec<RegistryName>),
+    /// Inside the ground: the origin is one of the given blocks, and the feature only replaces these blocks.
+    Buried(Vec<RegistryName>),
+}
+
+impl FeaturePlacement {
+    /// The blocks this placement rule refers to.
+    pub fn blocks(&self) -> &[RegistryName] {
+        match self {
+            Self::Surface(blocks) | Self::Buried(blocks) => blocks,
+        }
+    }
+}
+
+/// The shape of a feature, built around its origin.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FeatureShape {
+    /// A winding vein of `size` blocks.
+    OreVein {
+        /// The ore block.
+        block: RegistryName,
+        /// The number of steps of the vein.
+        size: u32,
+    },
+    /// A roughly spherical rock, half buried in the ground.
+    Boulder {
+        /// The rock block.
+        block: RegistryName,
+        /// The largest radius of the boulder.
+        radius: f64,
+    },
+    /// A trunk with a round canopy of leaves at its top.
+    Tree {
+        /// The trunk block.
+        trunk: RegistryName,
+        /// The leaves block.
+        leaves: RegistryName,
+        /// The shortest trunk.
+        min_height: i32,
+        /// The tallest trunk.
+        max_height: i32,
+        /// The radius of the canopy.
+        canopy_radius: i32,
+    },
+    /// A single plant block.
+    Vegetation {
+        /// The plant block.
+        block: RegistryName,
+    },
+    /// A structure template, placed by its origin block.
+    Structure {
+        /// The name of the template.
+        template: RegistryName,
+        /// Whether the structure is placed with a random rotation around the Y axis.
+        random_rotation: bool,
+    },
+}
+
+impl FeatureShape {
+    /// The furthest this shape can reach from its origin along any axis.
+    /// Structures only reach their origin here, the reach of their template is checked when it's loaded.
+    pub fn reach(&self) -> i32 {
+        match self {
+            Self::OreVein { size, .. } => *size as i32,
+            Self::Boulder { radius, .. } => radius.ceil() as i32,
+            Self::Tree {
+                max_height,
+                canopy_radius,
+                ..
+            } => max_height + canopy_radius,
+            Self::Vegetation { .. } | Self::Structure { .. } => 0,
+        }
+    }
+
+    /// The blocks this shape places, not counting the blocks of structure templates.
+    pub fn blocks(&self) -> Vec<&RegistryName> {
+        match self {
+            Self::OreVein { block, .. } | Self::Boulder { block, .. } | Self::Vegetation { block } => vec![block],
+            Self::Tree { trunk, leaves, .. } => vec![trunk, leaves],
+            Self::Structure { .. } => Vec::new(),
+        }
+    }
+}
+
+impl BiomeFeature {
+    /// All the blocks this feature refers to.
+    pub fn blocks(&self) -> impl Iterator<Item = &RegistryName> {
+        self.placement.blocks().iter().chain(self.shape.blocks())
+    }
+
+    /// Checks that the feature stays within [`MAX_FEATURE_REACH`] and its parameters are sensible.
+    pub fn validate(&self) -> Result<(), FeatureError> {
+        if !(0.0..=MAX_FEATURE_FREQUENCY).contains(&self.frequency) {
+            return Err(FeatureError::InvalidFrequency(self.frequency));
+        }
+        if let &FeatureShape::Tree {
+            min_height, max_height, ..
+        } = &self.shape
+        {
+            if min_height < 1 || min_height > max_height {
+                return Err(FeatureError::InvalidTreeHeight { min_height, max_height });
+            }
+        }
+        let reach = self.shape.reach();
+        if !(0..=MAX_FEATURE_REACH).contains(&reach) {
+            return Err(FeatureError::ReachTooFar { reach });
+        }
+        Ok(())
+    }
+}
+
+/// Problems with a [`BiomeFeature`] definition.
+#[derive(Debug, Error, PartialEq)]
+pub enum FeatureError {
+    /// The frequency is negative, not a number or above [`MAX_FEATURE_FREQUENCY`].
+    #[error("Invalid feature frequency {0}, it has to be between 0 and {MAX_FEATURE_FREQUENCY}")]
+    InvalidFrequency(f64),
+    /// The tree height range is empty or not positive.
+    #[error("Invalid tree height range {min_height}..={max_height}")]
+    InvalidTreeHeight {
+        /// The shortest trunk.
+        min_height: i32,
+        /// The tallest trunk.
+        max_height: i32,
+    },
+    /// The feature could reach further than [`MAX_FEATURE_REACH`] blocks from its origin.
+    #[error("The feature reaches {reach} blocks from its origin, more than the maximum of {MAX_FEATURE_REACH}")]
+    ReachTooFar {
+        /// How far the feature could reach.
+        reach: i32,
+    },
+}
@@ -3,22 +3,25 @@
 use std::fmt::{Debug, Display};
 use std::hash::{Hash, Hasher};
 
-use bevy_math::DVec2;
+use bevy_math::{DVec2, IVec3};
 use noise::OpenSimplex;
 use rgb::RGBA8;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
+use self::feature::BiomeFeature;
 use super::{
     generation::Context,
     voxeltypes::{BlockEntry, BlockRegistry},
 };
-use crate::voxel::generation::fbm_noise::Fbm;
+use crate::voxel::generation::{fbm_noise::Fbm, noise_graph::NoiseGraphDefinition};
 use crate::{
     range::Range,
     registry::{Registry, RegistryId, RegistryName, RegistryObject},
 };
 
 pub mod biome_map;
+pub mod feature;
 
 /// A biome entry stored in the per-planet biome map.
 #[derive(Clone, Copy, Debug, PartialOrd, PartialEq, Serialize, Deserialize)]
@@ -46,10 +49,10 @@ impl BiomeEntry {
 pub type BiomeRegistry = Registry<BiomeDefinition>;
 
 /// A definition of a biome type, specifying properties such as registry name, shape, textures.
-// TODO fix serialization of `BiomeDefinition`
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BiomeDefinition {
     /// The unique registry name
+    #[serde(default)]
     pub name: RegistryName,
     /// A color that can represent the biome on maps, debug views, etc.
     pub representative_color: RGBA8,
@@ -62,13 +65,174 @@ pub struct BiomeDefinition {
     /// Moisture of this biome.
     pub moisture: Range<f64>,
     /// The block placement rule source for this biome.
-    pub rule_source: fn(pos: &bevy_math::IVec3, ctx: &Context, registry: &BlockRegistry) -> Option<BlockEntry>,
+    pub rule_source: BiomeRules,
     /// The noise function for this biome.
-    pub surface_noise: fn(pos: DVec2, noise: &Fbm<OpenSimplex>) -> f64,
+    pub surface_noise: SurfaceNoise,
     /// The strength of this biome in the blending step.
     pub blend_influence: f64,
     /// The strength of this biome in the block placement step.
     pub block_influence: f64,
+    /// How caves are carved under this biome.
+    #[serde(default)]
+    pub cave_carving: CaveCarving,
+    /// The features decorating this biome after the terrain is generated.
+    #[serde(default)]
+    pub features: Vec<BiomeFeature>,
+}
+
+/// How the cave carvers treat the ground under a biome.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum CaveCarving {
+    /// No caves are carved.
+    None,
+    /// Caves are carved out as empty space.
+    #[default]
+    Dry,
+    /// Caves are carved out, and flooded with the biome's fluid below the sea level.
+    Flooded,
+}
+
+/// Block placement rules written in code, for biomes that can't be described with [`SurfaceRules`].
+pub type BiomeRuleFn = fn(pos: &IVec3, ctx: &Context, registry: &BlockRegistry) -> Option<BlockEntry>;
+/// A surface height function written in code, for biomes that can't be described with a noise graph.
+/// It gets the world's base terrain noise, see [`crate::voxel::generation::noise_graph::base_terrain_noise`].
+pub type SurfaceNoiseFn = fn(pos: DVec2, noise: &Fbm<OpenSimplex>) -> f64;
+
+/// The source of a biome's block placement rules.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BiomeRules {
+    /// Layered rules, loadable from data files.
+    Layered(Box<SurfaceRules>),
+    /// Rules defined in code, biomes using these can't be serialized, see [`BiomeDefinition::ensure_serializable`].
+    #[serde(skip)]
+    Code(BiomeRuleFn),
+}
+
+impl BiomeRules {
+    /// The block this biome places at the given position, if any.
+    pub fn place_block(&self, pos: &IVec3, ctx: &Context, registry: &BlockRegistry) -> Option<BlockEntry> {
+        match self {
+            Self::Layered(rules) => {
+                let name = rules.block_name_at(pos.y, ctx.ground_y, ctx.sea_level)?;
+                let (id, _) = registry.lookup_name_to_object(name.as_ref())?;
+                Some(BlockEntry::new(id, 0))
+            }
+            Self::Code(rules) => rules(pos, ctx, registry),
+        }
+    }
+
+    /// The fluid filling the space above the ground up to the sea level, if known.
+    pub fn fluid_block(&self) -> Option<&RegistryName> {
+        match self {
+            Self::Layered(rules) => rules.fluid_block.as_ref(),
+            Self::Code(_) => None,
+        }
+    }
+}
+
+/// Layered block placement rules: the top block, filler blocks under it, stone for the rest of the ground, and fluid
+/// above it up to the sea level.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SurfaceRules {
+    /// The block at the ground level.
+    pub top_block: Option<RegistryName>,
+    /// The block replacing the top block at and above the snow line.
+    pub snow_block: Option<RegistryName>,
+    /// The lowest ground level covered with the snow block.
+    pub snow_line: i32,
+    /// The block right under the top block.
+    pub filler_block: Option<RegistryName>,
+    /// How many layers of the filler block there are.
+    pub filler_depth: i32,
+    /// The block filling the ground under the filler.
+    pub stone_block: Option<RegistryName>,
+    /// The block replacing the top and filler blocks in columns with the ground close to or below the sea level.
+    pub shore_block: Option<RegistryName>,
+    /// How far above the sea level the ground can be for the column to be covered with the shore block.
+    pub shore_height: i32,
+    /// The fluid filling the space above the ground, up to the sea level.
+    pub fluid_block: Option<RegistryName>,
+}
+
+impl SurfaceRules {
+    /// The name of the block at the given height, in a column with the given ground and sea levels.
+    pub fn block_name_at(&self, y: i32, ground_y: i32, sea_level: i32) -> Option<&RegistryName> {
+        let shore = self
+            .shore_block
+            .as_ref()
+            .filter(|_| ground_y <= sea_level + self.shore_height);
+        if y > ground_y {
+            if y < sea_level {
+                self.fluid_block.as_ref()
+            } else {
+                None
+            }
+        } else if y == ground_y {
+            match &self.snow_block {
+                Some(snow) if y >= self.snow_line => Some(snow),
+                _ => shore.or(self.top_block.as_ref()),
+            }
+        } else if y >= ground_y - self.filler_depth {
+            shore.or(self.filler_block.as_ref())
+        } else {
+            self.stone_block.as_ref()
+        }
+    }
+
+    /// All the blocks these rules place.
+    pub fn blocks(&self) -> impl Iterator<Item = &RegistryName> {
+        [
+            &self.top_block,
+            &self.snow_block,
+            &self.filler_block,
+            &self.stone_block,
+            &self.shore_block,
+            &self.fluid_block,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// The source of a biome's surface height.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SurfaceNoise {
+    /// A noise graph, loadable from data files. Generators build it once with
+    /// [`crate::voxel::generation::noise_graph::NoiseGraph::new`].
+    Graph(NoiseGraphDefinition),
+    /// A function defined in code, biomes using these can't be serialized, see
+    /// [`BiomeDefinition::ensure_serializable`].
+    #[serde(skip)]
+    Code(SurfaceNoiseFn),
+}
+
+impl BiomeDefinition {
+    /// Checks that the biome has no block placement rules or surface noise defined in code.
+    /// Serializing a biome with parts defined in code fails with an error specific to the serializer instead.
+    pub fn ensure_serializable(&self) -> Result<(), CodeDefinedBiomeError> {
+        let part = if matches!(self.rule_source, BiomeRules::Code(_)) {
+            "block placement rules"
+        } else if matches!(self.surface_noise, SurfaceNoise::Code(_)) {
+            "a surface noise"
+        } else {
+            return Ok(());
+        };
+        Err(CodeDefinedBiomeError {
+            name: self.name.clone(),
+            part,
+        })
+    }
+}
+
+/// A biome with parts defined in code, which can't be serialized.
+#[derive(Debug, Error, PartialEq)]
+#[error("Biome {name} has {part} defined in code, so it can't be serialized")]
+pub struct CodeDefinedBiomeError {
+    /// The name of the biome.
+    pub name: RegistryName,
+    /// The part of the biome defined in code.
+    pub part: &'static str,
 }
 
 impl Debug for BiomeDefinition {
@@ -2,11 +2,17 @@
 
 use std::collections::BTreeMap;
 
-use crate::coordinates::AbsChunkPos;
+use bevy_math::Vec3;
+use smallvec::SmallVec;
+
+use crate::coordinates::{AbsBlockPos, AbsChunkPos, InChunkPos};
 use crate::mutwatcher::MutWatcher;
 use crate::voxel::chunk::Chunk;
+use crate::voxel::chunk_storage::ChunkStorage;
 use crate::voxel::neighborhood::OptionalChunkRefNeighborhood;
-use crate::GsExtraData;
+use crate::voxel::raycast::{RaycastBlockHit, VoxelRaycast};
+use crate::voxel::voxeltypes::BlockEntry;
+use crate::{GameSide, GsExtraData};
 
 /// A group of loaded chunks in memory, for example a planet, or a movable contraption.
 #[derive(Clone)]
@@ -53,4 +59,51 @@ impl<ED: GsExtraData> ChunkGroup<ED> {
     pub fn get_chunk(&self, pos: AbsChunkPos) -> Option<&MutWatcher<Chunk<ED>>> {
         self.chunks.get(&pos)
     }
+
+    /// Gets the block at the given position, or `None` if the chunk containing it is not loaded.
+    pub fn get_block(&self, pos: AbsBlockPos) -> Option<BlockEntry> {
+        let (cpos, ipos) = pos.split_chunk_component();
+        self.chunks.get(&cpos).map(|chunk| chunk.blocks.get_copy(ipos))
+    }
+
+    /// Applies a batch of block changes in order, creating exactly one new revision for each loaded chunk touched by the batch.
+    /// Uses [`MutWatcher::mutate_sided`], so on the client each batch must correspond to exactly one edit request sent to the server.
+    /// Changes inside chunks that are not loaded are skipped, returns the number of changes applied.
+    pub fn apply_block_edits(&mut self, edits: &[(AbsBlockPos, BlockEntry)], side: GameSide) -> usize {
+        let mut by_chunk: BTreeMap<AbsChunkPos, SmallVec<[(InChunkPos, BlockEntry); 8]>> = BTreeMap::new();
+        for &(pos, block) in edits {
+            let (cpos, ipos) = pos.split_chunk_component();
+            by_chunk.entry(cpos).or_default().push((ipos, block));
+        }
+        let mut applied = 0;
+        for (cpos, chunk_edits) in by_chunk {
+            let Some(chunk) = self.chunks.get_mut(&cpos) else {
+                continue;
+            };
+            let chunk = chunk.mutate_sided(side);
+            for (ipos, block) in chunk_edits {
+                chunk.blocks.put(ipos, block);
+                applied += 1;
+            }
+        }
+        applied
+    }
+
+    /// Finds the first block along the given ray for which `filter` returns true.
+    /// Stops at the first block that is not loaded.
+    pub fn raycast(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        mut filter: impl FnMut(BlockEntry) -> bool,
+    ) -> Option<(RaycastBlockHit, BlockEntry)> {
+        for hit in VoxelRaycast::new(origin, direction, max_distance) {
+            let block = self.get_block(hit.position)?;
+            if filter(block) {
+                return Some((hit, block));
+            }
+        }
+        None
+    }
 }
@@ -0,0 +1,541 @@
+//! Block collision geometry: convex hulls of the standard shapes in every orientation,
+//! and per-chunk collision data built from them, queryable with axis-aligned box sweeps and overlap tests.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use bevy_math::prelude::*;
+use once_cell::sync::Lazy;
+use smallvec::SmallVec;
+
+use crate::coordinates::{AbsBlockPos, AbsChunkPos, InChunkPos};
+use crate::direction::OctahedralOrientation;
+use crate::mutwatcher::MutWatcher;
+use crate::physics::Aabb;
+use crate::voxel::chunk::Chunk;
+use crate::voxel::chunk_group::ChunkGroup;
+use crate::voxel::chunk_storage::{ChunkStorage, PaletteStorage};
+use crate::voxel::standard_shapes::{
+    StandardShapeMetadata, VoxelShapeDef, STANDARD_SHAPE_CORNER, STANDARD_SHAPE_CUBE, STANDARD_SHAPE_INNER_CORNER,
+    STANDARD_SHAPE_SLOPE, VOXEL_CORNER_SHAPE, VOXEL_CUBE_SHAPE, VOXEL_INNER_CORNER_SHAPE, VOXEL_SLOPE_SHAPE,
+};
+use crate::voxel::voxeltypes::{BlockEntry, BlockRegistry, BlockShapeSet};
+use crate::GsExtraData;
+
+/// Tolerance for treating touching or very slightly overlapping shapes as not intersecting.
+const CONTACT_EPSILON: f32 = 1.0e-4;
+
+/// A convex collision hull of a single block, in coordinates relative to the block's minimum corner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollisionHull {
+    vertices: SmallVec<[Vec3; 8]>,
+    /// Normalized candidate separating axes against axis-aligned boxes.
+    axes: SmallVec<[Vec3; 16]>,
+    bounds: Aabb,
+}
+
+impl CollisionHull {
+    /// Builds the convex hull of the triangles of a block shape in the given orientation.
+    /// Returns `None` for shapes without any triangles.
+    pub fn from_shape(shape: &VoxelShapeDef, orientation: OctahedralOrientation) -> Option<Self> {
+        let matrix = orientation.to_matrix();
+        let mut vertices: SmallVec<[Vec3; 8]> = SmallVec::new();
+        let mut axes: SmallVec<[Vec3; 16]> = SmallVec::new();
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            push_unique_axis(&mut axes, axis);
+        }
+        for side in shape.sides.iter() {
+            let side_vertex = |i: u32| Vec3::from(matrix * side.vertices[i as usize].offset) + Vec3::splat(0.5);
+            for triangle in side.indices.chunks_exact(3) {
+                let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(side_vertex);
+                for v in [a, b, c] {
+                    if !vertices.iter().any(|&old| old.abs_diff_eq(v, CONTACT_EPSILON)) {
+                        vertices.push(v);
+                    }
+                }
+                push_unique_axis(&mut axes, (b - a).cross(c - a));
+                for edge in [b - a, c - b, a - c] {
+                    for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+                        push_unique_axis(&mut axes, axis.cross(edge));
+                    }
+                }
+            }
+        }
+        if vertices.is_empty() {
+            return None;
+        }
+        let bounds = vertices.iter().fold(Aabb::new(vertices[0], vertices[0]), |bounds, &v| {
+            Aabb::new(bounds.min.min(v), bounds.max.max(v))
+        });
+        Some(Self { vertices, axes, bounds })
+    }
+
+    /// The vertices of the hull.
+    pub fn vertices(&self) -> &[Vec3] {
+        &self.vertices
+    }
+
+    /// The bounding box of the hull.
+    pub fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    fn project(&self, axis: Vec3) -> (f32, f32) {
+        self.vertices
+            .iter()
+            .map(|v| v.dot(axis))
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), p| {
+                (min.min(p), max.max(p))
+            })
+    }
+
+    /// Checks if the hull moved by `offset` overlaps the interior of `aabb`, merely touching does not count.
+    pub fn intersects_aabb(&self, offset: Vec3, aabb: &Aabb) -> bool {
+        let aabb = aabb.translated(-offset);
+        if !self.bounds.intersects(&aabb) {
+            return false;
+        }
+        self.axes.iter().all(|&axis| {
+            let (hull_min, hull_max) = self.project(axis);
+            let (box_min, box_max) = project_aabb(&aabb, axis);
+            box_max > hull_min + CONTACT_EPSILON && hull_max > box_min + CONTACT_EPSILON
+        })
+    }
+
+    /// Finds the fraction of `motion` after which `aabb` moving along it first touches the hull moved by `offset`.
+    /// Returns `None` if they don't touch during the motion, or if the box already overlaps the hull at the start.
+    pub fn sweep_aabb(&self, offset: Vec3, aabb: &Aabb, motion: Vec3) -> Option<f32> {
+        let aabb = aabb.translated(-offset);
+        if !self
+            .bounds
+            .intersects(&aabb.swept(motion).expanded(Vec3::splat(CONTACT_EPSILON)))
+        {
+            return None;
+        }
+        // The time range in which the shapes overlap by more than the contact tolerance, and the time of first touch.
+        let mut enter = f32::NEG_INFINITY;
+        let mut exit = f32::INFINITY;
+        let mut touch = f32::NEG_INFINITY;
+        for &axis in self.axes.iter() {
+            let (hull_min, hull_max) = self.project(axis);
+            let (box_min, box_max) = project_aabb(&aabb, axis);
+            let speed = axis.dot(motion);
+            if speed.abs() <= f32::EPSILON {
+                if box_max <= hull_min + CONTACT_EPSILON || hull_max <= box_min + CONTACT_EPSILON {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (hull_min + CONTACT_EPSILON - box_max) / speed;
+            let t1 = (hull_max - CONTACT_EPSILON - box_min) / speed;
+            enter = enter.max(t0.min(t1));
+            exit = exit.min(t0.max(t1));
+            touch = touch.max(((hull_min - box_max) / speed).min((hull_max - box_min) / speed));
+        }
+        if enter >= exit || enter > 1.0 || enter <= 0.0 {
+            return None;
+        }
+        Some(touch.clamp(0.0, 1.0))
+    }
+
+    /// Clips a movement of `aabb` along a single `axis` (0=X, 1=Y, 2=Z) by `distance`, so that it stops at the hull moved by `offset`.
+    pub fn clip_axis_offset(&self, offset: Vec3, aabb: &Aabb, axis: usize, distance: f32) -> f32 {
+        let mut motion = Vec3::ZERO;
+        motion[axis] = distance;
+        match self.sweep_aabb(offset, aabb, motion) {
+            Some(fraction) => distance * fraction,
+            None => distance,
+        }
+    }
+}
+
+fn push_unique_axis(axes: &mut SmallVec<[Vec3; 16]>, axis: Vec3) {
+    let axis = axis.normalize_or_zero();
+    if axis != Vec3::ZERO && !axes.iter().any(|old| old.dot(axis).abs() > 1.0 - CONTACT_EPSILON) {
+        axes.push(axis);
+    }
+}
+
+fn project_aabb(aabb: &Aabb, axis: Vec3) -> (f32, f32) {
+    let center = aabb.center().dot(axis);
+    let radius = (aabb.size() * 0.5).dot(axis.abs());
+    (center - radius, center + radius)
+}
+
+/// Identifies a collision hull in [`STANDARD_COLLISION_HULLS`], zero means no collision.
+pub type CollisionHullId = u16;
+
+/// The [`CollisionHullId`] of blocks without collision.
+pub const NO_COLLISION_HULL: CollisionHullId = 0;
+
+const STANDARD_HULL_SHAPES: [u16; 4] = [
+    STANDARD_SHAPE_CUBE,
+    STANDARD_SHAPE_SLOPE,
+    STANDARD_SHAPE_CORNER,
+    STANDARD_SHAPE_INNER_CORNER,
+];
+
+/// The collision hulls of all standard shapes in all orientations, indexed by [`CollisionHullId`].
+pub static STANDARD_COLLISION_HULLS: Lazy<Vec<Option<CollisionHull>>> = Lazy::new(|| {
+    let mut hulls = vec![None];
+    for shape in STANDARD_HULL_SHAPES {
+        let shape_def: &VoxelShapeDef = match shape {
+            STANDARD_SHAPE_SLOPE => &VOXEL_SLOPE_SHAPE,
+            STANDARD_SHAPE_CORNER => &VOXEL_CORNER_SHAPE,
+            STANDARD_SHAPE_INNER_CORNER => &VOXEL_INNER_CORNER_SHAPE,
+            _ => &VOXEL_CUBE_SHAPE,
+        };
+        for orientation in 0..24 {
+            let orientation = OctahedralOrientation::try_from_index(orientation).unwrap();
+            hulls.push(CollisionHull::from_shape(shape_def, orientation));
+        }
+    }
+    hulls
+});
+
+/// Looks up a hull by its ID.
+pub fn collision_hull(id: CollisionHullId) -> Option<&'static CollisionHull> {
+    STANDARD_COLLISION_HULLS.get(id as usize)?.as_ref()
+}
+
+/// Finds the collision hull of the given block, respecting [`BlockDefinition::has_collision_box`](crate::voxel::voxeltypes::BlockDefinition::has_collision_box).
+pub fn block_collision_hull_id(block: BlockEntry, registry: &BlockRegistry) -> CollisionHullId {
+    let Some(def) = block.lookup(registry) else {
+        return NO_COLLISION_HULL;
+    };
+    if !def.has_collision_box {
+        return NO_COLLISION_HULL;
+    }
+    let cube_id = 1 + OctahedralOrientation::default().to_index() as CollisionHullId;
+    match def.shape_set {
+        BlockShapeSet::StandardShapedMaterial => {
+            let meta = StandardShapeMetadata::from_meta(block.metadata);
+            let Some(shape_index) = STANDARD_HULL_SHAPES.iter().position(|&s| s == meta.shape_bits()) else {
+                return cube_id;
+            };
+            1 + (shape_index * 24 + meta.orientation().to_index()) as CollisionHullId
+        }
+        BlockShapeSet::FullCubeOnly | BlockShapeSet::Custom {} => cube_id,
+    }
+}
+
+/// The collision hulls of all the blocks in a chunk.
+#[derive(Clone, Default)]
+pub struct ChunkCollision {
+    hulls: PaletteStorage<CollisionHullId>,
+}
+
+impl ChunkCollision {
+    /// Builds the collision data for all blocks of the chunk.
+    pub fn from_chunk<ED: GsExtraData>(chunk: &Chunk<ED>, registry: &BlockRegistry) -> Self {
+        let palette = chunk.blocks.palette_entries();
+        let mut hulls = PaletteStorage::new(block_collision_hull_id(palette[0], registry));
+        if palette.len() > 1 {
+            let palette_ids: SmallVec<[(BlockEntry, CollisionHullId); 16]> = palette
+                .iter()
+                .map(|&block| (block, block_collision_hull_id(block, registry)))
+                .collect();
+            for (pos, block) in chunk.blocks.iter_with_coords() {
+                let id = palette_ids
+                    .iter()
+                    .find(|(entry, _)| entry == block)
+                    .map(|&(_, id)| id)
+                    .unwrap_or_else(|| block_collision_hull_id(*block, registry));
+                hulls.put(pos, id);
+            }
+            hulls.optimize();
+        }
+        Self { hulls }
+    }
+
+    /// Updates the collision data of a single changed block.
+    pub fn update_block(&mut self, pos: InChunkPos, block: BlockEntry, registry: &BlockRegistry) {
+        self.hulls.put(pos, block_collision_hull_id(block, registry));
+    }
+
+    /// The collision hull of the block at the given position.
+    pub fn hull_at(&self, pos: InChunkPos) -> Option<&'static CollisionHull> {
+        collision_hull(self.hulls.get_copy(pos))
+    }
+
+    /// Checks if no block in the chunk has a collision hull.
+    pub fn is_empty(&self) -> bool {
+        self.hulls.palette_entries() == [NO_COLLISION_HULL]
+    }
+}
+
+/// Collision data for all the chunks of a [`ChunkGroup`], kept up to date with the chunks' revisions.
+#[derive(Clone, Default)]
+pub struct ChunkGroupCollision {
+    chunks: BTreeMap<AbsChunkPos, MutWatcher<ChunkCollision>>,
+}
+
+impl ChunkGroupCollision {
+    /// Constructs empty collision data.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuilds the collision data of all chunks changed since the last update, and forgets unloaded chunks.
+    /// Returns the number of rebuilt chunks.
+    pub fn update<ED: GsExtraData>(&mut self, group: &ChunkGroup<ED>, registry: &BlockRegistry) -> usize {
+        self.chunks.retain(|pos, _| group.chunks.contains_key(pos));
+        let mut rebuilt = 0;
+        for (&pos, chunk) in group.chunks.iter() {
+            if self
+                .chunks
+                .get(&pos)
+                .is_some_and(|collision| !collision.is_older_than(chunk))
+            {
+                continue;
+            }
+            let collision = chunk.new_with_same_revision(ChunkCollision::from_chunk(chunk, registry));
+            self.chunks.insert(pos, collision);
+            rebuilt += 1;
+        }
+        rebuilt
+    }
+
+    /// Updates the blocks changed by a single edit of a chunk, so that the next [`Self::update`] call does not
+    /// rebuild the whole chunk. `previous` carries the revision of the chunk from before the edit; if the collision
+    /// data was not up to date with it, the chunk is left for [`Self::update`] to rebuild.
+    /// Returns if the collision data was updated.
+    pub fn update_edited_blocks<ED: GsExtraData>(
+        &mut self,
+        pos: AbsChunkPos,
+        previous: &MutWatcher<()>,
+        chunk: &MutWatcher<Chunk<ED>>,
+        blocks: impl IntoIterator<Item = (InChunkPos, BlockEntry)>,
+        registry: &BlockRegistry,
+    ) -> bool {
+        let Some(collision) = self.chunks.get_mut(&pos) else {
+            return false;
+        };
+        if collision.compare_revisions(previous) != Ordering::Equal {
+            return false;
+        }
+        let mut updated = chunk.new_with_same_revision(std::mem::take(collision.mutate_without_revision()));
+        let data = updated.mutate_without_revision();
+        for (ipos, block) in blocks {
+            data.update_block(ipos, block, registry);
+        }
+        *collision = updated;
+        true
+    }
+
+    /// Gets the collision data of a chunk, if loaded.
+    pub fn get_chunk(&self, pos: AbsChunkPos) -> Option<&ChunkCollision> {
+        self.chunks.get(&pos).map(|c| c.read())
+    }
+
+    /// Collects the hulls of all blocks touching `region` along with their block positions.
+    /// Returns `None` if any of the blocks has no collision data loaded.
+    pub fn collect_hulls(&self, region: &Aabb, hulls: &mut Vec<(Vec3, &'static CollisionHull)>) -> Option<()> {
+        let (min, max) = region.block_range();
+        for y in min.y..=max.y {
+            for z in min.z..=max.z {
+                for x in min.x..=max.x {
+                    let pos = AbsBlockPos::new(x, y, z);
+                    let (cpos, ipos) = pos.split_chunk_component();
+                    let chunk = self.get_chunk(cpos)?;
+                    if let Some(hull) = chunk.hull_at(ipos) {
+                        hulls.push((pos.as_vec3(), hull));
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Checks if the box overlaps any block, returns `None` if the area is not loaded.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> Option<bool> {
+        let mut hulls = Vec::new();
+        self.collect_hulls(aabb, &mut hulls)?;
+        Some(hulls.iter().any(|(offset, hull)| hull.intersects_aabb(*offset, aabb)))
+    }
+
+    /// Finds the fraction of `motion` after which the moving box first touches any block,
+    /// `Some(1.0)` if it can move freely, or `None` if the swept area is not loaded.
+    /// A box that already overlaps a block can't move, and gets `Some(0.0)`.
+    pub fn sweep_aabb(&self, aabb: &Aabb, motion: Vec3) -> Option<f32> {
+        let mut hulls = Vec::new();
+        self.collect_hulls(&aabb.swept(motion), &mut hulls)?;
+        if hulls.iter().any(|(offset, hull)| hull.intersects_aabb(*offset, aabb)) {
+            return Some(0.0);
+        }
+        Some(
+            hulls
+                .iter()
+                .filter_map(|(offset, hull)| hull.sweep_aabb(*offset, aabb, motion))
+                .fold(1.0, f32::min),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rgb::RGBA8;
+
+    use super::*;
+    use crate::coordinates::AbsChunkPos;
+    use crate::direction::Direction;
+    use crate::registry::RegistryName;
+    use crate::voxel::voxeltypes::{BlockDefinition, EMPTY_BLOCK};
+    use crate::{GameSide, GsExtraData};
+
+    struct TestData;
+
+    impl GsExtraData for TestData {
+        type ChunkData = ();
+        type GroupData = ();
+
+        const SIDE: GameSide = GameSide::Server;
+    }
+
+    fn hull(shape: u16, orientation: OctahedralOrientation) -> &'static CollisionHull {
+        let shape_index = STANDARD_HULL_SHAPES.iter().position(|&s| s == shape).unwrap();
+        collision_hull(1 + (shape_index * 24 + orientation.to_index()) as CollisionHullId).unwrap()
+    }
+
+    #[test]
+    fn standard_hull_geometry() {
+        assert_eq!(STANDARD_COLLISION_HULLS.len(), 1 + 4 * 24);
+        for orientation in 0..24 {
+            let orientation = OctahedralOrientation::try_from_index(orientation).unwrap();
+            let cube = hull(STANDARD_SHAPE_CUBE, orientation);
+            assert_eq!(cube.vertices().len(), 8);
+            assert!(cube.bounds().min.abs_diff_eq(Vec3::ZERO, 1.0e-6));
+            assert!(cube.bounds().max.abs_diff_eq(Vec3::ONE, 1.0e-6));
+            assert_eq!(hull(STANDARD_SHAPE_SLOPE, orientation).vertices().len(), 6);
+        }
+
+        // The default slope is full at the back (Z+) and descends towards the front (Z-).
+        let slope = hull(STANDARD_SHAPE_SLOPE, OctahedralOrientation::default());
+        let probe = |x: f32, y: f32, z: f32| {
+            slope.intersects_aabb(
+                Vec3::ZERO,
+                &Aabb::from_center_half_extents(Vec3::new(x, y, z), Vec3::splat(0.05)),
+            )
+        };
+        assert!(probe(0.5, 0.1, 0.5));
+        assert!(probe(0.5, 0.8, 0.9));
+        assert!(!probe(0.5, 0.8, 0.1));
+        assert!(!probe(0.5, 1.2, 0.9));
+
+        // Rotated so that the slope descends towards X+.
+        let rotated = OctahedralOrientation::from_up_front(Direction::YPlus, Direction::XPlus).unwrap();
+        let slope = hull(STANDARD_SHAPE_SLOPE, rotated);
+        let probe = |x: f32, y: f32, z: f32| {
+            slope.intersects_aabb(
+                Vec3::ZERO,
+                &Aabb::from_center_half_extents(Vec3::new(x, y, z), Vec3::splat(0.05)),
+            )
+        };
+        assert_ne!(probe(0.1, 0.8, 0.5), probe(0.9, 0.8, 0.5));
+    }
+
+    #[test]
+    fn sweeps() {
+        let cube = hull(STANDARD_SHAPE_CUBE, OctahedralOrientation::default());
+        let falling = Aabb::new(Vec3::new(0.25, 2.0, 0.25), Vec3::new(0.75, 3.0, 0.75));
+        assert_eq!(
+            cube.sweep_aabb(Vec3::ZERO, &falling, Vec3::new(0.0, -2.0, 0.0)),
+            Some(0.5)
+        );
+        assert_eq!(cube.sweep_aabb(Vec3::ZERO, &falling, Vec3::new(0.0, -0.5, 0.0)), None);
+        assert_eq!(cube.sweep_aabb(Vec3::ZERO, &falling, Vec3::new(0.0, 2.0, 0.0)), None);
+        // Resting on the top face, sliding along it is not blocked but falling through it is.
+        let resting = falling.translated(Vec3::NEG_Y);
+        assert_eq!(cube.sweep_aabb(Vec3::ZERO, &resting, Vec3::X), None);
+        assert_eq!(cube.sweep_aabb(Vec3::ZERO, &resting, Vec3::NEG_Y), Some(0.0));
+        assert_eq!(cube.clip_axis_offset(Vec3::X, &resting, 1, -1.0), -1.0);
+
+        // A box sliding down onto the slope's surface stops above its lower part.
+        let slope = hull(STANDARD_SHAPE_SLOPE, OctahedralOrientation::default());
+        let above_low_end = Aabb::new(Vec3::new(0.4, 2.0, 0.0), Vec3::new(0.6, 2.5, 0.2));
+        let fraction = slope
+            .sweep_aabb(Vec3::ZERO, &above_low_end, Vec3::new(0.0, -2.0, 0.0))
+            .unwrap();
+        let landed_at = 2.0 - 2.0 * fraction;
+        assert!((landed_at - 0.2).abs() < 1.0e-3, "Landed at {landed_at}");
+    }
+
+    #[test]
+    fn chunk_collision_updates() {
+        let mut registry = BlockRegistry::default();
+        let empty = BlockEntry::new(registry.push_object(EMPTY_BLOCK.clone()).unwrap(), 0);
+        let mut block_type = |key: &'static str, has_collision_box: bool| {
+            let id = registry
+                .push_object(BlockDefinition {
+                    name: RegistryName::gs_const(key),
+                    shape_set: BlockShapeSet::StandardShapedMaterial,
+                    representative_color: RGBA8::new(0, 0, 0, 255),
+                    has_collision_box,
+                    has_drawable_mesh: true,
+                })
+                .unwrap();
+            BlockEntry::new(id, 0)
+        };
+        let stone = block_type("stone", true);
+        let ghost = block_type("ghost", false);
+
+        let mut group: ChunkGroup<TestData> = ChunkGroup::new();
+        group
+            .chunks
+            .insert(AbsChunkPos::ZERO, MutWatcher::new(Chunk::new(empty, ())));
+        let mut collision = ChunkGroupCollision::new();
+        assert_eq!(collision.update(&group, &registry), 1);
+        assert!(collision.get_chunk(AbsChunkPos::ZERO).unwrap().is_empty());
+        assert_eq!(collision.update(&group, &registry), 0);
+
+        let edits = [(AbsBlockPos::new(1, 1, 1), stone), (AbsBlockPos::new(2, 1, 1), ghost)];
+        group.apply_block_edits(&edits, GameSide::Server);
+        assert_eq!(collision.update(&group, &registry), 1);
+        let chunk = collision.get_chunk(AbsChunkPos::ZERO).unwrap();
+        assert!(chunk.hull_at(InChunkPos::try_new(1, 1, 1).unwrap()).is_some());
+        assert!(chunk.hull_at(InChunkPos::try_new(2, 1, 1).unwrap()).is_none());
+
+        let probe = |collision: &ChunkGroupCollision, x: f32| {
+            collision.intersects_aabb(&Aabb::from_center_half_extents(
+                Vec3::new(x, 1.5, 1.5),
+                Vec3::splat(0.25),
+            ))
+        };
+        assert_eq!(probe(&collision, 1.5), Some(true));
+        assert_eq!(probe(&collision, 2.5), Some(false));
+        assert_eq!(probe(&collision, -5.0), None);
+
+        let falling = Aabb::new(Vec3::new(1.25, 4.0, 1.25), Vec3::new(1.75, 5.0, 1.75));
+        assert_eq!(collision.sweep_aabb(&falling, Vec3::new(0.0, -4.0, 0.0)), Some(0.5));
+        assert_eq!(collision.sweep_aabb(&falling, Vec3::new(0.0, 4.0, 0.0)), Some(1.0));
+
+        // Already overlapping a block
+        let stuck = Aabb::from_center_half_extents(Vec3::new(1.5, 1.5, 1.5), Vec3::splat(0.25));
+        assert_eq!(collision.sweep_aabb(&stuck, Vec3::Y), Some(0.0));
+
+        // Incremental updates of edited blocks
+        let edit =
+            |group: &mut ChunkGroup<TestData>, collision: &mut ChunkGroupCollision, x: i32, block: BlockEntry| {
+                let chunk = group.chunks.get_mut(&AbsChunkPos::ZERO).unwrap();
+                let previous = chunk.new_with_same_revision(());
+                let ipos = InChunkPos::try_new(x, 1, 1).unwrap();
+                chunk.mutate_stored().blocks.put(ipos, block);
+                collision.update_edited_blocks(AbsChunkPos::ZERO, &previous, chunk, [(ipos, block)], &registry)
+            };
+        assert!(edit(&mut group, &mut collision, 1, empty));
+        assert_eq!(probe(&collision, 1.5), Some(false));
+        assert!(edit(&mut group, &mut collision, 2, stone));
+        assert_eq!(probe(&collision, 2.5), Some(true));
+        assert_eq!(collision.update(&group, &registry), 0);
+        // Collision data that missed an earlier edit is rebuilt instead.
+        group.apply_block_edits(&[(AbsBlockPos::new(3, 1, 1), stone)], GameSide::Server);
+        assert!(!edit(&mut group, &mut collision, 4, stone));
+        assert_eq!(collision.update(&group, &registry), 1);
+        assert_eq!(probe(&collision, 3.5), Some(true));
+        assert_eq!(probe(&collision, 4.5), Some(true));
+
+        group.chunks.clear();
+        collision.update(&group, &registry);
+        assert!(collision.get_chunk(AbsChunkPos::ZERO).is_none());
+    }
+}
@@ -8,6 +8,7 @@ use self::positional_random::PositionalRandomFactory;
 use super::{chunk_storage::PaletteStorage, voxeltypes::BlockEntry};
 
 pub mod fbm_noise;
+pub mod noise_graph;
 pub mod positional_random;
 
 /// Context data for world generation.
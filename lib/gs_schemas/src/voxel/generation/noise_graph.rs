@@ -0,0 +1,681 @@
+//! Composable noise graphs: terrain functions described as data, built into seeded noise sources and evaluated
+//! at single points or in batches, with optional memoization of shared inputs.
+//!
+//! A [`NoiseGraphDefinition`] is a list of [`NoiseNode`]s, where every node can only use the nodes before it as inputs,
+//! so a graph can never contain a cycle. Building it with [`NoiseGraph::new`] seeds all of its noise sources from
+//! the world seed.
+
+use bevy_math::{DVec2, DVec3};
+use hashbrown::HashMap;
+use noise::{NoiseFn, OpenSimplex};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{fbm_noise::Fbm, NoiseNDTo2D};
+
+/// The world's base terrain noise, sampled by [`NoiseNode::Terrain`] and by surface functions defined in code.
+pub fn base_terrain_noise(seed: u32) -> Fbm<OpenSimplex> {
+    Fbm::<OpenSimplex>::new(seed).set_octaves(vec![-4.0, 1.0, 1.0, 0.0])
+}
+
+/// Index of a node in a [`NoiseGraphDefinition`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NodeId(pub u32);
+
+impl NodeId {
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// One of the coordinate axes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Axis {
+    /// The X (east-west) axis.
+    X,
+    /// The Y (vertical) axis.
+    Y,
+    /// The Z (north-south) axis.
+    Z,
+}
+
+/// A control point of a [`NoiseNode::Curve`].
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    /// The input value.
+    pub x: f64,
+    /// The output value at the input value.
+    pub y: f64,
+}
+
+/// A single operation in a noise graph.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NoiseNode {
+    /// A constant value.
+    Constant(f64),
+    /// The position along the given axis.
+    Coordinate(Axis),
+    /// OpenSimplex noise, roughly in the -1..1 range.
+    OpenSimplex {
+        /// Seed of this source, combined with the world seed.
+        seed: u32,
+        /// Multiplier of the sampled position.
+        frequency: f64,
+        /// Whether the noise varies along the Y axis, or is the same for the whole column.
+        #[serde(default)]
+        volumetric: bool,
+    },
+    /// fBm noise with the given per-octave strengths, roughly in the -1..1 range, see [`Fbm`].
+    Fbm {
+        /// Seed of this source, combined with the world seed.
+        seed: u32,
+        /// Strengths of the octaves, the number of entries determines the number of octaves.
+        octaves: Vec<f64>,
+        /// Multiplier of the sampled position.
+        frequency: f64,
+        /// Frequency multiplier between successive octaves.
+        lacunarity: f64,
+        /// Amplitude multiplier between successive octaves.
+        persistence: f64,
+        /// Whether the noise varies along the Y axis, or is the same for the whole column.
+        #[serde(default)]
+        volumetric: bool,
+    },
+    /// The world's base terrain noise, see [`base_terrain_noise`], sampled as a plane of 4D noise at the column's
+    /// position multiplied by the frequency. Roughly in the -1.5..1.5 range.
+    Terrain {
+        /// Multiplier of the sampled position.
+        frequency: f64,
+    },
+    /// The sum of the inputs.
+    Add(Vec<NodeId>),
+    /// The product of the inputs.
+    Multiply(Vec<NodeId>),
+    /// The first input minus the second.
+    Subtract(NodeId, NodeId),
+    /// The smallest of the inputs.
+    Min(Vec<NodeId>),
+    /// The largest of the inputs.
+    Max(Vec<NodeId>),
+    /// The input multiplied by `scale`, plus `offset`.
+    Affine {
+        /// The input node.
+        input: NodeId,
+        /// Multiplier of the input.
+        scale: f64,
+        /// Added after the multiplication.
+        offset: f64,
+    },
+    /// The absolute value of the input.
+    Abs(NodeId),
+    /// The input clamped to the `min..=max` range.
+    Clamp {
+        /// The input node.
+        input: NodeId,
+        /// The lowest output value.
+        min: f64,
+        /// The highest output value.
+        max: f64,
+    },
+    /// The input remapped through a smooth curve going through the given points, sorted by `x`.
+    /// Inputs outside the range of the points map to the value of the first or last point.
+    Curve {
+        /// The input node.
+        input: NodeId,
+        /// The control points of the curve.
+        points: Vec<CurvePoint>,
+    },
+    /// The input sampled at a position displaced by the warp nodes, each multiplied by `strength`.
+    DomainWarp {
+        /// The node sampled at the displaced position.
+        input: NodeId,
+        /// The displacement along the X axis.
+        x: NodeId,
+        /// The displacement along the Y axis, if any.
+        #[serde(default)]
+        y: Option<NodeId>,
+        /// The displacement along the Z axis.
+        z: NodeId,
+        /// Multiplier of the displacements.
+        strength: f64,
+    },
+    /// The input, computed once per position in an evaluation batch, for inputs used by multiple nodes.
+    Cache(NodeId),
+    /// The input, computed once per column in an evaluation batch, for column-wide inputs of volumetric graphs.
+    /// The input is sampled at the Y coordinate 0.
+    ColumnCache(NodeId),
+}
+
+impl NoiseNode {
+    /// All the nodes this node uses as inputs.
+    pub fn inputs(&self) -> Vec<NodeId> {
+        match self {
+            Self::Constant(_)
+            | Self::Coordinate(_)
+            | Self::OpenSimplex { .. }
+            | Self::Fbm { .. }
+            | Self::Terrain { .. } => Vec::new(),
+            Self::Add(inputs) | Self::Multiply(inputs) | Self::Min(inputs) | Self::Max(inputs) => inputs.clone(),
+            Self::Subtract(a, b) => vec![*a, *b],
+            Self::Affine { input, .. }
+            | Self::Abs(input)
+            | Self::Clamp { input, .. }
+            | Self::Curve { input, .. }
+            | Self::Cache(input)
+            | Self::ColumnCache(input) => vec![*input],
+            Self::DomainWarp { input, x, y, z, .. } => {
+                [Some(*input), Some(*x), *y, Some(*z)].into_iter().flatten().collect()
+            }
+        }
+    }
+}
+
+/// A serializable description of a noise graph.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoiseGraphDefinition {
+    /// The nodes of the graph, each one can only use the nodes before it as inputs.
+    pub nodes: Vec<NoiseNode>,
+    /// The node whose value is the value of the graph, the last node if not specified.
+    #[serde(default)]
+    pub output: Option<NodeId>,
+}
+
+impl NoiseGraphDefinition {
+    /// Appends a node to the graph, returning its ID for use as an input of later nodes.
+    pub fn push(&mut self, node: NoiseNode) -> NodeId {
+        self.nodes.push(node);
+        NodeId((self.nodes.len() - 1) as u32)
+    }
+
+    /// The node whose value is the value of the graph.
+    pub fn output(&self) -> Option<NodeId> {
+        self.output
+            .or_else(|| self.nodes.len().checked_sub(1).map(|last| NodeId(last as u32)))
+    }
+
+    /// Checks that all node inputs refer to earlier nodes and all node parameters are valid.
+    pub fn validate(&self) -> Result<(), NoiseGraphError> {
+        let output = self.output().ok_or(NoiseGraphError::Empty)?;
+        if output.index() >= self.nodes.len() {
+            return Err(NoiseGraphError::MissingOutput { output });
+        }
+        for (index, node) in self.nodes.iter().enumerate() {
+            let node_id = NodeId(index as u32);
+            if let Some(&input) = node.inputs().iter().find(|input| input.index() >= index) {
+                return Err(NoiseGraphError::ForwardReference { node: node_id, input });
+            }
+            let valid = match node {
+                NoiseNode::Add(inputs)
+                | NoiseNode::Multiply(inputs)
+                | NoiseNode::Min(inputs)
+                | NoiseNode::Max(inputs) => !inputs.is_empty(),
+                NoiseNode::Fbm { octaves, .. } => {
+                    !octaves.is_empty() && octaves.len() <= Fbm::<OpenSimplex>::MAX_OCTAVES
+                }
+                NoiseNode::Clamp { min, max, .. } => min <= max,
+                NoiseNode::Curve { points, .. } => !points.is_empty() && points.windows(2).all(|w| w[0].x < w[1].x),
+                _ => true,
+            };
+            if !valid {
+                return Err(NoiseGraphError::InvalidNode { node: node_id });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Errors found when building a [`NoiseGraph`] from its definition.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NoiseGraphError {
+    /// The graph has no nodes.
+    #[error("The noise graph has no nodes")]
+    Empty,
+    /// The output refers to a node that doesn't exist.
+    #[error("The noise graph output {output:?} doesn't exist")]
+    MissingOutput {
+        /// The output node ID.
+        output: NodeId,
+    },
+    /// A node uses itself or a later node as an input.
+    #[error("Node {node:?} uses {input:?} as an input, but only earlier nodes can be used")]
+    ForwardReference {
+        /// The node with the invalid input.
+        node: NodeId,
+        /// The invalid input.
+        input: NodeId,
+    },
+    /// A node has invalid parameters, like a curve with unsorted points or an empty sum.
+    #[error("Node {node:?} has invalid parameters")]
+    InvalidNode {
+        /// The invalid node.
+        node: NodeId,
+    },
+}
+
+enum BuiltNode {
+    Node(NoiseNode),
+    OpenSimplex {
+        source: OpenSimplex,
+        frequency: f64,
+        volumetric: bool,
+    },
+    Fbm {
+        source: Fbm<OpenSimplex>,
+        volumetric: bool,
+    },
+    Terrain {
+        source: Fbm<OpenSimplex>,
+        frequency: f64,
+    },
+}
+
+/// Memoized node values, shared between the evaluations of a batch.
+#[derive(Default)]
+pub struct NoiseGraphCache {
+    points: HashMap<(NodeId, [u64; 3]), f64>,
+    columns: HashMap<(NodeId, [u64; 2]), f64>,
+}
+
+impl NoiseGraphCache {
+    /// Forgets all the memoized values.
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.columns.clear();
+    }
+}
+
+/// A noise graph with seeded noise sources, ready for evaluation.
+pub struct NoiseGraph {
+    nodes: Vec<BuiltNode>,
+    output: NodeId,
+}
+
+impl NoiseGraph {
+    /// Validates the definition and seeds all of its noise sources from the given world seed.
+    pub fn new(definition: &NoiseGraphDefinition, seed: u32) -> Result<Self, NoiseGraphError> {
+        definition.validate()?;
+        let nodes = definition
+            .nodes
+            .iter()
+            .map(|node| match node {
+                &NoiseNode::OpenSimplex {
+                    seed: node_seed,
+                    frequency,
+                    volumetric,
+                } => BuiltNode::OpenSimplex {
+                    source: OpenSimplex::new(source_seed(seed, node_seed)),
+                    frequency,
+                    volumetric,
+                },
+                NoiseNode::Fbm {
+                    seed: node_seed,
+                    octaves,
+                    frequency,
+                    lacunarity,
+                    persistence,
+                    volumetric,
+                } => BuiltNode::Fbm {
+                    source: Fbm::<OpenSimplex>::new(source_seed(seed, *node_seed))
+                        .set_octaves(octaves.clone())
+                        .set_frequency(*frequency)
+                        .set_lacunarity(*lacunarity)
+                        .set_persistence(*persistence),
+                    volumetric: *volumetric,
+                },
+                &NoiseNode::Terrain { frequency } => BuiltNode::Terrain {
+                    source: base_terrain_noise(seed),
+                    frequency,
+                },
+                node => BuiltNode::Node(node.clone()),
+            })
+            .collect();
+        Ok(Self {
+            nodes,
+            output: definition.output().ok_or(NoiseGraphError::Empty)?,
+        })
+    }
+
+    /// Evaluates the graph at a single column, with the Y coordinate 0.
+    pub fn sample_2d(&self, pos: DVec2) -> f64 {
+        self.sample(DVec3::new(pos.x, 0.0, pos.y))
+    }
+
+    /// Evaluates the graph at a single position, prefer the batch functions for multiple positions.
+    pub fn sample(&self, pos: DVec3) -> f64 {
+        self.sample_cached(pos, &mut NoiseGraphCache::default())
+    }
+
+    /// Evaluates the graph at the given heights of a single column, sharing the cache between all of them so that
+    /// the [`NoiseNode::ColumnCache`] inputs are only evaluated once per column.
+    pub fn sample_column(
+        &self,
+        column: DVec2,
+        heights: impl IntoIterator<Item = f64>,
+        cache: &mut NoiseGraphCache,
+    ) -> Vec<f64> {
+        heights
+            .into_iter()
+            .map(|y| self.sample_cached(DVec3::new(column.x, y, column.y), cache))
+            .collect()
+    }
+
+    /// Evaluates the graph at many columns with the Y coordinate 0, sharing the cache between all of them.
+    pub fn sample_2d_batch(&self, columns: impl IntoIterator<Item = DVec2>, cache: &mut NoiseGraphCache) -> Vec<f64> {
+        columns
+            .into_iter()
+            .map(|pos| self.sample_cached(DVec3::new(pos.x, 0.0, pos.y), cache))
+            .collect()
+    }
+
+    /// Evaluates the graph at a single position, reusing the values memoized by the cache nodes.
+    pub fn sample_cached(&self, pos: DVec3, cache: &mut NoiseGraphCache) -> f64 {
+        self.evaluate(self.output, pos, cache)
+    }
+
+    fn evaluate(&self, id: NodeId, pos: DVec3, cache: &mut NoiseGraphCache) -> f64 {
+        let node = match &self.nodes[id.index()] {
+            BuiltNode::OpenSimplex {
+                source,
+                frequency,
+                volumetric,
+            } => {
+                let pos = pos * *frequency;
+                return if *volumetric {
+                    source.get(pos.to_array())
+                } else {
+                    source.get([pos.x, pos.z])
+                };
+            }
+            BuiltNode::Fbm { source, volumetric } => {
+                return if *volumetric {
+                    source.get(pos.to_array())
+                } else {
+                    source.get([pos.x, pos.z])
+                };
+            }
+            BuiltNode::Terrain { source, frequency } => {
+                return <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(source, [pos.x * frequency, pos.z * frequency]);
+            }
+            BuiltNode::Node(node) => node,
+        };
+        let mut eval = |input: &NodeId| self.evaluate(*input, pos, cache);
+        match node {
+            NoiseNode::Constant(value) => *value,
+            NoiseNode::Coordinate(Axis::X) => pos.x,
+            NoiseNode::Coordinate(Axis::Y) => pos.y,
+            NoiseNode::Coordinate(Axis::Z) => pos.z,
+            NoiseNode::Add(inputs) => inputs.iter().map(eval).sum(),
+            NoiseNode::Multiply(inputs) => inputs.iter().map(eval).product(),
+            NoiseNode::Subtract(a, b) => eval(a) - eval(b),
+            NoiseNode::Min(inputs) => inputs.iter().map(eval).fold(f64::INFINITY, f64::min),
+            NoiseNode::Max(inputs) => inputs.iter().map(eval).fold(f64::NEG_INFINITY, f64::max),
+            NoiseNode::Affine { input, scale, offset } => eval(input) * scale + offset,
+            NoiseNode::Abs(input) => eval(input).abs(),
+            NoiseNode::Clamp { input, min, max } => eval(input).clamp(*min, *max),
+            NoiseNode::Curve { input, points } => evaluate_curve(points, eval(input)),
+            NoiseNode::DomainWarp {
+                input,
+                x,
+                y,
+                z,
+                strength,
+            } => {
+                let offset = DVec3::new(eval(x), y.as_ref().map_or(0.0, &mut eval), eval(z));
+                self.evaluate(*input, pos + offset * *strength, cache)
+            }
+            NoiseNode::Cache(input) => {
+                let key = (*input, pos.to_array().map(f64::to_bits));
+                if let Some(&value) = cache.points.get(&key) {
+                    return value;
+                }
+                let value = self.evaluate(*input, pos, cache);
+                cache.points.insert(key, value);
+                value
+            }
+            NoiseNode::ColumnCache(input) => {
+                let key = (*input, [pos.x.to_bits(), pos.z.to_bits()]);
+                if let Some(&value) = cache.columns.get(&key) {
+                    return value;
+                }
+                let value = self.evaluate(*input, DVec3::new(pos.x, 0.0, pos.z), cache);
+                cache.columns.insert(key, value);
+                value
+            }
+            NoiseNode::OpenSimplex { .. } | NoiseNode::Fbm { .. } | NoiseNode::Terrain { .. } => {
+                unreachable!("Noise sources are built")
+            }
+        }
+    }
+}
+
+fn source_seed(world_seed: u32, node_seed: u32) -> u32 {
+    world_seed ^ node_seed.wrapping_mul(0x9E37_79B9)
+}
+
+/// Cubic Hermite interpolation between the points, with tangents averaged from the neighbouring segments.
+/// Tangents are zero at local extrema and limited to three times the slopes of both neighbouring segments, which is
+/// enough for the Fritsch–Carlson monotonicity condition, so the curve never overshoots the points.
+fn evaluate_curve(points: &[CurvePoint], x: f64) -> f64 {
+    let (first, last) = (points[0], points[points.len() - 1]);
+    if x <= first.x {
+        return first.y;
+    }
+    if x >= last.x {
+        return last.y;
+    }
+    let i = points.partition_point(|p| p.x <= x) - 1;
+    let (a, b) = (points[i], points[i + 1]);
+    let slope = |i: usize| (points[i + 1].y - points[i].y) / (points[i + 1].x - points[i].x);
+    let tangent = |i: usize| {
+        if i == 0 {
+            return slope(0);
+        }
+        if i == points.len() - 1 {
+            return slope(i - 1);
+        }
+        let (left, right) = (slope(i - 1), slope(i));
+        if left * right <= 0.0 {
+            0.0
+        } else {
+            let limit = 3.0 * left.abs().min(right.abs());
+            ((left + right) / 2.0).clamp(-limit, limit)
+        }
+    };
+    let width = b.x - a.x;
+    let t = (x - a.x) / width;
+    let (t2, t3) = (t * t, t * t * t);
+    (2.0 * t3 - 3.0 * t2 + 1.0) * a.y
+        + (t3 - 2.0 * t2 + t) * width * tangent(i)
+        + (-2.0 * t3 + 3.0 * t2) * b.y
+        + (t3 - t2) * width * tangent(i + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ridged_terrain() -> NoiseGraphDefinition {
+        let mut graph = NoiseGraphDefinition::default();
+        let base = graph.push(NoiseNode::Fbm {
+            seed: 1,
+            octaves: vec![1.0, 1.0, 0.5],
+            frequency: 0.01,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            volumetric: false,
+        });
+        let cached = graph.push(NoiseNode::Cache(base));
+        let ridges = graph.push(NoiseNode::Abs(cached));
+        let warp = graph.push(NoiseNode::OpenSimplex {
+            seed: 2,
+            frequency: 0.05,
+            volumetric: false,
+        });
+        let warped = graph.push(NoiseNode::DomainWarp {
+            input: ridges,
+            x: warp,
+            y: None,
+            z: warp,
+            strength: 4.0,
+        });
+        let height = graph.push(NoiseNode::Curve {
+            input: warped,
+            points: vec![
+                CurvePoint { x: 0.0, y: 0.0 },
+                CurvePoint { x: 0.5, y: 20.0 },
+                CurvePoint { x: 1.0, y: 30.0 },
+            ],
+        });
+        graph.push(NoiseNode::Add(vec![height, cached]));
+        graph
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(NoiseGraphDefinition::default().validate(), Err(NoiseGraphError::Empty));
+        let mut graph = NoiseGraphDefinition::default();
+        graph.push(NoiseNode::Abs(NodeId(0)));
+        assert_eq!(
+            graph.validate(),
+            Err(NoiseGraphError::ForwardReference {
+                node: NodeId(0),
+                input: NodeId(0)
+            })
+        );
+        let mut graph = NoiseGraphDefinition::default();
+        let constant = graph.push(NoiseNode::Constant(1.0));
+        graph.push(NoiseNode::Clamp {
+            input: constant,
+            min: 1.0,
+            max: 0.0,
+        });
+        assert_eq!(graph.validate(), Err(NoiseGraphError::InvalidNode { node: NodeId(1) }));
+        graph.output = Some(NodeId(5));
+        assert_eq!(
+            graph.validate(),
+            Err(NoiseGraphError::MissingOutput { output: NodeId(5) })
+        );
+        assert_eq!(ridged_terrain().validate(), Ok(()));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut graph = NoiseGraphDefinition::default();
+        let x = graph.push(NoiseNode::Coordinate(Axis::X));
+        let two = graph.push(NoiseNode::Constant(2.0));
+        let doubled = graph.push(NoiseNode::Multiply(vec![x, two]));
+        let shifted = graph.push(NoiseNode::Affine {
+            input: doubled,
+            scale: 1.0,
+            offset: -3.0,
+        });
+        graph.push(NoiseNode::Clamp {
+            input: shifted,
+            min: -2.0,
+            max: 5.0,
+        });
+        let graph = NoiseGraph::new(&graph, 0).unwrap();
+        assert_eq!(graph.sample_2d(DVec2::new(2.0, 0.0)), 1.0);
+        assert_eq!(graph.sample_2d(DVec2::new(-5.0, 0.0)), -2.0);
+        assert_eq!(graph.sample_2d(DVec2::new(10.0, 0.0)), 5.0);
+    }
+
+    #[test]
+    fn curve_passes_through_points() {
+        let points = [
+            CurvePoint { x: -1.0, y: 4.0 },
+            CurvePoint { x: 0.0, y: 0.0 },
+            CurvePoint { x: 2.0, y: 1.0 },
+        ];
+        for p in points {
+            assert!((evaluate_curve(&points, p.x) - p.y).abs() < 1e-9);
+        }
+        assert_eq!(evaluate_curve(&points, -5.0), 4.0);
+        assert_eq!(evaluate_curve(&points, 5.0), 1.0);
+        let mid = evaluate_curve(&points, 1.0);
+        assert!((0.0..=1.0).contains(&mid));
+    }
+
+    #[test]
+    fn curve_is_monotonic_between_points() {
+        // Averaging the slopes of the steep and the flat segment would overshoot below 0 on the flat one.
+        let points = [
+            CurvePoint { x: 0.0, y: 0.0 },
+            CurvePoint { x: 1.0, y: 0.1 },
+            CurvePoint { x: 2.0, y: 10.0 },
+        ];
+        let mut previous = f64::NEG_INFINITY;
+        for step in 0..=200 {
+            let value = evaluate_curve(&points, step as f64 / 100.0);
+            assert!(value >= previous);
+            previous = value;
+        }
+    }
+
+    #[test]
+    fn terrain_samples_the_base_noise() {
+        let mut definition = NoiseGraphDefinition::default();
+        definition.push(NoiseNode::Terrain { frequency: 0.5 });
+        let graph = NoiseGraph::new(&definition, 1234).unwrap();
+        let noise = base_terrain_noise(1234);
+        let pos = DVec2::new(0.3, -1.7);
+        assert_eq!(
+            graph.sample_2d(pos),
+            <Fbm<OpenSimplex> as NoiseNDTo2D<4>>::get_2d(&noise, (pos * 0.5).to_array())
+        );
+    }
+
+    #[test]
+    fn column_cache_is_evaluated_once_per_column() {
+        let mut definition = NoiseGraphDefinition::default();
+        let base = definition.push(NoiseNode::Fbm {
+            seed: 1,
+            octaves: vec![1.0, 0.5],
+            frequency: 0.01,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            volumetric: false,
+        });
+        let column = definition.push(NoiseNode::ColumnCache(base));
+        let y = definition.push(NoiseNode::Coordinate(Axis::Y));
+        definition.push(NoiseNode::Add(vec![column, y]));
+        let graph = NoiseGraph::new(&definition, 1234).unwrap();
+
+        let heights = (-16..16).map(f64::from);
+        let mut cache = NoiseGraphCache::default();
+        for x in 0..2 {
+            let column = DVec2::new(x as f64, 5.0);
+            let single: Vec<f64> = heights
+                .clone()
+                .map(|y| graph.sample(DVec3::new(column.x, y, column.y)))
+                .collect();
+            assert_eq!(graph.sample_column(column, heights.clone(), &mut cache), single);
+            // The column's input is evaluated when it's first missing from the cache, and reused for the other heights.
+            assert_eq!(cache.columns.len(), x + 1);
+        }
+        assert!(cache.points.is_empty());
+
+        let columns = [DVec2::new(0.0, 5.0), DVec2::new(1.0, 5.0)];
+        assert_eq!(
+            graph.sample_2d_batch(columns, &mut NoiseGraphCache::default()),
+            columns.map(|pos| graph.sample_2d(pos)).to_vec()
+        );
+    }
+
+    #[test]
+    fn seeds_change_the_graph() {
+        let graph = NoiseGraph::new(&ridged_terrain(), 1234).unwrap();
+        let other_seed = NoiseGraph::new(&ridged_terrain(), 4321).unwrap();
+        let samples = |graph: &NoiseGraph| {
+            (0..16)
+                .map(|i| graph.sample_2d(DVec2::new(i as f64 * 7.0, -64.0)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            samples(&graph),
+            samples(&NoiseGraph::new(&ridged_terrain(), 1234).unwrap())
+        );
+        assert_ne!(samples(&graph), samples(&other_seed));
+    }
+}
@@ -1,38 +1,182 @@
 //! Positional random source.
+//!
+//! Randomness tied to a position is derived from a hash of the world seed, a salt and the position's coordinates,
+//! so that it's the same every time the position is generated, but independent between worlds, between the different
+//! uses of randomness (told apart by their salt) and between neighbouring positions.
 
 use std::marker::PhantomData;
 
-use bevy_math::IVec3;
+use bevy_math::{IVec2, IVec3};
 use rand::{RngCore, SeedableRng};
 
 /// Helper combination trait
 pub trait Random: RngCore + SeedableRng {}
 impl<T> Random for T where T: RngCore + SeedableRng {}
 
-/// Positional random generator.
-pub struct PositionalRandomFactory<Rand: Random>(PhantomData<Rand>);
+/// The 64-bit golden ratio constant, separating consecutive hash inputs.
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
 
-impl<Rand> Default for PositionalRandomFactory<Rand>
-where
-    Rand: RngCore + SeedableRng,
-{
-    fn default() -> Self {
-        Self(PhantomData)
+/// The SplitMix64 finalizer: a bijective mix of all the bits of the input into all the bits of the output.
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn positional_hash(seed: u64, salt: u64, coordinates: &[i32]) -> u64 {
+    let mut hash = mix64(seed ^ mix64(salt));
+    for &coordinate in coordinates {
+        hash = mix64(hash.wrapping_add(GOLDEN_GAMMA) ^ coordinate as u32 as u64);
     }
+    hash
+}
+
+/// Hashes a 3D position with the world seed and a salt telling apart the different uses of the hash.
+pub fn positional_hash_3d(seed: u64, salt: u64, pos: IVec3) -> u64 {
+    positional_hash(seed, salt, &[pos.x, pos.y, pos.z])
+}
+
+/// Hashes a 2D position (typically a column's X and Z) with the world seed and a salt telling apart the different
+/// uses of the hash.
+pub fn positional_hash_2d(seed: u64, salt: u64, pos: IVec2) -> u64 {
+    positional_hash(seed, salt, &[pos.x, pos.y])
+}
+
+/// Positional random generator for a world seed.
+pub struct PositionalRandomFactory<Rand: Random> {
+    seed: u64,
+    _rand: PhantomData<Rand>,
 }
 
 impl<Rand> PositionalRandomFactory<Rand>
 where
     Rand: RngCore + SeedableRng,
 {
-    /// Get a new random from this position.
-    pub fn get_at_pos(pos: IVec3) -> Rand {
-        Self::get_at_pos_i(pos.x, pos.y, pos.z)
+    /// Creates a positional random generator for the given world seed.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            _rand: PhantomData,
+        }
+    }
+
+    /// The world seed.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Get a new random for this position and salt.
+    pub fn get_at_pos(&self, salt: u64, pos: IVec3) -> Rand {
+        Rand::seed_from_u64(positional_hash_3d(self.seed, salt, pos))
+    }
+
+    /// Get a new random for this position and salt.
+    pub fn get_at_pos_i(&self, salt: u64, x: i32, y: i32, z: i32) -> Rand {
+        self.get_at_pos(salt, IVec3::new(x, y, z))
     }
 
-    /// Get a new random from this position.
-    pub fn get_at_pos_i(x: i32, y: i32, z: i32) -> Rand {
-        let seed: u64 = (x as u64 ^ 10645345) | (y as u64 * 136540234) & (z as u64 ^ 0xABCDEF01257);
-        Rand::seed_from_u64(seed)
+    /// Get a new random for this column and salt.
+    pub fn get_at_column(&self, salt: u64, pos: IVec2) -> Rand {
+        Rand::seed_from_u64(positional_hash_2d(self.seed, salt, pos))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hashbrown::HashSet;
+    use itertools::iproduct;
+    use rand::Rng;
+    use rand_xoshiro::Xoshiro256StarStar;
+
+    use super::*;
+
+    const SEED: u64 = 0x5EED;
+    const SALT: u64 = 17;
+
+    fn cube() -> impl Iterator<Item = IVec3> {
+        iproduct!(-32..32, -32..32, -32..32).map(|(x, y, z)| IVec3::new(x, y, z))
+    }
+
+    #[test]
+    fn no_collisions() {
+        let hashes: HashSet<u64> = cube().map(|pos| positional_hash_3d(SEED, SALT, pos)).collect();
+        assert_eq!(hashes.len(), 64 * 64 * 64);
+        let columns: HashSet<u64> = iproduct!(-256..256, -256..256)
+            .map(|(x, z)| positional_hash_2d(SEED, SALT, IVec2::new(x, z)))
+            .collect();
+        assert_eq!(columns.len(), 512 * 512);
+
+        // Truncated to 32 bits, the hashes collide about as often as random values would: n²/2³³ ≈ 8 times.
+        let mut truncated: Vec<u32> = cube().map(|pos| positional_hash_3d(SEED, SALT, pos) as u32).collect();
+        truncated.sort_unstable();
+        let collisions = truncated.windows(2).filter(|pair| pair[0] == pair[1]).count();
+        assert!(collisions < 32, "{collisions} collisions");
+    }
+
+    #[test]
+    fn uniform_distribution() {
+        let hashes: Vec<u64> = cube().map(|pos| positional_hash_3d(SEED, SALT, pos)).collect();
+        let n = hashes.len() as f64;
+
+        // Every bit is set about half of the time.
+        for bit in 0..64 {
+            let ones = hashes.iter().filter(|&&hash| hash & (1 << bit) != 0).count() as f64;
+            assert!(
+                (ones / n - 0.5).abs() < 0.01,
+                "Bit {bit} is set in {} of the hashes",
+                ones / n
+            );
+        }
+
+        // Chi-squared test of the top byte, with 255 degrees of freedom: the 99.9th percentile is about 330.
+        let mut buckets = [0u32; 256];
+        for hash in &hashes {
+            buckets[(hash >> 56) as usize] += 1;
+        }
+        let expected = n / 256.0;
+        let chi_squared: f64 = buckets
+            .iter()
+            .map(|&count| (count as f64 - expected).powi(2) / expected)
+            .sum();
+        assert!(chi_squared < 330.0, "Chi-squared {chi_squared}");
+
+        // Neighbouring positions flip about half of the bits.
+        let flipped: u32 = cube()
+            .map(|pos| {
+                (positional_hash_3d(SEED, SALT, pos) ^ positional_hash_3d(SEED, SALT, pos + IVec3::Y)).count_ones()
+            })
+            .sum();
+        let average = flipped as f64 / n;
+        assert!((average - 32.0).abs() < 0.5, "{average} bits flipped on average");
+    }
+
+    #[test]
+    fn seed_and_salt_aware() {
+        let same = |a: (u64, u64), b: (u64, u64)| {
+            cube()
+                .filter(|&pos| positional_hash_3d(a.0, a.1, pos) == positional_hash_3d(b.0, b.1, pos))
+                .count()
+        };
+        assert_eq!(same((SEED, SALT), (SEED, SALT)), 64 * 64 * 64);
+        assert_eq!(same((SEED, SALT), (SEED + 1, SALT)), 0);
+        assert_eq!(same((SEED, SALT), (SEED, SALT + 1)), 0);
+        // Swapping the seed and the salt gives a different hash too.
+        assert_eq!(same((SEED, SALT), (SALT, SEED)), 0);
+
+        let a = PositionalRandomFactory::<Xoshiro256StarStar>::new(SEED);
+        let b = PositionalRandomFactory::<Xoshiro256StarStar>::new(SEED + 1);
+        let pos = IVec3::new(3, -7, 12);
+        assert_eq!(
+            a.get_at_pos(SALT, pos).gen::<u64>(),
+            a.get_at_pos_i(SALT, 3, -7, 12).gen::<u64>()
+        );
+        assert_ne!(
+            a.get_at_pos(SALT, pos).gen::<u64>(),
+            b.get_at_pos(SALT, pos).gen::<u64>()
+        );
+        assert_ne!(
+            a.get_at_column(SALT, IVec2::new(3, 12)).gen::<u64>(),
+            a.get_at_column(SALT + 1, IVec2::new(3, 12)).gen::<u64>()
+        );
     }
 }
@@ -4,7 +4,10 @@ pub mod biome;
 pub mod chunk;
 pub mod chunk_group;
 pub mod chunk_storage;
+pub mod collision;
 pub mod generation;
 pub mod neighborhood;
+pub mod raycast;
 pub mod standard_shapes;
+pub mod structure;
 pub mod voxeltypes;
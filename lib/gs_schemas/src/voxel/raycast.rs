@@ -0,0 +1,156 @@
+//! Grid traversal of rays through the voxel world, used for block targeting.
+
+use bevy_math::prelude::*;
+
+use crate::coordinates::AbsBlockPos;
+use crate::direction::Direction;
+
+/// A single block crossed by a [`VoxelRaycast`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RaycastBlockHit {
+    /// The position of the crossed block.
+    pub position: AbsBlockPos,
+    /// The face of the block through which the ray entered it, `None` for the block containing the ray origin.
+    pub entry_face: Option<Direction>,
+    /// The distance from the ray origin to the point where the ray entered the block.
+    pub distance: f32,
+}
+
+impl RaycastBlockHit {
+    /// The position of the block neighboring the entry face, i.e. where a block would be placed against the hit face.
+    /// Returns `None` for the block containing the ray origin.
+    pub fn adjacent_position(&self) -> Option<AbsBlockPos> {
+        self.entry_face
+            .map(|face| AbsBlockPos::from(self.position.into_ivec3() + face.to_ivec()))
+    }
+}
+
+/// An iterator over all the blocks crossed by a ray, in the order of increasing distance from the ray origin.
+/// Implements the "A Fast Voxel Traversal Algorithm for Ray Tracing" algorithm by Amanatides and Woo.
+///
+/// A block at position `p` occupies the space from `p` to `p + 1` in every axis.
+#[derive(Clone, Debug)]
+pub struct VoxelRaycast {
+    current: IVec3,
+    step: IVec3,
+    t_max: Vec3,
+    t_delta: Vec3,
+    max_distance: f32,
+    next_hit: Option<RaycastBlockHit>,
+}
+
+impl VoxelRaycast {
+    /// Starts a new raycast from `origin` in the given (not necessarily normalized) `direction`, up to `max_distance` units away.
+    /// A zero direction only yields the block containing the origin.
+    pub fn new(origin: Vec3, direction: Vec3, max_distance: f32) -> Self {
+        let direction = direction.normalize_or_zero();
+        let current = origin.floor().as_ivec3();
+        let step = IVec3::select(direction.cmpgt(Vec3::ZERO), IVec3::ONE, IVec3::ZERO)
+            - IVec3::select(direction.cmplt(Vec3::ZERO), IVec3::ONE, IVec3::ZERO);
+        let t_delta = direction.recip().abs();
+        let first_boundary = current.as_vec3() + step.max(IVec3::ZERO).as_vec3();
+        let t_max = Vec3::select(
+            direction.cmpne(Vec3::ZERO),
+            (first_boundary - origin) / direction,
+            Vec3::INFINITY,
+        );
+        Self {
+            current,
+            step,
+            t_max,
+            t_delta,
+            max_distance,
+            next_hit: Some(RaycastBlockHit {
+                position: current.into(),
+                entry_face: None,
+                distance: 0.0,
+            }),
+        }
+    }
+}
+
+impl Iterator for VoxelRaycast {
+    type Item = RaycastBlockHit;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let hit = self.next_hit.take()?;
+        let axis = if self.t_max.x < self.t_max.y {
+            if self.t_max.x < self.t_max.z {
+                0
+            } else {
+                2
+            }
+        } else if self.t_max.y < self.t_max.z {
+            1
+        } else {
+            2
+        };
+        let distance = self.t_max[axis];
+        if self.step[axis] != 0 && distance <= self.max_distance {
+            self.current[axis] += self.step[axis];
+            self.t_max[axis] += self.t_delta[axis];
+            let mut face = IVec3::ZERO;
+            face[axis] = -self.step[axis];
+            self.next_hit = Some(RaycastBlockHit {
+                position: self.current.into(),
+                entry_face: Direction::try_from_ivec(face),
+                distance,
+            });
+        }
+        Some(hit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use super::*;
+
+    fn positions(ray: VoxelRaycast) -> Vec<IVec3> {
+        ray.map(|hit| hit.position.into_ivec3()).collect_vec()
+    }
+
+    #[test]
+    fn axis_aligned_rays() {
+        let ray = VoxelRaycast::new(Vec3::new(0.5, 0.5, 0.5), Vec3::X, 3.0);
+        assert_eq!(
+            positions(ray.clone()),
+            vec![IVec3::ZERO, IVec3::X, IVec3::new(2, 0, 0), IVec3::new(3, 0, 0)]
+        );
+        let hits = ray.collect_vec();
+        assert_eq!(hits[0].entry_face, None);
+        assert_eq!(hits[1].entry_face, Some(Direction::XMinus));
+        assert_eq!(hits[1].distance, 0.5);
+        assert_eq!(hits[1].adjacent_position(), Some(AbsBlockPos::ZERO));
+
+        let ray = VoxelRaycast::new(Vec3::new(0.5, 0.5, 0.5), Vec3::NEG_Y, 1.0);
+        let hits = ray.collect_vec();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[1].position, AbsBlockPos::new(0, -1, 0));
+        assert_eq!(hits[1].entry_face, Some(Direction::YPlus));
+    }
+
+    #[test]
+    fn diagonal_ray_is_connected() {
+        let ray = VoxelRaycast::new(Vec3::new(0.1, 0.2, 0.3), Vec3::new(1.0, -2.0, 3.0), 20.0);
+        let mut last: Option<RaycastBlockHit> = None;
+        for hit in ray {
+            if let Some(last) = last {
+                let delta = hit.position.into_ivec3() - last.position.into_ivec3();
+                assert_eq!(delta.abs().element_sum(), 1, "Consecutive blocks must share a face");
+                assert_eq!(Some(delta), hit.entry_face.map(|f| -f.to_ivec()));
+                assert!(hit.distance >= last.distance);
+                assert!(hit.distance <= 20.0);
+            }
+            last = Some(hit);
+        }
+        assert!(last.unwrap().distance > 19.0);
+    }
+
+    #[test]
+    fn zero_direction() {
+        let ray = VoxelRaycast::new(Vec3::new(-0.5, 7.5, 2.0), Vec3::ZERO, 10.0);
+        assert_eq!(positions(ray), vec![IVec3::new(-1, 7, 2)]);
+    }
+}
@@ -0,0 +1,661 @@
+//! Structure templates: block arrangements captured from a world, which can be rotated and placed into any world with
+//! the same block types.
+
+use bevy_math::{I64Vec3, IVec3};
+use capnp::message::{ReaderOptions, TypedBuilder, TypedReader};
+use hashbrown::HashMap;
+use itertools::iproduct;
+use thiserror::Error;
+
+use crate::coordinates::{AbsBlockPos, AbsChunkPos};
+use crate::direction::{Direction, OctahedralOrientation};
+use crate::registry::{RegistryId, RegistryName};
+use crate::schemas::game_types_capnp::{i_vec3, structure_template};
+use crate::voxel::chunk_group::ChunkGroup;
+use crate::voxel::chunk_storage::ChunkStorage;
+use crate::voxel::standard_shapes::StandardShapeMetadata;
+use crate::voxel::voxeltypes::{BlockEntry, BlockMetadata, BlockRegistry, BlockShapeSet};
+use crate::{GameSide, GsExtraData};
+
+/// A block variant used in a structure, referred to by name so that templates can be shared between worlds.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct StructureBlock {
+    /// The block type.
+    pub name: RegistryName,
+    /// The block metadata.
+    pub metadata: BlockMetadata,
+}
+
+/// The blocks of a structure, as indices into its palette.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StructureBlocks {
+    /// Every block of the bounding box, in XZY order.
+    Dense(Vec<u16>),
+    /// Only the listed blocks, as (index in the XZY order of the bounding box, palette index) pairs sorted by the
+    /// index. The rest of the bounding box is left untouched on placement.
+    Sparse(Vec<(u32, u16)>),
+}
+
+/// Error while capturing, placing or (de)serializing a structure.
+#[derive(Clone, Debug, Error)]
+pub enum StructureError {
+    /// Low level data encoding error.
+    #[error("Low level data encoding error {0}")]
+    SchemaError(#[from] capnp::Error),
+    /// A block of the captured region is in a chunk that is not loaded.
+    #[error("The block at {0:?} is not loaded")]
+    NotLoaded(AbsBlockPos),
+    /// A captured block ID is not in the block registry.
+    #[error("Unregistered block ID {0}")]
+    UnknownBlockId(RegistryId),
+    /// A block of the structure is not in the block registry.
+    #[error("Unknown block {0}")]
+    UnknownBlock(RegistryName),
+    /// The structure uses more block variants than palette indices can refer to.
+    #[error("The structure uses more than {} block variants", u16::MAX as usize + 1)]
+    PaletteOverflow,
+    /// The structure data is inconsistent.
+    #[error("Malformed structure data: {0}")]
+    Malformed(&'static str),
+}
+
+/// A block arrangement with a bounding box and an origin (anchor) block it is placed by.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StructureTemplate {
+    size: IVec3,
+    origin: IVec3,
+    palette: Vec<StructureBlock>,
+    blocks: StructureBlocks,
+}
+
+/// The number of blocks in a bounding box of the given (non-negative) size, which has to fit in an `i32` so that
+/// every index and position within the box can be converted without overflowing.
+fn volume(size: IVec3) -> Result<usize, StructureError> {
+    size.x
+        .checked_mul(size.y)
+        .and_then(|area| area.checked_mul(size.z))
+        .map(|volume| volume as usize)
+        .ok_or(StructureError::Malformed("The structure has more than i32::MAX blocks"))
+}
+
+/// The index of a position within a bounding box whose volume was checked with [`volume`].
+fn index_of(size: IVec3, pos: IVec3) -> usize {
+    let (size, pos) = (size.as_uvec3(), pos.as_uvec3());
+    (pos.x + size.x * (pos.z + size.z * pos.y)) as usize
+}
+
+/// The position of an index within a bounding box whose volume was checked with [`volume`].
+fn position_of(size: IVec3, index: usize) -> IVec3 {
+    let (width, depth) = (size.x as usize, size.z as usize);
+    IVec3::new(
+        (index % width) as i32,
+        (index / (width * depth)) as i32,
+        (index / width % depth) as i32,
+    )
+}
+
+impl StructureTemplate {
+    /// Constructs a template, checking that the block data matches the size and the palette.
+    pub fn new(
+        size: IVec3,
+        origin: IVec3,
+        palette: Vec<StructureBlock>,
+        blocks: StructureBlocks,
+    ) -> Result<Self, StructureError> {
+        if size.min_element() < 0 {
+            return Err(StructureError::Malformed("Negative size"));
+        }
+        if palette.len() > u16::MAX as usize + 1 {
+            return Err(StructureError::PaletteOverflow);
+        }
+        let volume = volume(size)?;
+        let in_palette = |&block: &u16| (block as usize) < palette.len();
+        match &blocks {
+            StructureBlocks::Dense(data) => {
+                if data.len() != volume {
+                    return Err(StructureError::Malformed("The block data doesn't match the size"));
+                }
+                if !data.iter().all(in_palette) {
+                    return Err(StructureError::Malformed("Palette index out of range"));
+                }
+            }
+            StructureBlocks::Sparse(data) => {
+                if !data.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+                    return Err(StructureError::Malformed("Sparse block indices are not increasing"));
+                }
+                if data.last().is_some_and(|&(index, _)| index as usize >= volume) {
+                    return Err(StructureError::Malformed("Sparse block index out of range"));
+                }
+                if !data.iter().map(|(_, block)| block).all(in_palette) {
+                    return Err(StructureError::Malformed("Palette index out of range"));
+                }
+            }
+        }
+        Ok(Self {
+            size,
+            origin,
+            palette,
+            blocks,
+        })
+    }
+
+    /// Captures the blocks between two (inclusive) corners, to be placed by the given origin block.
+    /// All the chunks of the region have to be loaded, and it can have at most `i32::MAX` blocks.
+    pub fn capture<ED: GsExtraData>(
+        group: &ChunkGroup<ED>,
+        a: AbsBlockPos,
+        b: AbsBlockPos,
+        origin: AbsBlockPos,
+        registry: &BlockRegistry,
+    ) -> Result<Self, StructureError> {
+        let (a, b) = (IVec3::from(a), IVec3::from(b));
+        let (min, max) = (a.min(b), a.max(b));
+        let size = IVec3::try_from(max.as_i64vec3() - min.as_i64vec3() + I64Vec3::ONE)
+            .map_err(|_| StructureError::Malformed("The structure has more than i32::MAX blocks"))?;
+        let volume = volume(size)?;
+        let (min_chunk, max_chunk) = (
+            AbsChunkPos::from(AbsBlockPos::from(min)),
+            AbsChunkPos::from(AbsBlockPos::from(max)),
+        );
+        for (y, z, x) in iproduct!(
+            min_chunk.y..=max_chunk.y,
+            min_chunk.z..=max_chunk.z,
+            min_chunk.x..=max_chunk.x
+        ) {
+            let cpos = AbsChunkPos::new(x, y, z);
+            if group.get_chunk(cpos).is_none() {
+                let first = IVec3::from(AbsBlockPos::from(cpos)).max(min);
+                return Err(StructureError::NotLoaded(AbsBlockPos::from(first)));
+            }
+        }
+
+        let mut palette = Vec::new();
+        let mut palette_indices: HashMap<BlockEntry, u16> = HashMap::new();
+        let mut data = Vec::with_capacity(volume);
+        for (y, z, x) in iproduct!(min.y..=max.y, min.z..=max.z, min.x..=max.x) {
+            let pos = AbsBlockPos::new(x, y, z);
+            let (cpos, ipos) = pos.split_chunk_component();
+            let chunk = group.get_chunk(cpos).ok_or(StructureError::NotLoaded(pos))?;
+            let block = chunk.blocks.get_copy(ipos);
+            let index = match palette_indices.get(&block) {
+                Some(&index) => index,
+                None => {
+                    let index = u16::try_from(palette.len()).map_err(|_| StructureError::PaletteOverflow)?;
+                    let definition = registry
+                        .lookup_id_to_object(block.id)
+                        .ok_or(StructureError::UnknownBlockId(block.id))?;
+                    palette.push(StructureBlock {
+                        name: definition.name.clone(),
+                        metadata: block.metadata,
+                    });
+                    palette_indices.insert(block, index);
+                    index
+                }
+            };
+            data.push(index);
+        }
+
+        Ok(Self {
+            size,
+            origin: IVec3::from(origin) - min,
+            palette,
+            blocks: StructureBlocks::Dense(data),
+        })
+    }
+
+    /// Size of the bounding box, in blocks.
+    pub fn size(&self) -> IVec3 {
+        self.size
+    }
+
+    /// Position of the origin block, relative to the minimum corner of the bounding box.
+    pub fn origin(&self) -> IVec3 {
+        self.origin
+    }
+
+    /// The block variants used in the structure.
+    pub fn palette(&self) -> &[StructureBlock] {
+        &self.palette
+    }
+
+    /// The blocks of the structure, as indices into the palette.
+    pub fn block_data(&self) -> &StructureBlocks {
+        &self.blocks
+    }
+
+    /// The furthest a block of the bounding box is from the origin along any axis.
+    pub fn reach(&self) -> i32 {
+        if self.size.min_element() == 0 {
+            return 0;
+        }
+        self.origin
+            .max(self.size - IVec3::ONE - self.origin)
+            .max_element()
+            .max(0)
+    }
+
+    /// Iterates over the (index in the XZY order of the bounding box, palette index) pairs of the structure's blocks.
+    fn indexed_blocks(&self) -> Box<dyn Iterator<Item = (usize, u16)> + '_> {
+        match &self.blocks {
+            StructureBlocks::Dense(data) => Box::new(data.iter().copied().enumerate()),
+            StructureBlocks::Sparse(data) => Box::new(data.iter().map(|&(index, block)| (index as usize, block))),
+        }
+    }
+
+    /// Iterates over the blocks of the structure, with their positions relative to the origin.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = (IVec3, &StructureBlock)> + '_ {
+        self.indexed_blocks().map(|(index, block)| {
+            (
+                position_of(self.size, index) - self.origin,
+                &self.palette[block as usize],
+            )
+        })
+    }
+
+    /// Drops the blocks of the given type from the structure, so that they leave the world untouched on placement.
+    /// Typically used with the empty block, to place a building without carving out the terrain around it.
+    pub fn without_block(&self, name: &RegistryName) -> Self {
+        let kept = |&(_, block): &(u32, u16)| self.palette[block as usize].name != *name;
+        let data = match &self.blocks {
+            StructureBlocks::Dense(data) => (0..).zip(data.iter().copied()).filter(kept).collect(),
+            StructureBlocks::Sparse(data) => data.iter().copied().filter(kept).collect(),
+        };
+        Self {
+            blocks: StructureBlocks::Sparse(data),
+            ..self.clone()
+        }
+    }
+
+    /// Rotates the structure around its origin, turning its X+, Y+ and Z+ axes to the orientation's right, up and
+    /// front directions. The orientations of standard-shaped blocks are rotated along with it.
+    pub fn rotated(&self, orientation: OctahedralOrientation, registry: &BlockRegistry) -> Self {
+        let turn = |v: IVec3| orientation.apply_to_ivec(v);
+        let turn_dir = |dir: Direction| Direction::try_from_ivec(turn(dir.to_ivec())).unwrap();
+        if self.size.min_element() == 0 {
+            return self.clone();
+        }
+
+        let far_corner = turn(self.size - IVec3::ONE);
+        let min = far_corner.min(IVec3::ZERO);
+        let size = far_corner.abs() + IVec3::ONE;
+        let new_index = |index: usize| index_of(size, turn(position_of(self.size, index)) - min);
+
+        let palette = self
+            .palette
+            .iter()
+            .map(|block| {
+                let standard_shaped = registry
+                    .lookup_name_to_object(block.name.as_ref())
+                    .is_some_and(|(_, def)| def.shape_set == BlockShapeSet::StandardShapedMaterial);
+                if !standard_shaped {
+                    return block.clone();
+                }
+                let shape = StandardShapeMetadata::from_meta(block.metadata);
+                let old = shape.orientation();
+                let new = OctahedralOrientation::from_right_up(turn_dir(old.right()), turn_dir(old.up())).unwrap();
+                let metadata = StandardShapeMetadata::from_parts(shape.shape_bits(), new.to_index() as u16)
+                    .map_or(block.metadata, StandardShapeMetadata::to_meta);
+                StructureBlock {
+                    name: block.name.clone(),
+                    metadata,
+                }
+            })
+            .collect();
+        let blocks = match &self.blocks {
+            StructureBlocks::Dense(data) => {
+                let mut rotated = vec![0; data.len()];
+                for (index, &block) in data.iter().enumerate() {
+                    rotated[new_index(index)] = block;
+                }
+                StructureBlocks::Dense(rotated)
+            }
+            StructureBlocks::Sparse(data) => {
+                let mut rotated: Vec<_> = data
+                    .iter()
+                    .map(|&(index, block)| (new_index(index as usize) as u32, block))
+                    .collect();
+                rotated.sort_unstable();
+                StructureBlocks::Sparse(rotated)
+            }
+        };
+
+        Self {
+            size,
+            origin: turn(self.origin) - min,
+            palette,
+            blocks,
+        }
+    }
+
+    /// Resolves the block types of the palette in the given registry.
+    pub fn resolve_palette(&self, registry: &BlockRegistry) -> Result<Vec<BlockEntry>, StructureError> {
+        self.palette
+            .iter()
+            .map(|block| {
+                registry
+                    .lookup_name_to_object(block.name.as_ref())
+                    .map(|(id, _)| BlockEntry::new(id, block.metadata))
+                    .ok_or_else(|| StructureError::UnknownBlock(block.name.clone()))
+            })
+            .collect()
+    }
+
+    /// The block changes placing the structure with its origin at the given position.
+    pub fn placement_edits(
+        &self,
+        at: AbsBlockPos,
+        registry: &BlockRegistry,
+    ) -> Result<Vec<(AbsBlockPos, BlockEntry)>, StructureError> {
+        let palette = self.resolve_palette(registry)?;
+        let at = IVec3::from(at);
+        Ok(self
+            .indexed_blocks()
+            .map(|(index, block)| {
+                let pos = at + position_of(self.size, index) - self.origin;
+                (AbsBlockPos::from(pos), palette[block as usize])
+            })
+            .collect())
+    }
+
+    /// Places the structure with its origin at the given position, skipping the blocks in chunks that are not loaded.
+    /// Returns the number of blocks placed.
+    pub fn paste<ED: GsExtraData>(
+        &self,
+        group: &mut ChunkGroup<ED>,
+        at: AbsBlockPos,
+        registry: &BlockRegistry,
+        side: GameSide,
+    ) -> Result<usize, StructureError> {
+        let edits = self.placement_edits(at, registry)?;
+        Ok(group.apply_block_edits(&edits, side))
+    }
+
+    /// Serializes the structure into a capnp message.
+    pub fn write_to_message(&self, builder: &mut structure_template::Builder) {
+        fn write_ivec3(mut builder: i_vec3::Builder, v: IVec3) {
+            builder.set_x(v.x);
+            builder.set_y(v.y);
+            builder.set_z(v.z);
+        }
+        write_ivec3(builder.reborrow().init_size(), self.size);
+        write_ivec3(builder.reborrow().init_origin(), self.origin);
+        let mut names = builder.reborrow().init_palette_names(self.palette.len() as u32);
+        for (i, block) in self.palette.iter().enumerate() {
+            let mut name = names.reborrow().get(i as u32);
+            name.set_ns(&block.name.ns);
+            name.set_key(&block.name.key);
+        }
+        let mut metadata = builder.reborrow().init_palette_metadata(self.palette.len() as u32);
+        for (i, block) in self.palette.iter().enumerate() {
+            metadata.set(i as u32, block.metadata);
+        }
+        match &self.blocks {
+            StructureBlocks::Dense(data) => {
+                let mut dense = builder.reborrow().init_dense(data.len() as u32);
+                for (i, &block) in data.iter().enumerate() {
+                    dense.set(i as u32, block);
+                }
+            }
+            StructureBlocks::Sparse(data) => {
+                let mut sparse = builder.reborrow().init_sparse();
+                let mut indices = sparse.reborrow().init_indices(data.len() as u32);
+                for (i, &(index, _)) in data.iter().enumerate() {
+                    indices.set(i as u32, index);
+                }
+                let mut blocks = sparse.init_blocks(data.len() as u32);
+                for (i, &(_, block)) in data.iter().enumerate() {
+                    blocks.set(i as u32, block);
+                }
+            }
+        }
+    }
+
+    /// Deserializes a structure from a capnp message.
+    pub fn read_from_message(reader: &structure_template::Reader) -> Result<Self, StructureError> {
+        fn read_ivec3(reader: i_vec3::Reader) -> IVec3 {
+            IVec3::new(reader.get_x(), reader.get_y(), reader.get_z())
+        }
+        let size = read_ivec3(reader.get_size()?);
+        let origin = read_ivec3(reader.get_origin()?);
+        let names = reader.get_palette_names()?;
+        let metadata = reader.get_palette_metadata()?;
+        if names.len() != metadata.len() {
+            return Err(StructureError::Malformed("Palette lists of different lengths"));
+        }
+        let palette = names
+            .iter()
+            .zip(metadata.iter())
+            .map(|(name, metadata)| -> capnp::Result<_> {
+                Ok(StructureBlock {
+                    name: RegistryName::new(name.get_ns()?.to_str()?, name.get_key()?.to_str()?),
+                    metadata,
+                })
+            })
+            .collect::<capnp::Result<_>>()?;
+        let blocks = match reader.which().map_err(capnp::Error::from)? {
+            structure_template::Dense(data) => StructureBlocks::Dense(data?.iter().collect()),
+            structure_template::Sparse(sparse) => {
+                let sparse = sparse?;
+                let (indices, blocks) = (sparse.get_indices()?, sparse.get_blocks()?);
+                if indices.len() != blocks.len() {
+                    return Err(StructureError::Malformed("Sparse block lists of different lengths"));
+                }
+                StructureBlocks::Sparse(indices.iter().zip(blocks.iter()).collect())
+            }
+        };
+        Self::new(size, origin, palette, blocks)
+    }
+
+    /// Serializes the structure into a byte array, the format of structure files.
+    pub fn write_to_bytes(&self) -> Vec<u8> {
+        let mut builder = TypedBuilder::<structure_template::Owned>::new_default();
+        self.write_to_message(&mut builder.init_root());
+        let mut buffer = Vec::new();
+        capnp::serialize::write_message(&mut buffer, builder.borrow_inner()).unwrap();
+        buffer
+    }
+
+    /// Deserializes a structure from a byte array.
+    pub fn read_from_bytes(bytes: &[u8], options: ReaderOptions) -> Result<Self, StructureError> {
+        let mut bytes_ref = bytes;
+        let msg = capnp::serialize::read_message_from_flat_slice_no_alloc(&mut bytes_ref, options)?;
+        let typed = TypedReader::<_, structure_template::Owned>::new(msg);
+        Self::read_from_message(&typed.get()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rgb::RGBA8;
+
+    use super::*;
+    use crate::mutwatcher::MutWatcher;
+    use crate::voxel::chunk::Chunk;
+    use crate::voxel::standard_shapes::{STANDARD_SHAPE_CUBE, STANDARD_SHAPE_SLOPE};
+    use crate::voxel::voxeltypes::{BlockDefinition, EMPTY_BLOCK};
+
+    struct TestData;
+
+    impl GsExtraData for TestData {
+        type ChunkData = ();
+        type GroupData = ();
+
+        const SIDE: GameSide = GameSide::Server;
+    }
+
+    fn registry() -> BlockRegistry {
+        let mut registry = BlockRegistry::default();
+        registry.push_object(EMPTY_BLOCK.clone()).unwrap();
+        for (key, shape_set) in [
+            ("stone", BlockShapeSet::StandardShapedMaterial),
+            ("glass", BlockShapeSet::FullCubeOnly),
+        ] {
+            registry
+                .push_object(BlockDefinition {
+                    name: RegistryName::new("test", key),
+                    shape_set,
+                    representative_color: RGBA8::new(0, 0, 0, 255),
+                    has_collision_box: true,
+                    has_drawable_mesh: true,
+                })
+                .unwrap();
+        }
+        registry
+    }
+
+    fn entry(registry: &BlockRegistry, key: &str, metadata: BlockMetadata) -> BlockEntry {
+        let (id, _) = registry
+            .lookup_name_to_object(RegistryName::new("test", key).as_ref())
+            .unwrap();
+        BlockEntry::new(id, metadata)
+    }
+
+    /// Two empty chunks along the X axis, with an L shape of stone crossing the border between them.
+    fn world(registry: &BlockRegistry) -> ChunkGroup<TestData> {
+        let air = BlockEntry::new(registry.lookup_name_to_object(EMPTY_BLOCK.name.as_ref()).unwrap().0, 0);
+        let mut group = ChunkGroup::new();
+        for x in [0, 1] {
+            group
+                .chunks
+                .insert(AbsChunkPos::new(x, 0, 0), MutWatcher::new(Chunk::new(air, ())));
+        }
+        let stone = entry(registry, "stone", 0);
+        let slope = entry(
+            registry,
+            "stone",
+            StandardShapeMetadata::from_parts(STANDARD_SHAPE_SLOPE, 0)
+                .unwrap()
+                .to_meta(),
+        );
+        let edits = [
+            (AbsBlockPos::new(30, 0, 0), stone),
+            (AbsBlockPos::new(31, 0, 0), stone),
+            (AbsBlockPos::new(32, 0, 0), stone),
+            (AbsBlockPos::new(32, 1, 0), slope),
+        ];
+        group.apply_block_edits(&edits, GameSide::Server);
+        group
+    }
+
+    #[test]
+    fn capture_and_paste() {
+        let registry = registry();
+        let mut group = world(&registry);
+        let template = StructureTemplate::capture(
+            &group,
+            AbsBlockPos::new(30, 0, 0),
+            AbsBlockPos::new(32, 1, 1),
+            AbsBlockPos::new(30, 0, 0),
+            &registry,
+        )
+        .unwrap();
+        assert_eq!(template.size(), IVec3::new(3, 2, 2));
+        assert_eq!(template.reach(), 2);
+        assert_eq!(template.palette().len(), 3);
+
+        let sparse = template.without_block(&EMPTY_BLOCK.name);
+        assert_eq!(sparse.iter_blocks().count(), 4);
+        let placed = sparse
+            .paste(&mut group, AbsBlockPos::new(30, 0, 1), &registry, GameSide::Server)
+            .unwrap();
+        assert_eq!(placed, 4);
+        for x in 30..=32 {
+            assert_eq!(
+                group.get_block(AbsBlockPos::new(x, 0, 1)),
+                group.get_block(AbsBlockPos::new(x, 0, 0))
+            );
+        }
+        assert_eq!(
+            group.get_block(AbsBlockPos::new(32, 1, 1)),
+            group.get_block(AbsBlockPos::new(32, 1, 0))
+        );
+
+        assert!(matches!(
+            StructureTemplate::capture(
+                &group,
+                AbsBlockPos::new(0, 0, 0),
+                AbsBlockPos::new(0, 40, 0),
+                AbsBlockPos::new(0, 0, 0),
+                &registry,
+            ),
+            Err(StructureError::NotLoaded(_))
+        ));
+        assert!(matches!(
+            StructureTemplate::capture(
+                &group,
+                AbsBlockPos::new(i32::MIN, 0, 0),
+                AbsBlockPos::new(i32::MAX, 0, 0),
+                AbsBlockPos::new(0, 0, 0),
+                &registry,
+            ),
+            Err(StructureError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn rotation() {
+        let registry = registry();
+        let group = world(&registry);
+        let template = StructureTemplate::capture(
+            &group,
+            AbsBlockPos::new(30, 0, 0),
+            AbsBlockPos::new(32, 1, 0),
+            AbsBlockPos::new(30, 0, 0),
+            &registry,
+        )
+        .unwrap()
+        .without_block(&EMPTY_BLOCK.name);
+
+        // A quarter turn around the Y axis turns X+ into Z-.
+        let quarter = OctahedralOrientation::from_right_up(Direction::ZMinus, Direction::YPlus).unwrap();
+        let rotated = template.rotated(quarter, &registry);
+        assert_eq!(rotated.size(), IVec3::new(1, 2, 3));
+        assert_eq!(rotated.reach(), 2);
+        let blocks: HashMap<IVec3, &StructureBlock> = rotated.iter_blocks().collect();
+        assert_eq!(blocks.len(), 4);
+        let slope = blocks[&IVec3::new(0, 1, -2)];
+        assert_eq!(StandardShapeMetadata::from_meta(slope.metadata).orientation(), quarter);
+        let cube = StandardShapeMetadata::from_meta(blocks[&IVec3::new(0, 0, -1)].metadata);
+        assert_eq!(cube.shape_bits(), STANDARD_SHAPE_CUBE);
+
+        let mut full_turn = template.clone();
+        for _ in 0..4 {
+            full_turn = full_turn.rotated(quarter, &registry);
+        }
+        assert_eq!(full_turn, template);
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        let registry = registry();
+        let group = world(&registry);
+        let template = StructureTemplate::capture(
+            &group,
+            AbsBlockPos::new(29, 0, 0),
+            AbsBlockPos::new(33, 2, 1),
+            AbsBlockPos::new(31, 0, 0),
+            &registry,
+        )
+        .unwrap();
+        for template in [template.clone(), template.without_block(&EMPTY_BLOCK.name)] {
+            let bytes = template.write_to_bytes();
+            let read = StructureTemplate::read_from_bytes(&bytes, ReaderOptions::new()).unwrap();
+            assert_eq!(read, template);
+        }
+
+        let malformed = StructureTemplate::new(
+            IVec3::new(2, 2, 2),
+            IVec3::ZERO,
+            template.palette().to_vec(),
+            StructureBlocks::Dense(vec![0; 7]),
+        );
+        assert!(matches!(malformed, Err(StructureError::Malformed(_))));
+        let too_large = StructureTemplate::new(
+            IVec3::new(1 << 16, 1 << 16, 1),
+            IVec3::ZERO,
+            template.palette().to_vec(),
+            StructureBlocks::Sparse(Vec::new()),
+        );
+        assert!(matches!(too_large, Err(StructureError::Malformed(_))));
+    }
+}